@@ -0,0 +1,411 @@
+//! Reassembling maps sharded over many blocks, `--adl`.
+//!
+//! A HAMT spreads the entries of one logical map over a tree of shard blocks.
+//! With `--adl` the shards of the inputs are found before the conversion, the
+//! shard at the root of each tree is written as one row holding the entries
+//! of the whole tree, and the shards below a root are left out. Shards are
+//! read through the resolver of inlined links, so the block cache keeps those
+//! decoded by the scan for reassembling the roots.
+
+use std::collections::HashSet;
+
+use anyhow::Result;
+use clap::ValueEnum;
+use libipld::{Cid, Ipld};
+use serde::{Deserialize, Serialize};
+
+use crate::{cache::Resolver, scan, ConvertArgs, Report, DAG_PB};
+
+/// Layouts whose shards are reassembled.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Adl {
+    /// UnixFS sharded directories, dag-pb nodes of type HAMTShard, written as
+    /// one node whose `Links` are the entries of every shard, named without
+    /// their bucket prefix.
+    UnixfsHamt,
+    /// HAMTs of go-hamt-ipld as in Filecoin state, dag-cbor nodes of a
+    /// bitfield and pointers, written as `{"entries": [{"key", "value"}]}`.
+    Hamt,
+}
+
+/// UnixFS node type of the shards of a HAMT directory.
+const HAMT_SHARD: u64 = 5;
+
+/// The shards of the inputs.
+pub(crate) struct Shards {
+    adl: Adl,
+    /// Shards linked from another shard, part of the tree of a root.
+    interior: HashSet<Cid>,
+    /// Number of those the inputs lack.
+    missing: usize,
+}
+
+impl Shards {
+    /// Finds the shards below other shards among the blocks of the resolver.
+    pub(crate) fn new(adl: Adl, args: &ConvertArgs, resolver: &Resolver) -> Result<Self> {
+        let mut interior = HashSet::new();
+        for cid in resolver.cids() {
+            if !adl.may_hold(cid) {
+                continue;
+            }
+            if let Some(node) = resolver.get(args, cid)? {
+                if let Some(shard) = adl.shard(&node) {
+                    interior.extend(shard.children());
+                }
+            }
+        }
+        let missing = interior.iter().filter(|c| !resolver.contains(c)).count();
+        Ok(Self {
+            adl,
+            interior,
+            missing,
+        })
+    }
+
+    /// Whether the block is a shard below another, left out of the output.
+    pub(crate) fn is_interior(&self, cid: &Cid) -> bool {
+        self.interior.contains(cid)
+    }
+
+    /// Rewrites the data of a root shard as the entries of its whole tree.
+    /// Other blocks are left as they are.
+    pub(crate) fn assemble(
+        &self,
+        args: &ConvertArgs,
+        resolver: &Resolver,
+        dag: &mut Ipld,
+    ) -> Result<()> {
+        let Some(shard) = self.adl.shard(dag) else {
+            return Ok(());
+        };
+        let mut entries = Vec::new();
+        let mut seen = HashSet::new();
+        self.collect(args, resolver, shard, &mut entries, &mut seen)?;
+        match (self.adl, dag) {
+            (Adl::UnixfsHamt, Ipld::Map(m)) => {
+                m.insert("Links".to_string(), Ipld::List(entries));
+            }
+            (Adl::Hamt, dag) => {
+                *dag = Ipld::Map([("entries".to_string(), Ipld::List(entries))].into());
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Adds the entries of the shard and the shards below it in the order of
+    /// their pointers.
+    fn collect(
+        &self,
+        args: &ConvertArgs,
+        resolver: &Resolver,
+        shard: Shard<'_>,
+        entries: &mut Vec<Ipld>,
+        seen: &mut HashSet<Cid>,
+    ) -> Result<()> {
+        for pointer in shard.pointers() {
+            match pointer {
+                Pointer::Entry(entry) => entries.push(entry),
+                Pointer::Child(cid) => {
+                    // A shard linked twice is a cycle or a corrupt tree.
+                    if !seen.insert(cid) {
+                        continue;
+                    }
+                    let child = resolver.get(args, &cid)?;
+                    // Shards the inputs lack are counted by the scan.
+                    if let Some(child) = child.as_deref().and_then(|node| self.adl.shard(node)) {
+                        self.collect(args, resolver, child, entries, seen)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds the shards left out, and the shards linked that the inputs lack,
+    /// to the report.
+    pub(crate) fn record(&self, report: &mut Report) {
+        report.adl_shards += self.interior.len() - self.missing;
+        report.adl_missing += self.missing;
+    }
+}
+
+impl Adl {
+    /// Whether blocks of the codec of the CID can be shards.
+    fn may_hold(self, cid: &Cid) -> bool {
+        match self {
+            Adl::UnixfsHamt => cid.codec() == DAG_PB,
+            Adl::Hamt => cid.codec() == 0x71,
+        }
+    }
+
+    /// The decoded block as a shard, `None` if it is not one.
+    fn shard(self, node: &Ipld) -> Option<Shard<'_>> {
+        match self {
+            Adl::UnixfsHamt => {
+                let Ok(Ipld::Bytes(data)) = node.get("Data") else {
+                    return None;
+                };
+                let (kind, fanout) = unixfs_kind(data)?;
+                if kind != HAMT_SHARD {
+                    return None;
+                }
+                let Ok(Ipld::List(links)) = node.get("Links") else {
+                    return None;
+                };
+                // Buckets are named by as many hex digits as the fanout needs.
+                let prefix = format!("{:X}", fanout.max(2) - 1).len();
+                Some(Shard::Unixfs { links, prefix })
+            }
+            Adl::Hamt => match node {
+                Ipld::List(node) => match node.as_slice() {
+                    [Ipld::Bytes(_), Ipld::List(pointers)] if pointers.iter().all(is_pointer) => {
+                        Some(Shard::Hamt { pointers })
+                    }
+                    _ => None,
+                },
+                _ => None,
+            },
+        }
+    }
+}
+
+/// A shard of a layout, borrowed from its decoded block.
+#[derive(Clone, Copy)]
+enum Shard<'a> {
+    Unixfs { links: &'a [Ipld], prefix: usize },
+    Hamt { pointers: &'a [Ipld] },
+}
+
+enum Pointer {
+    Entry(Ipld),
+    Child(Cid),
+}
+
+impl Shard<'_> {
+    fn pointers(self) -> Vec<Pointer> {
+        match self {
+            Shard::Unixfs { links, prefix } => links
+                .iter()
+                .filter_map(|link| {
+                    let Ipld::Map(link) = link else {
+                        return None;
+                    };
+                    let name = match link.get("Name") {
+                        Some(Ipld::String(name)) => name.as_str(),
+                        _ => "",
+                    };
+                    if name.len() <= prefix {
+                        return match link.get("Hash") {
+                            Some(Ipld::Link(cid)) => Some(Pointer::Child(*cid)),
+                            _ => None,
+                        };
+                    }
+                    let mut entry = link.clone();
+                    entry.insert(
+                        "Name".to_string(),
+                        Ipld::String(name.get(prefix..).unwrap_or(name).to_string()),
+                    );
+                    Some(Pointer::Entry(Ipld::Map(entry)))
+                })
+                .collect(),
+            Shard::Hamt { pointers } => pointers
+                .iter()
+                .flat_map(|pointer| match pointer {
+                    Ipld::Link(cid) => vec![Pointer::Child(*cid)],
+                    Ipld::List(kvs) => kvs
+                        .iter()
+                        .filter_map(|kv| match kv {
+                            Ipld::List(kv) => Some(Pointer::Entry(Ipld::Map(
+                                [
+                                    ("key".to_string(), kv[0].clone()),
+                                    ("value".to_string(), kv[1].clone()),
+                                ]
+                                .into(),
+                            ))),
+                            _ => None,
+                        })
+                        .collect(),
+                    _ => Vec::new(),
+                })
+                .collect(),
+        }
+    }
+
+    fn children(self) -> impl Iterator<Item = Cid> {
+        self.pointers().into_iter().filter_map(|p| match p {
+            Pointer::Child(cid) => Some(cid),
+            Pointer::Entry(_) => None,
+        })
+    }
+}
+
+/// Whether the value is a pointer of a go-hamt-ipld node: a link to a child
+/// node, or a bucket of `[key, value]` pairs with bytes keys.
+fn is_pointer(pointer: &Ipld) -> bool {
+    match pointer {
+        Ipld::Link(_) => true,
+        Ipld::List(bucket) => bucket.iter().all(|kv| match kv {
+            Ipld::List(kv) => kv.len() == 2 && matches!(kv[0], Ipld::Bytes(_)),
+            _ => false,
+        }),
+        _ => false,
+    }
+}
+
+/// The type and fanout of the UnixFS protobuf of a dag-pb node, fields 1 and
+/// 6, the fanout 256 if it is not given.
+fn unixfs_kind(data: &[u8]) -> Option<(u64, u64)> {
+    let (mut kind, mut fanout) = (None, 256);
+    let mut rest = data;
+    while !rest.is_empty() {
+        let (key, n) = scan::varint(rest)?;
+        rest = &rest[n..];
+        match key & 7 {
+            0 => {
+                let (value, n) = scan::varint(rest)?;
+                rest = &rest[n..];
+                match key >> 3 {
+                    1 => kind = Some(value),
+                    6 => fanout = value,
+                    _ => {}
+                }
+            }
+            2 => {
+                let (len, n) = scan::varint(rest)?;
+                rest = rest.get(n + usize::try_from(len).ok()?..)?;
+            }
+            _ => return None,
+        }
+    }
+    Some((kind?, fanout))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::PathBuf,
+        sync::{Arc, Mutex},
+    };
+
+    use libipld::{
+        cbor::DagCborCodec,
+        ipld,
+        multihash::{Code, MultihashDigest},
+        prelude::Codec,
+    };
+
+    use super::*;
+    use crate::{cache::Lru, plan::Unit};
+
+    fn cid(block: &Ipld) -> (Cid, Vec<u8>) {
+        let bytes = DagCborCodec.encode(block).unwrap();
+        (Cid::new_v1(0x71, Code::Sha2_256.digest(&bytes)), bytes)
+    }
+
+    fn car(name: &str, blocks: &[&Ipld]) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("carquet-adl-{}-{}.car", std::process::id(), name));
+        let mut car = Vec::new();
+        scan::write_header(&mut car, &[cid(blocks[0]).0]).unwrap();
+        for block in blocks {
+            let (cid, bytes) = cid(block);
+            scan::write_section(&mut car, Some(&cid), &bytes).unwrap();
+        }
+        std::fs::write(&path, car).unwrap();
+        path
+    }
+
+    #[test]
+    fn hamt_trees_are_assembled_at_their_root() {
+        let leaf = ipld!([Ipld::Bytes(vec![1]), [[[Ipld::Bytes(b"k2".to_vec()), 2]]]]);
+        let missing = cid(&ipld!("missing")).0;
+        let root = ipld!([
+            Ipld::Bytes(vec![7]),
+            [[[Ipld::Bytes(b"k1".to_vec()), 1]], cid(&leaf).0, missing]
+        ]);
+        let other = ipld!({"v": 1});
+        let path = car("hamt", &[&root, &leaf, &other]);
+        let args = ConvertArgs::from_flags(["--adl", "hamt"]).unwrap();
+        let cache = Arc::new(Mutex::new(Lru::new(1 << 20)));
+        let resolver = Resolver::new(&args, &[Unit::whole(&path)], cache).unwrap();
+        let shards = Shards::new(Adl::Hamt, &args, &resolver).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(shards.is_interior(&cid(&leaf).0));
+        assert!(!shards.is_interior(&cid(&root).0));
+        assert_eq!((shards.interior.len(), shards.missing), (2, 1));
+
+        let mut dag = root;
+        shards.assemble(&args, &resolver, &mut dag).unwrap();
+        let entries = ipld!({"entries": [
+            {"key": Ipld::Bytes(b"k1".to_vec()), "value": 1},
+            {"key": Ipld::Bytes(b"k2".to_vec()), "value": 2},
+        ]});
+        assert_eq!(dag, entries);
+        let mut dag = other.clone();
+        shards.assemble(&args, &resolver, &mut dag).unwrap();
+        assert_eq!(dag, other);
+    }
+
+    #[test]
+    fn hamt_nodes_need_a_bitfield_and_pointers() {
+        let link = cid(&ipld!(null)).0;
+        let shard = ipld!([Ipld::Bytes(vec![]), [link, [[Ipld::Bytes(vec![1]), null]]]]);
+        assert!(Adl::Hamt.shard(&shard).is_some());
+        for node in [
+            ipld!([Ipld::Bytes(vec![]), [1]]),
+            ipld!([Ipld::Bytes(vec![]), [[["key", 1]]]]),
+            ipld!([Ipld::Bytes(vec![]), [[[Ipld::Bytes(vec![1])]]]]),
+            ipld!(["bitfield", []]),
+            ipld!({"Links": []}),
+        ] {
+            assert!(Adl::Hamt.shard(&node).is_none(), "{:?}", node);
+        }
+    }
+
+    #[test]
+    fn unixfs_shards_strip_the_bucket_of_the_names() {
+        let (child, file) = (cid(&ipld!(1)).0, cid(&ipld!(2)).0);
+        // Type 5 and a fanout of 16, named by one hex digit.
+        let node = ipld!({
+            "Data": Ipld::Bytes(vec![0x08, 0x05, 0x30, 0x10]),
+            "Links": [
+                {"Name": "0", "Hash": child},
+                {"Name": "Afile.txt", "Hash": file, "Tsize": 3},
+            ],
+        });
+        let shard = Adl::UnixfsHamt.shard(&node).unwrap();
+        assert_eq!(shard.children().collect::<Vec<_>>(), [child]);
+        let entries: Vec<_> = shard
+            .pointers()
+            .into_iter()
+            .filter_map(|p| match p {
+                Pointer::Entry(e) => Some(e),
+                Pointer::Child(_) => None,
+            })
+            .collect();
+        assert_eq!(
+            entries,
+            [ipld!({"Name": "file.txt", "Hash": file, "Tsize": 3})]
+        );
+        // Directories that are not sharded are left as they are.
+        let dir = ipld!({"Data": Ipld::Bytes(vec![0x08, 0x01]), "Links": []});
+        assert!(Adl::UnixfsHamt.shard(&dir).is_none());
+    }
+
+    #[test]
+    fn unixfs_data_gives_the_type_and_fanout() {
+        assert_eq!(unixfs_kind(&[0x08, 0x05]), Some((5, 256)));
+        assert_eq!(unixfs_kind(&[0x08, 0x05, 0x30, 0x80, 0x02]), Some((5, 256)));
+        // Bytes fields are skipped.
+        assert_eq!(
+            unixfs_kind(&[0x12, 0x02, 0xaa, 0xbb, 0x08, 0x05, 0x30, 0x04]),
+            Some((5, 4))
+        );
+        assert_eq!(unixfs_kind(&[0x30, 0x10]), None);
+        assert_eq!(unixfs_kind(&[0x12, 0x05, 0xaa]), None);
+        assert_eq!(unixfs_kind(&[0x0d, 0, 0, 0, 0]), None);
+    }
+}
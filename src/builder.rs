@@ -0,0 +1,117 @@
+//! Construction of schemas in code, e.g. to pin the schemas of generated data.
+//!
+//! ```
+//! use carquet::{Schema, SchemaBuilder};
+//!
+//! let schema = SchemaBuilder::new()
+//!     .field("height", Schema::Integer)
+//!     .list("tags", Schema::String)
+//!     .map("author", SchemaBuilder::new().field("name", Schema::String))
+//!     .build_block()?;
+//! let parquet = schema.to_parquet()?;
+//! assert_eq!(parquet.get_fields().len(), 2);
+//! # Ok::<(), carquet::Error>(())
+//! ```
+//!
+//! The fields are sorted by name as inferred schemas are, so a built schema
+//! equals the one inferred from blocks of the same shape. Block schemas saved
+//! as [`crate::Pins`] are read by `--pin-schemas`.
+
+use crate::{field_path, Error, Schema};
+
+/// Builder of the schema of a map, checked to be writable when built.
+#[derive(Debug, Default, Clone)]
+pub struct SchemaBuilder {
+    fields: Vec<(String, Schema)>,
+}
+
+impl SchemaBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a field of the given schema.
+    pub fn field(mut self, name: impl Into<String>, schema: Schema) -> Self {
+        self.fields.push((name.into(), schema));
+        self
+    }
+
+    /// Adds a field of the given schema that values may lack, as in merged schemas.
+    pub fn optional(self, name: impl Into<String>, schema: Schema) -> Self {
+        self.field(name, Schema::Optional(Box::new(schema)))
+    }
+
+    /// Adds a list field of elements of the given schema.
+    pub fn list(self, name: impl Into<String>, element: Schema) -> Self {
+        self.field(name, Schema::List(Box::new(element)))
+    }
+
+    /// Adds a map field with the fields of another builder.
+    pub fn map(self, name: impl Into<String>, fields: SchemaBuilder) -> Self {
+        self.field(name, Schema::Map(fields.fields))
+    }
+
+    /// The schema of the map.
+    pub fn build(mut self) -> Result<Schema, Error> {
+        sort(&mut self.fields);
+        let schema = Schema::Map(self.fields);
+        validate(&schema, "")?;
+        Ok(schema)
+    }
+
+    /// The schema of the rows of a block table, with the map as the `data`
+    /// of each block and its CID as `cid`, as pinned schemas are.
+    pub fn build_block(self) -> Result<Schema, Error> {
+        let data = self.build()?;
+        Ok(Schema::Map(vec![
+            ("cid".to_string(), Schema::Link),
+            ("data".to_string(), data),
+        ]))
+    }
+}
+
+fn sort(fields: &mut [(String, Schema)]) {
+    fields.sort_by(|(a, _), (b, _)| a.cmp(b));
+    for (_, schema) in fields {
+        let mut schema = schema;
+        while let Schema::List(element) | Schema::Optional(element) = schema {
+            schema = element;
+        }
+        if let Schema::Map(m) = schema {
+            sort(m);
+        }
+    }
+}
+
+/// Checks the schema, at `path`, can be mapped to parquet columns.
+fn validate(schema: &Schema, path: &str) -> Result<(), Error> {
+    let invalid = |reason: &str| Error::Schema {
+        path: path.to_string(),
+        reason: reason.to_string(),
+    };
+    match schema {
+        Schema::List(l) => validate(l, path),
+        Schema::Optional(s) => match &**s {
+            Schema::Optional(_) => Err(invalid("an optional field cannot be optional again")),
+            s => validate(s, path),
+        },
+        Schema::Map(m) if m.is_empty() => Err(invalid("a map needs at least one field")),
+        Schema::Map(m) => {
+            for (i, (k, v)) in m.iter().enumerate() {
+                if k.is_empty() || k.contains('.') {
+                    return Err(invalid(&format!(
+                        "field name {:?} must be non-empty and without dots, which separate paths",
+                        k
+                    )));
+                }
+                // The fields are sorted, so a repeated name follows itself.
+                if i > 0 && m[i - 1].0 == *k {
+                    return Err(invalid(&format!("field {} is repeated", k)));
+                }
+                validate(v, &field_path(path, k))?;
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
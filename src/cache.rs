@@ -0,0 +1,405 @@
+//! Resolving links to the blocks of the inputs, for `--link-as PATH=inline`
+//! and `--adl`.
+//!
+//! The inputs are scanned once for the offset of the section of each CID, and
+//! a link is resolved by reading its block again from there and decoding it.
+//! Decoded blocks are kept in a [`BlockCache`], so a block linked from many
+//! others is decoded once while it stays in the cache. [`Lru`], the cache of
+//! `carquet convert`, keeps the most recently used blocks up to a number of
+//! bytes and can move the blocks it evicts to a file in a directory, from
+//! where they are read back without going through the CAR.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::{BufReader, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use anyhow::{anyhow, Context, Result};
+use libipld::{
+    cbor::DagCborCodec,
+    multihash::{Code, Multihash, MultihashDigest},
+    prelude::Codec,
+    Cid, Ipld,
+};
+
+use crate::{bytes_hash, carv2, plan::Unit, scan::Sections, ConvertArgs, Duplicates, Report, RAW};
+
+/// Decoded blocks kept between the links resolved to them.
+pub trait BlockCache: Send {
+    /// The block of the CID, if it is kept.
+    fn get(&mut self, cid: &Cid) -> Option<Arc<Ipld>>;
+
+    /// Keeps the block, whose section holds `size` bytes of data.
+    fn insert(&mut self, cid: Cid, block: Arc<Ipld>, size: usize);
+}
+
+/// The most recently used blocks up to a number of bytes of their data, the
+/// others moved to a file if there is a directory for it.
+pub struct Lru {
+    capacity: usize,
+    size: usize,
+    tick: u64,
+    blocks: HashMap<Cid, (Arc<Ipld>, usize, u64)>,
+    /// CIDs of the blocks by when they were last used.
+    used: BTreeMap<u64, Cid>,
+    spill: Option<Spill>,
+}
+
+impl Lru {
+    /// A cache of blocks of up to `capacity` bytes of data.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            size: 0,
+            tick: 0,
+            blocks: HashMap::new(),
+            used: BTreeMap::new(),
+            spill: None,
+        }
+    }
+
+    /// Moves the blocks evicted to a file created in `dir`, and removed with
+    /// the cache.
+    pub fn spill_to(mut self, dir: &Path) -> Result<Self> {
+        std::fs::create_dir_all(dir).context(format!("creating {}", dir.display()))?;
+        let path = dir.join(format!("blocks-{}.spill", std::process::id()));
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .context(format!("creating {}", path.display()))?;
+        self.spill = Some(Spill {
+            path,
+            file,
+            end: 0,
+            blocks: HashMap::new(),
+        });
+        Ok(self)
+    }
+
+    fn evict(&mut self) {
+        while self.size > self.capacity {
+            let Some((_, cid)) = self.used.pop_first() else {
+                break;
+            };
+            let (block, size, _) = self.blocks.remove(&cid).expect("used block");
+            self.size -= size;
+            if let Some(spill) = &mut self.spill {
+                // Blocks dag-cbor cannot encode, such as integers beyond 64
+                // bits, are read from the CAR again instead.
+                let _ = spill.write(cid, &block, size);
+            }
+        }
+    }
+}
+
+impl BlockCache for Lru {
+    fn get(&mut self, cid: &Cid) -> Option<Arc<Ipld>> {
+        if let Some((block, _, used)) = self.blocks.get_mut(cid) {
+            self.used.remove(used);
+            self.tick += 1;
+            *used = self.tick;
+            self.used.insert(self.tick, *cid);
+            return Some(block.clone());
+        }
+        let (block, size) = self.spill.as_mut()?.read(cid)?;
+        let block = Arc::new(block);
+        self.insert(*cid, block.clone(), size);
+        Some(block)
+    }
+
+    fn insert(&mut self, cid: Cid, block: Arc<Ipld>, size: usize) {
+        self.tick += 1;
+        if let Some((_, old, used)) = self.blocks.insert(cid, (block, size, self.tick)) {
+            self.size -= old;
+            self.used.remove(&used);
+        }
+        self.used.insert(self.tick, cid);
+        self.size += size;
+        self.evict();
+    }
+}
+
+/// Evicted blocks, encoded as dag-cbor one after the other in a file.
+struct Spill {
+    path: PathBuf,
+    file: File,
+    end: u64,
+    /// Offset and length of each block in the file, and the size of its data.
+    blocks: HashMap<Cid, (u64, usize, usize)>,
+}
+
+impl Spill {
+    fn write(&mut self, cid: Cid, block: &Ipld, size: usize) -> Result<()> {
+        if self.blocks.contains_key(&cid) {
+            return Ok(());
+        }
+        let bytes = DagCborCodec.encode(block)?;
+        self.file.seek(SeekFrom::Start(self.end))?;
+        self.file.write_all(&bytes)?;
+        self.blocks.insert(cid, (self.end, bytes.len(), size));
+        self.end += bytes.len() as u64;
+        Ok(())
+    }
+
+    fn read(&mut self, cid: &Cid) -> Option<(Ipld, usize)> {
+        let (offset, len, size) = *self.blocks.get(cid)?;
+        let mut bytes = vec![0; len];
+        self.file.seek(SeekFrom::Start(offset)).ok()?;
+        self.file.read_exact(&mut bytes).ok()?;
+        Some((DagCborCodec.decode(&bytes).ok()?, size))
+    }
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Where a block is found in the inputs.
+#[derive(Debug, Clone, Copy)]
+struct Location {
+    input: usize,
+    /// Offset of the section within the CARv1.
+    offset: u64,
+    /// Whether copies of the CID have different bytes, flagged by
+    /// `--duplicates flag`.
+    conflict: bool,
+}
+
+/// The blocks of the inputs links can be inlined from: those the conversion
+/// writes, not raw blocks nor the copies `--duplicates` and
+/// `--dedup-payloads` skip.
+pub(crate) struct Resolver {
+    paths: Vec<PathBuf>,
+    blocks: HashMap<Cid, Location>,
+    cache: Arc<Mutex<dyn BlockCache>>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+impl Resolver {
+    /// Scans the inputs for the sections of their blocks.
+    pub(crate) fn new(
+        args: &ConvertArgs,
+        inputs: &[Unit],
+        cache: Arc<Mutex<dyn BlockCache>>,
+    ) -> Result<Self> {
+        // Only flagging copies and finding payloads needs the bytes.
+        let read = args.duplicates == Duplicates::Flag || args.dedup_payloads;
+        let mut blocks: HashMap<Cid, (Location, u64)> = HashMap::new();
+        let mut payloads: HashMap<Multihash, Cid> = HashMap::new();
+        for (input, unit) in inputs.iter().enumerate() {
+            let mut car = unit.open()?;
+            for _ in 0..unit.blocks {
+                let offset = car.position();
+                let (cid, bytes) = if read {
+                    let Some((cid, bytes)) = car
+                        .next_block()
+                        .context(format!("reading {}", unit.path.display()))?
+                    else {
+                        break;
+                    };
+                    (cid, bytes)
+                } else {
+                    let Some(section) = car
+                        .next_section()
+                        .context(format!("reading {}", unit.path.display()))?
+                    else {
+                        break;
+                    };
+                    (section.cid, Vec::new())
+                };
+                if args.dedup_payloads
+                    && *payloads.entry(Code::Sha2_256.digest(&bytes)).or_insert(cid) != cid
+                {
+                    continue;
+                }
+                if cid.codec() == RAW {
+                    continue;
+                }
+                let hash = bytes_hash(&bytes);
+                let location = Location {
+                    input,
+                    offset,
+                    conflict: false,
+                };
+                match blocks.entry(cid) {
+                    std::collections::hash_map::Entry::Vacant(e) => {
+                        e.insert((location, hash));
+                    }
+                    std::collections::hash_map::Entry::Occupied(mut e) => {
+                        let conflict = e.get().0.conflict
+                            || (args.duplicates == Duplicates::Flag && e.get().1 != hash);
+                        // The copy written is the first kept, or the last.
+                        if matches!(args.duplicates, Duplicates::KeepLast | Duplicates::Flag) {
+                            e.get_mut().0 = location;
+                        }
+                        e.get_mut().0.conflict = conflict;
+                    }
+                }
+            }
+        }
+        Ok(Self {
+            paths: inputs.iter().map(|u| u.path.clone()).collect(),
+            blocks: blocks.into_iter().map(|(cid, (l, _))| (cid, l)).collect(),
+            cache,
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        })
+    }
+
+    /// The CIDs of the blocks, in no order.
+    pub(crate) fn cids(&self) -> impl Iterator<Item = &Cid> {
+        self.blocks.keys()
+    }
+
+    pub(crate) fn contains(&self, cid: &Cid) -> bool {
+        self.blocks.contains_key(cid)
+    }
+
+    /// The decoded block of the CID, `None` if the inputs do not have it.
+    pub(crate) fn get(&self, args: &ConvertArgs, cid: &Cid) -> Result<Option<Arc<Ipld>>> {
+        let Some(location) = self.blocks.get(cid) else {
+            return Ok(None);
+        };
+        if let Some(block) = self.cache.lock().expect("block cache lock").get(cid) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(Some(block));
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let path = &self.paths[location.input];
+        let section = carv2::open(path, location.offset)?;
+        let Some((_, bytes)) = Sections::resume(BufReader::new(section), location.offset)
+            .next_block()
+            .context(format!("reading {}", path.display()))?
+        else {
+            return Err(anyhow!(
+                "block {} is no longer at {} in {}",
+                cid,
+                location.offset,
+                path.display()
+            ));
+        };
+        // Invalid UTF-8 is counted once, for the block itself.
+        let mut dag = crate::decode_block(args, cid, &bytes, None, &mut 0)?;
+        if let (true, Ipld::Map(m)) = (location.conflict, &mut dag) {
+            m.insert("_duplicate_conflict".to_string(), Ipld::Bool(true));
+        }
+        let block = Arc::new(dag);
+        self.cache
+            .lock()
+            .expect("block cache lock")
+            .insert(*cid, block.clone(), bytes.len());
+        Ok(Some(block))
+    }
+
+    /// Adds the links resolved from the cache and by reading their block to
+    /// the report.
+    pub(crate) fn record(&self, report: &mut Report) {
+        report.block_cache_hits += self.hits.load(Ordering::Relaxed);
+        report.block_cache_misses += self.misses.load(Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libipld::ipld;
+
+    fn block(n: u64) -> (Cid, Arc<Ipld>) {
+        let ipld = ipld!({ "n": n });
+        let bytes = DagCborCodec.encode(&ipld).unwrap();
+        let cid = Cid::new_v1(0x71, Code::Sha2_256.digest(&bytes));
+        (cid, Arc::new(ipld))
+    }
+
+    fn spill_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("carquet-cache-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn hits_are_the_blocks_kept() {
+        let mut lru = Lru::new(100);
+        let (cid, b) = block(1);
+        assert!(lru.get(&cid).is_none());
+        lru.insert(cid, b.clone(), 10);
+        assert!(Arc::ptr_eq(&lru.get(&cid).unwrap(), &b));
+        assert!(lru.get(&block(2).0).is_none());
+    }
+
+    #[test]
+    fn the_least_recently_used_blocks_are_evicted() {
+        let mut lru = Lru::new(30);
+        let blocks: Vec<_> = (0..4).map(block).collect();
+        for (cid, b) in &blocks[..3] {
+            lru.insert(*cid, b.clone(), 10);
+        }
+        // Using the first block makes the second the least recently used.
+        assert!(lru.get(&blocks[0].0).is_some());
+        lru.insert(blocks[3].0, blocks[3].1.clone(), 10);
+        assert!(lru.get(&blocks[1].0).is_none());
+        for (cid, _) in [&blocks[0], &blocks[2], &blocks[3]] {
+            assert!(lru.get(cid).is_some());
+        }
+        assert_eq!(lru.size, 30);
+    }
+
+    #[test]
+    fn inserting_again_replaces_the_size() {
+        let mut lru = Lru::new(30);
+        let (cid, b) = block(1);
+        lru.insert(cid, b.clone(), 10);
+        lru.insert(cid, b, 20);
+        assert_eq!((lru.size, lru.blocks.len(), lru.used.len()), (20, 1, 1));
+    }
+
+    #[test]
+    fn blocks_larger_than_the_cache_are_not_kept() {
+        let mut lru = Lru::new(10);
+        let (cid, b) = block(1);
+        lru.insert(cid, b, 11);
+        assert!(lru.get(&cid).is_none());
+        assert_eq!(lru.size, 0);
+    }
+
+    #[test]
+    fn evicted_blocks_are_read_back_from_the_spill() {
+        let dir = spill_dir("read-back");
+        let mut lru = Lru::new(10).spill_to(&dir).unwrap();
+        let path = lru.spill.as_ref().unwrap().path.clone();
+        let (first, b) = block(1);
+        lru.insert(first, b.clone(), 10);
+        lru.insert(block(2).0, block(2).1, 10);
+        assert!(!lru.blocks.contains_key(&first));
+        assert_eq!(*lru.get(&first).unwrap(), *b);
+        // Reading it back keeps it again, and evicts the other.
+        assert!(lru.blocks.contains_key(&first));
+        assert!(!lru.blocks.contains_key(&block(2).0));
+        assert_eq!(lru.spill.as_ref().unwrap().blocks.len(), 2);
+        drop(lru);
+        assert!(!path.exists());
+        std::fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn blocks_dag_cbor_cannot_encode_are_not_spilled() {
+        let dir = spill_dir("unencodable");
+        let mut lru = Lru::new(10).spill_to(&dir).unwrap();
+        let (cid, _) = block(1);
+        lru.insert(cid, Arc::new(Ipld::Integer(1 << 70)), 10);
+        lru.insert(block(2).0, block(2).1, 10);
+        assert!(lru.get(&cid).is_none());
+        drop(lru);
+        std::fs::remove_dir(&dir).unwrap();
+    }
+}
@@ -0,0 +1,92 @@
+//! What this build of carquet supports, printed by `carquet capabilities`
+//! for orchestrators to check a job against before launching it.
+//!
+//! ```json
+//! {"version": "0.1.0", "codecs": ["dag-cbor", ..], "formats": ["parquet", "jsonl"], ..}
+//! ```
+//!
+//! The lists name the values of the flags choosing them, `--format`,
+//! `--compression`, `--preset` and `--adl`, and `options` the flags of
+//! `carquet convert`, so a job can be checked without running it.
+
+use std::fmt;
+
+use clap::{Args, ValueEnum};
+use serde::Serialize;
+
+use crate::{adl::Adl, preset::Preset, Compression, ConvertArgs, Format};
+
+#[derive(Debug, Serialize)]
+pub struct Capabilities {
+    pub version: &'static str,
+    /// Codecs of the blocks decoded, others are decoded as dag-cbor.
+    pub codecs: Vec<&'static str>,
+    pub inputs: Vec<&'static str>,
+    pub formats: Vec<String>,
+    pub compression: Vec<String>,
+    /// Where the files are written, and who is notified once a run ends.
+    pub sinks: Vec<&'static str>,
+    pub hooks: Vec<&'static str>,
+    pub presets: Vec<String>,
+    pub adl: Vec<String>,
+    /// Cargo features and cfgs of the build.
+    pub features: Vec<&'static str>,
+    /// Long names of the flags of `carquet convert`.
+    pub options: Vec<String>,
+}
+
+/// The capabilities of this build.
+pub fn probe() -> Capabilities {
+    let mut features = Vec::new();
+    if cfg!(feature = "conformance") {
+        features.push("conformance");
+    }
+    if cfg!(fuzzing) {
+        features.push("fuzzing");
+    }
+    let options = ConvertArgs::augment_args(clap::Command::new("convert"))
+        .get_arguments()
+        .filter_map(|arg| arg.get_long().map(str::to_string))
+        .collect();
+    Capabilities {
+        version: env!("CARGO_PKG_VERSION"),
+        codecs: vec!["dag-cbor", "dag-json", "dag-pb", "raw"],
+        inputs: vec!["carv1", "carv2"],
+        formats: names::<Format>(),
+        compression: names::<Compression>(),
+        sinks: vec!["directory"],
+        hooks: vec!["webhook", "exec"],
+        presets: names::<Preset>(),
+        adl: names::<Adl>(),
+        features,
+        options,
+    }
+}
+
+fn names<T: ValueEnum>() -> Vec<String> {
+    T::value_variants()
+        .iter()
+        .filter_map(|v| v.to_possible_value())
+        .map(|v| v.get_name().to_string())
+        .collect()
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "version: {}", self.version)?;
+        writeln!(f, "codecs: {}", self.codecs.join(", "))?;
+        writeln!(f, "inputs: {}", self.inputs.join(", "))?;
+        writeln!(f, "formats: {}", self.formats.join(", "))?;
+        writeln!(f, "compression: {}", self.compression.join(", "))?;
+        writeln!(f, "sinks: {}", self.sinks.join(", "))?;
+        writeln!(f, "hooks: {}", self.hooks.join(", "))?;
+        writeln!(f, "presets: {}", self.presets.join(", "))?;
+        writeln!(f, "adl: {}", self.adl.join(", "))?;
+        if self.features.is_empty() {
+            writeln!(f, "features: none")?;
+        } else {
+            writeln!(f, "features: {}", self.features.join(", "))?;
+        }
+        writeln!(f, "options: {}", self.options.join(", "))
+    }
+}
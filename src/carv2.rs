@@ -0,0 +1,488 @@
+//! Reading CARv2 files: the CARv1 payload they wrap, and their index.
+//!
+//! A CARv2 is a fixed pragma and header followed by a CARv1, the data, with
+//! optional padding before and after it and an optional index of the offsets
+//! of its blocks at the end:
+//!
+//! ```text
+//! pragma: 11 bytes | characteristics: 16 bytes | data offset: u64 le |
+//! data size: u64 le | index offset: u64 le | .. | CARv1 | .. | index
+//! ```
+//!
+//! Every command reads CARv2 inputs through [`open`] or [`open_blocks`], which
+//! read just the CARv1, so padding and index are never taken for sections.
+//! [`Index`] looks blocks up in the index of the multicodecs `car-index-sorted`
+//! and `car-multihash-index-sorted`, whose entries are sorted runs of digests
+//! of one length, each with the offset of its section within the data, and
+//! [`wrap`] turns a written CARv1 into a CARv2 with the latter index.
+
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use libipld::Cid;
+
+use crate::scan::{self, Sections};
+
+/// The first bytes of a CARv2, the CARv1 framed header `{"version": 2}`.
+pub const PRAGMA: [u8; 11] = [
+    0x0a, 0xa1, 0x67, b'v', b'e', b'r', b's', b'i', b'o', b'n', 0x02,
+];
+
+/// Length of the pragma and header, the least data offset.
+pub const HEADER_LEN: u64 = 51;
+
+const INDEX_SORTED: u64 = 0x0400;
+const MULTIHASH_INDEX_SORTED: u64 = 0x0401;
+
+/// The header of a CARv2.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    /// Whether the index has an entry for every block, not only some.
+    pub fully_indexed: bool,
+    pub data_offset: u64,
+    pub data_size: u64,
+    /// Offset of the index, `None` if there is none.
+    pub index_offset: Option<u64>,
+}
+
+/// Reads the CARv2 header at the start of the file, `None` if it is not a
+/// CARv2. Leaves the file at an unspecified position.
+pub fn header(f: &mut File) -> Result<Option<Header>> {
+    let mut head = [0; HEADER_LEN as usize];
+    f.seek(SeekFrom::Start(0))?;
+    let mut read = 0;
+    while read < head.len() {
+        match f.read(&mut head[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    if read < PRAGMA.len() || head[..PRAGMA.len()] != PRAGMA {
+        return Ok(None);
+    }
+    if read < head.len() {
+        return Err(anyhow!("CARv2 header truncated"));
+    }
+    let u64_at = |i: usize| u64::from_le_bytes(head[i..i + 8].try_into().expect("8 bytes"));
+    let header = Header {
+        fully_indexed: head[PRAGMA.len()] & 0x80 != 0,
+        data_offset: u64_at(27),
+        data_size: u64_at(35),
+        index_offset: Some(u64_at(43)).filter(|o| *o != 0),
+    };
+    let len = f.metadata()?.len();
+    match header.data_offset.checked_add(header.data_size) {
+        Some(end) if header.data_offset >= HEADER_LEN && end <= len => Ok(Some(header)),
+        _ => Err(anyhow!(
+            "CARv2 data of {} bytes at {} is outside the file of {} bytes",
+            header.data_size,
+            header.data_offset,
+            len
+        )),
+    }
+}
+
+/// Opens the CARv1 of the file at `pos` within it: the whole file for a
+/// CARv1, the data of a CARv2.
+pub fn open(path: &Path, pos: u64) -> Result<std::io::Take<File>> {
+    let mut f = File::open(path).context(format!("opening {}", path.display()))?;
+    let (start, size) = match header(&mut f).context(format!("reading {}", path.display()))? {
+        Some(header) => (header.data_offset, header.data_size),
+        None => (0, u64::MAX),
+    };
+    f.seek(SeekFrom::Start(start + pos))?;
+    Ok(f.take(size.saturating_sub(pos)))
+}
+
+/// Opens the sections of the CARv1 of the file, its header read.
+pub fn open_blocks(path: &Path) -> Result<Sections<BufReader<std::io::Take<File>>>> {
+    Sections::new(BufReader::with_capacity(scan::BUFFER, open(path, 0)?))
+        .context(format!("reading {}", path.display()))
+}
+
+/// Rewrites the CARv1 as a CARv2 of it followed by a multihash index of all
+/// its blocks, returning the number of blocks indexed.
+pub fn wrap(path: &Path) -> Result<u64> {
+    let mut input = File::open(path).context(format!("opening {}", path.display()))?;
+    let data_size = input.metadata()?.len();
+    // Entries by hash function and length of their digest and offset.
+    let mut entries: BTreeMap<u64, BTreeMap<u32, Vec<Vec<u8>>>> = BTreeMap::new();
+    let mut car = Sections::new(BufReader::with_capacity(scan::BUFFER, &mut input))
+        .context(format!("reading {}", path.display()))?;
+    let mut blocks = 0;
+    loop {
+        let offset = car.position();
+        let Some(section) = car.next_section()? else {
+            break;
+        };
+        let hash = section.cid.hash();
+        let mut entry = hash.digest().to_vec();
+        entry.extend_from_slice(&offset.to_le_bytes());
+        entries
+            .entry(hash.code())
+            .or_default()
+            .entry(entry.len() as u32)
+            .or_default()
+            .push(entry);
+        blocks += 1;
+    }
+    drop(car);
+
+    let tmp = PathBuf::from(format!("{}.tmp", path.display()));
+    let result = (|| {
+        let mut out =
+            BufWriter::new(File::create(&tmp).context(format!("creating {}", tmp.display()))?);
+        out.write_all(&PRAGMA)?;
+        // Every block is indexed, the fully indexed characteristic.
+        out.write_all(&[0x80])?;
+        out.write_all(&[0; 15])?;
+        out.write_all(&HEADER_LEN.to_le_bytes())?;
+        out.write_all(&data_size.to_le_bytes())?;
+        out.write_all(&(HEADER_LEN + data_size).to_le_bytes())?;
+        input.seek(SeekFrom::Start(0))?;
+        std::io::copy(&mut input, &mut out)?;
+
+        // The multicodec as a varint.
+        out.write_all(&[0x81, 0x08])?;
+        out.write_all(&(entries.len() as u32).to_le_bytes())?;
+        for (code, buckets) in &mut entries {
+            out.write_all(&code.to_le_bytes())?;
+            out.write_all(&(buckets.len() as u32).to_le_bytes())?;
+            for (width, bucket) in buckets {
+                bucket.sort_unstable();
+                out.write_all(&width.to_le_bytes())?;
+                out.write_all(&(bucket.len() as u64 * u64::from(*width)).to_le_bytes())?;
+                for entry in bucket.iter() {
+                    out.write_all(entry)?;
+                }
+            }
+        }
+        out.flush()?;
+        std::fs::rename(&tmp, path).context(format!("replacing {}", path.display()))
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    result.map(|()| blocks)
+}
+
+/// A run of entries of one digest length, and of one hash function in a
+/// multihash index.
+#[derive(Debug)]
+struct Bucket {
+    code: Option<u64>,
+    /// Bytes of each entry, the digest and a u64 offset.
+    width: u64,
+    /// Offset of the first entry in the file.
+    start: u64,
+    entries: u64,
+}
+
+/// The index of a CARv2, read from the file as it is searched.
+pub struct Index {
+    file: File,
+    header: Header,
+    buckets: Vec<Bucket>,
+}
+
+impl Index {
+    /// Opens the index of the file, `None` if it is a CARv1 or a CARv2
+    /// without index.
+    pub fn open(path: &Path) -> Result<Option<Self>> {
+        let mut file = File::open(path).context(format!("opening {}", path.display()))?;
+        let Some(header) = header(&mut file)?.filter(|h| h.index_offset.is_some()) else {
+            return Ok(None);
+        };
+        file.seek(SeekFrom::Start(header.index_offset.unwrap_or_default()))?;
+        let buckets =
+            read_buckets(&mut file).context(format!("reading the index of {}", path.display()))?;
+        Ok(Some(Self {
+            file,
+            header,
+            buckets,
+        }))
+    }
+
+    /// Whether the index has an entry for every block, as the header says.
+    pub fn fully_indexed(&self) -> bool {
+        self.header.fully_indexed
+    }
+
+    /// The offset within the data and the length of each section indexed, in
+    /// the order of the data, each up to the next or the end of the data.
+    pub fn sections(&mut self) -> Result<Vec<(u64, u64)>> {
+        let mut offsets = Vec::new();
+        for b in &self.buckets {
+            self.file.seek(SeekFrom::Start(b.start))?;
+            let mut reader = BufReader::new(&mut self.file);
+            let mut entry = vec![0; b.width as usize];
+            for _ in 0..b.entries {
+                reader.read_exact(&mut entry).context("index truncated")?;
+                let offset = &entry[entry.len() - 8..];
+                offsets.push(u64::from_le_bytes(offset.try_into().expect("8 bytes")));
+            }
+        }
+        offsets.sort_unstable();
+        offsets.dedup();
+        let ends = offsets
+            .iter()
+            .skip(1)
+            .copied()
+            .chain([self.header.data_size]);
+        offsets
+            .iter()
+            .zip(ends)
+            .map(|(&offset, end)| match end.checked_sub(offset) {
+                Some(len) if len > 0 => Ok((offset, len)),
+                _ => Err(anyhow!(
+                    "index offset {} is outside the data of {} bytes",
+                    offset,
+                    self.header.data_size
+                )),
+            })
+            .collect()
+    }
+
+    /// Number of entries, the blocks of a fully indexed CAR.
+    pub fn len(&self) -> u64 {
+        self.buckets.iter().map(|b| b.entries).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Offsets within the data of the sections whose CID has the multihash
+    /// of `cid`, which may have other codecs.
+    pub fn find(&mut self, cid: &Cid) -> Result<Vec<u64>> {
+        let digest = cid.hash().digest();
+        let width = digest.len() as u64 + 8;
+        let mut offsets = Vec::new();
+        let mut entry = vec![0; width as usize];
+        for b in &self.buckets {
+            if b.width != width || b.code.is_some_and(|c| c != cid.hash().code()) {
+                continue;
+            }
+            // The first entry whose digest is not below the one looked up.
+            let (mut lo, mut hi) = (0, b.entries);
+            while lo < hi {
+                let mid = lo + (hi - lo) / 2;
+                read_entry(&mut self.file, b, mid, &mut entry)?;
+                if &entry[..digest.len()] < digest {
+                    lo = mid + 1;
+                } else {
+                    hi = mid;
+                }
+            }
+            for i in lo..b.entries {
+                read_entry(&mut self.file, b, i, &mut entry)?;
+                if &entry[..digest.len()] != digest {
+                    break;
+                }
+                let offset = &entry[digest.len()..];
+                offsets.push(u64::from_le_bytes(offset.try_into().expect("8 bytes")));
+            }
+        }
+        Ok(offsets)
+    }
+}
+
+fn read_entry(file: &mut File, bucket: &Bucket, i: u64, entry: &mut [u8]) -> Result<()> {
+    file.seek(SeekFrom::Start(bucket.start + i * bucket.width))?;
+    file.read_exact(entry).context("index truncated")?;
+    Ok(())
+}
+
+/// Reads the buckets of the index at the position of the file, skipping over
+/// their entries.
+fn read_buckets(file: &mut File) -> Result<Vec<Bucket>> {
+    let mut codec = [0; 2];
+    file.read_exact(&mut codec)?;
+    let codec = match scan::varint(&codec) {
+        Some((codec, 2)) => codec,
+        _ => return Err(anyhow!("unsupported index codec {:02x?}", codec)),
+    };
+    let mut buckets = Vec::new();
+    match codec {
+        INDEX_SORTED => read_sorted(file, None, &mut buckets)?,
+        MULTIHASH_INDEX_SORTED => {
+            for _ in 0..read_u32(file)? {
+                let code = read_u64(file)?;
+                read_sorted(file, Some(code), &mut buckets)?;
+            }
+        }
+        _ => return Err(anyhow!("unsupported index codec 0x{:x}", codec)),
+    }
+    Ok(buckets)
+}
+
+fn read_sorted(file: &mut File, code: Option<u64>, buckets: &mut Vec<Bucket>) -> Result<()> {
+    let len = file.metadata()?.len();
+    for _ in 0..read_u32(file)? {
+        let width = u64::from(read_u32(file)?);
+        let bytes = read_u64(file)?;
+        let start = file.stream_position()?;
+        if width <= 8 || bytes % width != 0 || start.saturating_add(bytes) > len {
+            return Err(anyhow!(
+                "index bucket of {} bytes of {} byte entries does not fit the file",
+                bytes,
+                width
+            ));
+        }
+        buckets.push(Bucket {
+            code,
+            width,
+            start,
+            entries: bytes / width,
+        });
+        file.seek(SeekFrom::Current(bytes as i64))?;
+    }
+    Ok(())
+}
+
+fn read_u32(file: &mut File) -> Result<u32> {
+    let mut b = [0; 4];
+    file.read_exact(&mut b).context("index truncated")?;
+    Ok(u32::from_le_bytes(b))
+}
+
+fn read_u64(file: &mut File) -> Result<u64> {
+    let mut b = [0; 8];
+    file.read_exact(&mut b).context("index truncated")?;
+    Ok(u64::from_le_bytes(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use libipld::multihash::{Code, MultihashDigest};
+
+    use super::*;
+
+    static FILES: AtomicUsize = AtomicUsize::new(0);
+
+    fn temp() -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "carquet-carv2-{}-{}.car",
+            std::process::id(),
+            FILES.fetch_add(1, Ordering::Relaxed)
+        ))
+    }
+
+    fn fixture() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/unixfs.car")
+    }
+
+    /// The blocks of the CARv1 with the offsets of their sections.
+    fn blocks(path: &Path) -> Vec<(u64, Cid, Vec<u8>)> {
+        let mut car = open_blocks(path).unwrap();
+        let mut blocks = Vec::new();
+        loop {
+            let offset = car.position();
+            let Some((cid, data)) = car.next_block().unwrap() else {
+                return blocks;
+            };
+            blocks.push((offset, cid, data));
+        }
+    }
+
+    #[test]
+    fn wrapped_blocks_are_found_by_the_index() {
+        let path = temp();
+        std::fs::copy(fixture(), &path).unwrap();
+        let blocks = blocks(&fixture());
+        assert_eq!(wrap(&path).unwrap(), blocks.len() as u64);
+
+        let header = header(&mut File::open(&path).unwrap()).unwrap().unwrap();
+        assert!(header.fully_indexed);
+        assert_eq!(header.data_offset, HEADER_LEN);
+        assert_eq!(
+            header.data_size,
+            std::fs::metadata(fixture()).unwrap().len()
+        );
+        // The data read through the CARv2 is the CARv1 wrapped.
+        let unwrapped = self::blocks(&path);
+        assert_eq!(unwrapped.len(), blocks.len());
+
+        let mut index = Index::open(&path).unwrap().unwrap();
+        assert_eq!(index.len(), blocks.len() as u64);
+        for (offset, cid, data) in &blocks {
+            let offsets = index.find(cid).unwrap();
+            assert!(offsets.contains(offset), "{}", cid);
+            let input = BufReader::new(open(&path, *offset).unwrap());
+            let found = Sections::resume(input, *offset).next_block().unwrap();
+            assert_eq!(found, Some((*cid, data.clone())));
+        }
+        let absent = Cid::new_v1(0x55, Code::Sha2_256.digest(b"absent"));
+        assert!(index.find(&absent).unwrap().is_empty());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn carv1_has_no_header_or_index() {
+        let mut f = File::open(fixture()).unwrap();
+        assert_eq!(header(&mut f).unwrap(), None);
+        assert!(Index::open(&fixture()).unwrap().is_none());
+    }
+
+    #[test]
+    fn truncated_header_is_an_error() {
+        let path = temp();
+        let mut bytes = PRAGMA.to_vec();
+        bytes.extend_from_slice(&[0; 20]);
+        std::fs::write(&path, bytes).unwrap();
+        let err = header(&mut File::open(&path).unwrap()).unwrap_err();
+        assert_eq!(err.to_string(), "CARv2 header truncated");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn data_outside_the_file_is_an_error() {
+        let path = temp();
+        std::fs::copy(fixture(), &path).unwrap();
+        wrap(&path).unwrap();
+        let mut bytes = std::fs::read(&path).unwrap();
+        let len = bytes.len() as u64;
+        // The data size as if the data ran past the end of the file.
+        bytes[35..43].copy_from_slice(&len.to_le_bytes());
+        std::fs::write(&path, &bytes).unwrap();
+        let err = header(&mut File::open(&path).unwrap()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "CARv2 data of {} bytes at 51 is outside the file of {} bytes",
+                len, len
+            )
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn index_offset_outside_the_data_is_an_error() {
+        let path = temp();
+        std::fs::copy(fixture(), &path).unwrap();
+        wrap(&path).unwrap();
+        let header = header(&mut File::open(&path).unwrap()).unwrap().unwrap();
+        let mut bytes = std::fs::read(&path).unwrap();
+        // The offset of the last entry, past the data.
+        let end = bytes.len();
+        bytes[end - 8..].copy_from_slice(&(header.data_size + 1).to_le_bytes());
+        std::fs::write(&path, &bytes).unwrap();
+        let err = Index::open(&path).unwrap().unwrap().sections().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "index offset {} is outside the data of {} bytes",
+                header.data_size + 1,
+                header.data_size
+            )
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,140 @@
+//! The sidecar index of the row groups each CID was written to, `cid_index.json`.
+//!
+//! Each row group of the block tables is a roaring bitmap of the 32-bit
+//! FNV-1a hashes of the binary CIDs of its rows, in the portable format shared
+//! by the roaring libraries and base64 encoded:
+//!
+//! ```json
+//! {"hash": "fnv1a-32", "files": [{"file": "schema_0.parquet", "row_groups": ["OjAAAA.."]}]}
+//! ```
+//!
+//! A lookup hashes the CID and checks the bitmaps. Hashes may collide, so a
+//! hit names the row groups that may hold the CID and a miss rules them out.
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose, Engine as _};
+use libipld::Cid;
+use serde::Serialize;
+
+use crate::Block;
+
+#[derive(Debug, Default)]
+pub struct CidIndex {
+    files: Vec<(String, Vec<Vec<u32>>)>,
+}
+
+#[derive(Serialize)]
+struct IndexFile<'a> {
+    hash: &'a str,
+    files: Vec<FileEntry<'a>>,
+}
+
+#[derive(Serialize)]
+struct FileEntry<'a> {
+    file: &'a str,
+    /// Bitmap of the CIDs of each row group.
+    row_groups: Vec<String>,
+}
+
+impl CidIndex {
+    /// Adds the files of another index, written by a table on its own.
+    pub fn append(&mut self, other: CidIndex) {
+        self.files.extend(other.files);
+    }
+
+    /// Records the CIDs of the rows of a row group of the file.
+    pub fn push(&mut self, file: &str, row_group: usize, rows: &[Block]) {
+        let i = match self.files.iter().position(|(f, _)| f == file) {
+            Some(i) => i,
+            None => {
+                self.files.push((file.to_string(), Vec::new()));
+                self.files.len() - 1
+            }
+        };
+        let row_groups = &mut self.files[i].1;
+        if row_groups.len() <= row_group {
+            row_groups.resize(row_group + 1, Vec::new());
+        }
+        row_groups[row_group].extend(rows.iter().map(|(cid, _, _)| hash(cid)));
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let index = IndexFile {
+            hash: "fnv1a-32",
+            files: self
+                .files
+                .iter()
+                .map(|(file, row_groups)| FileEntry {
+                    file,
+                    row_groups: row_groups
+                        .iter()
+                        .map(|hashes| general_purpose::STANDARD.encode(roaring(hashes)))
+                        .collect(),
+                })
+                .collect(),
+        };
+        let f = std::fs::File::create(path).context(format!("creating {}", path.display()))?;
+        serde_json::to_writer_pretty(f, &index)?;
+        Ok(())
+    }
+}
+
+/// 32-bit FNV-1a of the binary CID.
+fn hash(cid: &Cid) -> u32 {
+    cid.to_bytes()
+        .iter()
+        .fold(0x811c9dc5, |h, b| (h ^ *b as u32).wrapping_mul(0x01000193))
+}
+
+/// Most values of a container stored as a sorted array, more are stored as a bitset.
+const MAX_ARRAY: usize = 4096;
+
+/// Serializes the values as a roaring bitmap without run containers.
+fn roaring(values: &[u32]) -> Vec<u8> {
+    let mut values = values.to_vec();
+    values.sort_unstable();
+    values.dedup();
+    let mut containers: Vec<(u16, Vec<u16>)> = Vec::new();
+    for v in values {
+        let (key, low) = ((v >> 16) as u16, v as u16);
+        match containers.last_mut() {
+            Some((k, c)) if *k == key => c.push(low),
+            _ => containers.push((key, vec![low])),
+        }
+    }
+
+    let mut out = Vec::new();
+    out.extend_from_slice(&12346u32.to_le_bytes());
+    out.extend_from_slice(&(containers.len() as u32).to_le_bytes());
+    for (key, c) in &containers {
+        out.extend_from_slice(&key.to_le_bytes());
+        out.extend_from_slice(&((c.len() - 1) as u16).to_le_bytes());
+    }
+    let mut offset = out.len() + 4 * containers.len();
+    for (_, c) in &containers {
+        out.extend_from_slice(&(offset as u32).to_le_bytes());
+        offset += if c.len() > MAX_ARRAY {
+            8192
+        } else {
+            2 * c.len()
+        };
+    }
+    for (_, c) in &containers {
+        if c.len() > MAX_ARRAY {
+            let mut bits = [0u64; 1024];
+            for v in c {
+                bits[*v as usize / 64] |= 1 << (v % 64);
+            }
+            for word in bits {
+                out.extend_from_slice(&word.to_le_bytes());
+            }
+        } else {
+            for v in c {
+                out.extend_from_slice(&v.to_le_bytes());
+            }
+        }
+    }
+    out
+}
@@ -0,0 +1,246 @@
+//! Golden file checks of the outputs of the presets, run by `cargo test` and
+//! for packagers to run against their builds.
+//!
+//! Each preset has a small fixture CAR shipped with the crate,
+//! `fixtures/<preset>.car`, that is converted with `--preset <preset>` and
+//! rendered as text: the report, then each file written with its parquet
+//! schema, footer metadata and the repetition level, definition level and
+//! value of each entry of each column chunk. The rendering must equal
+//! `fixtures/<preset>.golden`. After an intended change of the outputs,
+//! `CARQUET_BLESS=1 cargo test --features conformance` rewrites the golden
+//! files instead.
+//!
+//! The fixtures are a Ceramic stream with its dag-jose envelopes, an AT
+//! Protocol repository of two commits, a Filecoin chain of three tipsets with
+//! their messages, a UnixFS directory of a chunked and a single block file, and
+//! the default chain of `carquet gen-fixture --records 12 --seed 1`. Beside
+//! them, `fixtures/nested.car` of lists of lists, of maps and of nulls is
+//! converted without flags, see [`check_fixture`].
+
+use std::{
+    fmt::Write as _,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
+use parquet::{
+    basic::{ConvertedType, Type as PhysicalType},
+    column::reader::{get_typed_column_reader, ColumnReader},
+    data_type::{
+        BoolType, ByteArrayType, DataType, DoubleType, FixedLenByteArrayType, FloatType, Int32Type,
+        Int64Type, Int96Type,
+    },
+    file::reader::{FileReader, SerializedFileReader},
+    schema::types::ColumnDescriptor,
+};
+
+use crate::{preset::Preset, CarToParquetConverter, ConvertArgs};
+
+/// Directory of the fixtures shipped with the crate.
+pub fn fixtures() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures")
+}
+
+/// Names of the presets, each with a fixture.
+pub fn presets() -> Vec<String> {
+    Preset::value_variants()
+        .iter()
+        .filter_map(|p| p.to_possible_value())
+        .map(|v| v.get_name().to_string())
+        .collect()
+}
+
+/// Converts the fixture of the preset and renders the outputs as text.
+pub async fn render(preset: &str, fixtures: &Path) -> Result<String> {
+    render_fixture(preset, &["--preset", preset], fixtures).await
+}
+
+/// Converts the fixture `<name>.car` with the flags and renders the outputs as
+/// text.
+pub async fn render_fixture(name: &str, flags: &[&str], fixtures: &Path) -> Result<String> {
+    let dir = std::env::temp_dir().join(format!(
+        "carquet-conformance-{}-{}",
+        name,
+        std::process::id()
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    let report = CarToParquetConverter::new(&dir)
+        .input(fixtures.join(format!("{}.car", name)))
+        .options(ConvertArgs::from_flags(flags)?)
+        .run()
+        .await
+        .context(format!("converting the {} fixture", name));
+    let rendered = report.and_then(|report| render_dir(&report.to_string(), &dir));
+    let _ = std::fs::remove_dir_all(&dir);
+    rendered
+}
+
+fn render_dir(report: &str, dir: &Path) -> Result<String> {
+    let mut out = format!("== report\n{}", report);
+    let mut files: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .map(|e| e.map(|e| e.path()))
+        .collect::<Result<_, _>>()?;
+    files.sort();
+    for path in files {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        writeln!(out, "== {}", name)?;
+        if path.extension().is_some_and(|e| e == "parquet") {
+            let reader = SerializedFileReader::new(std::fs::File::open(&path)?)
+                .context(format!("reading {}", name))?;
+            let metadata = reader.metadata().file_metadata();
+            let mut schema = Vec::new();
+            parquet::schema::printer::print_schema(&mut schema, metadata.schema());
+            out.push_str(&String::from_utf8_lossy(&schema));
+            for kv in metadata.key_value_metadata().into_iter().flatten() {
+                writeln!(out, "{} = {}", kv.key, kv.value.as_deref().unwrap_or(""))?;
+            }
+            for i in 0..reader.num_row_groups() {
+                let row_group = reader.get_row_group(i)?;
+                writeln!(out, "-- row group {}", i)?;
+                for c in 0..row_group.num_columns() {
+                    let descr = row_group.metadata().column(c).column_descr_ptr();
+                    writeln!(out, "{}:", descr.path().string())?;
+                    let column = row_group.get_column_reader(c)?;
+                    for (rep, def, value) in column_values(column, &descr)? {
+                        writeln!(out, "  {} {} {}", rep, def, value)?;
+                    }
+                }
+            }
+        } else {
+            out.push_str(&std::fs::read_to_string(&path)?);
+        }
+    }
+    Ok(out)
+}
+
+/// The repetition level, definition level and value of each entry of a column
+/// chunk, `-` for an entry without a value such as an empty list.
+pub(crate) fn column_values(
+    column: ColumnReader,
+    descr: &ColumnDescriptor,
+) -> Result<Vec<(i16, i16, String)>> {
+    let utf8 = matches!(
+        descr.converted_type(),
+        ConvertedType::UTF8 | ConvertedType::JSON
+    );
+    Ok(match descr.physical_type() {
+        PhysicalType::BOOLEAN => levels::<BoolType>(column, descr, |v| v.to_string())?,
+        PhysicalType::INT32 => levels::<Int32Type>(column, descr, |v| v.to_string())?,
+        PhysicalType::INT64 => levels::<Int64Type>(column, descr, |v| v.to_string())?,
+        PhysicalType::INT96 => levels::<Int96Type>(column, descr, |v| v.to_string())?,
+        PhysicalType::FLOAT => levels::<FloatType>(column, descr, |v| v.to_string())?,
+        PhysicalType::DOUBLE => levels::<DoubleType>(column, descr, |v| v.to_string())?,
+        PhysicalType::BYTE_ARRAY => {
+            levels::<ByteArrayType>(column, descr, |v| bytes(v.data(), utf8))?
+        }
+        PhysicalType::FIXED_LEN_BYTE_ARRAY => {
+            levels::<FixedLenByteArrayType>(column, descr, |v| bytes(v.data(), false))?
+        }
+    })
+}
+
+fn levels<T: DataType>(
+    column: ColumnReader,
+    descr: &ColumnDescriptor,
+    show: impl Fn(&T::T) -> String,
+) -> Result<Vec<(i16, i16, String)>> {
+    const BATCH: usize = 1024;
+    let mut reader = get_typed_column_reader::<T>(column);
+    let mut entries = Vec::new();
+    loop {
+        let mut defs = vec![0; BATCH];
+        let mut reps = vec![0; BATCH];
+        let mut values = vec![T::T::default(); BATCH];
+        let (read, levels) =
+            reader.read_batch(BATCH, Some(&mut defs), Some(&mut reps), &mut values)?;
+        // Required columns have no levels, each value is an entry.
+        let levels = if descr.max_def_level() == 0 {
+            read
+        } else {
+            levels
+        };
+        if levels == 0 {
+            break;
+        }
+        let mut values = values[..read].iter();
+        for i in 0..levels {
+            let (rep, def) = if descr.max_def_level() == 0 {
+                (0, 0)
+            } else {
+                (reps[i], defs[i])
+            };
+            let value = if def == descr.max_def_level() {
+                values.next().map(&show).unwrap_or_default()
+            } else {
+                "-".to_string()
+            };
+            entries.push((rep, def, value));
+        }
+    }
+    Ok(entries)
+}
+
+fn bytes(data: &[u8], utf8: bool) -> String {
+    match std::str::from_utf8(data) {
+        Ok(s) if utf8 => format!("{:?}", s),
+        _ => data.iter().map(|b| format!("{:02x}", b)).collect(),
+    }
+}
+
+/// Checks the outputs of the preset against its golden file, or rewrites the
+/// golden file if `CARQUET_BLESS` is set in a build with the `conformance`
+/// feature.
+pub async fn check(preset: &str, fixtures: &Path) -> Result<()> {
+    check_fixture(preset, &["--preset", preset], fixtures).await
+}
+
+/// Checks the outputs of the fixture `<name>.car` converted with the flags
+/// against `<name>.golden`, or rewrites it as [`check`] does.
+pub async fn check_fixture(name: &str, flags: &[&str], fixtures: &Path) -> Result<()> {
+    let rendered = render_fixture(name, flags, fixtures).await?;
+    let golden = fixtures.join(format!("{}.golden", name));
+    if cfg!(feature = "conformance") && std::env::var_os("CARQUET_BLESS").is_some() {
+        return std::fs::write(&golden, rendered).context(format!("writing {}", golden.display()));
+    }
+    let expected =
+        std::fs::read_to_string(&golden).context(format!("reading {}", golden.display()))?;
+    let mismatch = expected
+        .lines()
+        .zip(rendered.lines())
+        .enumerate()
+        .find(|(_, (a, b))| a != b);
+    match mismatch {
+        Some((i, (expected, got))) => Err(anyhow!(
+            "{} differs from {} at line {}:\n  expected: {}\n  got:      {}",
+            name,
+            golden.display(),
+            i + 1,
+            expected,
+            got
+        )),
+        None if expected.lines().count() != rendered.lines().count() => Err(anyhow!(
+            "{} differs from {}: expected {} lines, got {}",
+            name,
+            golden.display(),
+            expected.lines().count(),
+            rendered.lines().count()
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Checks every preset, failing with the mismatches of all that differ.
+pub async fn check_all(fixtures: &Path) -> Result<()> {
+    let mut errors = Vec::new();
+    for preset in presets() {
+        if let Err(e) = check(&preset, fixtures).await {
+            errors.push(format!("{:#}", e));
+        }
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!("{}", errors.join("\n")))
+    }
+}
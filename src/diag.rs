@@ -0,0 +1,312 @@
+//! Rendering of raw CBOR in diagnostic notation (RFC 8949 section 8).
+//!
+//! Rendering is best effort, it works on blocks the dag-cbor decoder rejects
+//! and marks the point where the bytes stop making sense instead of failing.
+
+use std::fmt::Write;
+
+// Deeply nested input should not blow the stack.
+pub(crate) const MAX_DEPTH: usize = 64;
+
+/// Renders the bytes as CBOR diagnostic notation.
+pub fn diagnostic(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    let mut pos = 0;
+    while pos < bytes.len() {
+        if pos > 0 {
+            out.push_str(", ");
+        }
+        if let Err(e) = item(bytes, &mut pos, 0, &mut out) {
+            let _ = write!(out, "<error at byte {}: {}>", pos, e);
+            break;
+        }
+    }
+    out
+}
+
+/// Renders the bytes as lowercase hex.
+pub fn hex(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        let _ = write!(out, "{:02x}", b);
+    }
+    out
+}
+
+pub(crate) fn next(bytes: &[u8], pos: &mut usize) -> Result<u8, String> {
+    let b = *bytes.get(*pos).ok_or("unexpected end of input")?;
+    *pos += 1;
+    Ok(b)
+}
+
+pub(crate) fn take<'a>(bytes: &'a [u8], pos: &mut usize, n: u64) -> Result<&'a [u8], String> {
+    let end = usize::try_from(n)
+        .ok()
+        .and_then(|n| pos.checked_add(n))
+        .filter(|end| *end <= bytes.len())
+        .ok_or_else(|| format!("length {} runs past end of input", n))?;
+    let s = &bytes[*pos..end];
+    *pos = end;
+    Ok(s)
+}
+
+/// Reads the argument of an initial byte, `None` means indefinite length.
+pub(crate) fn argument(bytes: &[u8], pos: &mut usize, info: u8) -> Result<Option<u64>, String> {
+    let n = match info {
+        0..=23 => info as u64,
+        24 => next(bytes, pos)? as u64,
+        25 => u16::from_be_bytes(take(bytes, pos, 2)?.try_into().unwrap()) as u64,
+        26 => u32::from_be_bytes(take(bytes, pos, 4)?.try_into().unwrap()) as u64,
+        27 => u64::from_be_bytes(take(bytes, pos, 8)?.try_into().unwrap()),
+        31 => return Ok(None),
+        _ => return Err(format!("reserved additional information {}", info)),
+    };
+    Ok(Some(n))
+}
+
+fn is_break(bytes: &[u8], pos: &mut usize) -> bool {
+    if bytes.get(*pos) == Some(&0xff) {
+        *pos += 1;
+        true
+    } else {
+        false
+    }
+}
+
+fn item(bytes: &[u8], pos: &mut usize, depth: usize, out: &mut String) -> Result<(), String> {
+    if depth > MAX_DEPTH {
+        return Err("nesting too deep".to_string());
+    }
+    let initial = next(bytes, pos)?;
+    let major = initial >> 5;
+    let info = initial & 0x1f;
+    match major {
+        0 => {
+            let n = argument(bytes, pos, info)?.ok_or("indefinite length integer")?;
+            let _ = write!(out, "{}", n);
+        }
+        1 => {
+            let n = argument(bytes, pos, info)?.ok_or("indefinite length integer")?;
+            let _ = write!(out, "{}", -1 - n as i128);
+        }
+        2 | 3 => match argument(bytes, pos, info)? {
+            Some(n) => string(major, take(bytes, pos, n)?, out),
+            None => {
+                out.push_str("(_ ");
+                let mut first = true;
+                while !is_break(bytes, pos) {
+                    if !first {
+                        out.push_str(", ");
+                    }
+                    first = false;
+                    item(bytes, pos, depth + 1, out)?;
+                }
+                out.push(')');
+            }
+        },
+        4 | 5 => {
+            let len = argument(bytes, pos, info)?;
+            let (open, close) = if major == 4 { ('[', ']') } else { ('{', '}') };
+            out.push(open);
+            if len.is_none() {
+                out.push_str("_ ");
+            }
+            let mut i = 0;
+            loop {
+                match len {
+                    Some(n) if i >= n => break,
+                    None if is_break(bytes, pos) => break,
+                    _ => {}
+                }
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                item(bytes, pos, depth + 1, out)?;
+                if major == 5 {
+                    out.push_str(": ");
+                    item(bytes, pos, depth + 1, out)?;
+                }
+                i += 1;
+            }
+            out.push(close);
+        }
+        6 => {
+            let tag = argument(bytes, pos, info)?.ok_or("indefinite length tag")?;
+            let _ = write!(out, "{}(", tag);
+            item(bytes, pos, depth + 1, out)?;
+            out.push(')');
+        }
+        _ => match info {
+            20 => out.push_str("false"),
+            21 => out.push_str("true"),
+            22 => out.push_str("null"),
+            23 => out.push_str("undefined"),
+            24 => {
+                let _ = write!(out, "simple({})", next(bytes, pos)?);
+            }
+            25 => {
+                let b = take(bytes, pos, 2)?;
+                float(f16_to_f64(u16::from_be_bytes([b[0], b[1]])), "_1", out);
+            }
+            26 => {
+                let b = take(bytes, pos, 4)?;
+                float(f32::from_be_bytes(b.try_into().unwrap()) as f64, "_2", out);
+            }
+            27 => {
+                let b = take(bytes, pos, 8)?;
+                float(f64::from_be_bytes(b.try_into().unwrap()), "_3", out);
+            }
+            31 => return Err("unexpected break".to_string()),
+            0..=19 => {
+                let _ = write!(out, "simple({})", info);
+            }
+            _ => return Err(format!("reserved simple value {}", info)),
+        },
+    }
+    Ok(())
+}
+
+fn string(major: u8, s: &[u8], out: &mut String) {
+    match (major, std::str::from_utf8(s)) {
+        (3, Ok(s)) => {
+            let _ = write!(out, "{:?}", s);
+        }
+        // Invalid UTF-8 in a text string is shown as bytes so nothing is lost.
+        (3, Err(_)) => {
+            let _ = write!(out, "<invalid utf-8 h'{}'>", hex(s));
+        }
+        _ => {
+            let _ = write!(out, "h'{}'", hex(s));
+        }
+    }
+}
+
+fn float(f: f64, encoding: &str, out: &mut String) {
+    if f.is_nan() {
+        out.push_str("NaN");
+    } else if f.is_infinite() {
+        out.push_str(if f > 0.0 { "Infinity" } else { "-Infinity" });
+    } else {
+        let _ = write!(out, "{:?}", f);
+    }
+    out.push_str(encoding);
+}
+
+pub(crate) fn f16_to_f64(h: u16) -> f64 {
+    let sign = if h & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exp = ((h >> 10) & 0x1f) as i32;
+    let mant = (h & 0x3ff) as f64;
+    sign * match exp {
+        0 => mant * 2f64.powi(-24),
+        31 if mant == 0.0 => f64::INFINITY,
+        31 => f64::NAN,
+        _ => (1.0 + mant / 1024.0) * 2f64.powi(exp - 15),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(cases: &[(&[u8], &str)]) {
+        for (bytes, expected) in cases {
+            assert_eq!(diagnostic(bytes), *expected, "{}", hex(bytes));
+        }
+    }
+
+    #[test]
+    fn integers_at_the_argument_boundaries() {
+        let max = [0x1b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+        let min = [0x3b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+        check(&[
+            (&[0x00], "0"),
+            (&[0x17], "23"),
+            (&[0x18, 0x18], "24"),
+            (&[0x19, 0x01, 0x00], "256"),
+            (&max, "18446744073709551615"),
+            (&[0x20], "-1"),
+            (&min, "-18446744073709551616"),
+        ]);
+    }
+
+    #[test]
+    fn strings_and_indefinite_lengths() {
+        check(&[
+            (&[0x62, b'"', b'\n'], r#""\"\n""#),
+            (&[0x42, 0x01, 0x02], "h'0102'"),
+            (&[0x40], "h''"),
+            (&[0x61, 0xff], "<invalid utf-8 h'ff'>"),
+            (&[0x5f, 0x41, 0x01, 0x41, 0x02, 0xff], "(_ h'01', h'02')"),
+            (&[0x7f, 0x61, b'a', 0xff], r#"(_ "a")"#),
+            (&[0x9f, 0x01, 0x80, 0xff], "[_ 1, []]"),
+            (&[0xbf, 0x61, b'a', 0xa0, 0xff], r#"{_ "a": {}}"#),
+            (&[0xd8, 0x2a, 0x41, 0x00], "42(h'00')"),
+        ]);
+    }
+
+    #[test]
+    fn simple_values_and_floats() {
+        check(&[
+            (&[0xf4], "false"),
+            (&[0xf5], "true"),
+            (&[0xf6], "null"),
+            (&[0xf7], "undefined"),
+            (&[0xe0], "simple(0)"),
+            (&[0xf8, 0x20], "simple(32)"),
+            (&[0xf9, 0x3c, 0x00], "1.0_1"),
+            (&[0xf9, 0x80, 0x00], "-0.0_1"),
+            (&[0xf9, 0x00, 0x01], "5.960464477539063e-8_1"),
+            (&[0xf9, 0x7c, 0x00], "Infinity_1"),
+            (&[0xf9, 0xfc, 0x00], "-Infinity_1"),
+            (&[0xf9, 0x7e, 0x00], "NaN_1"),
+            (&[0xfa, 0x3f, 0xc0, 0x00, 0x00], "1.5_2"),
+            (&[0xfb, 0x3f, 0xf0, 0, 0, 0, 0, 0, 0], "1.0_3"),
+        ]);
+    }
+
+    #[test]
+    fn sequences_and_malformed_input() {
+        check(&[
+            (&[], ""),
+            (&[0x01, 0x02], "1, 2"),
+            (
+                &[0x62, b'h'],
+                "<error at byte 1: length 2 runs past end of input>",
+            ),
+            (&[0x01, 0xff], "1, <error at byte 2: unexpected break>"),
+            (
+                &[0x1c],
+                "<error at byte 1: reserved additional information 28>",
+            ),
+            (&[0xfc], "<error at byte 1: reserved simple value 28>"),
+            (
+                &[0x82, 0x01],
+                "[1, <error at byte 2: unexpected end of input>",
+            ),
+            (
+                &[0x9f, 0x01],
+                "[_ 1, <error at byte 2: unexpected end of input>",
+            ),
+            (&[0x3f], "<error at byte 1: indefinite length integer>"),
+        ]);
+    }
+
+    #[test]
+    fn deep_nesting_is_cut_off() {
+        let out = diagnostic(&[0x81; 1000]);
+        assert!(out.starts_with(&"[".repeat(MAX_DEPTH + 1)), "{}", out);
+        assert!(out.ends_with("nesting too deep>"), "{}", out);
+    }
+
+    #[test]
+    fn f16_matches_f32_widening() {
+        for (h, f) in [
+            (0x3c00, 1.0f32),
+            (0xc000, -2.0),
+            (0x7bff, 65504.0),
+            (0x0400, 6.1035156e-5),
+        ] {
+            assert_eq!(f16_to_f64(h), f as f64);
+        }
+    }
+}
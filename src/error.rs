@@ -0,0 +1,44 @@
+//! Errors of a conversion, grouped by the stage that failed so callers can
+//! tell bad input apart from unsupported schemas and output failures.
+
+use std::fmt;
+
+/// The underlying cause of an error.
+pub type Source = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The schema of the blocks cannot be represented in the output format.
+    Schema { path: String, reason: String },
+    /// Input bytes could not be decoded, e.g. a block or a CID.
+    Decode { what: String, source: Source },
+    /// A value or file could not be written.
+    Write { what: String, source: Source },
+    /// The conversion was cancelled before it finished.
+    Cancelled,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Schema { path, reason } if path.is_empty() => {
+                write!(f, "unsupported schema: {}", reason)
+            }
+            Error::Schema { path, reason } => {
+                write!(f, "unsupported schema at {}: {}", path, reason)
+            }
+            Error::Decode { what, .. } => write!(f, "decoding {}", what),
+            Error::Write { what, .. } => write!(f, "writing {}", what),
+            Error::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Schema { .. } | Error::Cancelled => None,
+            Error::Decode { source, .. } | Error::Write { source, .. } => Some(source.as_ref()),
+        }
+    }
+}
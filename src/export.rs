@@ -0,0 +1,222 @@
+//! Writing the blocks of converted files back to a CAR, `carquet export`.
+//!
+//! A block can be exported from a table that kept its bytes: the `rawdata`
+//! column of the tables of decoded blocks converted with `--include-raw`, or
+//! the `data` column of `raw_blocks`, whose data is the bytes of the raw
+//! blocks. Each block is checked against the hash of its CID, and written once
+//! however many tables it is in.
+
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, Context, Result};
+use iroh_car::{CarHeader, CarWriter};
+use libipld::Cid;
+use parquet::{
+    basic::{ConvertedType, Type as PhysicalType},
+    column::reader::get_typed_column_reader,
+    data_type::{ByteArray, ByteArrayType},
+    file::reader::{FileReader, RowGroupReader, SerializedFileReader},
+};
+
+use crate::verify;
+
+/// Column of the block bytes in the tables of decoded blocks.
+pub const RAWDATA: &str = "rawdata";
+
+const RAW: u64 = 0x55;
+
+/// Totals of an export.
+#[derive(Debug, Default)]
+pub struct Exported {
+    pub blocks: usize,
+    /// Rows whose block was already written from another row.
+    pub duplicates: usize,
+    /// Files without the bytes of their blocks, which were not exported.
+    pub skipped: Vec<PathBuf>,
+}
+
+/// Writes the blocks of the parquet files, and of those in the directories, to
+/// a CAR with the given roots, or the first block as its root if there are none.
+pub async fn export(
+    inputs: &[PathBuf],
+    cid_column: &str,
+    roots: &[Cid],
+    output: &Path,
+) -> Result<Exported> {
+    let files = parquet_files(inputs)?;
+    let mut exported = Exported::default();
+    let mut seen = HashSet::new();
+    let mut writer = None;
+    for path in &files {
+        let f = std::fs::File::open(path).context(format!("opening {}", path.display()))?;
+        let reader = SerializedFileReader::new(f).context(format!("reading {}", path.display()))?;
+        let columns = match block_columns(&reader, cid_column) {
+            Some(Some(columns)) => columns,
+            Some(None) => {
+                exported.skipped.push(path.clone());
+                continue;
+            }
+            // Such as the tables of exploded lists, rows of the blocks of others.
+            None => continue,
+        };
+        for i in 0..reader.num_row_groups() {
+            let row_group = reader.get_row_group(i)?;
+            let blocks = row_group_blocks(row_group.as_ref(), &columns).context(format!(
+                "reading row group {} of {}",
+                i,
+                path.display()
+            ))?;
+            for (cid, bytes) in blocks {
+                if !seen.insert(cid) {
+                    exported.duplicates += 1;
+                    continue;
+                }
+                verify::check_hash(&cid, &bytes).context(format!("in {}", path.display()))?;
+                let writer = match &mut writer {
+                    Some(writer) => writer,
+                    None => {
+                        let f = tokio::fs::File::create(output)
+                            .await
+                            .context(format!("creating {}", output.display()))?;
+                        let roots = if roots.is_empty() {
+                            vec![cid]
+                        } else {
+                            roots.to_vec()
+                        };
+                        writer.insert(CarWriter::new(CarHeader::new_v1(roots), f))
+                    }
+                };
+                writer.write(cid, bytes).await?;
+                exported.blocks += 1;
+            }
+        }
+    }
+    let Some(writer) = writer else {
+        return Err(anyhow!(
+            "no blocks to export, the files have no {} column and are not raw_blocks, \
+             convert with --include-raw to keep the bytes of the blocks",
+            RAWDATA
+        ));
+    };
+    writer.finish().await?;
+    Ok(exported)
+}
+
+/// Indices of the CID and block bytes columns of a table of blocks.
+struct BlockColumns {
+    cid: usize,
+    cid_utf8: bool,
+    bytes: usize,
+    /// Whether the bytes are the `data` of raw blocks, kept only for raw CIDs.
+    raw: bool,
+}
+
+/// The files, and the parquet files of the directories, in order of name.
+pub(crate) fn parquet_files(inputs: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for input in inputs {
+        if input.is_dir() {
+            let mut found = Vec::new();
+            for entry in std::fs::read_dir(input).context(format!("reading {}", input.display()))? {
+                let path = entry?.path();
+                if path.extension().is_some_and(|e| e == "parquet") {
+                    found.push(path);
+                }
+            }
+            found.sort();
+            files.extend(found);
+        } else {
+            files.push(input.clone());
+        }
+    }
+    Ok(files)
+}
+
+/// The columns of the blocks of the file, `Some(None)` if it is a table of
+/// blocks without their bytes and `None` if it is not one.
+fn block_columns(
+    reader: &SerializedFileReader<std::fs::File>,
+    cid_column: &str,
+) -> Option<Option<BlockColumns>> {
+    let schema = reader.metadata().file_metadata().schema_descr();
+    let top_level = |name: &str| {
+        schema.columns().iter().position(|c| {
+            c.path().parts() == [name]
+                && c.physical_type() == PhysicalType::BYTE_ARRAY
+                && c.max_rep_level() == 0
+        })
+    };
+    let cid = top_level(cid_column)?;
+    let (bytes, raw) = match (top_level(RAWDATA), top_level("data")) {
+        (Some(i), _) => (i, false),
+        (None, Some(i)) => (i, true),
+        (None, None) => return Some(None),
+    };
+    Some(Some(BlockColumns {
+        cid,
+        cid_utf8: schema.column(cid).converted_type() == ConvertedType::UTF8,
+        bytes,
+        raw,
+    }))
+}
+
+/// The CIDs and bytes of the blocks of a row group, leaving out rows without
+/// bytes and, in raw_blocks, blocks that are not raw.
+fn row_group_blocks(
+    row_group: &dyn RowGroupReader,
+    columns: &BlockColumns,
+) -> Result<Vec<(Cid, Vec<u8>)>> {
+    let cids = column_values(row_group, columns.cid)?;
+    let bytes = column_values(row_group, columns.bytes)?;
+    let mut blocks = Vec::new();
+    for (cid, bytes) in cids.into_iter().zip(bytes) {
+        let (Some(cid), Some(bytes)) = (cid, bytes) else {
+            continue;
+        };
+        let cid = if columns.cid_utf8 {
+            std::str::from_utf8(cid.data())
+                .map_err(anyhow::Error::from)
+                .and_then(|s| Ok(Cid::from_str(s)?))
+        } else {
+            Ok(Cid::try_from(cid.data())?)
+        }
+        .context("parsing a CID")?;
+        if columns.raw && cid.codec() != RAW {
+            continue;
+        }
+        blocks.push((cid, bytes.data().to_vec()));
+    }
+    Ok(blocks)
+}
+
+/// The values of a top level column, `None` for nulls.
+fn column_values(row_group: &dyn RowGroupReader, i: usize) -> Result<Vec<Option<ByteArray>>> {
+    const BATCH: usize = 1024;
+    let max_def = row_group
+        .metadata()
+        .column(i)
+        .column_descr()
+        .max_def_level();
+    let mut reader = get_typed_column_reader::<ByteArrayType>(row_group.get_column_reader(i)?);
+    let mut entries = Vec::new();
+    loop {
+        let mut defs = vec![0; BATCH];
+        let mut values = vec![ByteArray::default(); BATCH];
+        let (read, levels) = reader.read_batch(BATCH, Some(&mut defs), None, &mut values)?;
+        // Required columns have no levels, each value is an entry, and their
+        // definition levels are left at 0.
+        let levels = if max_def == 0 { read } else { levels };
+        if levels == 0 {
+            break;
+        }
+        let mut values = values.into_iter().take(read);
+        for def in &defs[..levels] {
+            entries.push(if *def == max_def { values.next() } else { None });
+        }
+    }
+    Ok(entries)
+}
@@ -0,0 +1,250 @@
+//! Synthesizing CAR files of dag-cbor blocks with configurable shapes.
+//!
+//! Shapes are described in JSON, a type name is one of `null`, `bool`,
+//! `integer`, `float`, `string`, `bytes` or `link`, and composite shapes are
+//! objects:
+//!
+//! ```json
+//! {"shapes": [
+//!   {"weight": 3, "shape": {"map": {
+//!     "height": "integer",
+//!     "prev": {"list": "link", "min": 0, "max": 4},
+//!     "note": {"optional": "string"},
+//!     "value": {"one_of": ["integer", "string"]}
+//!   }}}
+//! ]}
+//! ```
+//!
+//! Generation is deterministic for a given seed so fixtures are reproducible.
+
+use std::{collections::BTreeMap, path::Path};
+
+use anyhow::{anyhow, Context, Result};
+use iroh_car::{CarHeader, CarWriter};
+use libipld::{
+    cbor::DagCborCodec,
+    multihash::{Code, MultihashDigest},
+    prelude::Codec,
+    Cid, Ipld,
+};
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize)]
+pub struct Shapes {
+    pub shapes: Vec<WeightedShape>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WeightedShape {
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+    pub shape: Shape,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Shape {
+    Kind(Kind),
+    List {
+        list: Box<Shape>,
+        #[serde(default)]
+        min: usize,
+        #[serde(default = "default_max_len")]
+        max: usize,
+    },
+    Map {
+        map: BTreeMap<String, Shape>,
+    },
+    Optional {
+        optional: Box<Shape>,
+    },
+    OneOf {
+        one_of: Vec<Shape>,
+    },
+}
+
+fn default_max_len() -> usize {
+    4
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Null,
+    Bool,
+    Integer,
+    Float,
+    String,
+    Bytes,
+    Link,
+}
+
+impl Shapes {
+    pub fn load(path: &Path) -> Result<Self> {
+        let f = std::fs::File::open(path).context(format!("opening {}", path.display()))?;
+        let shapes: Shapes = serde_json::from_reader(std::io::BufReader::new(f))
+            .context(format!("parsing {}", path.display()))?;
+        if shapes.shapes.iter().map(|s| s.weight).sum::<u32>() == 0 {
+            return Err(anyhow!(
+                "shapes file must contain a shape with a positive weight"
+            ));
+        }
+        Ok(shapes)
+    }
+}
+
+impl Default for Shapes {
+    /// A small chain of records with links to earlier ones.
+    fn default() -> Self {
+        let shape = Shape::Map {
+            map: BTreeMap::from([
+                ("height".to_string(), Shape::Kind(Kind::Integer)),
+                ("name".to_string(), Shape::Kind(Kind::String)),
+                (
+                    "prev".to_string(),
+                    Shape::List {
+                        list: Box::new(Shape::Kind(Kind::Link)),
+                        min: 0,
+                        max: 3,
+                    },
+                ),
+                (
+                    "note".to_string(),
+                    Shape::Optional {
+                        optional: Box::new(Shape::Kind(Kind::String)),
+                    },
+                ),
+            ]),
+        };
+        Shapes {
+            shapes: vec![WeightedShape { weight: 1, shape }],
+        }
+    }
+}
+
+/// A splitmix64 generator, good enough for fixtures and free of dependencies.
+pub(crate) struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng(seed)
+    }
+
+    pub fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    pub fn below(&mut self, n: u64) -> u64 {
+        if n == 0 {
+            0
+        } else {
+            self.next() % n
+        }
+    }
+}
+
+struct Generator {
+    rng: Rng,
+    cids: Vec<Cid>,
+}
+
+impl Generator {
+    fn value(&mut self, shape: &Shape) -> Option<Ipld> {
+        Some(match shape {
+            Shape::Kind(kind) => self.kind(kind),
+            Shape::List { list, min, max } => {
+                let len = *min + self.rng.below((max.saturating_sub(*min) + 1) as u64) as usize;
+                Ipld::List((0..len).filter_map(|_| self.value(list)).collect())
+            }
+            Shape::Map { map } => Ipld::Map(
+                map.iter()
+                    .filter_map(|(k, v)| self.value(v).map(|v| (k.clone(), v)))
+                    .collect(),
+            ),
+            Shape::Optional { optional } => {
+                if self.rng.below(2) == 0 {
+                    return None;
+                }
+                return self.value(optional);
+            }
+            Shape::OneOf { one_of } => {
+                let i = self.rng.below(one_of.len() as u64) as usize;
+                return one_of.get(i).and_then(|s| self.value(s));
+            }
+        })
+    }
+
+    fn kind(&mut self, kind: &Kind) -> Ipld {
+        match kind {
+            Kind::Null => Ipld::Null,
+            Kind::Bool => Ipld::Bool(self.rng.below(2) == 1),
+            Kind::Integer => Ipld::Integer(self.rng.below(1_000_000) as i128),
+            Kind::Float => Ipld::Float(self.rng.below(1_000_000) as f64 / 100.0),
+            Kind::String => Ipld::String(format!("s{}", self.rng.below(10_000))),
+            Kind::Bytes => {
+                let len = 8 + self.rng.below(25) as usize;
+                Ipld::Bytes((0..len).map(|_| self.rng.next() as u8).collect())
+            }
+            Kind::Link => {
+                // Prefer links to blocks already in the fixture so DAGs are traversable.
+                if self.cids.is_empty() {
+                    let seed = self.rng.next().to_be_bytes();
+                    Ipld::Link(Cid::new_v1(0x71, Code::Sha2_256.digest(&seed)))
+                } else {
+                    let i = self.rng.below(self.cids.len() as u64) as usize;
+                    Ipld::Link(self.cids[i])
+                }
+            }
+        }
+    }
+}
+
+/// Writes a CAR of `records` blocks drawn from the shapes to `output`.
+pub async fn generate(shapes: &Shapes, records: usize, seed: u64, output: &Path) -> Result<()> {
+    let mut gen = Generator {
+        rng: Rng(seed),
+        cids: Vec::with_capacity(records),
+    };
+    let total: u64 = shapes.shapes.iter().map(|s| s.weight as u64).sum();
+    let mut blocks = Vec::with_capacity(records);
+    for _ in 0..records {
+        let mut pick = gen.rng.below(total);
+        let shape = shapes
+            .shapes
+            .iter()
+            .find(|s| {
+                if pick < s.weight as u64 {
+                    true
+                } else {
+                    pick -= s.weight as u64;
+                    false
+                }
+            })
+            .map(|s| &s.shape)
+            .expect("weights sum to the total");
+        let data = gen.value(shape).unwrap_or(Ipld::Null);
+        let bytes = DagCborCodec.encode(&data)?;
+        let cid = Cid::new_v1(0x71, Code::Sha2_256.digest(&bytes));
+        gen.cids.push(cid);
+        blocks.push((cid, bytes));
+    }
+
+    let roots = blocks.last().map(|(cid, _)| vec![*cid]).unwrap_or_default();
+    let mut f = tokio::fs::File::create(output)
+        .await
+        .context(format!("creating {}", output.display()))?;
+    let mut writer = CarWriter::new(CarHeader::new_v1(roots), &mut f);
+    for (cid, bytes) in blocks {
+        writer.write(cid, bytes).await?;
+    }
+    writer.finish().await?;
+    Ok(())
+}
@@ -0,0 +1,48 @@
+//! Entry points for the cargo-fuzz targets in `fuzz/`.
+//!
+//! Each function takes arbitrary bytes and runs them through the same parse
+//! path as a conversion. Errors are expected, panics are bugs.
+
+use libipld::{cbor::DagCborCodec, prelude::Codec, Ipld};
+
+use crate::{
+    diag, lazy,
+    scan::Sections,
+    utf8::{self, Utf8Policy},
+};
+
+/// Parses the bytes as a CAR header.
+pub fn car_header(data: &[u8]) {
+    let _ = Sections::new(data);
+}
+
+/// Parses the bytes as a whole CAR, reading the varint framed blocks and
+/// decoding each one.
+pub fn car(data: &[u8]) {
+    let Ok(mut car) = Sections::new(data) else {
+        return;
+    };
+    while let Ok(Some((_, bytes))) = car.next_block() {
+        block(&bytes);
+    }
+}
+
+/// Scans the bytes as a CAR, reading only the framing and CIDs of the blocks.
+pub fn scan(data: &[u8]) {
+    let Ok(mut car) = Sections::new(data) else {
+        return;
+    };
+    while let Ok(Some(_)) = car.next_section() {}
+}
+
+/// Decodes the bytes as a dag-cbor block, falling back to the invalid UTF-8
+/// decoder and rendering them as diagnostic notation when they are rejected,
+/// and partially as for a query selecting a field.
+pub fn block(data: &[u8]) {
+    let _ = lazy::decode(data, &[vec!["a".to_string(), "b".to_string()]]);
+    let decoded: Result<Ipld, _> = DagCborCodec.decode(data);
+    if decoded.is_err() {
+        let _ = utf8::decode(data, Utf8Policy::Bytes);
+        let _ = diag::diagnostic(data);
+    }
+}
@@ -0,0 +1,278 @@
+//! Notifying other jobs once a run ends, `--on-complete-webhook` and
+//! `--on-complete-exec`.
+//!
+//! When a run succeeds, fails or is cancelled its outcome is posted as JSON
+//! to the webhook and written to the standard input of the command:
+//!
+//! ```json
+//! {"status": "succeeded", "output_dir": "out", "error": null, "report": {"files_written": 3, ..}}
+//! ```
+//!
+//! The status is `succeeded`, `failed` or `cancelled`, and the report holds
+//! the counters of [`Report`] with its text as printed. Webhooks are plain
+//! HTTP, sending the headers of `--webhook-header`; a command such as `curl`
+//! posts to others. Their credentials can be given by name, see
+//! [`crate::secrets`]. A hook that fails, by a status other than 2xx or an
+//! exit code other than 0, fails a run that succeeded. With `convert-sharded`
+//! each shard notifies on its own.
+
+use std::{
+    fmt,
+    io::{Read, Write},
+    net::TcpStream,
+    path::Path,
+    process::{Command, Stdio},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context, Result};
+use serde_json::json;
+
+use crate::{secrets::Secrets, ConvertArgs, Report};
+
+/// How long connecting to a webhook, and each write and read, may take.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The hooks of a run, their secrets resolved.
+#[derive(Debug)]
+pub(crate) struct Hooks {
+    webhook: Option<Webhook>,
+    exec: Option<String>,
+    secrets: Secrets,
+}
+
+/// An `http://` URL posted to, and the headers sent. Its debug form leaves
+/// out what the secrets were resolved into.
+struct Webhook {
+    /// The URL as given, before its secrets are resolved.
+    template: String,
+    host: String,
+    port: u16,
+    path: String,
+    headers: Vec<String>,
+}
+
+/// Checks the scheme of a webhook URL.
+pub(crate) fn parse_webhook(url: &str) -> Result<String> {
+    if !url.starts_with("http://") {
+        return Err(anyhow!(
+            "expected an http:// URL, got {url}; post to others with --on-complete-exec \
+             'curl --json @- URL'"
+        ));
+    }
+    Ok(url.to_string())
+}
+
+pub(crate) fn parse_header(header: &str) -> Result<String> {
+    match header.split_once(':') {
+        Some((name, _)) if !name.trim().is_empty() => Ok(header.to_string()),
+        _ => Err(anyhow!("expected NAME: VALUE, got {header}")),
+    }
+}
+
+impl Hooks {
+    /// The hooks of the options, failing before the run if a secret is not set.
+    pub(crate) fn new(args: &ConvertArgs) -> Result<Self> {
+        let secrets = Secrets::load(args.secrets_file.as_deref())?;
+        let webhook = match &args.on_complete_webhook {
+            Some(template) => Some(
+                Webhook::parse(template, &secrets, &args.webhook_headers)
+                    .context(format!("--on-complete-webhook {template}"))?,
+            ),
+            None => None,
+        };
+        Ok(Self {
+            webhook,
+            exec: args.on_complete_exec.clone(),
+            secrets,
+        })
+    }
+
+    /// Notifies the hooks of the outcome of a run into `dir`.
+    pub(crate) fn notify(&self, dir: &Path, report: &Report, result: &Result<()>) -> Result<()> {
+        if self.webhook.is_none() && self.exec.is_none() {
+            return Ok(());
+        }
+        let (status, error) = match result {
+            Ok(()) => ("succeeded", None),
+            Err(_) if report.cancelled => ("cancelled", None),
+            Err(e) => ("failed", Some(format!("{:#}", e))),
+        };
+        let body = serde_json::to_vec(&json!({
+            "status": status,
+            "output_dir": dir.display().to_string(),
+            "error": error,
+            "report": report.to_json(),
+        }))?;
+        if let Some(webhook) = &self.webhook {
+            webhook
+                .post(&body)
+                .context(format!("posting the report to {}", webhook.template))?;
+        }
+        if let Some(command) = &self.exec {
+            self.run(command, &body)
+                .context(format!("running {command:?}"))?;
+        }
+        Ok(())
+    }
+
+    /// Runs the command with `sh -c`, the body written to its standard input
+    /// and the secrets of the file in its environment.
+    fn run(&self, command: &str, body: &[u8]) -> Result<()> {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .envs(self.secrets.values())
+            .stdin(Stdio::piped())
+            .spawn()?;
+        let mut stdin = child.stdin.take().expect("piped stdin");
+        // A command that does not read its input may exit before it is written.
+        let written = stdin.write_all(body);
+        drop(stdin);
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(anyhow!("exited with {status}"));
+        }
+        match written {
+            Err(e) if e.kind() != std::io::ErrorKind::BrokenPipe => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl fmt::Debug for Webhook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Webhook")
+            .field("template", &self.template)
+            .field("headers", &self.headers.len())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Resolves the secrets of the template, rejecting values that would break
+/// out of the line of the request they are sent in.
+fn resolve_line(secrets: &Secrets, template: &str) -> Result<String> {
+    let line = secrets.resolve(template)?;
+    if line.chars().any(|c| c.is_control() && c != '\t') {
+        return Err(anyhow!(
+            "the value has a control character, such as a line break"
+        ));
+    }
+    Ok(line)
+}
+
+impl Webhook {
+    fn parse(template: &str, secrets: &Secrets, headers: &[String]) -> Result<Self> {
+        let url = resolve_line(secrets, template)?;
+        let rest = url.strip_prefix("http://").unwrap_or(&url);
+        let (authority, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (
+                host,
+                port.parse()
+                    .map_err(|_| anyhow!("expected a port number after the host"))?,
+            ),
+            None => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(anyhow!("expected a host"));
+        }
+        let headers = headers
+            .iter()
+            .map(|h| resolve_line(secrets, h).context(format!("--webhook-header {h}")))
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            template: template.to_string(),
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+            headers,
+        })
+    }
+
+    fn post(&self, body: &[u8]) -> Result<()> {
+        let addr = std::net::ToSocketAddrs::to_socket_addrs(&(self.host.as_str(), self.port))?
+            .next()
+            .ok_or_else(|| anyhow!("{} has no address", self.host))?;
+        let mut stream = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        let mut request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\nConnection: close\r\n",
+            self.path,
+            self.host,
+            body.len()
+        );
+        for header in &self.headers {
+            request.push_str(header);
+            request.push_str("\r\n");
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes())?;
+        stream.write_all(body)?;
+        stream.flush()?;
+        // Only the status line of the response is read.
+        let mut response = Vec::new();
+        stream.take(1024).read_to_end(&mut response)?;
+        let response = String::from_utf8_lossy(&response);
+        let status = response.lines().next().unwrap_or_default();
+        match status.split_whitespace().nth(1) {
+            Some(code) if code.starts_with('2') => Ok(()),
+            _ => Err(anyhow!("answered {status:?}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secrets(values: &[(&str, &str)]) -> Secrets {
+        let path = std::env::temp_dir().join(format!("carquet-hooks-{}", std::process::id()));
+        let text: String = values.iter().map(|(k, v)| format!("{k}={v}\n")).collect();
+        std::fs::write(&path, text).unwrap();
+        let secrets = Secrets::load(Some(&path)).unwrap();
+        std::fs::remove_file(path).unwrap();
+        secrets
+    }
+
+    #[test]
+    fn headers_resolve_their_secrets() {
+        let secrets = secrets(&[("CARQUET_TEST_TOKEN", "s3cr3t")]);
+        let webhook = Webhook::parse(
+            "http://localhost:8080/hook",
+            &secrets,
+            &["Authorization: Bearer ${CARQUET_TEST_TOKEN}".to_string()],
+        )
+        .unwrap();
+        assert_eq!(
+            (webhook.host.as_str(), webhook.port, webhook.path.as_str()),
+            ("localhost", 8080, "/hook")
+        );
+        assert_eq!(webhook.headers, ["Authorization: Bearer s3cr3t"]);
+        let debug = format!("{:?}", webhook);
+        assert!(!debug.contains("s3cr3t"), "{}", debug);
+        let debug = format!("{:?}", secrets);
+        assert!(!debug.contains("s3cr3t"), "{}", debug);
+    }
+
+    #[test]
+    fn line_breaks_in_resolved_values_are_rejected() {
+        // A value read from the environment may hold any character.
+        std::env::set_var("CARQUET_TEST_INJECTED", "x\r\nX-Injected: 1");
+        let secrets = Secrets::default();
+        let header = "X-Token: ${CARQUET_TEST_INJECTED}".to_string();
+        let err = Webhook::parse("http://localhost/", &secrets, &[header]).unwrap_err();
+        assert_eq!(
+            format!("{:#}", err),
+            "--webhook-header X-Token: ${CARQUET_TEST_INJECTED}: the value has a control character, such as a line break"
+        );
+        let err =
+            Webhook::parse("http://localhost/${CARQUET_TEST_INJECTED}", &secrets, &[]).unwrap_err();
+        assert!(!format!("{:#}", err).contains("Injected"), "{:#}", err);
+    }
+}
@@ -0,0 +1,188 @@
+//! The schemas inferred for blocks by their CID, kept by `--inference-cache`.
+//!
+//! Inferring the schemas before writing, for `--schema-cache` and
+//! `--infer-sample`, decodes every block once more. The cache file lists the
+//! schema of each block inferred by earlier runs and shards under the same
+//! options, so a block with a CID it has, once a block of its schema was
+//! decoded in the run, is counted towards the schema without being decoded:
+//!
+//! ```json
+//! {"options": "9b74c9897bac770f..", "schemas": [..], "blocks": {"bafyrei..": 0}}
+//! ```
+//!
+//! As the CID includes the codec, blocks of one multihash decoded by
+//! different codecs are kept apart. A cache of other options, those changing
+//! the decoded blocks, is replaced. The blocks of a run are added to the file
+//! once its inference succeeds; shards of one `convert-sharded` add theirs in
+//! turn, but separate processes sharing a cache must not overlap.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::{Context, Result};
+use libipld::Cid;
+use serde::{Deserialize, Serialize};
+
+use crate::{registry, ConvertArgs, Schema};
+
+/// Held while a cache file is read and rewritten, by the shards of a run.
+static SAVING: Mutex<()> = Mutex::new(());
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CacheFile {
+    options: String,
+    schemas: Vec<Schema>,
+    blocks: BTreeMap<String, usize>,
+}
+
+#[derive(Debug)]
+pub(crate) struct Cache {
+    path: PathBuf,
+    options: String,
+    known: Mutex<Known>,
+}
+
+#[derive(Debug, Default)]
+struct Known {
+    /// The schemas of the file and of the blocks recorded, and their index.
+    schemas: Vec<Schema>,
+    index: HashMap<Schema, usize>,
+    blocks: HashMap<Cid, usize>,
+    /// The number of blocks of each schema decoded in this run.
+    decoded: HashMap<usize, usize>,
+    /// The number of blocks of each schema counted without decoding.
+    counted: HashMap<usize, usize>,
+}
+
+impl Cache {
+    /// Loads the cache, empty if the file does not exist yet or was written
+    /// under other options.
+    pub(crate) fn load(path: &Path, args: &ConvertArgs) -> Result<Self> {
+        let options = options(args);
+        let mut known = Known::default();
+        if let Some(file) = read(path)?.filter(|f| f.options == options) {
+            known.merge(file, path)?;
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            options,
+            known: Mutex::new(known),
+        })
+    }
+
+    /// Whether the schema of the block is known and a block of it was decoded
+    /// in this run, counting the block if so.
+    pub(crate) fn hit(&self, cid: &Cid) -> bool {
+        let mut known = self.known.lock().expect("inference cache lock");
+        let Some(i) = known.blocks.get(cid).copied() else {
+            return false;
+        };
+        if !known.decoded.contains_key(&i) {
+            return false;
+        }
+        *known.counted.entry(i).or_default() += 1;
+        true
+    }
+
+    /// Records the schema inferred for a decoded block.
+    pub(crate) fn record(&self, cid: Cid, schema: &Schema) {
+        let mut known = self.known.lock().expect("inference cache lock");
+        let i = known.add(schema.clone());
+        known.blocks.insert(cid, i);
+        *known.decoded.entry(i).or_default() += 1;
+    }
+
+    /// The numbers of blocks counted without decoding, by their schema.
+    pub(crate) fn counted(&self) -> Vec<(Schema, usize)> {
+        let known = self.known.lock().expect("inference cache lock");
+        known
+            .counted
+            .iter()
+            .map(|(i, n)| (known.schemas[*i].clone(), *n))
+            .collect()
+    }
+
+    /// Adds the blocks to the file, with those other shards added since it
+    /// was loaded.
+    pub(crate) fn save(self) -> Result<()> {
+        let _saving = SAVING.lock().unwrap_or_else(|e| e.into_inner());
+        let mut known = self.known.into_inner().expect("inference cache lock");
+        if let Some(file) = read(&self.path)?.filter(|f| f.options == self.options) {
+            known.merge(file, &self.path)?;
+        }
+        let file = CacheFile {
+            options: self.options,
+            blocks: known
+                .blocks
+                .iter()
+                .map(|(cid, i)| (cid.to_string(), *i))
+                .collect(),
+            schemas: known.schemas,
+        };
+        let tmp = PathBuf::from(format!("{}.tmp", self.path.display()));
+        let f = std::fs::File::create(&tmp).context(format!("creating {}", tmp.display()))?;
+        let mut out = std::io::BufWriter::new(f);
+        serde_json::to_writer(&mut out, &file)?;
+        out.flush()?;
+        std::fs::rename(&tmp, &self.path).context(format!("replacing {}", self.path.display()))
+    }
+}
+
+impl Known {
+    /// Adds the blocks of the file, keeping the schemas of those known.
+    fn merge(&mut self, file: CacheFile, path: &Path) -> Result<()> {
+        let ids: Vec<usize> = file.schemas.into_iter().map(|s| self.add(s)).collect();
+        for (cid, i) in file.blocks {
+            let cid = Cid::try_from(cid.as_str()).context(format!("parsing {}", path.display()))?;
+            if let Some(id) = ids.get(i) {
+                self.blocks.entry(cid).or_insert(*id);
+            }
+        }
+        Ok(())
+    }
+
+    fn add(&mut self, schema: Schema) -> usize {
+        if let Some(i) = self.index.get(&schema) {
+            return *i;
+        }
+        self.schemas.push(schema.clone());
+        self.index.insert(schema, self.schemas.len() - 1);
+        self.schemas.len() - 1
+    }
+}
+
+/// The cache file, `None` if it does not exist.
+fn read(path: &Path) -> Result<Option<CacheFile>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let f = std::fs::File::open(path).context(format!("opening {}", path.display()))?;
+    serde_json::from_reader(std::io::BufReader::new(f))
+        .map(Some)
+        .context(format!("parsing {}", path.display()))
+}
+
+/// Fingerprint of the options the decoded blocks and their schemas depend on.
+fn options(args: &ConvertArgs) -> String {
+    let options = format!(
+        "{:?}",
+        (
+            args.invalid_utf8,
+            args.duplicates,
+            args.link_codecs,
+            args.explode_cids,
+            &args.link_forms,
+            args.adl,
+            &args.sql,
+            args.include_raw,
+            args.block_size,
+            &args.schema_registry,
+            args.schema_fingerprint,
+        )
+    );
+    registry::digest(options.as_bytes())
+}
@@ -0,0 +1,375 @@
+//! Commands that look at the blocks of a CAR without converting it.
+
+use std::{collections::VecDeque, io::Write, path::Path};
+
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
+use iroh_car::{CarHeader, CarWriter};
+use libipld::{cid::multibase::Base, Cid, Ipld};
+
+use crate::{
+    carv2, decode_dag, ipld_kind,
+    scan::{self, Section, Sections},
+    write_output, Block, Columns, Config, Format, Schema,
+};
+
+/// Multibase of printed CIDs.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CidBase {
+    #[default]
+    Base32,
+    Base36,
+    Base58btc,
+    Base64,
+    Base64url,
+    Base16,
+}
+
+impl CidBase {
+    fn base(&self) -> Base {
+        match self {
+            CidBase::Base32 => Base::Base32Lower,
+            CidBase::Base36 => Base::Base36Lower,
+            CidBase::Base58btc => Base::Base58Btc,
+            CidBase::Base64 => Base::Base64,
+            CidBase::Base64url => Base::Base64Url,
+            CidBase::Base16 => Base::Base16Lower,
+        }
+    }
+}
+
+/// Names of the common multicodecs of blocks.
+const CODECS: &[(&str, u64)] = &[
+    ("raw", 0x55),
+    ("dag-pb", 0x70),
+    ("dag-cbor", 0x71),
+    ("dag-json", 0x0129),
+    ("cbor", 0x51),
+    ("json", 0x0200),
+    ("libp2p-key", 0x72),
+];
+
+/// Parses a multicodec by name, e.g. `dag-cbor`, or by code, e.g. `0x71` or `113`.
+pub fn parse_codec(s: &str) -> Result<u64> {
+    if let Some((_, code)) = CODECS.iter().find(|(name, _)| *name == s) {
+        return Ok(*code);
+    }
+    match s.strip_prefix("0x") {
+        Some(hex) => Ok(u64::from_str_radix(hex, 16)?),
+        None => s
+            .parse()
+            .map_err(|_| anyhow!("unknown codec {s}, expected a name such as dag-cbor or a code")),
+    }
+}
+
+/// Names of the common multihash functions of CIDs.
+const HASHES: &[(&str, u64)] = &[
+    ("identity", 0x00),
+    ("sha1", 0x11),
+    ("sha2-256", 0x12),
+    ("sha2-512", 0x13),
+    ("sha3-256", 0x16),
+    ("sha3-512", 0x14),
+    ("keccak-256", 0x1b),
+    ("blake3", 0x1e),
+    ("blake2b-256", 0xb220),
+    ("blake2s-256", 0xb260),
+    ("sha2-256-trunc254-padded", 0x1012),
+    ("poseidon-bls12_381-a2-fc1", 0xb401),
+];
+
+/// Parses a multihash function by name, e.g. `sha2-256`, or by code, e.g.
+/// `0x12` or `18`.
+pub fn parse_hash(s: &str) -> Result<u64> {
+    if let Some((_, code)) = HASHES.iter().find(|(name, _)| *name == s) {
+        return Ok(*code);
+    }
+    match s.strip_prefix("0x") {
+        Some(hex) => Ok(u64::from_str_radix(hex, 16)?),
+        None => s
+            .parse()
+            .map_err(|_| anyhow!("unknown hash {s}, expected a name such as sha2-256 or a code")),
+    }
+}
+
+fn codec_name(code: u64) -> String {
+    match CODECS.iter().find(|(_, c)| *c == code) {
+        Some((name, _)) => name.to_string(),
+        None => format!("0x{:x}", code),
+    }
+}
+
+/// Prints the CID of every block, or of those with the codec, one per line.
+/// CIDv0 can only be written in base58btc and keeps it. Only the framing of
+/// the CAR is scanned, the blocks are skipped.
+pub async fn ls_cids(path: &Path, base: CidBase, codec: Option<u64>) -> Result<()> {
+    let f = carv2::open(path, 0)?;
+    let mut car = Sections::new(std::io::BufReader::with_capacity(scan::BUFFER, f))?;
+    let mut out = std::io::BufWriter::new(std::io::stdout().lock());
+    while let Some(Section { cid, .. }) = car.next_section()? {
+        if codec.is_some_and(|c| c != cid.codec()) {
+            continue;
+        }
+        let s = cid
+            .to_string_of_base(base.base())
+            .unwrap_or_else(|_| cid.to_string());
+        writeln!(out, "{}", s)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Prints a summary of each of the first `n` blocks.
+pub async fn head(path: &Path, n: usize) -> Result<()> {
+    let mut car = carv2::open_blocks(path)?;
+    let mut out = std::io::stdout().lock();
+    for _ in 0..n {
+        let Some((cid, bytes)) = car.next_block()? else {
+            break;
+        };
+        writeln!(out, "{}", summary(&cid, &bytes))?;
+    }
+    Ok(())
+}
+
+/// Prints a summary of each of the last `n` blocks.
+pub async fn tail(path: &Path, n: usize) -> Result<()> {
+    let mut out = std::io::stdout().lock();
+    for (cid, bytes) in last_blocks(path, n)? {
+        writeln!(out, "{}", summary(&cid, &bytes))?;
+    }
+    Ok(())
+}
+
+/// The last `n` blocks. The sections of a fully indexed CARv2 are found by
+/// its index, those of other CARs by scanning their framing, keeping the
+/// offsets of the last `n`, then only the last `n` blocks are read.
+fn last_blocks(path: &Path, n: usize) -> Result<Vec<(Cid, Vec<u8>)>> {
+    if n == 0 {
+        return Ok(Vec::new());
+    }
+    let start = match carv2::Index::open(path)? {
+        Some(mut index) if index.fully_indexed() => {
+            let sections = index.sections()?;
+            sections.get(sections.len().saturating_sub(n)).map(|s| s.0)
+        }
+        _ => {
+            let mut car = carv2::open_blocks(path)?;
+            let mut last = VecDeque::with_capacity(n);
+            loop {
+                let offset = car.position();
+                if car.next_section()?.is_none() {
+                    break;
+                }
+                if last.len() == n {
+                    last.pop_front();
+                }
+                last.push_back(offset);
+            }
+            last.front().copied()
+        }
+    };
+    let Some(start) = start else {
+        return Ok(Vec::new());
+    };
+    let input = std::io::BufReader::with_capacity(scan::BUFFER, carv2::open(path, start)?);
+    let mut car = Sections::resume(input, start);
+    let mut blocks = Vec::with_capacity(n);
+    while let Some(block) = car.next_block()? {
+        blocks.push(block);
+    }
+    Ok(blocks)
+}
+
+/// Maps with more keys than this are summarized with the first ones only.
+const SUMMARY_KEYS: usize = 8;
+
+/// One line describing a block: CID, codec, size and, for blocks other than
+/// raw ones, the shape of the data, decoded as by the conversion.
+fn summary(cid: &Cid, bytes: &[u8]) -> String {
+    let mut line = format!("{} {} {} bytes", cid, codec_name(cid.codec()), bytes.len());
+    if let Some(decoded) = decode_dag(cid, bytes) {
+        line.push(' ');
+        match decoded {
+            Ok(Ipld::Map(m)) => {
+                let mut keys: Vec<&str> = m.keys().take(SUMMARY_KEYS).map(|k| k.as_str()).collect();
+                if m.len() > SUMMARY_KEYS {
+                    keys.push("..");
+                }
+                line.push_str(&format!("map {{{}}}", keys.join(", ")));
+            }
+            Ok(Ipld::List(l)) => line.push_str(&format!("list of {}", l.len())),
+            Ok(data) => line.push_str(ipld_kind(&data)),
+            Err(e) => line.push_str(&format!("undecodable: {}", e)),
+        }
+    }
+    line
+}
+
+/// Prints the CID and path of every string or bytes value of the blocks other
+/// than raw ones containing `pattern`, returning the number of matching blocks.
+///
+/// With `output` the matching blocks are also written to a CAR, or their
+/// matches to a parquet or JSON lines table of (cid, path), by its extension.
+pub async fn grep(path: &Path, pattern: &str, output: Option<&Path>) -> Result<usize> {
+    let mut car = carv2::open_blocks(path)?;
+    let mut out = std::io::BufWriter::new(std::io::stdout().lock());
+    let mut blocks = Vec::new();
+    let mut rows: Vec<Block> = Vec::new();
+    let mut undecodable = 0;
+    while let Some((cid, bytes)) = car.next_block()? {
+        let Some(decoded) = decode_dag(&cid, &bytes) else {
+            continue;
+        };
+        let Ok(data) = decoded else {
+            undecodable += 1;
+            continue;
+        };
+        let mut paths = Vec::new();
+        matches(&data, "data", pattern, &mut paths);
+        if paths.is_empty() {
+            continue;
+        }
+        for p in paths {
+            writeln!(out, "{} {}", cid, p)?;
+            rows.push((
+                cid,
+                Ipld::Map([("path".to_string(), Ipld::String(p))].into()),
+                Vec::new(),
+            ));
+        }
+        if output.is_some() {
+            blocks.push((cid, bytes));
+        }
+    }
+    out.flush()?;
+    if undecodable > 0 {
+        eprintln!(
+            "{} blocks could not be decoded and were skipped",
+            undecodable
+        );
+    }
+    let found = blocks.len();
+    match output {
+        Some(path) if path.extension().is_some_and(|e| e == "car") => {
+            let mut f = tokio::fs::File::create(path)
+                .await
+                .context(format!("creating {}", path.display()))?;
+            let roots = blocks.iter().map(|(cid, _)| *cid).collect();
+            let mut writer = CarWriter::new(CarHeader::new_v1(roots), &mut f);
+            for (cid, bytes) in blocks {
+                writer.write(cid, bytes).await?;
+            }
+            writer.finish().await?;
+        }
+        Some(path) => {
+            let format = match path.extension().and_then(|e| e.to_str()) {
+                Some("parquet") => Format::Parquet,
+                Some("jsonl") => Format::Jsonl,
+                _ => {
+                    return Err(anyhow!(
+                        "unknown output {}, expected a .car, .parquet or .jsonl file",
+                        path.display()
+                    ))
+                }
+            };
+            let config = Config {
+                format,
+                ..Default::default()
+            };
+            let schema = Schema::Map(vec![
+                ("cid".to_string(), Schema::Bytes),
+                ("path".to_string(), Schema::String),
+            ]);
+            write_output(
+                path,
+                &schema,
+                &rows,
+                &[],
+                &Columns::default(),
+                &config,
+                None,
+            )
+            .context(format!("writing {}", path.display()))?;
+        }
+        None => {}
+    }
+    Ok(found)
+}
+
+/// Collects the paths of the string and bytes values containing the pattern,
+/// naming list elements by index.
+fn matches(data: &Ipld, path: &str, pattern: &str, out: &mut Vec<String>) {
+    match data {
+        Ipld::String(s) if s.contains(pattern) => out.push(path.to_string()),
+        Ipld::Bytes(b)
+            if b.windows(pattern.len().max(1))
+                .any(|w| w == pattern.as_bytes()) =>
+        {
+            out.push(path.to_string())
+        }
+        Ipld::List(l) => {
+            for (i, v) in l.iter().enumerate() {
+                matches(v, &format!("{}[{}]", path, i), pattern, out);
+            }
+        }
+        Ipld::Map(m) => {
+            for (k, v) in m {
+                matches(v, &crate::field_path(path, k), pattern, out);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libipld::{
+        cbor::DagCborCodec,
+        ipld,
+        multihash::{Code, MultihashDigest},
+        prelude::Codec,
+    };
+
+    use super::*;
+
+    #[test]
+    fn last_blocks_of_indexed_and_unindexed_cars() {
+        let fixture = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/unixfs.car");
+        let wrapped =
+            std::env::temp_dir().join(format!("carquet-inspect-{}-unixfs.car", std::process::id()));
+        std::fs::copy(&fixture, &wrapped).unwrap();
+        carv2::wrap(&wrapped).unwrap();
+        let mut car = carv2::open_blocks(&fixture).unwrap();
+        let mut all = Vec::new();
+        while let Some(block) = car.next_block().unwrap() {
+            all.push(block);
+        }
+        for path in [&fixture, &wrapped] {
+            for n in [0, 1, 3, all.len(), all.len() + 5] {
+                let last = &all[all.len().saturating_sub(n)..];
+                assert_eq!(last_blocks(path, n).unwrap(), last, "{} of {:?}", n, path);
+            }
+        }
+        std::fs::remove_file(wrapped).unwrap();
+    }
+
+    #[test]
+    fn blocks_of_other_codecs_are_summarized_as_dag_cbor() {
+        let bytes = DagCborCodec.encode(&ipld!({"a": 1, "b": [2]})).unwrap();
+        let cbor = Cid::new_v1(0x51, Code::Sha2_256.digest(&bytes));
+        assert_eq!(
+            summary(&cbor, &bytes),
+            format!("{} cbor {} bytes map {{a, b}}", cbor, bytes.len())
+        );
+        let raw = Cid::new_v1(0x55, Code::Sha2_256.digest(&bytes));
+        assert_eq!(
+            summary(&raw, &bytes),
+            format!("{} raw {} bytes", raw, bytes.len())
+        );
+        let other = Cid::new_v1(0x300, Code::Sha2_256.digest(b"\xff"));
+        assert!(
+            summary(&other, b"\xff").starts_with(&format!("{} 0x300 1 bytes undecodable: ", other))
+        );
+    }
+}
@@ -0,0 +1,191 @@
+//! DAG-JSON encoding of IPLD values.
+//!
+//! Output is canonical: map keys are sorted and floats use the shortest
+//! representation that round-trips, which does not depend on the locale.
+
+use std::fmt::Write;
+
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose, Engine as _};
+use libipld::Ipld;
+
+/// Options that change how values are rendered.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct JsonOptions {
+    /// Render integers as strings so consumers parsing numbers as doubles,
+    /// e.g. JavaScript, do not lose precision on 64-bit values.
+    pub int_strings: bool,
+    /// Render links as plain strings of the CID rather than `{"/": cid}`.
+    pub cid_strings: bool,
+}
+
+/// Appends the value as a single line of DAG-JSON.
+pub fn write_json(out: &mut String, ipld: &Ipld, opts: JsonOptions) -> Result<()> {
+    match ipld {
+        Ipld::Null => out.push_str("null"),
+        Ipld::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+        Ipld::Integer(i) if opts.int_strings => {
+            let _ = write!(out, "\"{}\"", i);
+        }
+        Ipld::Integer(i) => {
+            let _ = write!(out, "{}", i);
+        }
+        Ipld::Float(f) => write_float(out, *f)?,
+        Ipld::String(s) => write_str(out, s),
+        Ipld::Bytes(b) => {
+            out.push_str("{\"/\":{\"bytes\":\"");
+            out.push_str(&general_purpose::STANDARD_NO_PAD.encode(b));
+            out.push_str("\"}}");
+        }
+        Ipld::Link(cid) if opts.cid_strings => {
+            let _ = write!(out, "\"{}\"", cid);
+        }
+        Ipld::Link(cid) => {
+            let _ = write!(out, "{{\"/\":\"{}\"}}", cid);
+        }
+        Ipld::List(l) => {
+            out.push('[');
+            for (i, v) in l.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_json(out, v, opts)?;
+            }
+            out.push(']');
+        }
+        Ipld::Map(m) => {
+            out.push('{');
+            for (i, (k, v)) in m.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_str(out, k);
+                out.push(':');
+                write_json(out, v, opts)?;
+            }
+            out.push('}');
+        }
+    }
+    Ok(())
+}
+
+fn write_float(out: &mut String, f: f64) -> Result<()> {
+    if !f.is_finite() {
+        return Err(anyhow!("float {} cannot be represented in JSON", f));
+    }
+    // Debug formatting is the shortest round-trip form and always keeps a
+    // decimal point or exponent, so floats stay distinguishable from integers.
+    let _ = write!(out, "{:?}", f);
+    Ok(())
+}
+
+fn write_str(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use libipld::{
+        ipld,
+        multihash::{Code, MultihashDigest},
+        Cid,
+    };
+
+    use super::*;
+
+    fn json(ipld: &Ipld, opts: JsonOptions) -> String {
+        let mut out = String::new();
+        write_json(&mut out, ipld, opts).unwrap();
+        out
+    }
+
+    #[test]
+    fn floats_are_shortest_and_stay_floats() {
+        let table = [
+            (0.1, "0.1"),
+            (1.0, "1.0"),
+            (-0.0, "-0.0"),
+            (1e21, "1e21"),
+            (1.5e-7, "1.5e-7"),
+            (f64::MAX, "1.7976931348623157e308"),
+        ];
+        for (f, expected) in table {
+            let mut out = String::new();
+            write_float(&mut out, f).unwrap();
+            assert_eq!(out, expected);
+            // Every rendering parses back to the same float.
+            assert_eq!(out.parse::<f64>().unwrap().to_bits(), f.to_bits());
+            assert!(serde_json::from_str::<serde_json::Value>(&out).is_ok());
+        }
+    }
+
+    #[test]
+    fn floats_json_cannot_represent_are_rejected() {
+        for f in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            let mut out = String::new();
+            assert!(write_float(&mut out, f).is_err(), "{}", f);
+        }
+        let mut out = String::new();
+        let err = write_json(&mut out, &ipld!([1, f64::NAN]), JsonOptions::default());
+        assert_eq!(
+            err.unwrap_err().to_string(),
+            "float NaN cannot be represented in JSON"
+        );
+    }
+
+    #[test]
+    fn strings_escape_quotes_and_control_characters() {
+        let table = [
+            ("plain", r#""plain""#),
+            ("a\"b\\c", r#""a\"b\\c""#),
+            ("\n\r\t", r#""\n\r\t""#),
+            ("\u{0}\u{1}\u{1f}", r#""\u0000\u0001\u001f""#),
+            // DEL and non-ASCII characters are written as they are.
+            ("\u{7f}é☃", "\"\u{7f}é☃\""),
+        ];
+        for (s, expected) in table {
+            let mut out = String::new();
+            write_str(&mut out, s);
+            assert_eq!(out, expected);
+            assert_eq!(serde_json::from_str::<String>(&out).unwrap(), s);
+        }
+    }
+
+    #[test]
+    fn integers_and_links_as_strings_in_sorted_maps() {
+        let cid = Cid::new_v1(0x71, Code::Sha2_256.digest(b"block"));
+        let value = ipld!({"n": -(1i128 << 64), "link": cid, "bytes": Ipld::Bytes(vec![1, 2, 3])});
+        assert_eq!(
+            json(&value, JsonOptions::default()),
+            format!(
+                r#"{{"bytes":{{"/":{{"bytes":"AQID"}}}},"link":{{"/":"{}"}},"n":-18446744073709551616}}"#,
+                cid
+            )
+        );
+        let opts = JsonOptions {
+            int_strings: true,
+            cid_strings: true,
+        };
+        assert_eq!(
+            json(&value, opts),
+            format!(
+                r#"{{"bytes":{{"/":{{"bytes":"AQID"}}}},"link":"{}","n":"-18446744073709551616"}}"#,
+                cid
+            )
+        );
+    }
+}
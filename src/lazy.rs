@@ -0,0 +1,227 @@
+//! Partial decoding of dag-cbor blocks, for conversions whose query selects
+//! only some of the fields.
+//!
+//! Only the values at the paths the query reads, and the maps on the way to
+//! them, are decoded. Every other value is skipped over in the bytes, which
+//! only checks that it is a well formed CBOR item, so wide records cost little
+//! more than their selected fields. The decoded data has the same values at
+//! those paths as the whole block, so the query gives the same rows.
+
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Result};
+use libipld::{cbor::DagCborCodec, prelude::Codec, Ipld};
+
+const TEXT: u8 = 3;
+const ARRAY: u8 = 4;
+const MAP: u8 = 5;
+
+/// Decodes the block, keeping only the fields at the given paths below it.
+pub fn decode(bytes: &[u8], paths: &[Vec<String>]) -> Result<Ipld> {
+    let paths: Vec<&[String]> = paths.iter().map(|p| p.as_slice()).collect();
+    decode_paths(bytes, &paths)
+}
+
+/// Decodes the CBOR item, whole if it is not a map or a path ends at it.
+fn decode_paths(bytes: &[u8], paths: &[&[String]]) -> Result<Ipld> {
+    let (major, len, mut pos) = head(bytes, 0)?;
+    if major != MAP || paths.iter().any(|p| p.is_empty()) {
+        return DagCborCodec.decode(bytes);
+    }
+    let mut m = BTreeMap::new();
+    for _ in 0..len {
+        let (major, len, start) = head(bytes, pos)?;
+        let end = string_end(bytes, start, len)?;
+        if major != TEXT {
+            return Err(anyhow!("map key is not a string"));
+        }
+        let key = std::str::from_utf8(&bytes[start..end])?;
+        let value_end = skip(bytes, end)?;
+        let rest: Vec<&[String]> = paths
+            .iter()
+            .filter_map(|p| p.split_first())
+            .filter(|(k, _)| *k == key)
+            .map(|(_, rest)| rest)
+            .collect();
+        if !rest.is_empty() {
+            m.insert(
+                key.to_string(),
+                decode_paths(&bytes[end..value_end], &rest)?,
+            );
+        }
+        pos = value_end;
+    }
+    if pos != bytes.len() {
+        return Err(anyhow!("{} bytes after the block data", bytes.len() - pos));
+    }
+    Ok(Ipld::Map(m))
+}
+
+/// Major type and argument of the item starting at `pos`, with the position
+/// after its head.
+fn head(bytes: &[u8], pos: usize) -> Result<(u8, u64, usize)> {
+    let truncated = || anyhow!("CBOR truncated at byte {}", pos);
+    let b = *bytes.get(pos).ok_or_else(truncated)?;
+    let (major, info) = (b >> 5, b & 0x1f);
+    let (arg, next) = match info {
+        0..=23 => (u64::from(info), pos + 1),
+        24..=27 => {
+            let n = 1 << (info - 24);
+            let arg = bytes.get(pos + 1..pos + 1 + n).ok_or_else(truncated)?;
+            (
+                arg.iter().fold(0, |v, b| v << 8 | u64::from(*b)),
+                pos + 1 + n,
+            )
+        }
+        // Indefinite lengths are not allowed in dag-cbor.
+        _ => return Err(anyhow!("unsupported CBOR head {:#04x} at byte {}", b, pos)),
+    };
+    Ok((major, arg, next))
+}
+
+/// End of the `len` bytes of a string or byte string starting at `start`.
+fn string_end(bytes: &[u8], start: usize, len: u64) -> Result<usize> {
+    usize::try_from(len)
+        .ok()
+        .and_then(|len| start.checked_add(len))
+        .filter(|end| *end <= bytes.len())
+        .ok_or_else(|| anyhow!("CBOR string of {} bytes truncated", len))
+}
+
+/// Position after the item starting at `pos`. Items are counted rather than
+/// recursed into, so deeply nested values cannot overflow the stack.
+fn skip(bytes: &[u8], mut pos: usize) -> Result<usize> {
+    let mut pending: u64 = 1;
+    while pending > 0 {
+        let (major, arg, next) = head(bytes, pos)?;
+        pending -= 1;
+        pos = next;
+        match major {
+            2 | TEXT => pos = string_end(bytes, pos, arg)?,
+            ARRAY => pending = pending.saturating_add(arg),
+            MAP => pending = pending.saturating_add(arg.saturating_mul(2)),
+            // A tag, such as that of a CID, is followed by its item.
+            6 => pending += 1,
+            _ => {}
+        }
+    }
+    Ok(pos)
+}
+
+#[cfg(test)]
+mod tests {
+    use libipld::{
+        ipld,
+        multihash::{Code, MultihashDigest},
+        Cid,
+    };
+
+    use super::*;
+
+    /// The value with only the fields at the paths, as decoding them should
+    /// give.
+    fn project(value: &Ipld, paths: &[&[String]]) -> Ipld {
+        let Ipld::Map(m) = value else {
+            return value.clone();
+        };
+        if paths.iter().any(|p| p.is_empty()) {
+            return value.clone();
+        }
+        let mut out = BTreeMap::new();
+        for (key, value) in m {
+            let rest: Vec<&[String]> = paths
+                .iter()
+                .filter_map(|p| p.split_first())
+                .filter(|(k, _)| *k == key)
+                .map(|(_, rest)| rest)
+                .collect();
+            if !rest.is_empty() {
+                out.insert(key.clone(), project(value, &rest));
+            }
+        }
+        Ipld::Map(out)
+    }
+
+    fn paths(paths: &[&str]) -> Vec<Vec<String>> {
+        paths
+            .iter()
+            .map(|p| {
+                p.split('.')
+                    .filter(|k| !k.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn partial_decode_equals_full_decode_at_the_paths() {
+        let cid = Cid::new_v1(0x71, Code::Sha2_256.digest(b"block"));
+        let block = ipld!({
+            "a": 1,
+            "b": {"c": "x", "d": [1, 2, {"e": true}], "f": cid},
+            "g": [{"h": 1.5}, null],
+            "i": Ipld::Bytes(vec![0, 1, 2]),
+            "j": {"k": {"l": -(1i128 << 64)}},
+        });
+        let bytes = DagCborCodec.encode(&block).unwrap();
+        let table: &[&[&str]] = &[
+            &["a"],
+            &["b.c"],
+            &["b.d", "b.f"],
+            &["b", "b.c"],
+            &["g.h"],
+            &["i", "j.k.l"],
+            &["j.k.missing", "missing"],
+            &[""],
+            &[],
+        ];
+        for selected in table {
+            let paths = paths(selected);
+            let slices: Vec<&[String]> = paths.iter().map(|p| p.as_slice()).collect();
+            assert_eq!(
+                decode(&bytes, &paths).unwrap(),
+                project(&block, &slices),
+                "{:?}",
+                selected
+            );
+        }
+    }
+
+    #[test]
+    fn blocks_that_are_not_maps_are_decoded_whole() {
+        for block in [ipld!([1, {"a": 2}]), ipld!("s"), ipld!(3)] {
+            let bytes = DagCborCodec.encode(&block).unwrap();
+            assert_eq!(decode(&bytes, &paths(&["a"])).unwrap(), block);
+        }
+    }
+
+    #[test]
+    fn skipped_values_are_checked_to_be_well_formed() {
+        let table: &[(&[u8], &str)] = &[
+            // {"a": 1, "b": [1, 2 truncated.
+            (b"\xa2\x61a\x01\x61b\x82\x01", "CBOR truncated at byte 8"),
+            // {"b": "abc" truncated after one byte.
+            (b"\xa1\x61b\x63a", "CBOR string of 3 bytes truncated"),
+            // {"b": an indefinite length list}.
+            (b"\xa1\x61b\x9f\xff", "unsupported CBOR head 0x9f at byte 3"),
+            // {"a": 1} followed by another byte.
+            (b"\xa1\x61a\x01\x00", "1 bytes after the block data"),
+            // {1: 2}.
+            (b"\xa1\x01\x02", "map key is not a string"),
+        ];
+        for (bytes, error) in table {
+            let err = decode(bytes, &paths(&["a"])).unwrap_err();
+            assert_eq!(err.to_string(), *error, "{:02x?}", bytes);
+        }
+    }
+
+    #[test]
+    fn deeply_nested_skipped_values_do_not_overflow() {
+        let depth = 100_000;
+        let mut bytes = b"\xa2\x61a\x01\x61b".to_vec();
+        bytes.extend(std::iter::repeat_n(0x81, depth));
+        bytes.push(0x00);
+        assert_eq!(decode(&bytes, &paths(&["a"])).unwrap(), ipld!({"a": 1}));
+    }
+}
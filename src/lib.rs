@@ -0,0 +1,3749 @@
+//! Conversion of CAR files of IPLD blocks into Parquet files, one per schema.
+//!
+//! [`CarToParquetConverter`] runs a conversion with the options of the
+//! `carquet convert` command, writing the files into a directory and
+//! returning a [`Report`] of what was written:
+//!
+//! ```no_run
+//! # async fn run() -> anyhow::Result<()> {
+//! let report = carquet::CarToParquetConverter::new("out")
+//!     .input("all.car")
+//!     .options(carquet::ConvertArgs::from_flags(["--explode", "data.entries"])?)
+//!     .run()
+//!     .await?;
+//! println!("{}", report);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The options can also be given as data with [`ConvertOptions`], e.g. read
+//! from a JSON file, as `carquet convert --options FILE` does.
+//!
+//! Schemas can be built in code with [`SchemaBuilder`], e.g. to write them as
+//! the pinned schemas of a conversion with [`Pins`].
+//!
+//! The parsing helpers and errors are shared by the carquet binaries and
+//! fuzz targets.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, Context, Result};
+use clap::{FromArgMatches, ValueEnum};
+use libipld::multihash::{Code, Multihash, MultihashDigest};
+use libipld::Cid;
+use libipld::{cbor::DagCborCodec, json::DagJsonCodec, pb::DagPbCodec, prelude::Codec, Ipld};
+use parquet::data_type::Int96;
+use serde::{Deserialize, Serialize};
+use tokio_util::sync::CancellationToken;
+
+mod adl;
+pub mod builder;
+pub mod cache;
+pub mod capabilities;
+pub mod carv2;
+mod cid_index;
+pub mod conformance;
+pub mod diag;
+mod error;
+pub mod export;
+pub mod fixture;
+#[cfg(fuzzing)]
+pub mod fuzz;
+mod hooks;
+mod inference;
+pub mod inspect;
+mod json;
+mod lazy;
+mod limits;
+mod manifest;
+mod options;
+mod parquet_schema;
+mod pins;
+pub mod plan;
+mod preset;
+mod registry;
+pub mod reorder;
+mod report;
+mod scan;
+mod schema;
+mod secrets;
+pub mod select;
+mod shape;
+mod shred;
+mod sql;
+mod state;
+mod target;
+mod timestamp;
+mod tuning;
+pub mod utf8;
+pub mod verify;
+mod writer;
+
+pub use builder::SchemaBuilder;
+use cid_index::CidIndex;
+pub use error::{Error, Source};
+use json::JsonOptions;
+pub use limits::Limit;
+use manifest::Reservoir;
+pub use options::ConvertOptions;
+pub use pins::{Pin, Pins};
+use plan::Unit;
+use preset::Preset;
+use registry::Registry;
+use report::Failures;
+pub use report::{CapabilityGap, Report, Thresholds};
+pub use schema::Schema;
+use schema::{field_path, optional, schema};
+use shape::Shapes;
+use shred::{column_types, option_path, resolve_entries, Resolved};
+use target::Target;
+use tuning::Tuning;
+use utf8::Utf8Policy;
+use writer::{write_output, OutputFile};
+
+/// Options of a conversion, the flags of `carquet convert`.
+#[derive(clap::Args, Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct ConvertArgs {
+    /// Representation of a list of links at the given field path, e.g. `data.prev=list`.
+    /// May be repeated, fields not listed use `repeated`.
+    #[arg(long = "link-list", value_name = "PATH=REPR", value_parser = parse_link_list)]
+    #[serde(rename = "link-list", with = "options::pairs")]
+    link_lists: Vec<(String, LinkListRepr)>,
+
+    /// Write the list at the given field path, e.g. `data.entries`, to a separate
+    /// table keyed by parent CID and index instead of repeating it inline.
+    #[arg(long = "explode", value_name = "PATH")]
+    explode: Vec<String>,
+
+    /// Integer type of the given field path, e.g. `data.height=int32`. One of
+    /// int8/16/32/64, uint8/16/32/64, decimal(P,S), timestamp_s/ms/us/ns for
+    /// time since the Unix epoch or date for days since it. Defaults to uint64, or for the fields of a
+    /// file with negative values int64, and decimal(20,0) mixing them with
+    /// values beyond int64. Files written with --stream, --schema-cache or
+    /// --write-after take the default from their first rows, so give the type
+    /// of fields whose later values may be negative or beyond int64.
+    #[arg(long = "int-type", value_name = "PATH=TYPE", value_parser = parse_int_type)]
+    #[serde(rename = "int-type", with = "options::pairs")]
+    int_types: Vec<(String, IntType)>,
+
+    /// Time type of the given field path, e.g. `data.createdAt=timestamp_ms`,
+    /// one of timestamp_s/ms/us/ns or date. Integers are taken in its unit,
+    /// RFC 3339 strings and dates are converted to it. --int-type wins over it.
+    #[arg(long = "type-hint", value_name = "PATH=TYPE", value_parser = parse_type_hint)]
+    #[serde(rename = "type-hint", with = "options::pairs")]
+    type_hints: Vec<(String, IntType)>,
+
+    /// Write the fields of a file whose strings are all RFC 3339 times as
+    /// timestamp_us, all dates as date, and integer fields named like times,
+    /// e.g. `createdAt` or `updated_at`, with values of seconds or milliseconds
+    /// of this era as timestamp_s or timestamp_ms.
+    #[arg(long)]
+    infer_times: bool,
+
+    /// What to do with integers beyond 64 bits in fields without an
+    /// --int-type, which dag-cbor allows down to -2^64.
+    #[arg(long, value_enum, default_value_t = IntOverflow::Fail)]
+    int_overflow: IntOverflow,
+
+    /// Format of the output files.
+    #[arg(long, value_enum, default_value_t = Format::Parquet)]
+    format: Format,
+
+    /// Compression codec of the parquet files.
+    #[arg(long, value_enum, default_value_t = Compression::Snappy)]
+    compression: Compression,
+
+    /// Level of the compression codec, 0 to 9 for gzip, 0 to 11 for brotli and
+    /// 1 to 22 for zstd. Defaults to the default of the codec, 6, 1 and 1.
+    #[arg(long, value_name = "N")]
+    compression_level: Option<u32>,
+
+    /// Prefix of the file names of the schemas, `<prefix>_<i>`. Defaults to the
+    /// name of the --preset, or `schema`.
+    #[arg(long, value_name = "PREFIX")]
+    schema_prefix: Option<String>,
+
+    /// Fill in the options for the blocks of an ecosystem. Options given keep
+    /// their values and lists given are extended.
+    #[arg(long, value_enum)]
+    preset: Option<Preset>,
+
+    /// Print the files to standard error as they are written, twice to also
+    /// print their schemas.
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Print nothing but errors.
+    #[arg(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Render integers as strings in JSON output, protecting 64-bit values
+    /// from consumers that parse numbers as doubles.
+    #[arg(long)]
+    json_int_strings: bool,
+
+    /// Null out values that cannot be converted instead of failing, describing
+    /// the problems of each row in a `_conversion_errors` column.
+    #[arg(long)]
+    lenient: bool,
+
+    /// Map blocks into the schemas of a pins file, failing on blocks that do not fit.
+    #[arg(long, value_name = "FILE")]
+    pin_schemas: Option<PathBuf>,
+
+    /// Write timestamp fields, see --int-type, as INT96 for legacy Hive and Impala readers.
+    #[arg(long)]
+    legacy_int96_timestamps: bool,
+
+    /// Move columns that have the same value in every row of a file to the
+    /// `carquet.constants` footer metadata, which readers ignoring key-value
+    /// metadata do not see. Files of one schema may then differ in columns.
+    #[arg(long)]
+    prune_constant_columns: bool,
+
+    /// Write all blocks into a single file with the schema of an existing parquet
+    /// file, e.g. to append to a table, failing up front on blocks that do not fit.
+    #[arg(long, value_name = "FILE", conflicts_with = "pin_schemas")]
+    target_schema: Option<PathBuf>,
+
+    /// Start writing a schema once this many of its blocks are buffered instead
+    /// of after reading the whole input. Each batch is written to its own part
+    /// file, `schema_<i>.part<j>`, and blocks of the schema read later go to the
+    /// next part. The integer types of each part are chosen from the blocks
+    /// it starts with, see --int-type.
+    #[arg(long, value_name = "N", conflicts_with_all = ["pin_schemas", "target_schema", "write_pins"])]
+    write_after: Option<usize>,
+
+    /// Schemas file, in the pins format, to stream the blocks into with a row
+    /// group of buffering per schema. If it does not exist the input is read
+    /// twice, first to infer the schemas and save them to it. Blocks must fit
+    /// the cached schemas, as with --pin-schemas.
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["pin_schemas", "target_schema", "write_after", "write_pins"]
+    )]
+    schema_cache: Option<PathBuf>,
+
+    /// File of the schemas inferred for the blocks by their CID, created if
+    /// missing, so the blocks earlier runs and shards inferred are not decoded
+    /// again while inferring the schemas of --schema-cache or --infer-sample.
+    #[arg(long, value_name = "FILE")]
+    inference_cache: Option<PathBuf>,
+
+    /// Write the rows of each table into directories by the value of this
+    /// field, `<field>=<value>/<table>`, for engines to prune the partitions by
+    /// it, e.g. `data.type`. The field is left out of the files, and blocks
+    /// without it go to `<field>=__HIVE_DEFAULT_PARTITION__`.
+    #[arg(
+        long,
+        value_name = "PATH",
+        value_parser = parse_partition_path,
+        conflicts_with_all = ["write_after", "schema_cache", "infer_sample", "stream", "write_pins"]
+    )]
+    partition_by: Option<String>,
+
+    /// Infer the schemas from the first N blocks of each input, then stream the
+    /// blocks into them as with --schema-cache, widening as pinned schemas are.
+    /// Blocks fitting none of them are written to files of schemas of their
+    /// own, added as with --stream.
+    #[arg(
+        long,
+        value_name = "N",
+        conflicts_with_all = ["pin_schemas", "target_schema", "write_after", "schema_cache", "stream", "merge_schemas"]
+    )]
+    infer_sample: Option<usize>,
+
+    /// Read the input once, writing a row group of a schema whenever enough of
+    /// its blocks are buffered, so memory is bounded by a row group per schema
+    /// rather than the input, besides the hash per CID that --duplicates keeps.
+    /// Files are named in the order their schemas are first seen and constant
+    /// columns are kept, see --prune-constant-columns.
+    #[arg(
+        long,
+        conflicts_with_all = ["pin_schemas", "target_schema", "write_after", "schema_cache"]
+    )]
+    stream: bool,
+
+    /// Write the blocks whose maps differ only by fields some of them lack to
+    /// one file, the fields they lack as optional columns, null or for lists
+    /// empty in the rows without them. The rows of each merged schema follow
+    /// those of the schema it was merged into.
+    #[arg(
+        long,
+        conflicts_with_all = ["pin_schemas", "target_schema", "write_after", "schema_cache", "stream"]
+    )]
+    merge_schemas: bool,
+
+    /// Number the schemas by the ids of a registry file, stable across runs and
+    /// created if missing. Files are named `<prefix>_<id>` and each block gets a
+    /// `_schema_id` field, written as a column.
+    #[arg(long, value_name = "FILE")]
+    schema_registry: Option<PathBuf>,
+
+    /// Add a `_schema_fingerprint` field to each block, the SHA-256 of the JSON
+    /// of its schema as in the manifest and registry, e.g. to join the rows of
+    /// one shape across files and runs.
+    #[arg(long)]
+    schema_fingerprint: bool,
+
+    /// Name the files `<prefix>_<fingerprint>` by the first 16 hex digits of the
+    /// fingerprint of their schema, as in the manifest, rather than by their
+    /// rank by rows, so a schema keeps its file name across runs on different
+    /// CARs without a registry.
+    #[arg(long, conflicts_with = "schema_registry")]
+    fingerprint_names: bool,
+
+    /// Write the schemas of this run to a pins file for use with --pin-schemas.
+    #[arg(long, value_name = "FILE")]
+    write_pins: Option<PathBuf>,
+
+    /// Write only the blocks earlier runs into the output directory have not,
+    /// as listed by its state file `_carquet_state`, into files named after
+    /// the run such as `schema_0-1.parquet`, e.g. to convert an updated CAR.
+    #[arg(long)]
+    append: bool,
+
+    /// How to handle text strings that are not valid UTF-8, which the dag-cbor
+    /// codec otherwise rejects.
+    #[arg(long, value_enum, default_value_t = Utf8Policy::Reject)]
+    invalid_utf8: Utf8Policy,
+
+    /// Include a CBOR diagnostic notation rendering and hex dump of any block
+    /// that fails to decode in the error.
+    #[arg(long)]
+    cbor_diagnostics: bool,
+
+    /// Skip blocks whose bytes are identical to an earlier block with a different CID,
+    /// e.g. the same payload hashed with another function, recording the equivalence
+    /// in payload_aliases.parquet.
+    #[arg(long)]
+    dedup_payloads: bool,
+
+    /// What to do with a block whose CID was already read with different bytes,
+    /// which means the CAR is corrupt. Every policy keeps a hash or position
+    /// per CID read, so memory grows with the number of blocks, also with --stream.
+    #[arg(long, value_enum, default_value_t = Duplicates::Error)]
+    duplicates: Duplicates,
+
+    /// Write each CID once, the first copy --duplicates keeps, with a `_copies`
+    /// field counting the copies of the block in the inputs, reading the input
+    /// twice.
+    #[arg(long)]
+    dedup_cids: bool,
+
+    /// Add `_codec` and `_multihash` columns beside each link field with the
+    /// codes of its CID, e.g. to filter for links to dag-cbor blocks.
+    #[arg(long)]
+    link_codecs: bool,
+
+    /// Write each link of the data as a group of the parts of its CID,
+    /// `version`, `codec`, `multihash_code` and `digest`, e.g. to count the
+    /// links by hash function, after the forms of --link-as.
+    #[arg(long, conflicts_with = "cid_strings")]
+    explode_cids: bool,
+
+    /// Form of the link at the given field path, e.g. `data.author=string`. May be
+    /// repeated, applies to each link of a list and fields not listed keep `bytes`.
+    #[arg(long = "link-as", value_name = "PATH=FORM", value_parser = parse_link_form)]
+    #[serde(rename = "link-as", with = "options::pairs")]
+    link_forms: Vec<(String, LinkForm)>,
+
+    /// Write the CID columns and the links not given a form by --link-as as
+    /// strings, base32 for CIDv1 and base58btc for CIDv0, to join on in SQL
+    /// tools, and links in JSON output as plain strings.
+    #[arg(long)]
+    cid_strings: bool,
+
+    /// Write each map sharded over a tree of blocks in this layout as one row
+    /// of the shard at its root, holding the entries of the whole tree, and
+    /// leave out the shards below it.
+    #[arg(long, value_enum, value_name = "LAYOUT")]
+    adl: Option<adl::Adl>,
+
+    /// Bytes of the blocks links are inlined from kept decoded, the most
+    /// recently used, with a suffix such as 64MiB. Blocks evicted are read
+    /// from the CAR and decoded again.
+    #[arg(long, value_name = "BYTES", default_value = "256MiB", value_parser = parse_bytes)]
+    #[serde(with = "options::bytes")]
+    block_cache_size: usize,
+
+    /// Move the blocks evicted from the block cache to a file in this
+    /// directory instead of decoding them again, removed after the conversion.
+    #[arg(long, value_name = "DIR")]
+    block_cache_dir: Option<PathBuf>,
+
+    /// Add a `_root_cids` field listing the roots of the CAR headers each block
+    /// is reachable from by following links within the input, e.g. to filter
+    /// the blocks shared by several roots.
+    #[arg(long)]
+    root_cids: bool,
+
+    /// Root for `--root-cids` in place of the roots of the CAR headers, for
+    /// CARs without roots or whose roots are kept apart. May be repeated.
+    #[arg(long = "root", value_name = "CID", requires = "root_cids")]
+    #[serde(rename = "root", with = "options::cids")]
+    roots: Vec<Cid>,
+
+    /// Project and filter the blocks with a query of the table `blocks`, e.g.
+    /// `SELECT cid, data.height FROM blocks WHERE data.type = 'post'`, see the
+    /// sql module for the supported subset.
+    #[arg(long, value_name = "QUERY")]
+    sql: Option<String>,
+
+    /// Write manifest.json listing each file with the schema of its columns,
+    /// the fingerprint of the schema, the constant fields moved to its footer,
+    /// its rows, its bytes and the CID of one of its blocks.
+    #[arg(long)]
+    manifest: bool,
+
+    /// Write manifest.json with a uniform sample of up to this many records of
+    /// each file as DAG-JSON.
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    samples: usize,
+
+    /// Write cid_index.json, a roaring bitmap of the CIDs of each row group of
+    /// the block files for finding the files and row groups that may hold a CID.
+    #[arg(long)]
+    cid_index: bool,
+
+    /// Order of the rows within each file, or within each part file and row group
+    /// when writing while reading.
+    #[arg(long, value_enum, default_value_t = Order::Input)]
+    order: Order,
+
+    /// Write the columns at these paths first and in this order, e.g.
+    /// `data.height,cid,data.author.name`, for loaders that depend on the
+    /// position of each column. A path orders the fields of its group, the
+    /// fields not listed follow sorted by name and paths a file lacks are skipped.
+    #[arg(
+        long,
+        value_name = "PATHS",
+        value_delimiter = ',',
+        conflicts_with = "target_schema"
+    )]
+    column_order: Vec<String>,
+
+    /// Name of the column of the block CID.
+    #[arg(long, value_name = "NAME", default_value = "cid")]
+    cid_column_name: String,
+
+    /// Name of the group holding the block data.
+    #[arg(long, value_name = "NAME", default_value = "data")]
+    data_column_name: String,
+
+    /// Write the fields of the block data as the top level columns, without a
+    /// CID column. Options still name fields by their path below `data`.
+    #[arg(long, conflicts_with_all = ["cid_column_name", "data_column_name"])]
+    no_wrapper: bool,
+
+    /// Write the fields of the block data as top level columns beside the CID
+    /// column instead of within the data group.
+    #[arg(long, conflicts_with_all = ["data_column_name", "no_wrapper"])]
+    hoist_data: bool,
+
+    /// Keep the encoded bytes of each block in a `rawdata` column beside its
+    /// data, so the files hold every block of the CAR and `carquet export` can
+    /// write it back.
+    #[arg(long, conflicts_with_all = ["no_wrapper", "hoist_data"])]
+    include_raw: bool,
+
+    /// Add a `block_size` column of the length of the encoded bytes of each
+    /// block beside its data, e.g. to sum the storage of each type of block.
+    #[arg(long, conflicts_with_all = ["no_wrapper", "hoist_data"])]
+    block_size: bool,
+
+    /// Leave out the column and offset indexes, which record the value range
+    /// and location of every data page so readers can skip pages, keeping only
+    /// the statistics of each column chunk.
+    #[arg(long)]
+    no_page_index: bool,
+
+    /// Start a new data page after about this many rows, so the page indexes let
+    /// readers skip in smaller steps. By default pages are cut by size.
+    #[arg(long, value_name = "N")]
+    page_rows: Option<usize>,
+
+    /// Bytes after which a data page is cut, instead of the size chosen for
+    /// each table to hold about 20000 values.
+    #[arg(long, value_name = "BYTES")]
+    page_size: Option<usize>,
+
+    /// Write the column with or without dictionary encoding, instead of by the
+    /// share of distinct values of the first rows, e.g. data.sig=off. May be
+    /// repeated.
+    #[arg(long, value_name = "PATH=on|off", value_parser = parse_dictionary)]
+    #[serde(with = "options::pairs")]
+    dictionary: Vec<(String, bool)>,
+
+    /// Cut the row groups of every file at this many rows, or at about this
+    /// many bytes of values with a suffix such as 64MiB, instead of at about
+    /// 128 MiB.
+    #[arg(long, value_name = "ROWS|BYTES", value_parser = parse_row_group_size)]
+    #[serde(with = "options::row_group_size")]
+    row_group_size: Option<RowGroupSize>,
+
+    /// Write with the page and dictionary settings of the parquet defaults,
+    /// streamed row groups of 10000 rows and other files as one row group,
+    /// rather than choosing them for each table from its first rows.
+    #[arg(long)]
+    default_writer_props: bool,
+
+    /// Warn about schemas whose rows flatten into more than this many columns.
+    #[arg(long, value_name = "N", default_value_t = 500)]
+    warn_row_width: usize,
+
+    /// Warn about records containing lists longer than this.
+    #[arg(long, value_name = "N", default_value_t = 1000)]
+    warn_list_len: usize,
+
+    /// Stop reading blocks after this many, writing those read before. With
+    /// --append the blocks earlier runs wrote are skipped without being counted.
+    #[arg(long, value_name = "N")]
+    max_blocks: Option<usize>,
+
+    /// Stop reading blocks once the block tables written hold this many bytes,
+    /// with a suffix such as 10GiB, and leave out the tables not written yet.
+    #[arg(long, value_name = "BYTES", value_parser = parse_bytes)]
+    #[serde(with = "options::bytes::option")]
+    max_output_bytes: Option<usize>,
+
+    /// Stop reading blocks after this long, e.g. 90s, 30m or 2h, writing those
+    /// read before.
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    #[serde(with = "options::duration")]
+    max_runtime: Option<std::time::Duration>,
+
+    /// Post the outcome and report of the run as JSON to this http:// URL
+    /// once it ends, whether it succeeded, failed or was cancelled. It may
+    /// name secrets as ${NAME}, see --secrets-file.
+    #[arg(long, value_name = "URL", value_parser = hooks::parse_webhook)]
+    on_complete_webhook: Option<String>,
+
+    /// Send this header with the report to --on-complete-webhook, e.g.
+    /// 'Authorization: Bearer ${HOOK_TOKEN}'. May be repeated.
+    #[arg(long = "webhook-header", value_name = "NAME: VALUE", value_parser = hooks::parse_header)]
+    #[serde(rename = "webhook-header")]
+    webhook_headers: Vec<String>,
+
+    /// Run this command with sh once the run ends, the outcome and report of
+    /// the run as JSON on its standard input.
+    #[arg(long, value_name = "COMMAND")]
+    on_complete_exec: Option<String>,
+
+    /// Take the secrets the webhook names as ${NAME} from this file of
+    /// NAME=value lines when the environment does not set them, and set them
+    /// in the environment of --on-complete-exec.
+    #[arg(long, value_name = "FILE")]
+    secrets_file: Option<PathBuf>,
+
+    /// Number of tables written at once, one for each core by default. With
+    /// convert-sharded, the number of shards converted at once, 4 by default.
+    #[arg(long, value_name = "N")]
+    jobs: Option<usize>,
+
+    /// Take the options not given as flags from this JSON file of the flags by
+    /// their long names, e.g. `{"compression": "zstd", "explode":
+    /// ["data.entries"]}`, see ConvertOptions. Flags given with other values
+    /// than their defaults win over the file.
+    #[arg(long, value_name = "FILE")]
+    #[serde(skip)]
+    options: Option<PathBuf>,
+}
+
+/// How a list of links is laid out in the parquet output.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum LinkListRepr {
+    /// A REPEATED BYTE_ARRAY column.
+    #[default]
+    Repeated,
+    /// A LIST annotated group with a BYTE_ARRAY element.
+    List,
+    /// A separate child table of (parent_cid, index, target_cid) rows.
+    Explode,
+}
+
+fn parse_link_list(s: &str) -> Result<(String, LinkListRepr)> {
+    let (path, repr) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow!("expected PATH=REPR, got {s}"))?;
+    let repr = LinkListRepr::from_str(repr, true).map_err(|e| anyhow!(e))?;
+    Ok((path.to_string(), repr))
+}
+
+/// How a link field is stored.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum LinkForm {
+    /// The binary CID.
+    #[default]
+    Bytes,
+    /// The CID as a string, base32 for CIDv1 and base58btc for CIDv0.
+    String,
+    /// The decoded target block in place of the link, links to blocks outside
+    /// the CAR are kept.
+    Inline,
+}
+
+fn parse_link_form(s: &str) -> Result<(String, LinkForm)> {
+    let (path, form) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow!("expected PATH=FORM, got {s}"))?;
+    let form = LinkForm::from_str(form, true).map_err(|e| anyhow!(e))?;
+    Ok((path.to_string(), form))
+}
+
+/// Handling of the copies of a CID that do not all have the same bytes.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Duplicates {
+    /// Fail the conversion.
+    #[default]
+    Error,
+    /// Write the first copy and skip the others.
+    KeepFirst,
+    /// Write the last copy and skip the others, reading the input twice.
+    KeepLast,
+    /// Write every copy with a `_duplicate_conflict` field, reading the input
+    /// twice. Copies whose data is not a map are written without it.
+    Flag,
+}
+
+/// Handling of integers that do not fit the 64 bits of a column without an
+/// integer type.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum IntOverflow {
+    /// Fail the values, naming their block, or with --lenient write them as
+    /// null.
+    #[default]
+    Fail,
+    /// Write the field as decimal(20,0) in files with such values among the
+    /// rows their columns are chosen from, all of them unless streamed.
+    Widen,
+}
+
+/// Order of the rows of an output file.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Order {
+    /// The order of the blocks in the CAR, for reproducible output.
+    #[default]
+    Input,
+    /// Sorted by the binary CID, so readers can prune row groups by CID.
+    Cid,
+    /// No guarantee, leaving the writer free to pick the fastest order.
+    None,
+}
+
+/// Compression codec of the parquet files.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Compression {
+    #[value(alias = "none")]
+    #[serde(alias = "none")]
+    Uncompressed,
+    #[default]
+    Snappy,
+    Gzip,
+    Brotli,
+    Lz4,
+    Lz4Raw,
+    Zstd,
+}
+
+impl Compression {
+    /// The parquet codec at the level, or the default level of the codec if `None`.
+    fn parquet(&self, level: Option<u32>) -> Result<parquet::basic::Compression> {
+        use parquet::basic::{BrotliLevel, GzipLevel, ZstdLevel};
+        let invalid = |e| anyhow!("--compression-level: {}", e);
+        Ok(match (self, level) {
+            (Compression::Gzip, Some(l)) => {
+                parquet::basic::Compression::GZIP(GzipLevel::try_new(l).map_err(invalid)?)
+            }
+            (Compression::Brotli, Some(l)) => {
+                parquet::basic::Compression::BROTLI(BrotliLevel::try_new(l).map_err(invalid)?)
+            }
+            (Compression::Zstd, Some(l)) => {
+                let l = i32::try_from(l).unwrap_or(i32::MAX);
+                parquet::basic::Compression::ZSTD(ZstdLevel::try_new(l).map_err(invalid)?)
+            }
+            (_, Some(_)) => {
+                return Err(anyhow!(
+                    "--compression-level needs --compression gzip, brotli or zstd"
+                ))
+            }
+            (Compression::Uncompressed, None) => parquet::basic::Compression::UNCOMPRESSED,
+            (Compression::Snappy, None) => parquet::basic::Compression::SNAPPY,
+            (Compression::Gzip, None) => parquet::basic::Compression::GZIP(Default::default()),
+            (Compression::Brotli, None) => parquet::basic::Compression::BROTLI(Default::default()),
+            (Compression::Lz4, None) => parquet::basic::Compression::LZ4,
+            (Compression::Lz4Raw, None) => parquet::basic::Compression::LZ4_RAW,
+            (Compression::Zstd, None) => parquet::basic::Compression::ZSTD(Default::default()),
+        })
+    }
+}
+
+/// Output file format.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Format {
+    #[default]
+    Parquet,
+    /// One DAG-JSON record per line.
+    Jsonl,
+}
+
+impl Format {
+    fn extension(&self) -> &'static str {
+        match self {
+            Format::Parquet => "parquet",
+            Format::Jsonl => "jsonl",
+        }
+    }
+}
+
+/// Integer representation of a field in the parquet output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum IntType {
+    Int {
+        bits: u8,
+        signed: bool,
+    },
+    Decimal {
+        precision: u32,
+        scale: u32,
+    },
+    /// Time since the Unix epoch in UTC.
+    Timestamp {
+        unit: TimeUnit,
+    },
+    /// Days since the Unix epoch.
+    Date,
+}
+
+/// Unit of an integer timestamp.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TimeUnit {
+    Seconds,
+    Millis,
+    Micros,
+    Nanos,
+}
+
+impl TimeUnit {
+    fn nanos(&self) -> i128 {
+        match self {
+            TimeUnit::Seconds => 1_000_000_000,
+            TimeUnit::Millis => 1_000_000,
+            TimeUnit::Micros => 1_000,
+            TimeUnit::Nanos => 1,
+        }
+    }
+
+    fn suffix(&self) -> &'static str {
+        match self {
+            TimeUnit::Seconds => "s",
+            TimeUnit::Millis => "ms",
+            TimeUnit::Micros => "us",
+            TimeUnit::Nanos => "ns",
+        }
+    }
+}
+
+impl Default for IntType {
+    fn default() -> Self {
+        IntType::Int {
+            bits: 64,
+            signed: false,
+        }
+    }
+}
+
+impl std::str::FromStr for IntType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(args) = s.strip_prefix("decimal(").and_then(|s| s.strip_suffix(')')) {
+            let (precision, scale) = args
+                .split_once(',')
+                .ok_or_else(|| anyhow!("expected decimal(P,S), got {s}"))?;
+            let precision: u32 = precision.trim().parse()?;
+            let scale: u32 = scale.trim().parse()?;
+            if !(1..=38).contains(&precision) || scale > precision {
+                return Err(anyhow!(
+                    "decimal precision must be within 1..=38 and scale at most the precision, got {s}"
+                ));
+            }
+            return Ok(IntType::Decimal { precision, scale });
+        }
+        if s == "date" {
+            return Ok(IntType::Date);
+        }
+        if let Some(unit) = s.strip_prefix("timestamp_") {
+            let unit = match unit {
+                "s" => TimeUnit::Seconds,
+                "ms" => TimeUnit::Millis,
+                "us" => TimeUnit::Micros,
+                "ns" => TimeUnit::Nanos,
+                _ => {
+                    return Err(anyhow!(
+                        "unknown timestamp unit {unit}, expected s, ms, us or ns"
+                    ))
+                }
+            };
+            return Ok(IntType::Timestamp { unit });
+        }
+        let (signed, bits) = match s.strip_prefix('u') {
+            Some(bits) => (false, bits),
+            None => (true, s),
+        };
+        match bits {
+            "int8" => Ok(IntType::Int { bits: 8, signed }),
+            "int16" => Ok(IntType::Int { bits: 16, signed }),
+            "int32" => Ok(IntType::Int { bits: 32, signed }),
+            "int64" => Ok(IntType::Int { bits: 64, signed }),
+            _ => Err(anyhow!("unknown integer type {s}")),
+        }
+    }
+}
+
+impl IntType {
+    /// Physical type of the column, timestamps are INT96 for legacy readers.
+    fn physical_type(&self, legacy_int96: bool) -> parquet::basic::Type {
+        match *self {
+            IntType::Timestamp { .. } if legacy_int96 => parquet::basic::Type::INT96,
+            IntType::Timestamp { .. } => parquet::basic::Type::INT64,
+            IntType::Int { bits, .. } if bits <= 32 => parquet::basic::Type::INT32,
+            IntType::Date => parquet::basic::Type::INT32,
+            IntType::Int { .. } => parquet::basic::Type::INT64,
+            IntType::Decimal { precision, .. } if precision <= 9 => parquet::basic::Type::INT32,
+            IntType::Decimal { precision, .. } if precision <= 18 => parquet::basic::Type::INT64,
+            IntType::Decimal { .. } => parquet::basic::Type::FIXED_LEN_BYTE_ARRAY,
+        }
+    }
+
+    /// Number of bytes of a FIXED_LEN_BYTE_ARRAY decimal.
+    fn byte_len(&self) -> usize {
+        match *self {
+            IntType::Decimal { precision, .. } => {
+                ((precision as f64 * 10f64.log2() + 1.0) / 8.0).ceil() as usize
+            }
+            IntType::Int { bits, .. } => bits as usize / 8,
+            IntType::Timestamp { .. } => 8,
+            IntType::Date => 4,
+        }
+    }
+
+    /// Unit the timestamp is stored in, parquet has no seconds so they are stored as millis.
+    fn stored_unit(unit: TimeUnit) -> TimeUnit {
+        match unit {
+            TimeUnit::Seconds => TimeUnit::Millis,
+            unit => unit,
+        }
+    }
+
+    /// Converts an IPLD integer to the (unscaled) value stored in the column,
+    /// failing if it does not fit.
+    fn convert(&self, i: i128) -> Result<i128> {
+        let fits = match *self {
+            IntType::Int { bits, signed: true } => {
+                let max = (1i128 << (bits - 1)) - 1;
+                (-max - 1..=max).contains(&i)
+            }
+            IntType::Int {
+                bits,
+                signed: false,
+            } => (0..1i128 << bits).contains(&i),
+            IntType::Decimal { precision, scale } => {
+                return i
+                    .checked_mul(10i128.pow(scale))
+                    .filter(|v| v.unsigned_abs() < 10u128.pow(precision))
+                    .ok_or_else(|| self.out_of_range(i));
+            }
+            IntType::Timestamp { unit } => {
+                return i
+                    .checked_mul(unit.nanos() / Self::stored_unit(unit).nanos())
+                    .filter(|v| i64::try_from(*v).is_ok())
+                    .ok_or_else(|| self.out_of_range(i));
+            }
+            IntType::Date => i32::try_from(i).is_ok(),
+        };
+        if fits {
+            Ok(i)
+        } else {
+            Err(self.out_of_range(i))
+        }
+    }
+
+    /// Whether strings can be written as the type, as the times they hold.
+    fn is_time(&self) -> bool {
+        matches!(self, IntType::Timestamp { .. } | IntType::Date)
+    }
+
+    /// Converts the time of a string to the value of the type, in its unit.
+    fn time(&self, s: &str) -> Result<i128> {
+        let parsed = timestamp::parse(s)
+            .ok_or_else(|| anyhow!("expected an RFC 3339 time or a date, got {s:?}"))?;
+        match *self {
+            IntType::Timestamp { unit } => Ok(parsed.nanos.div_euclid(unit.nanos())),
+            IntType::Date => Ok(parsed.nanos.div_euclid(timestamp::NANOS_PER_DAY)),
+            _ => Err(anyhow!("strings are not written as {}", self)),
+        }
+    }
+
+    fn out_of_range(&self, value: i128) -> anyhow::Error {
+        OutOfRange { value, ty: *self }.into()
+    }
+
+    /// Converts an IPLD timestamp to the legacy INT96 layout, nanoseconds
+    /// within the day followed by the Julian day number.
+    fn int96(&self, i: i128) -> Result<Int96> {
+        const NANOS_PER_DAY: i128 = 86_400_000_000_000;
+        const UNIX_EPOCH_JULIAN_DAY: i128 = 2_440_588;
+        let IntType::Timestamp { unit } = *self else {
+            return Err(anyhow!(
+                "INT96 is only written for timestamps, not {}",
+                self
+            ));
+        };
+        let nanos = i
+            .checked_mul(unit.nanos())
+            .ok_or_else(|| self.out_of_range(i))?;
+        let day = u32::try_from(nanos.div_euclid(NANOS_PER_DAY) + UNIX_EPOCH_JULIAN_DAY)
+            .map_err(|_| self.out_of_range(i))?;
+        let nanos_of_day = nanos.rem_euclid(NANOS_PER_DAY) as u64;
+        let mut value = Int96::new();
+        value.set_data(nanos_of_day as u32, (nanos_of_day >> 32) as u32, day);
+        Ok(value)
+    }
+}
+
+impl fmt::Display for IntType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            IntType::Int { bits, signed } => {
+                write!(f, "{}int{}", if signed { "" } else { "u" }, bits)
+            }
+            IntType::Decimal { precision, scale } => write!(f, "decimal({},{})", precision, scale),
+            IntType::Timestamp { unit } => write!(f, "timestamp_{}", unit.suffix()),
+            IntType::Date => write!(f, "date"),
+        }
+    }
+}
+
+impl Serialize for IntType {
+    fn serialize<S: serde::Serializer>(&self, s: S) -> std::result::Result<S::Ok, S::Error> {
+        s.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for IntType {
+    fn deserialize<D: serde::Deserializer<'de>>(d: D) -> std::result::Result<Self, D::Error> {
+        String::deserialize(d)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// An integer that does not fit the type of its column.
+#[derive(Debug)]
+struct OutOfRange {
+    value: i128,
+    ty: IntType,
+}
+
+impl fmt::Display for OutOfRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "integer {} out of range for {}", self.value, self.ty)
+    }
+}
+
+impl std::error::Error for OutOfRange {}
+
+fn parse_dictionary(s: &str) -> Result<(String, bool)> {
+    match s.split_once('=') {
+        Some((path, "on")) => Ok((path.to_string(), true)),
+        Some((path, "off")) => Ok((path.to_string(), false)),
+        _ => Err(anyhow!("expected PATH=on or PATH=off, got {s}")),
+    }
+}
+
+/// Size at which row groups are cut.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RowGroupSize {
+    Rows(usize),
+    /// Bytes of values, before encoding and compression.
+    Bytes(usize),
+}
+
+fn parse_row_group_size(s: &str) -> Result<RowGroupSize> {
+    let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (n, unit) = s.split_at(digits);
+    let n: usize = n
+        .parse()
+        .ok()
+        .filter(|n| *n > 0)
+        .ok_or_else(|| anyhow!("expected a positive number of rows or bytes, got {s}"))?;
+    if unit.trim().is_empty() {
+        return Ok(RowGroupSize::Rows(n));
+    }
+    Ok(RowGroupSize::Bytes(n.saturating_mul(byte_scale(unit)?)))
+}
+
+/// A number of bytes, with an optional unit suffix such as MiB.
+fn parse_bytes(s: &str) -> Result<usize> {
+    let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (n, unit) = s.split_at(digits);
+    let n: usize = n
+        .parse()
+        .map_err(|_| anyhow!("expected a number of bytes, got {s}"))?;
+    Ok(n.saturating_mul(byte_scale(unit)?))
+}
+
+fn byte_scale(unit: &str) -> Result<usize> {
+    Ok(match unit.trim() {
+        "" | "B" => 1,
+        "KB" => 1000,
+        "MB" => 1000 * 1000,
+        "GB" => 1000 * 1000 * 1000,
+        "KiB" => 1 << 10,
+        "MiB" => 1 << 20,
+        "GiB" => 1 << 30,
+        _ => {
+            return Err(anyhow!(
+                "unknown unit {unit:?}, expected B, KB, MB, GB, KiB, MiB or GiB"
+            ))
+        }
+    })
+}
+
+/// A duration with a unit suffix of ms, s, m or h, seconds without one.
+fn parse_duration(s: &str) -> Result<std::time::Duration> {
+    let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (n, unit) = s.split_at(digits);
+    let n: u64 = n
+        .parse()
+        .map_err(|_| anyhow!("expected a duration such as 90s, got {s}"))?;
+    let millis = match unit.trim() {
+        "ms" => 1,
+        "" | "s" => 1000,
+        "m" => 60 * 1000,
+        "h" => 60 * 60 * 1000,
+        _ => return Err(anyhow!("unknown unit {unit:?}, expected ms, s, m or h")),
+    };
+    Ok(std::time::Duration::from_millis(n.saturating_mul(millis)))
+}
+
+fn parse_partition_path(s: &str) -> Result<String> {
+    match s.strip_prefix("data.") {
+        Some(field) if !field.is_empty() => Ok(s.to_string()),
+        _ => Err(anyhow!(
+            "expected the path of a field of the data such as data.type, got {s}"
+        )),
+    }
+}
+
+fn parse_int_type(s: &str) -> Result<(String, IntType)> {
+    let (path, ty) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow!("expected PATH=TYPE, got {s}"))?;
+    Ok((path.to_string(), ty.parse()?))
+}
+
+fn parse_type_hint(s: &str) -> Result<(String, IntType)> {
+    let (path, ty) = parse_int_type(s)?;
+    Ok((path, time_type(ty)?))
+}
+
+fn time_type(ty: IntType) -> Result<IntType> {
+    if !ty.is_time() {
+        return Err(anyhow!(
+            "expected timestamp_s/ms/us/ns or date, got {ty}; integer types are set with --int-type"
+        ));
+    }
+    Ok(ty)
+}
+
+/// Settings that influence how schemas are mapped to output files.
+#[derive(Debug, Default)]
+struct Config {
+    format: Format,
+    compression: Compression,
+    /// Level of the codec, its default if `None`.
+    compression_level: Option<u32>,
+    /// Prefix of the file names of the schemas.
+    schema_prefix: String,
+    /// 1 prints the files written, 2 also their schemas.
+    verbose: u8,
+    json: JsonOptions,
+    /// Write the links and CID columns of the parquet files as strings.
+    cid_strings: bool,
+    lenient: bool,
+    link_lists: HashMap<String, LinkListRepr>,
+    explode: HashSet<String>,
+    int_types: HashMap<String, IntType>,
+    int_overflow: IntOverflow,
+    /// Infer the time types of fields from their names and values.
+    infer_times: bool,
+    /// Write timestamps as INT96 instead of annotated INT64.
+    legacy_int96: bool,
+    /// Move fields with the same value in every row to the footer metadata.
+    prune_constants: bool,
+    /// Existing table all blocks are written as.
+    target: Option<Target>,
+    /// Merge the tables whose schemas differ only by fields some rows lack.
+    merge_schemas: bool,
+    /// Record the fingerprint of the schema of each block in its
+    /// `_schema_fingerprint` field.
+    schema_fingerprint: bool,
+    /// Name the tables by the fingerprint of their schema.
+    fingerprint_names: bool,
+    /// Ids of the schemas, recorded in the `_schema_id` field of the blocks.
+    registry: Option<std::sync::Mutex<Registry>>,
+    /// Schemas of the blocks seen, inferred once for each shape.
+    shapes: std::sync::Mutex<Shapes>,
+    order: Order,
+    /// Paths of the fields written first within their group, in this order.
+    column_order: Vec<String>,
+    /// Top level columns of the block tables.
+    columns: Columns,
+    /// Write the column and offset indexes of the data pages.
+    page_index: bool,
+    /// Rows after which a data page is cut.
+    page_rows: Option<usize>,
+    /// Bytes after which a data page is cut, chosen for each table if `None`.
+    page_bytes: Option<usize>,
+    /// Columns written with or without dictionary, by their option path.
+    dictionary: HashMap<String, bool>,
+    /// Size of the row groups, chosen for each table if `None`.
+    row_group_size: Option<RowGroupSize>,
+    /// Keep the writer properties of the parquet crate, see the tuning module.
+    default_writer_props: bool,
+    /// Write manifest.json.
+    manifest: bool,
+    /// Number of records of each file sampled into the manifest.
+    samples: usize,
+    /// Record the CIDs of each row group of the block files in cid_index.json.
+    cid_index: bool,
+    /// Number of tables written at once.
+    jobs: usize,
+    /// Projection and filter of the blocks.
+    query: Option<sql::Query>,
+    /// Blocks written by earlier runs with --append, and the number of this one.
+    append: Option<state::State>,
+    /// Limits that stop the reading of blocks.
+    limits: limits::Limits,
+    /// Cancelled on Ctrl-C, checked between blocks, rows and columns.
+    cancel: CancellationToken,
+}
+
+impl Config {
+    fn file_name(&self, stem: &str) -> String {
+        format!("{}.{}", self.run_stem(stem), self.format.extension())
+    }
+
+    /// The stem of a file of the output, numbered after the run with --append
+    /// for the files of earlier runs to be kept.
+    fn run_stem(&self, stem: &str) -> String {
+        match self.append.as_ref().map(state::State::run) {
+            Some(run) if run > 0 => format!("{}-{}", stem, run),
+            _ => stem.to_string(),
+        }
+    }
+
+    /// The integer types of the fields of a file: those of --int-type, and
+    /// for the others the narrowest of uint64, int64 and decimal(20,0) that
+    /// holds the values of the rows. Fields mixing negative values with
+    /// values beyond int64 are decimal(20,0), as are with --int-overflow widen
+    /// those with values beyond 64 bits, which otherwise fail.
+    fn int_types_of(&self, rows: &[Block]) -> HashMap<String, IntType> {
+        /// Whether the strings of a path are all times, and all dates.
+        #[derive(Clone, Copy)]
+        struct Times {
+            times: bool,
+            dates: bool,
+        }
+        fn walk(
+            data: &Ipld,
+            path: &str,
+            ranges: &mut HashMap<String, (i128, i128)>,
+            strings: &mut Option<HashMap<String, Times>>,
+        ) {
+            match data {
+                Ipld::Integer(i) => {
+                    let range = ranges.entry(path.to_string()).or_insert((*i, *i));
+                    *range = (range.0.min(*i), range.1.max(*i));
+                }
+                Ipld::String(s) => {
+                    if let Some(strings) = strings {
+                        let parsed = timestamp::parse(s);
+                        let times = strings.entry(path.to_string()).or_insert(Times {
+                            times: true,
+                            dates: true,
+                        });
+                        times.times &= parsed.is_some();
+                        times.dates &= parsed.is_some_and(|p| p.date_only);
+                    }
+                }
+                Ipld::List(l) => l.iter().for_each(|v| walk(v, path, ranges, strings)),
+                Ipld::Map(m) => m
+                    .iter()
+                    .for_each(|(k, v)| walk(v, &field_path(path, k), ranges, strings)),
+                _ => {}
+            }
+        }
+        let mut ranges = HashMap::new();
+        let mut strings = self.infer_times.then(HashMap::new);
+        for (_, data, _) in rows {
+            walk(data, "data", &mut ranges, &mut strings);
+        }
+        let widen = self.int_overflow == IntOverflow::Widen;
+        let int64 = IntType::Int {
+            bits: 64,
+            signed: true,
+        };
+        let decimal = IntType::Decimal {
+            precision: 20,
+            scale: 0,
+        };
+        let mut types = self.int_types.clone();
+        for (path, times) in strings.unwrap_or_default() {
+            let int_type = match times {
+                Times { dates: true, .. } => IntType::Date,
+                Times { times: true, .. } => IntType::Timestamp {
+                    unit: TimeUnit::Micros,
+                },
+                _ => continue,
+            };
+            types.entry(path).or_insert(int_type);
+        }
+        if self.infer_times {
+            for (path, &(min, max)) in &ranges {
+                if types.contains_key(path) || !timestamp::named_like_time(path) {
+                    continue;
+                }
+                // Seconds and milliseconds from 2001 to 2286.
+                let unit = if min >= 1_000_000_000 && max < 10_000_000_000 {
+                    TimeUnit::Seconds
+                } else if min >= 1_000_000_000_000 && max < 10_000_000_000_000 {
+                    TimeUnit::Millis
+                } else {
+                    continue;
+                };
+                types.insert(path.clone(), IntType::Timestamp { unit });
+            }
+        }
+        for (path, (min, max)) in ranges {
+            if types.contains_key(&path) || min >= 0 && (max <= u64::MAX as i128 || !widen) {
+                continue;
+            }
+            let signed = max <= i64::MAX as i128 && (min >= i64::MIN as i128 || !widen);
+            types.insert(path, if signed { int64 } else { decimal });
+        }
+        types
+    }
+
+    fn link_list_repr(&self, path: &str) -> LinkListRepr {
+        self.link_lists.get(path).copied().unwrap_or_default()
+    }
+
+    /// Name of the `i`th table of the schemas, or with a registry of the table
+    /// whose first row has the data `first`, by the id of its schema. With
+    /// --fingerprint-names it is named by the fingerprint of its schema.
+    fn table_name(&self, i: usize, schema: &Schema, first: &Ipld) -> String {
+        if self.fingerprint_names {
+            let fingerprint = registry::table_fingerprint(schema);
+            return format!("{}_{}", self.schema_prefix, &fingerprint[..16]);
+        }
+        match (&self.registry, first.get("_schema_id")) {
+            (Some(_), Ok(Ipld::Integer(id))) => format!("{}_{}", self.schema_prefix, id),
+            _ => format!("{}_{}", self.schema_prefix, i),
+        }
+    }
+}
+
+/// Names of the top level columns of the block tables, which are rooted at
+/// `cid` and `data` within the schemas and the options.
+#[derive(Debug, Clone)]
+struct Columns {
+    /// Column of the block CID, left out if `None`.
+    cid: Option<String>,
+    /// Group of the block data, whose fields are the top level columns if `None`.
+    data: Option<String>,
+}
+
+impl Default for Columns {
+    fn default() -> Self {
+        Columns {
+            cid: Some("cid".to_string()),
+            data: Some("data".to_string()),
+        }
+    }
+}
+
+impl Columns {
+    /// Path, rooted at `cid` or `data`, of the column at the output path.
+    fn internal(&self, parts: &[String]) -> Vec<String> {
+        match parts.split_first() {
+            Some((p, [])) if self.cid.as_ref() == Some(p) => vec!["cid".to_string()],
+            Some((p, rest)) if self.data.as_ref() == Some(p) => std::iter::once("data".to_string())
+                .chain(rest.iter().cloned())
+                .collect(),
+            Some((p, _)) if self.data.is_none() && p != "_conversion_errors" => {
+                std::iter::once("data".to_string())
+                    .chain(parts.iter().cloned())
+                    .collect()
+            }
+            _ => parts.to_vec(),
+        }
+    }
+
+    /// Output path of a path rooted at `data`.
+    fn output(&self, path: &str) -> String {
+        let rest = path.strip_prefix("data").unwrap_or(path);
+        match &self.data {
+            Some(name) => format!("{}{}", name, rest),
+            None => rest.trim_start_matches('.').to_string(),
+        }
+    }
+}
+
+type Block = (Cid, Ipld, Vec<u8>);
+
+/// Multicodecs of dag-json and dag-pb blocks.
+const DAG_JSON: u64 = 0x0129;
+const DAG_PB: u64 = 0x70;
+
+/// Decodes a block by the codec of its CID, `None` for a raw block. Blocks of
+/// codecs other than dag-json and dag-pb are decoded as dag-cbor, as the
+/// conversion decodes them.
+fn decode_dag(cid: &Cid, bytes: &[u8]) -> Option<Result<Ipld>> {
+    match cid.codec() {
+        RAW => None,
+        DAG_JSON => Some(DagJsonCodec.decode(bytes)),
+        DAG_PB => Some(DagPbCodec.decode(bytes)),
+        _ => Some(DagCborCodec.decode(bytes)),
+    }
+}
+
+/// Error of a value whose kind does not fit the column.
+fn wrong_kind(v: &Ipld, expected: &str) -> anyhow::Error {
+    anyhow!("expected {}, got {}", expected, ipld_kind(v))
+}
+
+/// Name of the kind of a value.
+fn ipld_kind(data: &Ipld) -> &'static str {
+    match data {
+        Ipld::Null => "null",
+        Ipld::Bool(_) => "bool",
+        Ipld::Integer(_) => "integer",
+        Ipld::Float(_) => "float",
+        Ipld::String(_) => "string",
+        Ipld::Bytes(_) => "bytes",
+        Ipld::List(_) => "list",
+        Ipld::Map(_) => "map",
+        Ipld::Link(_) => "link",
+    }
+}
+
+/// Rows of the tables of exploded lists, by list path and row schema.
+type ExplodedTables = HashMap<String, HashMap<Schema, Vec<Block>>>;
+
+/// Footer metadata key holding the fields pruned for having the same value in every row.
+const CONSTANTS_KEY: &str = "carquet.constants";
+
+impl ConvertArgs {
+    fn config(&self, cancel: CancellationToken) -> Result<Config> {
+        if self.write_after == Some(0) {
+            return Err(anyhow!("--write-after must be at least 1"));
+        }
+        if self.page_rows == Some(0) {
+            return Err(anyhow!("--page-rows must be at least 1"));
+        }
+        // Checked here so a bad level fails before any file is written.
+        self.compression.parquet(self.compression_level)?;
+        if self.infer_sample == Some(0) {
+            return Err(anyhow!("--infer-sample must be at least 1"));
+        }
+        if self.inference_cache.is_some()
+            && self.schema_cache.is_none()
+            && self.infer_sample.is_none()
+        {
+            return Err(anyhow!(
+                "--inference-cache needs --schema-cache or --infer-sample, which infer the schemas first"
+            ));
+        }
+        if self.page_size == Some(0) {
+            return Err(anyhow!("--page-size must be at least 1"));
+        }
+        if self.cid_index && self.format != Format::Parquet {
+            return Err(anyhow!(
+                "--cid-index needs parquet output, which has row groups"
+            ));
+        }
+        for (used, flag) in [
+            (self.write_after.is_some(), "--write-after"),
+            (self.schema_cache.is_some(), "--schema-cache"),
+            (self.infer_sample.is_some(), "--infer-sample"),
+            (self.stream, "--stream"),
+        ] {
+            if used && self.root_cids {
+                return Err(anyhow!(
+                    "{} cannot be used with --root-cids, which needs every block decoded first",
+                    flag
+                ));
+            }
+        }
+        let mut config = Config {
+            format: self.format,
+            compression: self.compression,
+            compression_level: self.compression_level,
+            schema_prefix: self
+                .schema_prefix
+                .as_deref()
+                .unwrap_or("schema")
+                .to_string(),
+            verbose: self.verbose,
+            json: JsonOptions {
+                int_strings: self.json_int_strings,
+                cid_strings: self.cid_strings,
+            },
+            cid_strings: self.cid_strings,
+            lenient: self.lenient,
+            link_lists: self.link_lists.iter().cloned().collect(),
+            explode: self.explode.iter().cloned().collect(),
+            int_types: self
+                .type_hints
+                .iter()
+                .chain(&self.int_types)
+                .cloned()
+                .collect(),
+            int_overflow: self.int_overflow,
+            infer_times: self.infer_times,
+            legacy_int96: self.legacy_int96_timestamps,
+            // Pinned, target and cached schemas fix the columns, and streamed
+            // files are written before all their rows are seen, so nothing may be pruned.
+            prune_constants: self.prune_constant_columns
+                && self.format == Format::Parquet
+                && self.pin_schemas.is_none()
+                && self.target_schema.is_none()
+                && self.schema_cache.is_none()
+                && self.infer_sample.is_none()
+                && !self.stream,
+            target: None,
+            merge_schemas: self.merge_schemas,
+            schema_fingerprint: self.schema_fingerprint,
+            fingerprint_names: self.fingerprint_names,
+            registry: self
+                .schema_registry
+                .as_deref()
+                .map(Registry::load)
+                .transpose()?
+                .map(std::sync::Mutex::new),
+            shapes: std::sync::Mutex::new(Shapes::new(self.include_raw, self.block_size)),
+            order: self.order,
+            column_order: self.column_order.clone(),
+            columns: if self.no_wrapper {
+                Columns {
+                    cid: None,
+                    data: None,
+                }
+            } else {
+                Columns {
+                    cid: Some(self.cid_column_name.clone()),
+                    data: (!self.hoist_data).then(|| self.data_column_name.clone()),
+                }
+            },
+            page_index: !self.no_page_index,
+            page_rows: self.page_rows,
+            page_bytes: self.page_size,
+            dictionary: self.dictionary.iter().cloned().collect(),
+            row_group_size: self.row_group_size,
+            default_writer_props: self.default_writer_props,
+            manifest: self.manifest || self.samples > 0,
+            samples: self.samples,
+            cid_index: self.cid_index,
+            jobs: self
+                .jobs
+                .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()))
+                .max(1),
+            query: self
+                .sql
+                .as_deref()
+                .map(sql::Query::parse)
+                .transpose()
+                .context("parsing --sql")?,
+            append: None,
+            limits: limits::Limits::new(
+                self.max_blocks,
+                self.max_output_bytes.map(|n| n as u64),
+                self.max_runtime,
+            ),
+            cancel,
+        };
+        if config.columns.cid == config.columns.data && !self.no_wrapper {
+            return Err(anyhow!(
+                "--cid-column-name and --data-column-name must differ, both are {}",
+                self.cid_column_name
+            ));
+        }
+        if let Some(path) = &self.target_schema {
+            // The columns of the target take precedence over the options given.
+            let target = Target::load(path, &config.columns)?;
+            config.int_types.extend(target.int_types.iter().cloned());
+            config.link_lists.extend(
+                target
+                    .link_lists
+                    .iter()
+                    .map(|p| (p.clone(), LinkListRepr::List)),
+            );
+            config.target = Some(target);
+        }
+        Ok(config)
+    }
+
+    fn report(&self) -> Report {
+        Report::new(Thresholds {
+            row_width: self.warn_row_width,
+            list_len: self.warn_list_len,
+        })
+    }
+}
+
+impl Default for ConvertArgs {
+    /// The options of `carquet convert` without flags.
+    fn default() -> Self {
+        Self::from_flags(std::iter::empty::<&str>()).expect("the defaults parse")
+    }
+}
+
+impl ConvertArgs {
+    /// Parses the options from flags as given to `carquet convert`, e.g.
+    /// `["--format", "jsonl", "--explode", "data.entries"]`.
+    pub fn from_flags<I, T>(flags: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<std::ffi::OsString> + Clone,
+    {
+        use clap::Args as _;
+        let command = Self::augment_args(clap::Command::new("convert").no_binary_name(true));
+        let matches = command.try_get_matches_from(flags)?;
+        Ok(Self::from_arg_matches(&matches)?)
+    }
+
+    /// The options with the defaults of the preset, if any, filled in.
+    fn with_preset(&self) -> Self {
+        let mut args = self.clone();
+        if let Some(preset) = self.preset {
+            preset.apply(&mut args);
+        }
+        args
+    }
+
+    /// The options of the --options file, if given, with the flags that differ
+    /// from their defaults in place of those of the file.
+    pub fn with_options_file(self) -> Result<Self> {
+        match &self.options {
+            Some(path) => options::merge(ConvertOptions::load(path)?, &self),
+            None => Ok(self),
+        }
+    }
+
+    /// Whether nothing but errors should be printed.
+    pub fn quiet(&self) -> bool {
+        self.quiet
+    }
+
+    /// Checks the options before any shard is converted, rejecting those
+    /// that cannot be shared by shards converted at once.
+    pub fn check_sharded(&self) -> Result<()> {
+        self.config(CancellationToken::new())?;
+        if self.write_pins.is_some() {
+            return Err(anyhow!(
+                "--write-pins is not supported with convert-sharded, write pins from one representative shard with convert"
+            ));
+        }
+        // Shards inferring at once would race to write the cache.
+        if let Some(path) = self.schema_cache.as_ref().filter(|p| !p.exists()) {
+            return Err(anyhow!(
+                "schema cache {} does not exist, create it from one representative shard with convert",
+                path.display()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Number of shards converted at once, and the options of each shard,
+    /// which writes its tables one at a time as the shards share the cores.
+    pub fn sharded_jobs(&self) -> (usize, ConvertArgs) {
+        let shard = ConvertArgs {
+            jobs: Some(1),
+            ..self.clone()
+        };
+        (self.jobs.unwrap_or(4).max(1), shard)
+    }
+
+    /// With --manifest, writes manifest.json of the output directory of
+    /// convert-sharded, the manifests of the directories of the shards in it
+    /// merged.
+    pub fn merge_manifests(&self, dir: &Path, shards: &[PathBuf]) -> Result<()> {
+        if !(self.manifest || self.samples > 0) {
+            return Ok(());
+        }
+        manifest::merge(dir, shards)?.save(&dir.join("manifest.json"))
+    }
+}
+
+/// A conversion of CAR files into a directory of files, one per schema.
+#[derive(Clone)]
+pub struct CarToParquetConverter {
+    args: ConvertArgs,
+    inputs: Vec<Unit>,
+    dir: PathBuf,
+    cancel: CancellationToken,
+    cache: Option<Arc<Mutex<dyn cache::BlockCache>>>,
+}
+
+impl fmt::Debug for CarToParquetConverter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CarToParquetConverter")
+            .field("args", &self.args)
+            .field("inputs", &self.inputs)
+            .field("dir", &self.dir)
+            .field("cancel", &self.cancel)
+            .finish_non_exhaustive()
+    }
+}
+
+impl CarToParquetConverter {
+    /// A conversion into `dir`, created if missing, with the default options
+    /// and no inputs.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            args: ConvertArgs::default(),
+            inputs: Vec::new(),
+            dir: dir.into(),
+            cancel: CancellationToken::new(),
+            cache: None,
+        }
+    }
+
+    /// Adds a CAR file to convert as a whole.
+    pub fn input(mut self, path: impl AsRef<Path>) -> Self {
+        self.inputs.push(Unit::whole(path.as_ref()));
+        self
+    }
+
+    /// Adds ranges of blocks of CAR files to convert, e.g. the units of a task
+    /// of a [`plan::Plan`].
+    pub fn units(mut self, units: impl IntoIterator<Item = Unit>) -> Self {
+        self.inputs.extend(units);
+        self
+    }
+
+    /// Sets the options of the conversion.
+    pub fn options(mut self, args: ConvertArgs) -> Self {
+        self.args = args;
+        self
+    }
+
+    /// Token that stops the conversion between blocks, rows and columns when
+    /// cancelled, failing it with [`Error::Cancelled`].
+    pub fn cancel_token(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// Cache of the blocks links are inlined from, in place of a
+    /// [`cache::Lru`] of `--block-cache-size` bytes. Clones of the
+    /// converter and later runs share it.
+    pub fn block_cache(mut self, cache: impl cache::BlockCache + 'static) -> Self {
+        self.cache = Some(Arc::new(Mutex::new(cache)));
+        self
+    }
+
+    /// An empty report with the warning thresholds of the options.
+    pub fn report(&self) -> Report {
+        self.args.report()
+    }
+
+    /// Converts the inputs, returning the report of what was written.
+    pub async fn run(&self) -> Result<Report> {
+        let mut report = self.report();
+        self.run_with(&mut report).await?;
+        Ok(report)
+    }
+
+    /// Converts the inputs, recording what was written in `report`. If the
+    /// conversion fails or is cancelled the report covers the files completely
+    /// written before.
+    pub async fn run_with(&self, report: &mut Report) -> Result<()> {
+        let args = self.args.with_preset();
+        let hooks = hooks::Hooks::new(&args)?;
+        let result = self.convert(&args, report).await;
+        let notified = hooks.notify(&self.dir, report, &result);
+        result.and(notified)
+    }
+
+    async fn convert(&self, args: &ConvertArgs, report: &mut Report) -> Result<()> {
+        let mut config = args.config(self.cancel.clone())?;
+        std::fs::create_dir_all(&self.dir).context(format!("creating {}", self.dir.display()))?;
+        if args.append {
+            config.append = Some(state::State::load(&self.dir)?);
+        }
+        let mut result = write_all(
+            args,
+            &config,
+            &self.inputs,
+            &self.dir,
+            report,
+            self.cache.clone(),
+        )
+        .await;
+        if result.is_ok() && config.manifest {
+            let name = format!("{}.json", config.run_stem("manifest"));
+            result = report.manifest.save(&self.dir.join(name));
+        }
+        if result.is_ok() && config.cid_index {
+            let name = format!("{}.json", config.run_stem("cid_index"));
+            result = report.cid_index.save(&self.dir.join(name));
+        }
+        if let (true, Some(registry)) = (result.is_ok(), &config.registry) {
+            result = registry.lock().expect("registry lock").save();
+        }
+        if let (true, Some(state)) = (result.is_ok(), &config.append) {
+            result = state.save();
+        }
+        report.cancelled = config.cancel.is_cancelled();
+        result
+    }
+}
+
+/// Reads the raw blocks of the inputs, passing each to `f` with its position,
+/// the index of its input and its index within the file.
+async fn raw_blocks(
+    inputs: &[Unit],
+    cancel: &CancellationToken,
+    mut f: impl FnMut((usize, usize), Cid, Vec<u8>) -> Result<()>,
+) -> Result<()> {
+    for (u, unit) in inputs.iter().enumerate() {
+        let mut car = unit.open()?;
+        for index in unit.first_block..unit.first_block.saturating_add(unit.blocks) {
+            let Some((cid, bytes)) = car
+                .next_block()
+                .context(format!("reading {}", unit.path.display()))?
+            else {
+                break;
+            };
+            if cancel.is_cancelled() {
+                return Err(Error::Cancelled.into());
+            }
+            f((u, index), cid, bytes)?;
+        }
+    }
+    Ok(())
+}
+
+fn bytes_hash(bytes: &[u8]) -> u64 {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    std::hash::Hash::hash(bytes, &mut hasher);
+    std::hash::Hasher::finish(&hasher)
+}
+
+/// Position of the last copy of each CID whose copies do not all have the
+/// same bytes.
+async fn duplicate_conflicts(
+    inputs: &[Unit],
+    cancel: &CancellationToken,
+) -> Result<HashMap<Cid, (usize, usize)>> {
+    let mut seen: HashMap<Cid, (u64, (usize, usize), bool)> = HashMap::new();
+    raw_blocks(inputs, cancel, |pos, cid, bytes| {
+        let hash = bytes_hash(&bytes);
+        let entry = seen.entry(cid).or_insert((hash, pos, false));
+        entry.1 = pos;
+        entry.2 |= entry.0 != hash;
+        Ok(())
+    })
+    .await?;
+    Ok(seen
+        .into_iter()
+        .filter(|(_, (_, _, conflict))| *conflict)
+        .map(|(cid, (_, last, _))| (cid, last))
+        .collect())
+}
+
+/// Number of copies of each CID the inputs have more than one of.
+async fn cid_copies(inputs: &[Unit], cancel: &CancellationToken) -> Result<HashMap<Cid, usize>> {
+    let mut copies: HashMap<Cid, usize> = HashMap::new();
+    raw_blocks(inputs, cancel, |_, cid, _| {
+        *copies.entry(cid).or_default() += 1;
+        Ok(())
+    })
+    .await?;
+    copies.retain(|_, n| *n > 1);
+    Ok(copies)
+}
+
+/// Reads and decodes the blocks of the inputs, passing each to `f`. Blocks
+/// skipped by --dedup-payloads are collected in `aliases` instead, raw blocks
+/// are written to `raw` or dropped without it, copies of a CID with
+/// different bytes are handled by --duplicates, later copies are skipped with
+/// --dedup-cids, and blocks earlier runs wrote are skipped with --append.
+/// Reading stops early at the limits of the run. Blocks whose schema is known to `inferred` are
+/// counted by it instead of being decoded.
+#[allow(clippy::too_many_arguments)]
+async fn read_blocks(
+    args: &ConvertArgs,
+    config: &Config,
+    inputs: &[Unit],
+    report: &mut Report,
+    aliases: &mut Vec<Block>,
+    mut raw: Option<&mut RawBlocks<'_>>,
+    inferred: Option<&inference::Cache>,
+    mut f: impl FnMut(&mut Report, Cid, Ipld, Vec<u8>) -> Result<()>,
+) -> Result<()> {
+    // Keeping the last copy or flagging every copy needs the conflicts up front.
+    let conflicts = match args.duplicates {
+        Duplicates::KeepLast | Duplicates::Flag => {
+            duplicate_conflicts(inputs, &config.cancel).await?
+        }
+        Duplicates::Error | Duplicates::KeepFirst => HashMap::new(),
+    };
+    let copies = if args.dedup_cids {
+        cid_copies(inputs, &config.cancel).await?
+    } else {
+        HashMap::new()
+    };
+    let mut written: HashSet<Cid> = HashSet::new();
+    // Failing or keeping the first copy checks each block against the hash of
+    // the first copy of its CID, the other policies read the conflicts above.
+    let mut hashes: Option<HashMap<Cid, u64>> =
+        matches!(args.duplicates, Duplicates::Error | Duplicates::KeepFirst).then(HashMap::new);
+    let mut payloads: HashMap<Multihash, Cid> = HashMap::new();
+    // Inlining and following links reads the blocks beyond the query.
+    let partial = config
+        .query
+        .as_ref()
+        .and_then(sql::Query::paths)
+        .filter(|_| !args.root_cids)
+        .filter(|_| !args.link_forms.iter().any(|(_, f)| *f == LinkForm::Inline));
+    let mut read = 0;
+    let mut limit = None;
+    let result = raw_blocks(inputs, &config.cancel, |pos, cid, bytes| {
+        limit = config.limits.reached(read);
+        if limit.is_some() {
+            return Err(anyhow!("limit reached"));
+        }
+        // Blocks earlier runs wrote are not counted towards --max-blocks.
+        if config.append.as_ref().is_some_and(|state| !state.admit(&cid)) {
+            report.appended_skipped += 1;
+            return Ok(());
+        }
+        read += 1;
+        let conflict = hashes.as_mut().is_some_and(|hashes| {
+            let hash = bytes_hash(&bytes);
+            *hashes.entry(cid).or_insert(hash) != hash
+        });
+        match args.duplicates {
+            Duplicates::Error if conflict => {
+                return Err(anyhow!(
+                    "block {} of {} has the CID {} of an earlier block but different bytes, see --duplicates",
+                    pos.1,
+                    inputs[pos.0].path.display(),
+                    cid
+                ))
+            }
+            Duplicates::KeepFirst if conflict => {
+                report.duplicate_conflicts += 1;
+                return Ok(());
+            }
+            Duplicates::KeepLast => match conflicts.get(&cid) {
+                Some(last) if *last != pos => {
+                    report.duplicate_conflicts += 1;
+                    return Ok(());
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+        if args.dedup_cids && copies.contains_key(&cid) && !written.insert(cid) {
+            report.cid_copies += 1;
+            return Ok(());
+        }
+        if args.dedup_payloads {
+            let canonical = *payloads.entry(Code::Sha2_256.digest(&bytes)).or_insert(cid);
+            if canonical != cid {
+                let alias = Ipld::Map(BTreeMap::from([(
+                    "canonical_cid".to_string(),
+                    Ipld::Link(canonical),
+                )]));
+                aliases.push((cid, alias, Vec::new()));
+                return Ok(());
+            }
+        }
+        if cid.codec() == RAW {
+            return match raw.as_deref_mut() {
+                Some(raw) => raw.push(report, (cid, Ipld::Bytes(bytes), Vec::new())),
+                None => Ok(()),
+            };
+        }
+        if inferred.is_some_and(|cache| cache.hit(&cid)) {
+            return Ok(());
+        }
+        let mut dag = decode_block(args, &cid, &bytes, partial.as_deref(), &mut report.invalid_utf8)?;
+        // Only maps can hold the field, copies of other data are written
+        // unflagged and not counted.
+        if let (Duplicates::Flag, Ipld::Map(m)) = (args.duplicates, &mut dag) {
+            if conflicts.contains_key(&cid) {
+                report.duplicate_conflicts += 1;
+                m.insert("_duplicate_conflict".to_string(), Ipld::Bool(true));
+            }
+        }
+        if let (true, Ipld::Map(m)) = (args.dedup_cids, &mut dag) {
+            let n = copies.get(&cid).copied().unwrap_or(1);
+            m.insert("_copies".to_string(), Ipld::Integer(n as i128));
+        }
+        f(report, cid, dag, bytes)
+    })
+    .await;
+    if let Some(limit) = limit {
+        report.limit = Some(limit);
+        return Ok(());
+    }
+    result
+}
+
+/// Decodes the bytes of a non-raw block by the codec of its CID, only the
+/// fields at `partial` if given, counting blocks whose invalid UTF-8 was
+/// handled by the policy in `invalid_utf8`.
+fn decode_block(
+    args: &ConvertArgs,
+    cid: &Cid,
+    bytes: &[u8],
+    partial: Option<&[Vec<String>]>,
+    invalid_utf8: &mut usize,
+) -> Result<Ipld> {
+    // Blocks of codecs other than dag-json and dag-pb are decoded as dag-cbor.
+    let other = match cid.codec() {
+        DAG_JSON => Some(("dag-json", DagJsonCodec.decode(bytes))),
+        DAG_PB => Some(("dag-pb", DagPbCodec.decode(bytes))),
+        _ => None,
+    };
+    let codec = other.as_ref().map(|(codec, _)| *codec);
+    let decoded = if let Some((_, decoded)) = other {
+        decoded
+    } else if let Some(dag) = partial.and_then(|paths| lazy::decode(bytes, paths).ok()) {
+        // Blocks the partial decode rejects are decoded whole, for their errors.
+        Ok(dag)
+    } else {
+        // Only blocks rejected for invalid UTF-8 are decoded again under the policy.
+        DagCborCodec
+            .decode(bytes)
+            .or_else(|e| match utf8::decode(bytes, args.invalid_utf8) {
+                Ok((dag, invalid)) if invalid > 0 => {
+                    *invalid_utf8 += 1;
+                    Ok(dag)
+                }
+                _ => Err(e),
+            })
+    };
+    match (decoded, codec) {
+        (Ok(dag), _) => Ok(dag),
+        (Err(e), Some(codec)) => Err(Error::Decode {
+            what: format!("{} block {}", codec, cid),
+            source: e.into(),
+        }
+        .into()),
+        (Err(e), None) if args.cbor_diagnostics => Err(Error::Decode {
+            what: format!(
+                "block {}\ndiagnostic: {}\nhex: {}",
+                cid,
+                diag::diagnostic(bytes),
+                diag::hex(bytes)
+            ),
+            source: e.into(),
+        }
+        .into()),
+        (Err(e), None) => Err(Error::Decode {
+            what: format!("block {}", cid),
+            source: e.into(),
+        }
+        .into()),
+    }
+}
+
+/// Converts the blocks of the inputs, recording what was written in the report.
+async fn write_all(
+    args: &ConvertArgs,
+    config: &Config,
+    inputs: &[Unit],
+    dir: &Path,
+    report: &mut Report,
+    cache: Option<Arc<Mutex<dyn cache::BlockCache>>>,
+) -> Result<()> {
+    let forms: HashMap<String, LinkForm> = args.link_forms.iter().cloned().collect();
+    let resolver = if args.adl.is_some() || forms.values().any(|f| *f == LinkForm::Inline) {
+        let cache = match cache {
+            Some(cache) => cache,
+            None => {
+                let mut lru = cache::Lru::new(args.block_cache_size);
+                if let Some(dir) = &args.block_cache_dir {
+                    lru = lru.spill_to(dir)?;
+                }
+                Arc::new(Mutex::new(lru))
+            }
+        };
+        Some(cache::Resolver::new(args, inputs, cache)?)
+    } else {
+        None
+    };
+    let shards = match (args.adl, &resolver) {
+        (Some(adl), Some(resolver)) => Some(adl::Shards::new(adl, args, resolver)?),
+        _ => None,
+    };
+    let links = Links {
+        forms,
+        resolver,
+        shards,
+    };
+    let result = write_with(args, config, inputs, dir, report, &links).await;
+    if let Some(resolver) = &links.resolver {
+        resolver.record(report);
+    }
+    if let Some(shards) = &links.shards {
+        shards.record(report);
+    }
+    result
+}
+
+/// The forms of the links by field path, the blocks links are inlined from
+/// if any are, and the shards of --adl.
+struct Links {
+    forms: HashMap<String, LinkForm>,
+    resolver: Option<cache::Resolver>,
+    shards: Option<adl::Shards>,
+}
+
+async fn write_with(
+    args: &ConvertArgs,
+    config: &Config,
+    inputs: &[Unit],
+    dir: &Path,
+    report: &mut Report,
+    links: &Links,
+) -> Result<()> {
+    if let Some(path) = &args.schema_cache {
+        return write_cached(args, config, inputs, dir, report, links, path).await;
+    }
+    if let Some(n) = args.infer_sample {
+        let sample: Vec<Unit> = inputs
+            .iter()
+            .map(|unit| Unit {
+                blocks: unit.blocks.min(n),
+                ..unit.clone()
+            })
+            .collect();
+        let pins = infer_pins(args, config, &sample, links).await?;
+        let schemas = StreamSchemas::Sampled(pins);
+        return write_streams(args, config, inputs, dir, report, links, schemas).await;
+    }
+    if args.stream {
+        let schemas = StreamSchemas::Seen;
+        return write_streams(args, config, inputs, dir, report, links, schemas).await;
+    }
+    let mut schemas: HashMap<Schema, Vec<Block>> = HashMap::new();
+    let mut aliases: Vec<Block> = Vec::new();
+    let mut raw = RawBlocks::new(dir, config);
+    let mut blocks: Vec<Block> = Vec::new();
+    let mut parts: HashMap<Schema, (usize, usize)> = HashMap::new();
+    let mut exploded: ExplodedTables = HashMap::new();
+    read_blocks(
+        args,
+        config,
+        inputs,
+        report,
+        &mut aliases,
+        Some(&mut raw),
+        None,
+        |report, cid, dag, bytes| {
+            if args.root_cids {
+                blocks.push((cid, dag, bytes));
+                return Ok(());
+            }
+            let mut dag = dag;
+            if !prepare_block(args, config, links, &cid, &mut dag)? {
+                return Ok(());
+            }
+            report.check_lists(&cid, &dag);
+            let mut shapes = config.shapes.lock().expect("shapes lock");
+            let schema = shapes.schema(&dag);
+            let rows = rows_of(&mut schemas, schema);
+            rows.push((cid, dag, bytes));
+            let Some(n) = args.write_after else {
+                return Ok(());
+            };
+            if rows.len() >= n {
+                let mut rows = std::mem::take(rows);
+                let schema = schema.clone();
+                drop(shapes);
+                let name = part_name(&mut parts, &schema, &rows, config);
+                write_schema(
+                    dir,
+                    &name,
+                    &schema,
+                    &mut rows,
+                    config,
+                    report,
+                    &mut exploded,
+                )?;
+            }
+            Ok(())
+        },
+    )
+    .await?;
+
+    // The roots a block is reachable from are only known once every block
+    // is decoded.
+    if args.root_cids {
+        let roots = if args.roots.is_empty() {
+            car_roots(inputs).await?
+        } else {
+            args.roots.clone()
+        };
+        let mut reached = reachable_roots(&roots, &blocks);
+        for (cid, mut dag, bytes) in blocks {
+            if let Ipld::Map(m) = &mut dag {
+                let roots = reached.remove(&cid).unwrap_or_default();
+                m.entry("_root_cids".to_string())
+                    .or_insert_with(|| Ipld::List(roots.into_iter().map(Ipld::Link).collect()));
+            }
+            if !prepare_block(args, config, links, &cid, &mut dag)? {
+                continue;
+            }
+            report.check_lists(&cid, &dag);
+            let mut shapes = config.shapes.lock().expect("shapes lock");
+            rows_of(&mut schemas, shapes.schema(&dag)).push((cid, dag, bytes));
+        }
+    }
+
+    let mut schemas: Vec<(String, Schema, Vec<Block>)> = if let Some(path) = &args.pin_schemas {
+        let pins = Pins::load(path)?;
+        let mut pinned: Vec<Vec<Block>> = pins.schemas.iter().map(|_| Vec::new()).collect();
+        for (schema, mut rows) in schemas {
+            let i = pins
+                .find(&schema)
+                .context(format!("pinning block {}", rows[0].0))?;
+            coerce_rows(&mut rows, &pins.schemas[i].schema);
+            pinned[i].append(&mut rows);
+        }
+        pins.schemas
+            .into_iter()
+            .zip(pinned)
+            .filter(|(_, rows)| !rows.is_empty())
+            .map(|(pin, rows)| (pin.name, pin.schema, rows))
+            .collect()
+    } else if let Some(target) = &config.target {
+        // Report every kind of block that does not fit before writing anything.
+        let mut problems: BTreeMap<String, (usize, Cid)> = BTreeMap::new();
+        for (schema, rows) in &schemas {
+            if let Some(m) = pins::mismatch(schema, &target.schema, "") {
+                problems.entry(m).or_insert((0, rows[0].0)).0 += rows.len();
+            }
+        }
+        if !problems.is_empty() {
+            let problems: Vec<String> = problems
+                .iter()
+                .map(|(m, (n, cid))| format!("{}: {} blocks, e.g. {}", m, n, cid))
+                .collect();
+            return Err(anyhow!(
+                "blocks do not fit the target schema of {}:\n  {}",
+                target.name,
+                problems.join("\n  ")
+            ));
+        }
+        let mut all = Vec::new();
+        for (_, mut rows) in schemas {
+            coerce_rows(&mut rows, &target.schema);
+            all.append(&mut rows);
+        }
+        vec![(target.name.clone(), target.schema.clone(), all)]
+    } else if args.write_after.is_some() {
+        let mut rest: Vec<(String, Schema, Vec<Block>)> = schemas
+            .into_iter()
+            .filter(|(_, rows)| !rows.is_empty())
+            .map(|(schema, rows)| (part_name(&mut parts, &schema, &rows, config), schema, rows))
+            .collect();
+        rest.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        rest
+    } else {
+        if config.merge_schemas {
+            schemas = merge_tables(schemas);
+        }
+        sorted_tables(schemas)
+            .into_iter()
+            .enumerate()
+            .map(|(i, (schema, rows))| (config.table_name(i, &schema, &rows[0].1), schema, rows))
+            .collect()
+    };
+
+    if config.verbose > 0 {
+        eprintln!("num schemas {}", schemas.len());
+    }
+    if let Some(path) = &args.partition_by {
+        schemas = partitions(dir, path, schemas)?;
+    }
+    write_schemas(dir, &mut schemas, config, report, &mut exploded)?;
+
+    if let Some(path) = &args.write_pins {
+        let pins = Pins {
+            schemas: schemas
+                .iter()
+                .map(|(name, schema, _)| Pin {
+                    name: name.clone(),
+                    schema: schema.clone(),
+                })
+                .collect(),
+        };
+        pins.save(path)?;
+    }
+
+    write_side_tables(dir, config, report, exploded, &mut aliases, raw)
+}
+
+/// Splits the rows of each table by the value of the field at `path`, into
+/// tables named by the directory of their partition, which is created, and
+/// whose schema lacks the field.
+fn partitions(
+    dir: &Path,
+    path: &str,
+    schemas: Vec<(String, Schema, Vec<Block>)>,
+) -> Result<Vec<(String, Schema, Vec<Block>)>> {
+    let fields: Vec<&str> = path.split('.').skip(1).collect();
+    let key = fields.last().expect("parsed partition path");
+    let mut tables = Vec::new();
+    for (name, schema, rows) in schemas {
+        let mut parts: BTreeMap<String, Vec<Block>> = BTreeMap::new();
+        for (cid, data, bytes) in rows {
+            let value = fields
+                .iter()
+                .try_fold(&data, |v, k| match v {
+                    Ipld::Map(m) => m.get(*k),
+                    _ => None,
+                })
+                .unwrap_or(&Ipld::Null);
+            let value = partition_value(value).context(format!("partitioning block {}", cid))?;
+            parts.entry(value).or_default().push((cid, data, bytes));
+        }
+        let schema = without_fields(&schema, "", &[path]);
+        for (value, rows) in parts {
+            let part = format!("{}={}", key, value);
+            std::fs::create_dir_all(dir.join(&part))
+                .context(format!("creating {}", dir.join(&part).display()))?;
+            tables.push((format!("{}/{}", part, name), schema.clone(), rows));
+        }
+    }
+    Ok(tables)
+}
+
+/// The directory name of a partition of the value, escaped as Hive does.
+fn partition_value(value: &Ipld) -> Result<String> {
+    let value = match value {
+        Ipld::Null => return Ok("__HIVE_DEFAULT_PARTITION__".to_string()),
+        Ipld::String(s) if s.is_empty() => return Ok("__HIVE_DEFAULT_PARTITION__".to_string()),
+        Ipld::String(s) => s.clone(),
+        Ipld::Bool(b) => b.to_string(),
+        Ipld::Integer(i) => i.to_string(),
+        Ipld::Float(f) => f.to_string(),
+        Ipld::Bytes(b) => diag::hex(b),
+        Ipld::Link(cid) => cid.to_string(),
+        Ipld::List(_) | Ipld::Map(_) => {
+            return Err(anyhow!("only a scalar field can partition the blocks"))
+        }
+    };
+    Ok(value
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || "-_.".contains(c) {
+                c.to_string()
+            } else {
+                let mut buf = [0; 4];
+                c.encode_utf8(&mut buf)
+                    .bytes()
+                    .map(|b| format!("%{:02X}", b))
+                    .collect()
+            }
+        })
+        .collect())
+}
+
+/// Roots of the CAR headers of the inputs, in order and without repeats.
+async fn car_roots(inputs: &[Unit]) -> Result<Vec<Cid>> {
+    let mut roots = Vec::new();
+    for unit in inputs {
+        // Concatenated CARs have headers all through the file.
+        let mut car = carv2::open_blocks(&unit.path)?;
+        while car
+            .next_section()
+            .context(format!("reading {}", unit.path.display()))?
+            .is_some()
+        {}
+        for root in car.roots() {
+            if !roots.contains(root) {
+                roots.push(*root);
+            }
+        }
+    }
+    Ok(roots)
+}
+
+/// The roots each block is reachable from, following the links between the
+/// blocks. Blocks reachable from no root are left out.
+fn reachable_roots(roots: &[Cid], blocks: &[Block]) -> HashMap<Cid, Vec<Cid>> {
+    let dags: HashMap<Cid, &Ipld> = blocks.iter().map(|(cid, dag, _)| (*cid, dag)).collect();
+    let mut reached: HashMap<Cid, Vec<Cid>> = HashMap::new();
+    for root in roots {
+        let mut stack = vec![*root];
+        while let Some(cid) = stack.pop() {
+            let Some(dag) = dags.get(&cid) else {
+                continue;
+            };
+            let found = reached.entry(cid).or_default();
+            if found.last() == Some(root) {
+                continue;
+            }
+            found.push(*root);
+            dag.references(&mut stack);
+        }
+    }
+    reached
+}
+
+/// Rows buffered per schema before they are written as a row group by
+/// --schema-cache and --stream, the first and with --default-writer-props all.
+const ROW_GROUP_ROWS: usize = 10_000;
+
+/// Converts the inputs into the schemas of the cache, inferring and saving them
+/// first if the cache does not exist, then streaming the blocks into their files.
+async fn write_cached(
+    args: &ConvertArgs,
+    config: &Config,
+    inputs: &[Unit],
+    dir: &Path,
+    report: &mut Report,
+    links: &Links,
+    path: &Path,
+) -> Result<()> {
+    let pins = if path.exists() {
+        Pins::load(path)?
+    } else {
+        let pins = infer_pins(args, config, inputs, links).await?;
+        pins.save(path)?;
+        pins
+    };
+    let schemas = StreamSchemas::Cache(pins, path);
+    write_streams(args, config, inputs, dir, report, links, schemas).await
+}
+
+/// The schemas of the blocks of the inputs, ranked by their number of blocks.
+async fn infer_pins(
+    args: &ConvertArgs,
+    config: &Config,
+    inputs: &[Unit],
+    links: &Links,
+) -> Result<Pins> {
+    // The first pass only infers, its report would count every block twice.
+    let mut counts: HashMap<Schema, (usize, Ipld)> = HashMap::new();
+    let cache = args
+        .inference_cache
+        .as_deref()
+        .map(|path| inference::Cache::load(path, args))
+        .transpose()?;
+    read_blocks(
+        args,
+        config,
+        inputs,
+        &mut args.report(),
+        &mut Vec::new(),
+        None,
+        cache.as_ref(),
+        |_, cid, mut dag, _| {
+            if prepare_block(args, config, links, &cid, &mut dag)? {
+                let mut shapes = config.shapes.lock().expect("shapes lock");
+                let schema = shapes.schema(&dag);
+                if let Some(cache) = &cache {
+                    cache.record(cid, schema);
+                }
+                match counts.get_mut(schema) {
+                    Some((n, _)) => *n += 1,
+                    None => {
+                        counts.insert(schema.clone(), (1, dag));
+                    }
+                }
+            }
+            Ok(())
+        },
+    )
+    .await?;
+    if let Some(cache) = cache {
+        let counted = cache.counted();
+        if config.verbose > 0 {
+            let n: usize = counted.iter().map(|(_, n)| n).sum();
+            eprintln!("{} blocks inferred from the inference cache", n);
+        }
+        // A block is only counted once one of its schema was decoded.
+        for (schema, n) in counted {
+            if let Some((count, _)) = counts.get_mut(&schema) {
+                *count += n;
+            }
+        }
+        cache.save()?;
+    }
+    let mut counts: Vec<(Schema, (usize, Ipld))> = counts.into_iter().collect();
+    counts.sort_unstable_by_key(|(_, (n, _))| std::cmp::Reverse(*n));
+    Ok(Pins {
+        schemas: counts
+            .into_iter()
+            .enumerate()
+            .map(|(i, (schema, (_, first)))| Pin {
+                name: config.table_name(i, &schema, &first),
+                schema,
+            })
+            .collect(),
+    })
+}
+
+/// The schemas blocks are streamed into.
+enum StreamSchemas<'a> {
+    /// Each added as it is first seen.
+    Seen,
+    /// Those of the schema cache, which every block must fit.
+    Cache(Pins, &'a Path),
+    /// Those inferred from a sample, and those of the blocks fitting none
+    /// added as they are seen.
+    Sampled(Pins),
+}
+
+/// Streams the blocks into the files of their schemas.
+async fn write_streams(
+    args: &ConvertArgs,
+    config: &Config,
+    inputs: &[Unit],
+    dir: &Path,
+    report: &mut Report,
+    links: &Links,
+    schemas: StreamSchemas<'_>,
+) -> Result<()> {
+    let (pins, cache, sampled) = match schemas {
+        StreamSchemas::Seen => (Pins::default(), None, false),
+        StreamSchemas::Cache(pins, path) => (pins, Some(path), false),
+        StreamSchemas::Sampled(pins) => (pins, None, true),
+    };
+    let mut streams = Streams {
+        dir,
+        config,
+        files: pins.schemas.iter().map(|_| None).collect(),
+        buffers: pins.schemas.iter().map(|_| Vec::new()).collect(),
+        reservoirs: pins
+            .schemas
+            .iter()
+            .map(|_| Reservoir::new(config.samples))
+            .collect(),
+        exploded: HashMap::new(),
+        pins,
+    };
+    let mut aliases: Vec<Block> = Vec::new();
+    let mut raw = RawBlocks::new(dir, config);
+    let result = read_blocks(
+        args,
+        config,
+        inputs,
+        report,
+        &mut aliases,
+        Some(&mut raw),
+        None,
+        |report, cid, mut dag, bytes| {
+            if !prepare_block(args, config, links, &cid, &mut dag)? {
+                return Ok(());
+            }
+            report.check_lists(&cid, &dag);
+            let mut shapes = config.shapes.lock().expect("shapes lock");
+            let schema = shapes.schema(&dag);
+            let i = match cache {
+                Some(path) => streams.pins.find(schema).context(format!(
+                    "block {} does not fit the schema cache {}, remove it to infer the schemas again",
+                    cid,
+                    path.display()
+                ))?,
+                None if sampled => match streams.pins.find(schema) {
+                    Ok(i) => i,
+                    Err(_) => {
+                        let n = streams.pins.schemas.len();
+                        let i = streams.add(schema, &dag);
+                        report.unsampled_schemas += streams.pins.schemas.len() - n;
+                        i
+                    }
+                },
+                None => streams.add(schema, &dag),
+            };
+            drop(shapes);
+            streams.push(i, (cid, dag, bytes), report)
+        },
+    )
+    .await;
+    let (pins, exploded) = match result {
+        Ok(()) => streams.finish(report)?,
+        Err(e) => {
+            streams.remove();
+            return Err(e);
+        }
+    };
+    if let Some(path) = &args.write_pins {
+        pins.save(path)?;
+    }
+    write_side_tables(dir, config, report, exploded, &mut aliases, raw)
+}
+
+/// Output files of the streamed schemas, buffering up to a row group of each.
+struct Streams<'a> {
+    pins: Pins,
+    dir: &'a Path,
+    config: &'a Config,
+    files: Vec<Option<(PathBuf, OutputFile)>>,
+    buffers: Vec<Vec<Block>>,
+    reservoirs: Vec<Reservoir>,
+    exploded: ExplodedTables,
+}
+
+impl Streams<'_> {
+    /// Index of the schema of the block data, adding it with the next file
+    /// name if it is new.
+    fn add(&mut self, schema: &Schema, data: &Ipld) -> usize {
+        if let Some(i) = self.pins.schemas.iter().position(|p| p.schema == *schema) {
+            return i;
+        }
+        let name = self
+            .config
+            .table_name(self.pins.schemas.len(), schema, data);
+        self.pins.schemas.push(Pin {
+            name,
+            schema: schema.clone(),
+        });
+        self.files.push(None);
+        self.buffers.push(Vec::new());
+        self.reservoirs.push(Reservoir::new(self.config.samples));
+        self.pins.schemas.len() - 1
+    }
+
+    fn push(&mut self, i: usize, block: Block, report: &mut Report) -> Result<()> {
+        let mut rows = vec![block];
+        coerce_rows(&mut rows, &self.pins.schemas[i].schema);
+        self.reservoirs[i].push(&rows[0].0, &rows[0].1);
+        self.buffers[i].append(&mut rows);
+        let row_group_rows = match &self.files[i] {
+            Some((_, file)) => file.row_group_rows.unwrap_or(ROW_GROUP_ROWS),
+            // The rows of a size in bytes are known once the first are seen.
+            None => match self.config.row_group_size {
+                Some(RowGroupSize::Rows(rows)) => rows,
+                _ => ROW_GROUP_ROWS,
+            },
+        };
+        if self.buffers[i].len() >= row_group_rows {
+            self.flush(i, report)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self, i: usize, report: &mut Report) -> Result<()> {
+        let pin = &self.pins.schemas[i];
+        let mut rows = std::mem::take(&mut self.buffers[i]);
+        sort_rows(&mut rows, self.config.order);
+        for list in exploded_lists(&pin.schema, "", self.config) {
+            let tables = self.exploded.entry(list.clone()).or_default();
+            for (cid, data, _) in &rows {
+                for row in explode_list(data, &list)? {
+                    tables
+                        .entry(exploded_schema(&row))
+                        .or_default()
+                        .push((*cid, row, Vec::new()));
+                }
+            }
+        }
+        let (_, file) = match &mut self.files[i] {
+            Some(file) => file,
+            None => {
+                let name = self.config.file_name(&pin.name);
+                report.check_width(&name, &pin.schema, rows.len(), self.config);
+                let path = self.dir.join(name);
+                let file = OutputFile::create(
+                    &path,
+                    &pin.schema,
+                    &rows,
+                    &[],
+                    &self.config.columns,
+                    self.config,
+                )?;
+                self.files[i].insert((path, file))
+            }
+        };
+        let cid_index = self.config.cid_index.then_some(&mut report.cid_index);
+        file.write(&pin.schema, &rows, self.config, cid_index)
+    }
+
+    /// Writes the remaining rows and closes the files, returning the schemas
+    /// and the rows of the exploded lists.
+    fn finish(mut self, report: &mut Report) -> Result<(Pins, ExplodedTables)> {
+        for i in 0..self.buffers.len() {
+            if !self.buffers[i].is_empty() {
+                if let Err(e) = self.flush(i, report) {
+                    self.remove();
+                    return Err(e);
+                }
+            }
+        }
+        let files = self.files.into_iter().zip(self.reservoirs).enumerate();
+        for (i, path, file, reservoir) in
+            files.filter_map(|(i, (f, r))| f.map(|(p, f)| (i, p, f, r)))
+        {
+            let failures = file.close()?;
+            report.files_written += 1;
+            self.config.limits.wrote(&path);
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            report.add_failures(&name, failures);
+            if self.config.format == Format::Parquet {
+                report.check_sizes(&name, &path, &self.config.columns)?;
+            }
+            if self.config.manifest {
+                let schema = &self.pins.schemas[i].schema;
+                let entry = reservoir.entry(&name, schema, &[], &path, self.config)?;
+                report.manifest.files.push(entry);
+            }
+        }
+        Ok((self.pins, self.exploded))
+    }
+
+    /// Removes the partially written files.
+    fn remove(self) {
+        for (path, _) in self.files.into_iter().flatten() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Writes the tables of exploded lists and the payload aliases, and finishes
+/// the table of the raw blocks.
+fn write_side_tables(
+    dir: &Path,
+    config: &Config,
+    report: &mut Report,
+    exploded: ExplodedTables,
+    aliases: &mut [Block],
+    raw: RawBlocks,
+) -> Result<()> {
+    let mut exploded: Vec<_> = exploded.into_iter().collect();
+    exploded.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+    for (path, mut tables) in exploded {
+        if config.merge_schemas {
+            tables = merge_tables(tables);
+        }
+        let mut tables = sorted_tables(tables);
+        let n = tables.len();
+        for (i, (schema, rows)) in tables.iter_mut().enumerate() {
+            sort_rows(rows, config.order);
+            let name = if n == 1 {
+                config.file_name(&path)
+            } else {
+                config.file_name(&format!("{}_{}", path, i))
+            };
+            let failures = write_output(
+                &dir.join(&name),
+                schema,
+                rows,
+                &[],
+                &Columns::default(),
+                config,
+                None,
+            )
+            .context(format!("writing exploded list {}", path))?;
+            report.add_failures(&name, failures);
+            report.files_written += 1;
+        }
+    }
+
+    if !aliases.is_empty() {
+        report.payload_aliases = aliases.len();
+        sort_rows(aliases, config.order);
+        let schema = Schema::Map(vec![
+            ("cid".to_string(), Schema::Bytes),
+            ("canonical_cid".to_string(), Schema::Link),
+        ]);
+        write_output(
+            &dir.join(config.file_name("payload_aliases")),
+            &schema,
+            aliases,
+            &[],
+            &Columns::default(),
+            config,
+            None,
+        )
+        .context("writing payload aliases")?;
+        report.files_written += 1;
+    }
+
+    raw.finish(report)
+}
+
+/// Multicodec of raw blocks, whose bytes are the data.
+const RAW: u64 = 0x55;
+
+/// Rows of raw blocks buffered before they are written as a row group, unless
+/// their bytes reach RAW_ROW_GROUP_BYTES first.
+const RAW_ROW_GROUP_ROWS: usize = 10_000;
+const RAW_ROW_GROUP_BYTES: usize = 128 * 1024 * 1024;
+
+/// The table of the raw blocks, `raw_blocks`, with their bytes as the data.
+/// Raw blocks are often most of a CAR, so they are written a row group at a
+/// time as they are read rather than buffered.
+struct RawBlocks<'a> {
+    path: PathBuf,
+    config: &'a Config,
+    file: Option<OutputFile>,
+    buffer: Vec<Block>,
+    bytes: usize,
+}
+
+impl<'a> RawBlocks<'a> {
+    fn new(dir: &Path, config: &'a Config) -> Self {
+        Self {
+            path: dir.join(config.file_name("raw_blocks")),
+            config,
+            file: None,
+            buffer: Vec::new(),
+            bytes: 0,
+        }
+    }
+
+    fn schema() -> Schema {
+        Schema::Map(vec![
+            ("cid".to_string(), Schema::Link),
+            ("data".to_string(), Schema::Bytes),
+        ])
+    }
+
+    fn push(&mut self, report: &mut Report, block: Block) -> Result<()> {
+        if let Ipld::Bytes(b) = &block.1 {
+            self.bytes += b.len();
+        }
+        self.buffer.push(block);
+        // A size given by --row-group-size replaces both limits.
+        let full = match (&self.file, self.config.row_group_size) {
+            (Some(file), Some(_)) => self.buffer.len() >= file.row_group_rows.unwrap_or(1),
+            _ => self.buffer.len() >= RAW_ROW_GROUP_ROWS || self.bytes >= RAW_ROW_GROUP_BYTES,
+        };
+        if full {
+            self.flush(report)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self, report: &mut Report) -> Result<()> {
+        let mut rows = std::mem::take(&mut self.buffer);
+        self.bytes = 0;
+        sort_rows(&mut rows, self.config.order);
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => self.file.insert(OutputFile::create(
+                &self.path,
+                &Self::schema(),
+                &rows,
+                &[],
+                &Columns::default(),
+                self.config,
+            )?),
+        };
+        report.raw_blocks += rows.len();
+        let cid_index = self.config.cid_index.then_some(&mut report.cid_index);
+        file.write(&Self::schema(), &rows, self.config, cid_index)
+    }
+
+    /// Writes the remaining rows and closes the file, if any block was raw.
+    fn finish(mut self, report: &mut Report) -> Result<()> {
+        if !self.buffer.is_empty() {
+            self.flush(report)?;
+        }
+        let Some(file) = self.file.take() else {
+            return Ok(());
+        };
+        let failures = file.close()?;
+        let name = self.path.file_name().unwrap_or_default().to_string_lossy();
+        report.add_failures(&name, failures);
+        report.files_written += 1;
+        Ok(())
+    }
+}
+
+impl Drop for RawBlocks<'_> {
+    /// Removes the file if the conversion failed before it was finished.
+    fn drop(&mut self) {
+        if self.file.take().is_some() {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Writes the tables, `config.jobs` at a time. Each is written with a report
+/// and exploded lists of its own, added back in the order of the tables so
+/// the output is the same whichever finishes first.
+fn write_schemas(
+    dir: &Path,
+    schemas: &mut [(String, Schema, Vec<Block>)],
+    config: &Config,
+    report: &mut Report,
+    exploded: &mut ExplodedTables,
+) -> Result<()> {
+    let n = schemas.len();
+    if config.jobs <= 1 || n <= 1 {
+        for (name, schema, cids) in schemas {
+            write_schema(dir, name, schema, cids, config, report, exploded)?;
+        }
+        return Ok(());
+    }
+    let tables = std::sync::Mutex::new(schemas.iter_mut().enumerate());
+    let parts = std::sync::Mutex::new((0..n).map(|_| None).collect::<Vec<_>>());
+    let failed = std::sync::atomic::AtomicBool::new(false);
+    let base: &Report = report;
+    std::thread::scope(|scope| {
+        for _ in 0..config.jobs.min(n) {
+            scope.spawn(|| {
+                while !failed.load(std::sync::atomic::Ordering::Relaxed) {
+                    let Some((i, (name, schema, cids))) =
+                        tables.lock().expect("tables lock").next()
+                    else {
+                        break;
+                    };
+                    let mut part = base.part();
+                    let mut lists = ExplodedTables::new();
+                    let result =
+                        write_schema(dir, name, schema, cids, config, &mut part, &mut lists);
+                    if result.is_err() {
+                        failed.store(true, std::sync::atomic::Ordering::Relaxed);
+                    }
+                    parts.lock().expect("parts lock")[i] = Some((part, lists, result));
+                }
+            });
+        }
+    });
+
+    // Tables left unwritten after a failure have no part.
+    let mut result = Ok(());
+    let parts = parts.into_inner().expect("parts lock");
+    for (part, lists, written) in parts.into_iter().flatten() {
+        report.append(part);
+        for (path, tables) in lists {
+            let merged = exploded.entry(path).or_default();
+            for (schema, mut rows) in tables {
+                merged.entry(schema).or_default().append(&mut rows);
+            }
+        }
+        if result.is_ok() {
+            result = written;
+        }
+    }
+    result
+}
+
+/// Writes the rows of one schema to the file `name` in `dir`, queueing the rows
+/// of its exploded lists for their own tables.
+fn write_schema(
+    dir: &Path,
+    name: &str,
+    schema: &Schema,
+    cids: &mut [Block],
+    config: &Config,
+    report: &mut Report,
+    exploded: &mut ExplodedTables,
+) -> Result<()> {
+    if config.limits.output_full() {
+        report.rows_left_out += cids.len();
+        return Ok(());
+    }
+    sort_rows(cids, config.order);
+    let cids: &[Block] = cids;
+    for path in exploded_lists(schema, "", config) {
+        let tables = exploded.entry(path.clone()).or_default();
+        for (cid, data, _) in cids {
+            for row in explode_list(data, &path)? {
+                tables
+                    .entry(exploded_schema(&row))
+                    .or_default()
+                    .push((*cid, row, Vec::new()));
+            }
+        }
+    }
+    let file = config.file_name(name);
+    if config.verbose > 1 {
+        eprintln!("schema: {:#?}\nexample: {:?}", &schema, cids.first());
+    }
+    if config.verbose > 0 {
+        eprintln!("writing {}: {} rows", file, cids.len());
+    }
+    let constants = if config.prune_constants {
+        constant_fields(schema, cids, config)
+    } else {
+        Vec::new()
+    };
+    let constant_paths: Vec<&str> = constants.iter().map(|(p, _)| p.as_str()).collect();
+    let pruned = without_fields(schema, "", &constant_paths);
+    if !constants.is_empty() {
+        report.constant_columns.push((
+            file.clone(),
+            constants.iter().map(|(p, _)| p.clone()).collect(),
+        ));
+    }
+    report.check_width(&file, &pruned, cids.len(), config);
+    let out = dir.join(&file);
+    let failures = write_output(
+        &out,
+        &pruned,
+        cids,
+        &constants,
+        &config.columns,
+        config,
+        config.cid_index.then_some(&mut report.cid_index),
+    )?;
+    report.add_failures(&file, failures);
+    report.files_written += 1;
+    config.limits.wrote(&out);
+    if config.manifest {
+        let mut reservoir = Reservoir::new(config.samples);
+        for (cid, data, _) in cids {
+            reservoir.push(cid, data);
+        }
+        let entry = reservoir.entry(&file, &pruned, &constants, &out, config)?;
+        report.manifest.files.push(entry);
+    }
+    if config.format == Format::Parquet {
+        report.check_sizes(&file, &out, &config.columns)?;
+    }
+    Ok(())
+}
+
+/// The tables in the order they are numbered, most rows first and those with
+/// as many rows by the CID of their first row, so the names are reproducible.
+fn sorted_tables(tables: HashMap<Schema, Vec<Block>>) -> Vec<(Schema, Vec<Block>)> {
+    let mut tables: Vec<_> = tables.into_iter().collect();
+    tables.sort_by_cached_key(|(_, rows)| {
+        (
+            std::cmp::Reverse(rows.len()),
+            rows.first().map(|(cid, _, _)| cid.to_bytes()),
+        )
+    });
+    tables
+}
+
+/// Merges each table into the first larger one its schema merges with.
+fn merge_tables(tables: HashMap<Schema, Vec<Block>>) -> HashMap<Schema, Vec<Block>> {
+    let mut merged: Vec<(Schema, Vec<Block>)> = Vec::new();
+    for (schema, mut rows) in sorted_tables(tables) {
+        let into = merged
+            .iter_mut()
+            .find_map(|(m, r)| merged_schema(m, &schema).map(|s| (m, r, s)));
+        match into {
+            Some((m, r, s)) => {
+                *m = s;
+                r.append(&mut rows);
+            }
+            None => merged.push((schema, rows)),
+        }
+    }
+    let mut tables: HashMap<Schema, Vec<Block>> = HashMap::new();
+    for (schema, mut rows) in merged {
+        tables.entry(schema).or_default().append(&mut rows);
+    }
+    tables
+}
+
+/// The schema of the values of both schemas if their maps differ only by
+/// fields one of them lacks, which become optional. The fields of each map
+/// must include those of the other's, so unrelated maps stay apart, and an
+/// empty list merges with any list.
+fn merged_schema(a: &Schema, b: &Schema) -> Option<Schema> {
+    match (a, b) {
+        _ if a == b => Some(a.clone()),
+        (Schema::Optional(a), b) | (b, Schema::Optional(a)) => merged_schema(a, b).map(optional),
+        (Schema::List(l), Schema::List(e)) | (Schema::List(e), Schema::List(l))
+            if **l == Schema::Null =>
+        {
+            Some(Schema::List(e.clone()))
+        }
+        (Schema::List(a), Schema::List(b)) => {
+            merged_schema(a, b).map(|s| Schema::List(Box::new(s)))
+        }
+        (Schema::Map(a), Schema::Map(b)) => {
+            let has = |m: &[(String, Schema)], k: &str| m.iter().any(|(mk, _)| mk == k);
+            if !a.iter().all(|(k, _)| has(b, k)) && !b.iter().all(|(k, _)| has(a, k)) {
+                return None;
+            }
+            let mut keys: Vec<&String> = a.iter().chain(b).map(|(k, _)| k).collect();
+            keys.sort();
+            keys.dedup();
+            let mut fields = Vec::new();
+            for k in keys {
+                let v = match (
+                    a.iter().find(|(ak, _)| ak == k),
+                    b.iter().find(|(bk, _)| bk == k),
+                ) {
+                    (Some((_, a)), Some((_, b))) => merged_schema(a, b)?,
+                    (Some((_, v)), None) | (None, Some((_, v))) => optional(v.clone()),
+                    (None, None) => continue,
+                };
+                fields.push((k.clone(), v));
+            }
+            Some(Schema::Map(fields))
+        }
+        _ => None,
+    }
+}
+
+/// Sorts the rows into the order of the output, keeping the input order of
+/// rows with the same CID such as those of an exploded list.
+fn sort_rows(rows: &mut [Block], order: Order) {
+    if order == Order::Cid {
+        rows.sort_by_cached_key(|(cid, _, _)| cid.to_bytes());
+    }
+}
+
+/// Name of the next part file of the schema of the rows, numbering schemas in
+/// the order their first part is written.
+fn part_name(
+    parts: &mut HashMap<Schema, (usize, usize)>,
+    schema: &Schema,
+    rows: &[Block],
+    config: &Config,
+) -> String {
+    let n = parts.len();
+    let (i, part) = parts.entry(schema.clone()).or_insert((n, 0));
+    *part += 1;
+    let first = rows.first().map_or(&Ipld::Null, |(_, data, _)| data);
+    let stem = config.table_name(*i, schema, first);
+    format!("{}.part{}", stem, *part - 1)
+}
+
+/// Applies the link options and the query to the data of a decoded block,
+/// returning false if the block is filtered out.
+fn prepare_block(
+    args: &ConvertArgs,
+    config: &Config,
+    links: &Links,
+    cid: &Cid,
+    dag: &mut Ipld,
+) -> Result<bool> {
+    if let (Some(shards), Some(resolver)) = (&links.shards, &links.resolver) {
+        if shards.is_interior(cid) {
+            return Ok(false);
+        }
+        shards.assemble(args, resolver, dag)?;
+    }
+    if args.link_codecs {
+        add_link_codecs(dag);
+    }
+    if !links.forms.is_empty() {
+        apply_link_forms(args, dag, "data", links)?;
+    }
+    if args.explode_cids {
+        explode_cids(dag);
+    }
+    let kept = match &config.query {
+        Some(query) => query.matches(cid, dag) && query.project(dag),
+        None => true,
+    };
+    if kept && (config.registry.is_some() || config.schema_fingerprint) {
+        let mut shapes = config.shapes.lock().expect("shapes lock");
+        let schema = shapes.schema(dag);
+        let id = config
+            .registry
+            .as_ref()
+            .map(|registry| registry.lock().expect("registry lock").id(schema));
+        let fingerprint = config
+            .schema_fingerprint
+            .then(|| registry::fingerprint(schema));
+        drop(shapes);
+        if let Ipld::Map(m) = dag {
+            if let Some(id) = id {
+                m.insert("_schema_id".to_string(), Ipld::Integer(id as i128));
+            }
+            if let Some(fingerprint) = fingerprint {
+                m.insert("_schema_fingerprint".to_string(), Ipld::String(fingerprint));
+            }
+        }
+    }
+    Ok(kept)
+}
+
+/// The rows of the schema, adding a copy of it the first time it is seen so
+/// blocks of known schemas are grouped without allocating.
+fn rows_of<'a, V: Default>(tables: &'a mut HashMap<Schema, V>, schema: &Schema) -> &'a mut V {
+    if !tables.contains_key(schema) {
+        tables.insert(schema.clone(), V::default());
+    }
+    tables.get_mut(schema).expect("inserted")
+}
+
+/// Schema of the row written for a block, with its bytes if `rawdata` and
+/// their length if `block_size`.
+fn wrapper_schema(dag: &Ipld, rawdata: bool, block_size: bool) -> Schema {
+    let mut data = schema(dag);
+    // Blocks reachable from no root would otherwise get schemas of their own.
+    if let Schema::Map(m) = &mut data {
+        for (k, v) in m {
+            if k == "_root_cids" && *v == Schema::List(Box::new(Schema::Null)) {
+                *v = Schema::List(Box::new(Schema::Link));
+            }
+        }
+    }
+    let mut fields = vec![
+        ("cid".to_string(), Schema::Bytes),
+        ("data".to_string(), data),
+    ];
+    if rawdata {
+        fields.push(("rawdata".to_string(), Schema::Bytes));
+    }
+    if block_size {
+        fields.push(("block_size".to_string(), Schema::Integer));
+    }
+    Schema::Map(fields)
+}
+
+/// Converts the block data of the rows to the representation of a wrapper schema they fit.
+fn coerce_rows(rows: &mut [Block], schema: &Schema) {
+    let data_schema = match schema {
+        Schema::Map(m) => m.iter().find(|(k, _)| k == "data").map(|(_, s)| s),
+        _ => None,
+    };
+    if let Some(data_schema) = data_schema {
+        for (_, data, _) in rows {
+            pins::coerce(data, data_schema);
+        }
+    }
+}
+
+/// Paths of the fields outside lists that have the same value in every row,
+/// with that value. A map keeps at least one field besides exploded lists so
+/// it is not left empty.
+fn constant_fields(schema: &Schema, rows: &[Block], config: &Config) -> Vec<(String, Ipld)> {
+    let mut constants = Vec::new();
+    if rows.len() > 1 {
+        if let Schema::Map(m) = schema {
+            for (k, v) in m {
+                if k == "data" {
+                    constant_leaves(v, k, rows, config, &mut constants);
+                }
+            }
+        }
+    }
+    constants
+}
+
+fn constant_leaves(
+    schema: &Schema,
+    path: &str,
+    rows: &[Block],
+    config: &Config,
+    constants: &mut Vec<(String, Ipld)>,
+) {
+    let Schema::Map(m) = schema else {
+        return;
+    };
+    let before = constants.len();
+    let mut kept = 0;
+    for (k, v) in m {
+        let path = field_path(path, k);
+        match v {
+            Schema::Map(_) => {
+                constant_leaves(v, &path, rows, config, constants);
+                kept += 1;
+            }
+            Schema::List(_) if is_exploded_list(v, &path, config) => {}
+            Schema::List(_) => kept += 1,
+            _ => {
+                let parts: Vec<&str> = path.split('.').skip(1).collect();
+                let value =
+                    |data: &Ipld| parts.iter().try_fold(data, |d, p| d.get(*p).ok()).cloned();
+                let first = value(&rows[0].1);
+                // Infinite floats cannot be written to the DAG-JSON metadata.
+                let constant = match &first {
+                    Some(Ipld::Float(f)) if !f.is_finite() => false,
+                    Some(first) => rows[1..]
+                        .iter()
+                        .all(|(_, data, _)| value(data).as_ref() == Some(first)),
+                    None => false,
+                };
+                match first {
+                    Some(first) if constant => constants.push((path, first)),
+                    _ => kept += 1,
+                }
+            }
+        }
+    }
+    if kept == 0 && constants.len() > before {
+        constants.pop();
+    }
+}
+
+/// The schema with the fields at the given paths removed, along with the maps
+/// left without fields.
+fn without_fields(schema: &Schema, path: &str, fields: &[&str]) -> Schema {
+    match schema {
+        Schema::Map(m) if !fields.is_empty() => Schema::Map(
+            m.iter()
+                .filter_map(|(k, v)| {
+                    let path = field_path(path, k);
+                    if fields.contains(&path.as_str()) {
+                        return None;
+                    }
+                    let v = without_fields(v, &path, fields);
+                    (!matches!(&v, Schema::Map(m) if m.is_empty())).then(|| (k.clone(), v))
+                })
+                .collect(),
+        ),
+        Schema::List(l) => Schema::List(Box::new(without_fields(l, path, fields))),
+        Schema::Optional(s) => Schema::Optional(Box::new(without_fields(s, path, fields))),
+        _ => schema.clone(),
+    }
+}
+
+/// Fields of the schema parquet columns cannot represent, with what they are.
+/// Lists are only exploded from the data of a block, not from within a list.
+fn capability_gaps(schema: &Schema, path: &str, config: &Config) -> Vec<(String, &'static str)> {
+    match schema {
+        Schema::List(l) => exploded_within(l, path, config),
+        Schema::Optional(s) => capability_gaps(s, path, config),
+        Schema::Map(m) => m
+            .iter()
+            .filter(|(k, v)| !is_exploded_list(v, &field_path(path, k), config))
+            .flat_map(|(k, v)| capability_gaps(v, &field_path(path, k), config))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Lists to explode within the elements of a list.
+fn exploded_within(schema: &Schema, path: &str, config: &Config) -> Vec<(String, &'static str)> {
+    match schema {
+        Schema::List(l) | Schema::Optional(l) => exploded_within(l, path, config),
+        Schema::Map(m) => m
+            .iter()
+            .flat_map(|(k, v)| {
+                let path = field_path(path, k);
+                if is_exploded_list(v, &path, config) {
+                    vec![(path, "exploded list within a list")]
+                } else {
+                    exploded_within(v, &path, config)
+                }
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+/// Nulls the floats JSON cannot represent, recording the path of each and why.
+fn null_non_finite(data: &mut Ipld, path: &str, errors: &mut Vec<(String, String)>) {
+    match data {
+        Ipld::Float(f) if !f.is_finite() => {
+            errors.push((
+                path.to_string(),
+                format!("float {} cannot be represented in JSON", f),
+            ));
+            *data = Ipld::Null;
+        }
+        Ipld::List(l) => {
+            for v in l {
+                null_non_finite(v, path, errors);
+            }
+        }
+        Ipld::Map(m) => {
+            for (k, v) in m {
+                null_non_finite(v, &field_path(path, k), errors);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn remove_path(data: &mut Ipld, parts: &[&str]) {
+    if let (Some((p, rest)), Ipld::Map(m)) = (parts.split_first(), data) {
+        if rest.is_empty() {
+            m.remove(*p);
+        } else if let Some(v) = m.get_mut(*p) {
+            remove_path(v, rest);
+        }
+    }
+}
+
+/// Adds `{field}_codec` and `{field}_multihash` siblings to every link field, and
+/// to every non-empty list of links, holding the codes decoded from the CIDs.
+/// Existing fields of those names are kept.
+fn add_link_codecs(data: &mut Ipld) {
+    match data {
+        Ipld::List(l) => {
+            for v in l {
+                add_link_codecs(v);
+            }
+        }
+        Ipld::Map(m) => {
+            let mut siblings = Vec::new();
+            for (k, v) in m.iter_mut() {
+                match link_codes(v) {
+                    Some((codec, multihash)) => siblings.push((k.clone(), codec, multihash)),
+                    None => add_link_codecs(v),
+                }
+            }
+            for (k, codec, multihash) in siblings {
+                m.entry(format!("{}_codec", k)).or_insert(codec);
+                m.entry(format!("{}_multihash", k)).or_insert(multihash);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Rewrites the links at the field paths of the link forms, inlining targets
+/// found by the resolver.
+fn apply_link_forms(args: &ConvertArgs, data: &mut Ipld, path: &str, links: &Links) -> Result<()> {
+    match data {
+        Ipld::Link(cid) => match (links.forms.get(path), &links.resolver) {
+            (Some(LinkForm::Bytes), _) => *data = Ipld::Bytes(cid.to_bytes()),
+            (Some(LinkForm::String), _) => *data = Ipld::String(cid.to_string()),
+            (Some(LinkForm::Inline), Some(resolver)) => {
+                if let Some(target) = resolver.get(args, cid)? {
+                    *data = Ipld::clone(&target);
+                }
+            }
+            (Some(LinkForm::Inline), None) | (None, _) => {}
+        },
+        Ipld::List(l) => {
+            for v in l {
+                apply_link_forms(args, v, path, links)?;
+            }
+        }
+        Ipld::Map(m) => {
+            for (k, v) in m {
+                apply_link_forms(args, v, &field_path(path, k), links)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Replaces every link with a map of the parts of its CID.
+fn explode_cids(data: &mut Ipld) {
+    match data {
+        Ipld::Link(cid) => {
+            *data = Ipld::Map(
+                [
+                    ("version", Ipld::Integer(u64::from(cid.version()) as i128)),
+                    ("codec", Ipld::Integer(cid.codec() as i128)),
+                    ("multihash_code", Ipld::Integer(cid.hash().code() as i128)),
+                    ("digest", Ipld::Bytes(cid.hash().digest().to_vec())),
+                ]
+                .map(|(k, v)| (k.to_string(), v))
+                .into(),
+            )
+        }
+        Ipld::List(l) => l.iter_mut().for_each(explode_cids),
+        Ipld::Map(m) => m.values_mut().for_each(explode_cids),
+        _ => {}
+    }
+}
+
+fn link_codes(data: &Ipld) -> Option<(Ipld, Ipld)> {
+    let codes = |cid: &Cid| {
+        (
+            Ipld::Integer(cid.codec() as i128),
+            Ipld::Integer(cid.hash().code() as i128),
+        )
+    };
+    match data {
+        Ipld::Link(cid) => Some(codes(cid)),
+        Ipld::List(l) if !l.is_empty() => {
+            let (codecs, multihashes) = l
+                .iter()
+                .map(|v| match v {
+                    Ipld::Link(cid) => Some(codes(cid)),
+                    _ => None,
+                })
+                .collect::<Option<(Vec<Ipld>, Vec<Ipld>)>>()?;
+            Some((Ipld::List(codecs), Ipld::List(multihashes)))
+        }
+        _ => None,
+    }
+}
+
+fn is_exploded_list(schema: &Schema, path: &str, config: &Config) -> bool {
+    match schema {
+        Schema::List(l) => {
+            config.explode.contains(path)
+                || (**l == Schema::Link && config.link_list_repr(path) == LinkListRepr::Explode)
+        }
+        Schema::Optional(s) => is_exploded_list(s, path, config),
+        _ => false,
+    }
+}
+
+/// Paths of all lists in the schema that are written to a child table.
+fn exploded_lists(schema: &Schema, path: &str, config: &Config) -> Vec<String> {
+    match schema {
+        Schema::Map(m) => m
+            .iter()
+            .flat_map(|(k, v)| {
+                let path = field_path(path, k);
+                if is_exploded_list(v, &path, config) {
+                    vec![path]
+                } else {
+                    exploded_lists(v, &path, config)
+                }
+            })
+            .collect(),
+        Schema::Optional(s) => exploded_lists(s, path, config),
+        _ => Vec::new(),
+    }
+}
+
+/// Child table rows for the list at path, one per element.
+///
+/// Map elements have their fields alongside the index, links are stored as
+/// `target_cid` and any other value as `element`.
+fn explode_list(mut data: &Ipld, path: &str) -> Result<Vec<Ipld>> {
+    // The path is rooted at the wrapper schema, i.e. `data.entries`.
+    for p in path.split('.').skip(1) {
+        data = match data {
+            // An optional list that is absent has no elements.
+            Ipld::Map(m) if !m.contains_key(p) => return Ok(Vec::new()),
+            _ => data.get(p)?,
+        };
+    }
+    match data {
+        Ipld::List(l) => Ok(l
+            .iter()
+            .enumerate()
+            .map(|(i, v)| {
+                let mut row = match v {
+                    Ipld::Map(m) if !m.contains_key("parent_cid") && !m.contains_key("index") => {
+                        m.clone()
+                    }
+                    Ipld::Link(_) => BTreeMap::from([("target_cid".to_string(), v.clone())]),
+                    _ => BTreeMap::from([("element".to_string(), v.clone())]),
+                };
+                row.insert("index".to_string(), Ipld::Integer(i as i128));
+                Ipld::Map(row)
+            })
+            .collect()),
+        _ => Err(wrong_kind(data, "list")),
+    }
+}
+
+fn exploded_schema(row: &Ipld) -> Schema {
+    let mut fields = vec![("parent_cid".to_string(), Schema::Bytes)];
+    if let Schema::Map(m) = schema(row) {
+        fields.extend(m);
+    }
+    Schema::Map(fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use libipld::ipld;
+
+    use super::*;
+
+    #[test]
+    fn int_types_check_their_range() {
+        let uint8: IntType = "uint8".parse().unwrap();
+        assert_eq!(uint8.convert(255).unwrap(), 255);
+        assert!(uint8.convert(256).is_err());
+        assert!(uint8.convert(-1).is_err());
+        let int8: IntType = "int8".parse().unwrap();
+        assert_eq!(int8.convert(-128).unwrap(), -128);
+        assert!(int8.convert(128).is_err());
+        let decimal: IntType = "decimal(20,0)".parse().unwrap();
+        assert_eq!(decimal.convert(-(1 << 64)).unwrap(), -(1 << 64));
+        assert!(decimal.convert(10i128.pow(20)).is_err());
+        let decimal: IntType = "decimal(4,2)".parse().unwrap();
+        assert_eq!(decimal.convert(99).unwrap(), 9900);
+        assert!(decimal.convert(100).is_err());
+    }
+
+    fn rows(data: &[Ipld]) -> Vec<Block> {
+        data.iter()
+            .map(|d| (Cid::default(), d.clone(), Vec::new()))
+            .collect()
+    }
+
+    fn int_types(config: &Config, data: &[Ipld]) -> Vec<(String, String)> {
+        let mut types: Vec<_> = config
+            .int_types_of(&rows(data))
+            .into_iter()
+            .map(|(path, t)| (path, t.to_string()))
+            .collect();
+        types.sort();
+        types
+    }
+
+    fn types(types: &[(&str, &str)]) -> Vec<(String, String)> {
+        types
+            .iter()
+            .map(|(p, t)| (p.to_string(), t.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn int_types_follow_the_values_of_the_file() {
+        let config = Config::default();
+        let data = [
+            ipld!({"pos": 1, "neg": -1, "list": [1, -2], "m": {"big": u64::MAX}}),
+            ipld!({"pos": 2, "neg": 3, "list": [], "m": {"big": 0}}),
+        ];
+        assert_eq!(
+            int_types(&config, &data),
+            types(&[("data.list", "int64"), ("data.neg", "int64")])
+        );
+        // Negative values mixed with values beyond int64 fit neither.
+        let data = [ipld!({"n": -1}), ipld!({"n": u64::MAX})];
+        assert_eq!(
+            int_types(&config, &data),
+            types(&[("data.n", "decimal(20,0)")])
+        );
+    }
+
+    #[test]
+    fn int_types_widen_beyond_64_bits() {
+        let data = [ipld!({"low": Ipld::Integer(-(1 << 64)), "high": Ipld::Integer(1 << 64)})];
+        // Failing leaves the values to fail when written.
+        let config = Config::default();
+        assert_eq!(int_types(&config, &data), types(&[("data.low", "int64")]));
+        let config = Config {
+            int_overflow: IntOverflow::Widen,
+            ..Config::default()
+        };
+        assert_eq!(
+            int_types(&config, &data),
+            types(&[
+                ("data.high", "decimal(20,0)"),
+                ("data.low", "decimal(20,0)")
+            ])
+        );
+    }
+
+    #[test]
+    fn int_types_given_are_kept() {
+        let config = Config {
+            int_types: HashMap::from([("data.n".to_string(), "uint8".parse().unwrap())]),
+            int_overflow: IntOverflow::Widen,
+            ..Config::default()
+        };
+        let data = [ipld!({"n": -1, "m": -(1i128 << 64)})];
+        assert_eq!(
+            int_types(&config, &data),
+            types(&[("data.m", "decimal(20,0)"), ("data.n", "uint8")])
+        );
+    }
+
+    #[test]
+    fn times_are_inferred_from_names_and_values() {
+        let data = [
+            ipld!({
+                "when": "2023-04-01T12:30:00Z",
+                "day": "2023-04-01",
+                "mixed": "2023-04-01",
+                "text": "post",
+                "createdAt": 1_680_352_200_000i64,
+                "updated_at": 1_680_352_200,
+                "seq": 1_680_352_200,
+                "birthDate": 12,
+            }),
+            ipld!({"when": "2023-04-01T14:30:00+02:00", "mixed": "2023-04-01T00:00:00Z"}),
+        ];
+        assert_eq!(int_types(&Config::default(), &data), types(&[]));
+        let config = Config {
+            infer_times: true,
+            int_types: HashMap::from([("data.day".to_string(), "timestamp_ns".parse().unwrap())]),
+            ..Config::default()
+        };
+        assert_eq!(
+            int_types(&config, &data),
+            types(&[
+                ("data.createdAt", "timestamp_ms"),
+                ("data.day", "timestamp_ns"),
+                ("data.mixed", "timestamp_us"),
+                ("data.updated_at", "timestamp_s"),
+                ("data.when", "timestamp_us"),
+            ])
+        );
+        let data = [ipld!({"day": "2023-04-01"}), ipld!({"day": "1969-12-31"})];
+        assert_eq!(int_types(&config, &data)[0].1, "timestamp_ns");
+        let config = Config {
+            infer_times: true,
+            ..Config::default()
+        };
+        assert_eq!(int_types(&config, &data), types(&[("data.day", "date")]));
+    }
+
+    #[test]
+    fn strings_are_converted_to_the_unit_of_their_type() {
+        let ms: IntType = "timestamp_ms".parse().unwrap();
+        assert_eq!(
+            ms.time("2023-04-01T12:30:00.5Z").unwrap(),
+            1_680_352_200_500
+        );
+        // Times before the epoch round down.
+        assert_eq!(ms.time("1969-12-31T23:59:59.9995Z").unwrap(), -1);
+        let s: IntType = "timestamp_s".parse().unwrap();
+        assert_eq!(s.time("2023-04-01").unwrap(), 1_680_307_200);
+        assert_eq!(IntType::Date.time("2023-04-01T23:59:59Z").unwrap(), 19_448);
+        assert_eq!(IntType::Date.time("1969-12-31T12:00:00Z").unwrap(), -1);
+        assert!(ms.time("post").is_err());
+        assert!("int64"
+            .parse::<IntType>()
+            .unwrap()
+            .time("2023-04-01")
+            .is_err());
+    }
+
+    /// A CAR whose CIDs `a` of a map and `b` of a list each have two copies
+    /// with different bytes, the data of the first copy of each being that
+    /// of its CID, and a block `c` of its own.
+    fn conflicting_car(name: &str) -> PathBuf {
+        let blocks = [
+            ipld!({"v": 1}),
+            ipld!([1]),
+            ipld!({"v": 2}),
+            ipld!([2]),
+            ipld!({"v": 3}),
+        ];
+        let bytes: Vec<Vec<u8>> = blocks
+            .iter()
+            .map(|b| DagCborCodec.encode(b).unwrap())
+            .collect();
+        let cid = |b: &[u8]| Cid::new_v1(0x71, Code::Sha2_256.digest(b));
+        let (a, b, c) = (cid(&bytes[0]), cid(&bytes[1]), cid(&bytes[4]));
+        let path = std::env::temp_dir().join(format!(
+            "carquet-duplicates-{}-{}.car",
+            std::process::id(),
+            name
+        ));
+        let mut car = Vec::new();
+        scan::write_header(&mut car, &[a]).unwrap();
+        for (cid, bytes) in [a, b, a, b, c].iter().zip(&bytes) {
+            scan::write_section(&mut car, Some(cid), bytes).unwrap();
+        }
+        std::fs::write(&path, car).unwrap();
+        path
+    }
+
+    /// Converts the CAR with the policy, returning the report and the data of
+    /// the rows written, sorted.
+    async fn convert_duplicates(policy: &str) -> Result<(Report, Vec<String>)> {
+        let car = conflicting_car(policy);
+        let dir = car.with_extension("out");
+        let _ = std::fs::remove_dir_all(&dir);
+        let report = CarToParquetConverter::new(&dir)
+            .input(&car)
+            .options(ConvertArgs::from_flags([
+                "--duplicates",
+                policy,
+                "--format",
+                "jsonl",
+            ])?)
+            .run()
+            .await;
+        let mut rows = Vec::new();
+        if report.is_ok() {
+            for entry in std::fs::read_dir(&dir)? {
+                let text = std::fs::read_to_string(entry?.path())?;
+                for line in text.lines() {
+                    let row: serde_json::Value = serde_json::from_str(line)?;
+                    rows.push(row["data"].to_string());
+                }
+            }
+            rows.sort();
+        }
+        let _ = std::fs::remove_file(&car);
+        let _ = std::fs::remove_dir_all(&dir);
+        Ok((report?, rows))
+    }
+
+    #[tokio::test]
+    async fn duplicates_error_names_the_block() {
+        let err = convert_duplicates("error").await.unwrap_err();
+        let err = format!("{:#}", err);
+        assert!(err.contains("block 2 of "), "{}", err);
+        assert!(
+            err.contains("of an earlier block but different bytes, see --duplicates"),
+            "{}",
+            err
+        );
+    }
+
+    #[tokio::test]
+    async fn duplicates_keep_first_and_last_skip_the_other_copies() {
+        let (report, rows) = convert_duplicates("keep-first").await.unwrap();
+        assert_eq!(rows, ["[1]", r#"{"v":1}"#, r#"{"v":3}"#]);
+        assert_eq!(report.duplicate_conflicts, 2);
+        let (report, rows) = convert_duplicates("keep-last").await.unwrap();
+        assert_eq!(rows, ["[2]", r#"{"v":2}"#, r#"{"v":3}"#]);
+        assert_eq!(report.duplicate_conflicts, 2);
+    }
+
+    #[tokio::test]
+    async fn duplicates_flag_counts_the_maps_flagged() {
+        let (report, rows) = convert_duplicates("flag").await.unwrap();
+        assert_eq!(
+            rows,
+            [
+                "[1]",
+                "[2]",
+                r#"{"_duplicate_conflict":true,"v":1}"#,
+                r#"{"_duplicate_conflict":true,"v":2}"#,
+                r#"{"v":3}"#,
+            ]
+        );
+        // The copies of the list are written, but not flagged or counted.
+        assert_eq!(report.duplicate_conflicts, 2);
+    }
+
+    #[tokio::test]
+    async fn max_blocks_counts_the_blocks_append_does_not_skip() {
+        let fixture = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/generic.car");
+        let dir = std::env::temp_dir().join(format!("carquet-max-blocks-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let flags = ["--append", "--max-blocks", "2", "--format", "jsonl"];
+        let mut appended = Vec::new();
+        for _ in 0..2 {
+            let report = CarToParquetConverter::new(&dir)
+                .input(&fixture)
+                .options(ConvertArgs::from_flags(flags).unwrap())
+                .run()
+                .await
+                .unwrap();
+            assert_eq!(report.limit, Some(Limit::Blocks));
+            appended.push(report.appended_skipped);
+        }
+        let rows: usize = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| p.extension().is_some_and(|e| e == "jsonl"))
+            .map(|p| std::fs::read_to_string(p).unwrap().lines().count())
+            .sum();
+        std::fs::remove_dir_all(&dir).unwrap();
+        // The second run skips the two blocks of the first and reads two more.
+        assert_eq!(appended, [0, 2]);
+        assert_eq!(rows, 4);
+    }
+}
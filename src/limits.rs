@@ -0,0 +1,86 @@
+//! Limits of a run, `--max-blocks`, `--max-output-bytes` and `--max-runtime`.
+//!
+//! A run that reaches a limit stops reading blocks and writes the blocks it
+//! read before, so the files it leaves are complete and hold a prefix of the
+//! inputs. The limits are checked between blocks: the blocks read, the time
+//! since the run started, and the bytes of the block tables written so far,
+//! which are also checked before each table is written, the tables left once
+//! the output is full being left out. A file being written when a limit is
+//! reached is finished, so the output can exceed `--max-output-bytes` by it.
+
+use std::{
+    fmt,
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Default)]
+pub(crate) struct Limits {
+    blocks: Option<usize>,
+    output_bytes: Option<u64>,
+    deadline: Option<Instant>,
+    /// Bytes of the block tables written.
+    written: AtomicU64,
+}
+
+/// A limit a run reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    Blocks,
+    OutputBytes,
+    Runtime,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Limit::Blocks => "--max-blocks",
+            Limit::OutputBytes => "--max-output-bytes",
+            Limit::Runtime => "--max-runtime",
+        })
+    }
+}
+
+impl Limits {
+    /// Limits of a run starting now.
+    pub(crate) fn new(
+        blocks: Option<usize>,
+        output_bytes: Option<u64>,
+        runtime: Option<Duration>,
+    ) -> Self {
+        Self {
+            blocks,
+            output_bytes,
+            deadline: runtime.map(|d| Instant::now() + d),
+            written: AtomicU64::new(0),
+        }
+    }
+
+    /// The limit that stops the run before another block is read, after
+    /// `read` blocks were.
+    pub(crate) fn reached(&self, read: usize) -> Option<Limit> {
+        if self.blocks.is_some_and(|n| read >= n) {
+            Some(Limit::Blocks)
+        } else if self.output_full() {
+            Some(Limit::OutputBytes)
+        } else if self.deadline.is_some_and(|d| Instant::now() >= d) {
+            Some(Limit::Runtime)
+        } else {
+            None
+        }
+    }
+
+    /// Whether the block tables written hold `--max-output-bytes`.
+    pub(crate) fn output_full(&self) -> bool {
+        self.output_bytes
+            .is_some_and(|n| self.written.load(Ordering::Relaxed) >= n)
+    }
+
+    /// Counts a block table written.
+    pub(crate) fn wrote(&self, path: &Path) {
+        if let Ok(meta) = std::fs::metadata(path) {
+            self.written.fetch_add(meta.len(), Ordering::Relaxed);
+        }
+    }
+}
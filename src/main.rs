@@ -1,372 +1,754 @@
-use std::{collections::HashMap, path::PathBuf, sync::Arc};
+use std::{collections::HashSet, path::PathBuf, sync::Arc};
 
 use anyhow::{anyhow, Context, Result};
-use iroh_car::CarReader;
+use clap::{CommandFactory, Parser, Subcommand};
 use libipld::Cid;
-use libipld::{cbor::DagCborCodec, prelude::Codec, Ipld};
-use parquet::{
-    basic::Repetition,
-    data_type::{BoolType, ByteArray, ByteArrayType, DoubleType, FloatType, Int32Type, Int64Type},
-    file::{
-        properties::WriterProperties,
-        writer::{SerializedColumnWriter, SerializedFileWriter},
-    },
-    schema::types::{ColumnDescPtr, ColumnPath, Type},
+use tokio_util::sync::CancellationToken;
+
+use carquet::{
+    capabilities, carv2, export, fixture, inspect,
+    plan::Plan,
+    reorder::{self, CarOrder},
+    select::{self, CidEncoding, CidList, CidSet, Filter},
+    verify, CarToParquetConverter, ConvertArgs, Error,
 };
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let mut f = tokio::fs::File::open("all.car").await?;
-    let mut car = CarReader::new(&mut f).await?;
-    let mut schemas: HashMap<Schema, Vec<(Cid, Ipld, Vec<u8>)>> = HashMap::new();
-    loop {
-        if let Some((cid, bytes)) = car.next_block().await? {
-            let dag: Ipld = DagCborCodec.decode(&bytes)?;
-            schemas
-                .entry(Schema::Map(vec![
-                    ("cid".to_string(), Schema::Bytes),
-                    ("data".to_string(), schema(&dag)),
-                    //("rawdata".to_string(), Schema::Bytes),
-                ]))
-                .or_default()
-                .push((cid, dag, bytes));
-        } else {
-            break;
-        }
-    }
+#[derive(Parser, Debug)]
+#[command(about = "Convert CAR files of IPLD blocks into Parquet files, one per schema")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
 
-    let mut schemas: Vec<(Schema, Vec<(Cid, Ipld, Vec<u8>)>)> = schemas.into_iter().collect();
-    schemas.sort_unstable_by_key(|s| s.1.len());
-    schemas.reverse();
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Convert CAR files into files, one per schema.
+    #[command(after_help = CONVERT_EXAMPLES)]
+    Convert(ConvertCommandArgs),
+    /// Convert several CAR shards in parallel, each into its own directory.
+    #[command(after_help = CONVERT_SHARDED_EXAMPLES)]
+    ConvertSharded(ConvertShardedArgs),
+    /// Split CAR files into balanced tasks and write them to a plan file for run-plan.
+    #[command(after_help = PLAN_EXAMPLES)]
+    Plan(PlanArgs),
+    /// Convert one task of a plan file into task-N/ of the output directory.
+    #[command(after_help = PLAN_EXAMPLES)]
+    RunPlan(RunPlanArgs),
+    /// Write a CAR of synthetic dag-cbor blocks for tests and benchmarks.
+    #[command(after_help = GEN_FIXTURE_EXAMPLES)]
+    GenFixture(GenFixtureArgs),
+    /// Print the CIDs of the blocks of a CAR, one per line, without decoding them.
+    #[command(after_help = LS_CIDS_EXAMPLES)]
+    LsCids(LsCidsArgs),
+    /// Print a summary of the first blocks of a CAR.
+    Head(HeadTailArgs),
+    /// Print a summary of the last blocks of a CAR.
+    Tail(HeadTailArgs),
+    /// Print the CIDs and paths of string and bytes values containing a pattern.
+    #[command(after_help = GREP_EXAMPLES)]
+    Grep(GrepArgs),
+    /// Write the blocks kept in converted parquet files back to a CAR.
+    #[command(after_help = EXPORT_EXAMPLES)]
+    Export(ExportArgs),
+    /// Copy the blocks of a CAR whose CIDs are listed, or which have a codec,
+    /// size or hash function, into a CAR of their own.
+    #[command(after_help = SELECT_EXAMPLES)]
+    Select(SelectArgs),
+    /// Print the codecs, formats, compression codecs, sinks and features this
+    /// build supports, and the flags of convert.
+    #[command(after_help = CAPABILITIES_EXAMPLES)]
+    Capabilities(CapabilitiesArgs),
+    /// Print a completion script for the shell.
+    #[command(after_help = COMPLETIONS_EXAMPLES)]
+    Completions(CompletionsArgs),
+}
 
-    let dir = PathBuf::from("out");
+const CONVERT_EXAMPLES: &str = "\
+Examples:
+  Convert all.car into out/ with the default settings:
+    carquet convert
+  Convert my.car into parquet/ with zstd compression at level 9:
+    carquet convert --input my.car --output-dir parquet --compression zstd --compression-level 9
+  Store data.prev as a LIST and move data.entries to its own table:
+    carquet convert --link-list data.prev=list --explode data.entries
+  Write narrow integers and millisecond timestamps:
+    carquet convert --int-type data.height=uint32 --int-type data.time=timestamp_ms
+  Write the schemas of this run, then map later runs into them:
+    carquet convert --write-pins pins.json
+    carquet convert --pin-schemas pins.json";
 
-    println!("num schemas {}", schemas.len());
-    for (i, (schema, cids)) in schemas.iter().enumerate() {
-        let p_schema = parquet_schema(&schema, "", false);
-        println!(
-            "schema: {:#?}\np schema: {:#?}\n exmaple: {:?}",
-            &schema,
-            p_schema,
-            cids.first()
-        );
-        let props = Arc::new(
-            WriterProperties::builder()
-                .set_compression(parquet::basic::Compression::SNAPPY)
-                .build(),
-        );
-        let f = std::fs::File::create(dir.join(format!("schema_{}.parquet", i)))?;
-        let mut writer = SerializedFileWriter::new(f, Arc::new(p_schema), props)?;
-        let mut row_group_writer = writer.next_row_group().context("creating row group")?;
-        while let Some(mut col_writer) = row_group_writer.next_column().context("next column")? {
-            let desc = col_desc(&mut col_writer);
-            let path = desc.path().string();
-            parquet_write_col(&mut col_writer, cids).context("writing column")?;
-            col_writer
-                .close()
-                .context(format!("closing col_writer {} {}", path, cids.len()))?;
-        }
-        row_group_writer.close()?;
-        writer.close()?;
-    }
+const CONVERT_SHARDED_EXAMPLES: &str = "\
+Examples:
+  Convert eight shards at a time, shard-0.car into out/shard-0/ and so on:
+    carquet convert-sharded shard-*.car --jobs 8
+  Also merge the manifests of the shards into out/manifest.json:
+    carquet convert-sharded shard-*.car --manifest
+  Give every shard the same files by pinning the schemas of one:
+    carquet convert --write-pins pins.json
+    carquet convert-sharded shard-*.car --pin-schemas pins.json";
 
-    Ok(())
+const PLAN_EXAMPLES: &str = "\
+Examples:
+  Split the shards into 16 tasks, then run each on its own worker:
+    carquet plan shard-*.car --tasks 16 -o plan.json
+    carquet run-plan --plan plan.json --task 0";
+
+const GEN_FIXTURE_EXAMPLES: &str = "\
+Examples:
+  Write a million linked records to all.car:
+    carquet gen-fixture --records 1000000 -o all.car
+  Write blocks of the shapes in a file, see the fixture module for the format:
+    carquet gen-fixture --shape shapes.json --seed 7";
+
+const LS_CIDS_EXAMPLES: &str = "\
+Examples:
+  Count the dag-cbor blocks:
+    carquet ls-cids all.car --codec dag-cbor | wc -l
+  List the raw blocks in base58btc:
+    carquet ls-cids all.car --codec 0x55 --base base58btc";
+
+const GREP_EXAMPLES: &str = "\
+Examples:
+  Find the blocks mentioning a DID:
+    carquet grep did:key:z6MkhaXgBZDvotDkL5257faiztiGiC2QtKLGpbnnEGta2doK all.car
+  Copy the matching blocks into a CAR of their own:
+    carquet grep s5689 all.car -o matches.car";
+
+const EXPORT_EXAMPLES: &str = "\
+Examples:
+  Rebuild the CAR of the blocks of a conversion:
+    carquet export out -o all.car
+  Export only the raw blocks, under the root of the original CAR:
+    carquet export out/raw_blocks.parquet -o raw.car --root bafy2bzaced..
+  Write the blocks depth first from the root, as trustless gateways do:
+    carquet export out -o all.car --root bafy2bzaced.. --order dfs-from-roots";
+
+const SELECT_EXAMPLES: &str = "\
+Examples:
+  Copy the blocks listed by their base64 binary CIDs:
+    carquet select -i all.car --cids cids.csv -o some.car
+  Copy the blocks of CIDs piped in as strings:
+    carquet ls-cids all.car --codec raw | carquet select -i all.car --cids - --cids-encoding string -o raw.car
+  Copy the dag-cbor blocks of at most 1 KiB, without a list:
+    carquet select -i all.car --codec dag-cbor --max-size 1024 -o small.car
+  Copy the blocks of the rows of converted files that pass a condition:
+    carquet select -i all.car --parquet out --where 'data.type = 3' -o type3.car
+  Copy the listed blocks of a CARv2 by its index, into an indexed CARv2:
+    carquet select -i all.car --cids cids.csv --use-index --carv2 -o some.car";
+
+const CAPABILITIES_EXAMPLES: &str = "\
+Examples:
+  Check that the installed carquet writes zstd before a job needs it:
+    carquet capabilities --json | jq -e '.compression | index(\"zstd\")'";
+
+const COMPLETIONS_EXAMPLES: &str = "\
+Examples:
+  bash:
+    carquet completions bash > ~/.local/share/bash-completion/completions/carquet
+  zsh, with the directory on the fpath:
+    carquet completions zsh > ~/.zfunc/_carquet
+  fish:
+    carquet completions fish > ~/.config/fish/completions/carquet.fish";
+
+#[derive(clap::Args, Debug)]
+struct LsCidsArgs {
+    /// CAR file to list.
+    #[arg(value_name = "CAR", default_value = "all.car")]
+    input: PathBuf,
+
+    /// Multibase of the CIDs.
+    #[arg(long, value_enum, default_value_t = inspect::CidBase::Base32)]
+    base: inspect::CidBase,
+
+    /// Only list blocks of this codec, a name such as dag-cbor or raw, or a code.
+    #[arg(long, value_parser = inspect::parse_codec)]
+    codec: Option<u64>,
+}
+
+#[derive(clap::Args, Debug)]
+struct HeadTailArgs {
+    /// CAR file to read.
+    #[arg(value_name = "CAR", default_value = "all.car")]
+    input: PathBuf,
+
+    /// Number of blocks to print.
+    #[arg(short, long = "lines", value_name = "N", default_value_t = 10)]
+    n: usize,
+}
+
+#[derive(clap::Args, Debug)]
+struct GrepArgs {
+    /// Text to search for. Strings match if they contain it, bytes if they
+    /// contain its UTF-8 encoding.
+    pattern: String,
+
+    /// CAR file to search.
+    #[arg(value_name = "CAR", default_value = "all.car")]
+    input: PathBuf,
+
+    /// Also write the matching blocks to a .car file, or the matches to a
+    /// .parquet or .jsonl table of (cid, path).
+    #[arg(long, short, value_name = "FILE")]
+    output: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+struct ExportArgs {
+    /// Parquet files, or directories of them, to read the blocks of.
+    #[arg(value_name = "PATH", default_value = "out")]
+    inputs: Vec<PathBuf>,
+
+    /// Path of the CAR to write.
+    #[arg(long, short, value_name = "FILE", default_value = "export.car")]
+    output: PathBuf,
+
+    /// Root CID of the CAR, may be repeated. Defaults to the first block.
+    #[arg(long = "root", value_name = "CID")]
+    roots: Vec<Cid>,
+
+    /// Name of the column of the block CID, as given to --cid-column-name.
+    #[arg(long, value_name = "NAME", default_value = "cid")]
+    cid_column_name: String,
+
+    /// Order of the blocks in the CAR.
+    #[arg(long, value_enum, default_value_t)]
+    order: CarOrder,
+
+    /// Read the CAR again once written, checking its framing, that its
+    /// blocks match their CIDs and that its roots are among them.
+    #[arg(long)]
+    verify: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct SelectArgs {
+    /// CAR file to copy the blocks from.
+    #[arg(long, short, value_name = "CAR")]
+    input: PathBuf,
+
+    /// Path of the CAR to write.
+    #[arg(long, short, value_name = "FILE")]
+    output: PathBuf,
+
+    /// File of the CIDs to copy, one per line, or - to read them from stdin.
+    #[arg(long, value_name = "FILE|-", required_if_eq("use_index", "true"))]
+    cids: Option<PathBuf>,
+
+    /// Encoding of the listed CIDs.
+    #[arg(long, value_enum, default_value_t)]
+    cids_encoding: CidEncoding,
+
+    /// Root CID of the CAR written, in place of the roots of the input, may
+    /// be repeated.
+    #[arg(long = "root", value_name = "CID")]
+    roots: Vec<Cid>,
+
+    /// Parquet file of converted blocks, or directory of them, whose rows
+    /// passing --where are the blocks to copy. May be repeated.
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["cids", "use_index"])]
+    parquet: Vec<PathBuf>,
+
+    /// Condition on the rows of --parquet, as the WHERE of a --sql query of
+    /// convert, e.g. `data.type = 3`.
+    #[arg(long = "where", value_name = "CONDITION", requires = "parquet")]
+    condition: Option<String>,
+
+    /// Name of the column of the block CID in --parquet, as given to
+    /// --cid-column-name.
+    #[arg(long, value_name = "NAME", default_value = "cid")]
+    cid_column_name: String,
+
+    /// Name of the group of the block data in --parquet, as given to
+    /// --data-column-name.
+    #[arg(long, value_name = "NAME", default_value = "data")]
+    data_column_name: String,
+
+    /// Sorted index of the list, built if missing or older than the list.
+    /// Defaults to the list with an .idx extension, or for stdin and
+    /// --parquet to the output with a .cids.idx extension, removed once done.
+    #[arg(long, value_name = "FILE")]
+    index: Option<PathBuf>,
+
+    /// Copy only blocks of this codec, by name such as dag-cbor or by code.
+    /// May be repeated.
+    #[arg(long, value_parser = inspect::parse_codec)]
+    codec: Vec<u64>,
+
+    /// Copy only blocks of at least this many bytes.
+    #[arg(long, value_name = "BYTES")]
+    min_size: Option<u64>,
+
+    /// Copy only blocks of at most this many bytes.
+    #[arg(long, value_name = "BYTES")]
+    max_size: Option<u64>,
+
+    /// Copy only blocks whose CID has this hash function, by name such as
+    /// sha2-256 or by multihash code. May be repeated.
+    #[arg(long, value_parser = inspect::parse_hash)]
+    hash: Vec<u64>,
+
+    /// Record progress in this file, and resume from it if present.
+    #[arg(long, value_name = "FILE")]
+    checkpoint: Option<PathBuf>,
+
+    /// Read only the listed blocks, found by the index of the input, a CARv2,
+    /// rather than the whole CAR.
+    #[arg(long, conflicts_with = "checkpoint")]
+    use_index: bool,
+
+    /// Order of the blocks in the CAR written.
+    #[arg(long, value_enum, default_value_t)]
+    order: CarOrder,
+
+    /// Write a CARv2 with a multihash index of the blocks, so they can be
+    /// read without scanning it.
+    #[arg(long)]
+    carv2: bool,
+
+    /// Read the CAR again once written, checking its framing, that its
+    /// blocks match their CIDs, that its roots are among them and that its
+    /// index finds them.
+    #[arg(long)]
+    verify: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct CapabilitiesArgs {
+    /// Print them as JSON.
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct CompletionsArgs {
+    /// Shell to complete for.
+    #[arg(value_enum)]
+    shell: clap_complete::Shell,
+}
+
+#[derive(clap::Args, Debug)]
+struct ConvertCommandArgs {
+    /// CAR file to convert, may be repeated to convert several into one set of files.
+    #[arg(long, short, value_name = "CAR", default_value = "all.car")]
+    input: Vec<PathBuf>,
+
+    /// Directory to write the files into, created if missing.
+    #[arg(long, short, value_name = "DIR", default_value = "out")]
+    output_dir: PathBuf,
+
+    #[command(flatten)]
+    convert: ConvertArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct ConvertShardedArgs {
+    /// CAR files to convert, shard `a/b.car` is written to `b/` of the output directory.
+    #[arg(required = true, value_name = "CAR")]
+    shards: Vec<PathBuf>,
+
+    /// Directory to write the directories of the shards into.
+    #[arg(long, short, value_name = "DIR", default_value = "out")]
+    output_dir: PathBuf,
+
+    #[command(flatten)]
+    convert: ConvertArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct PlanArgs {
+    /// CAR files to split.
+    #[arg(required = true, value_name = "CAR")]
+    inputs: Vec<PathBuf>,
+
+    /// Number of tasks to split the blocks into.
+    #[arg(long, default_value_t = 4)]
+    tasks: usize,
+
+    /// Path of the plan file to write.
+    #[arg(long, short, value_name = "FILE", default_value = "plan.json")]
+    output: PathBuf,
+}
+
+#[derive(clap::Args, Debug)]
+struct RunPlanArgs {
+    /// Plan file written by the plan command.
+    #[arg(long, value_name = "FILE", default_value = "plan.json")]
+    plan: PathBuf,
+
+    /// Index of the task to convert, from 0.
+    #[arg(long, value_name = "N")]
+    task: usize,
+
+    /// Directory to write the directory of the task into.
+    #[arg(long, short, value_name = "DIR", default_value = "out")]
+    output_dir: PathBuf,
+
+    #[command(flatten)]
+    convert: ConvertArgs,
 }
 
-#[derive(Debug, PartialEq, Hash, Eq)]
-enum Schema {
-    Null,
-    Bool,
-    Integer,
-    Float,
-    String,
-    Bytes,
-    List(Box<Schema>),
-    Map(Vec<(String, Schema)>),
-    Link,
+#[derive(clap::Args, Debug)]
+struct GenFixtureArgs {
+    /// Number of blocks to generate.
+    #[arg(long, default_value_t = 1000)]
+    records: usize,
+
+    /// JSON file describing the shapes of the blocks, see the fixture module
+    /// for the format. Defaults to a small chain of linked records.
+    #[arg(long, value_name = "FILE")]
+    shape: Option<PathBuf>,
+
+    /// Seed of the generator, the same seed and shapes produce the same CAR.
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+
+    /// Path of the CAR to write.
+    #[arg(long, short, value_name = "FILE", default_value = "fixture.car")]
+    output: PathBuf,
 }
 
-fn schema(dag: &Ipld) -> Schema {
-    match dag {
-        Ipld::Null => Schema::Null,
-        Ipld::Bool(_) => Schema::Bool,
-        Ipld::Integer(_) => Schema::Integer,
-        Ipld::Float(_) => Schema::Float,
-        Ipld::String(_) => Schema::String,
-        Ipld::Bytes(_) => Schema::Bytes,
-        Ipld::Link(_) => Schema::Link,
-        Ipld::List(l) => Schema::List(Box::new(if let Some(first) = l.first() {
-            schema(first)
-        } else {
-            Schema::Null
-        })),
-        Ipld::Map(m) => {
-            let mut sm = Vec::new();
-            for (k, v) in m {
-                sm.push((k.to_owned(), schema(v)));
+/// Exit code of a run stopped by Ctrl-C, as shells report for SIGINT.
+const EXIT_CANCELLED: i32 = 130;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cancel = CancellationToken::new();
+    tokio::spawn({
+        let cancel = cancel.clone();
+        async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                eprintln!("cancelling, press Ctrl-C again to exit immediately");
+                cancel.cancel();
+            }
+            if tokio::signal::ctrl_c().await.is_ok() {
+                std::process::exit(EXIT_CANCELLED);
             }
-            sm.sort_by_key(|i| i.0.to_owned());
-            Schema::Map(sm)
         }
-    }
-}
+    });
 
-fn parquet_schema(schema: &Schema, name: &str, repeated: bool) -> Type {
-    match schema {
-        //TODO proper null handling
-        Schema::Null => Type::primitive_type_builder(name, parquet::basic::Type::BOOLEAN)
-            .with_repetition(if repeated {
-                Repetition::REPEATED
-            } else {
-                Repetition::REQUIRED
-            })
-            .build()
-            .unwrap(),
-
-        Schema::Bool => Type::primitive_type_builder(name, parquet::basic::Type::BOOLEAN)
-            .with_repetition(if repeated {
-                Repetition::REPEATED
-            } else {
-                Repetition::REQUIRED
-            })
-            .build()
-            .unwrap(),
-
-        Schema::Integer => Type::primitive_type_builder(name, parquet::basic::Type::INT64)
-            .with_repetition(if repeated {
-                Repetition::REPEATED
-            } else {
-                Repetition::REQUIRED
-            })
-            .with_logical_type(Some(parquet::basic::LogicalType::Integer {
-                bit_width: 64,
-                is_signed: false,
-            }))
-            .build()
-            .unwrap(),
-        Schema::Float => Type::primitive_type_builder(name, parquet::basic::Type::DOUBLE)
-            .with_repetition(if repeated {
-                Repetition::REPEATED
-            } else {
-                Repetition::REQUIRED
-            })
-            .build()
-            .unwrap(),
-
-        Schema::String => Type::primitive_type_builder(name, parquet::basic::Type::BYTE_ARRAY)
-            .with_repetition(if repeated {
-                Repetition::REPEATED
-            } else {
-                Repetition::REQUIRED
-            })
-            .with_converted_type(parquet::basic::ConvertedType::UTF8)
-            .build()
-            .unwrap(),
-        Schema::Bytes => Type::primitive_type_builder(name, parquet::basic::Type::BYTE_ARRAY)
-            .with_repetition(if repeated {
-                Repetition::REPEATED
-            } else {
-                Repetition::REQUIRED
-            })
-            .build()
-            .unwrap(),
-        Schema::Link => Type::primitive_type_builder(name, parquet::basic::Type::BYTE_ARRAY)
-            .with_repetition(if repeated {
-                Repetition::REPEATED
-            } else {
-                Repetition::REQUIRED
-            })
-            .build()
-            .unwrap(),
-
-        Schema::List(l) => {
-            if repeated {
-                //TODO handle lists of lists
-                todo!()
+    let result = match Cli::parse().command {
+        Command::Convert(args) => convert(args, cancel).await,
+        Command::ConvertSharded(args) => convert_sharded(args, cancel).await,
+        Command::Plan(args) => {
+            let plan = Plan::new(&args.inputs, args.tasks).await?;
+            for (i, task) in plan.tasks.iter().enumerate() {
+                let bytes: u64 = task.units.iter().map(|u| u.bytes).sum();
+                println!("task {}: {} units, {} bytes", i, task.units.len(), bytes);
+            }
+            plan.save(&args.output)
+        }
+        Command::RunPlan(args) => run_plan(args, cancel).await,
+        Command::GenFixture(args) => gen_fixture(args).await,
+        Command::LsCids(args) => inspect::ls_cids(&args.input, args.base, args.codec).await,
+        Command::Head(args) => inspect::head(&args.input, args.n).await,
+        Command::Tail(args) => inspect::tail(&args.input, args.n).await,
+        Command::Grep(args) => {
+            let found = inspect::grep(&args.input, &args.pattern, args.output.as_deref()).await?;
+            if found == 0 {
+                // As with grep, no match is a failure for scripts.
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        Command::Export(args) => export(args).await,
+        Command::Select(args) => select(args),
+        Command::Capabilities(args) => {
+            let capabilities = capabilities::probe();
+            if args.json {
+                println!("{}", serde_json::to_string_pretty(&capabilities)?);
             } else {
-                parquet_schema(l, name, true)
+                print!("{}", capabilities);
             }
+            Ok(())
         }
-        Schema::Map(m) => {
-            let mut fields = m
-                .iter()
-                .map(|(k, v)| Arc::new(parquet_schema(v, k, false)))
-                .collect();
-            Type::group_type_builder(name)
-                .with_repetition(if repeated {
-                    Repetition::REPEATED
-                } else {
-                    Repetition::REQUIRED
-                })
-                .with_fields(&mut fields)
-                .build()
-                .unwrap()
+        Command::Completions(args) => {
+            let mut cmd = Cli::command();
+            let name = cmd.get_name().to_string();
+            clap_complete::generate(args.shell, &mut cmd, name, &mut std::io::stdout());
+            Ok(())
+        }
+    };
+    match result {
+        Err(e) if matches!(e.downcast_ref::<Error>(), Some(Error::Cancelled)) => {
+            eprintln!("cancelled");
+            std::process::exit(EXIT_CANCELLED)
         }
+        result => result,
     }
 }
 
-fn col_desc<'a>(col_writer: &'a mut SerializedColumnWriter) -> &'a ColumnDescPtr {
-    match col_writer.untyped() {
-        parquet::column::writer::ColumnWriter::BoolColumnWriter(cw) => cw.get_descriptor(),
-        parquet::column::writer::ColumnWriter::Int32ColumnWriter(cw) => cw.get_descriptor(),
-        parquet::column::writer::ColumnWriter::Int64ColumnWriter(cw) => cw.get_descriptor(),
-        parquet::column::writer::ColumnWriter::Int96ColumnWriter(cw) => cw.get_descriptor(),
-        parquet::column::writer::ColumnWriter::FloatColumnWriter(cw) => cw.get_descriptor(),
-        parquet::column::writer::ColumnWriter::DoubleColumnWriter(cw) => cw.get_descriptor(),
-        parquet::column::writer::ColumnWriter::ByteArrayColumnWriter(cw) => cw.get_descriptor(),
-        parquet::column::writer::ColumnWriter::FixedLenByteArrayColumnWriter(cw) => {
-            cw.get_descriptor()
+async fn export(args: ExportArgs) -> Result<()> {
+    let exported = export::export(
+        &args.inputs,
+        &args.cid_column_name,
+        &args.roots,
+        &args.output,
+    )
+    .await?;
+    for path in &exported.skipped {
+        eprintln!(
+            "{} has no {} column, its blocks were not exported, convert with --include-raw to keep them",
+            path.display(),
+            export::RAWDATA
+        );
+    }
+    let unreachable = reorder::reorder(&args.output, args.order)?;
+    println!(
+        "{} blocks written to {}",
+        exported.blocks,
+        args.output.display()
+    );
+    if unreachable > 0 {
+        println!(
+            "{} blocks not reachable from the roots written after the others",
+            unreachable
+        );
+    }
+    if exported.duplicates > 0 {
+        println!(
+            "{} rows repeated a block already written",
+            exported.duplicates
+        );
+    }
+    if args.verify {
+        let verified = verify::verify(&args.output)?;
+        println!("verified {} blocks", verified.blocks);
+        if verified.unchecked > 0 {
+            println!(
+                "{} blocks of hash functions this build lacks were not rehashed",
+                verified.unchecked
+            );
         }
     }
+    Ok(())
 }
 
-// Does not recurse
-fn parquet_write_col(
-    col_writer: &mut SerializedColumnWriter,
-    cids: &[(Cid, Ipld, Vec<u8>)],
-) -> Result<()> {
-    let desc = col_desc(col_writer);
-    let path = desc.path();
-    //println!("parquet_write_col desc: {:?}", desc);
-
-    let (values, rep_levels): (Vec<Ipld>, Vec<i16>) = cids
-        .iter()
-        .flat_map(|(cid, data, bytes)| {
-            resolve_index(cid, data, &bytes.as_slice(), path, desc.max_rep_level())
-                .expect("data path should resolve")
-                .into_iter()
-        })
-        .unzip();
-    let def_levels = if desc.max_def_level() > 0 {
-        let level = desc.max_def_level();
-        Some((0..values.len()).map(|_| level).collect::<Vec<i16>>())
-    } else {
-        None
+fn select(args: SelectArgs) -> Result<()> {
+    let filter = Filter {
+        codecs: args.codec,
+        hashes: args.hash,
+        min_size: args.min_size,
+        max_size: args.max_size,
     };
-    match desc.physical_type() {
-        parquet::basic::Type::BOOLEAN => {
-            col_writer.typed::<BoolType>().write_batch(
-                values
-                    .into_iter()
-                    .map(|v| match v {
-                        Ipld::Bool(b) => Ok(b),
-                        _ => Err(anyhow!("bad type {:?} expecting integer", v)),
-                    })
-                    .collect::<Result<Vec<bool>>>()?
-                    .as_slice(),
-                def_levels.as_ref().map(|d| d.as_slice()),
-                Some(rep_levels.as_slice()),
-            )?;
-        }
-        parquet::basic::Type::INT32 => {
-            col_writer.typed::<Int32Type>().write_batch(
-                values
-                    .into_iter()
-                    .map(|v| match v {
-                        Ipld::Integer(i) => Ok(i as i32),
-                        _ => Err(anyhow!("bad type {:?} expecting integer", v)),
-                    })
-                    .collect::<Result<Vec<i32>>>()?
-                    .as_slice(),
-                def_levels.as_ref().map(|d| d.as_slice()),
-                Some(rep_levels.as_slice()),
-            )?;
-        }
-        parquet::basic::Type::INT64 => {
-            col_writer.typed::<Int64Type>().write_batch(
-                values
-                    .into_iter()
-                    .map(|v| match v {
-                        Ipld::Integer(i) => Ok(i as i64),
-                        _ => Err(anyhow!("bad type {:?} expecting integer", v)),
-                    })
-                    .collect::<Result<Vec<i64>>>()?
-                    .as_slice(),
-                def_levels.as_ref().map(|d| d.as_slice()),
-                Some(rep_levels.as_slice()),
-            )?;
-        }
-        parquet::basic::Type::INT96 => todo!(),
-        parquet::basic::Type::FLOAT => {
-            col_writer.typed::<FloatType>().write_batch(
-                values
-                    .into_iter()
-                    .map(|v| match v {
-                        Ipld::Float(f) => Ok(f as f32),
-                        _ => Err(anyhow!("bad type {:?} expecting float", v)),
-                    })
-                    .collect::<Result<Vec<f32>>>()?
-                    .as_slice(),
-                def_levels.as_ref().map(|d| d.as_slice()),
-                Some(rep_levels.as_slice()),
-            )?;
-        }
-        parquet::basic::Type::DOUBLE => {
-            col_writer.typed::<DoubleType>().write_batch(
-                values
-                    .into_iter()
-                    .map(|v| match v {
-                        Ipld::Float(f) => Ok(f as f64),
-                        _ => Err(anyhow!("bad type {:?} expecting float", v)),
-                    })
-                    .collect::<Result<Vec<f64>>>()?
-                    .as_slice(),
-                def_levels.as_ref().map(|d| d.as_slice()),
-                Some(rep_levels.as_slice()),
+    if args.cids.is_none() && args.parquet.is_empty() && filter.is_empty() {
+        return Err(anyhow!(
+            "give the CIDs to copy with --cids or --parquet, or a filter"
+        ));
+    }
+    let roots = (!args.roots.is_empty()).then_some(&args.roots[..]);
+    let list = args.cids.map(|path| CidList {
+        path,
+        encoding: args.cids_encoding,
+    });
+    let (selection, listed) = match list {
+        Some(list) if args.use_index => (
+            select::select_indexed(&args.input, &list, &filter, roots, &args.output)?,
+            None,
+        ),
+        list => {
+            let temporary = args.index.is_none()
+                && (!args.parquet.is_empty() || list.as_ref().is_some_and(|l| l.is_stdin()));
+            let index = match (args.index, &list) {
+                (Some(index), _) => Some(index),
+                _ if temporary => {
+                    Some(PathBuf::from(format!("{}.cids.idx", args.output.display())))
+                }
+                (None, Some(list)) => Some(list.path.with_extension("idx")),
+                (None, None) => None,
+            };
+            let mut set = match (&list, &index) {
+                (_, Some(index)) if !args.parquet.is_empty() => Some(select::parquet_set(
+                    &args.parquet,
+                    &args.cid_column_name,
+                    &args.data_column_name,
+                    args.condition.as_deref(),
+                    index,
+                )?),
+                (Some(list), Some(index)) => Some(CidSet::open_or_build(list, index)?),
+                _ => None,
+            };
+            if let (true, Some(index)) = (temporary, &index) {
+                // The set keeps the index open, it needs no name any more.
+                let _ = std::fs::remove_file(index);
+            }
+            let selection = select::select(
+                &args.input,
+                set.as_mut(),
+                &filter,
+                roots,
+                &args.output,
+                args.checkpoint.as_deref(),
             )?;
+            (selection, set.map(|set| set.len()))
         }
-        parquet::basic::Type::BYTE_ARRAY => {
-            col_writer.typed::<ByteArrayType>().write_batch(
-                values
-                    .into_iter()
-                    .map(|v| match v {
-                        Ipld::String(s) => Ok(ByteArray::from(s.as_bytes())),
-                        Ipld::Bytes(b) => Ok(ByteArray::from(b)),
-                        Ipld::Link(cid) => Ok(ByteArray::from(cid.to_bytes())),
-                        // TODO proper handling of nulls
-                        Ipld::Null => Ok(ByteArray::from(vec![])),
-                        _ => Err(anyhow!("bad type {:?} expecting byteish", v)),
-                    })
-                    .collect::<Result<Vec<ByteArray>>>()?
-                    .as_slice(),
-                def_levels.as_ref().map(|d| d.as_slice()),
-                Some(rep_levels.as_slice()),
-            )?;
+    };
+    let unreachable = reorder::reorder(&args.output, args.order)?;
+    if args.carv2 {
+        carv2::wrap(&args.output)?;
+    }
+    if selection.resumed {
+        println!(
+            "resumed from {}",
+            args.checkpoint.unwrap_or_default().display()
+        );
+    }
+    print!(
+        "{} of {} blocks written to {}",
+        selection.selected,
+        selection.blocks,
+        args.output.display()
+    );
+    match listed {
+        Some(listed) => println!(", {} CIDs listed", listed),
+        None => println!(),
+    }
+    if unreachable > 0 {
+        println!(
+            "{} blocks not reachable from the roots written after the others",
+            unreachable
+        );
+    }
+    if args.verify {
+        let verified = verify::verify(&args.output)?;
+        println!("verified {} blocks", verified.blocks);
+        if verified.unchecked > 0 {
+            println!(
+                "{} blocks of hash functions this build lacks were not rehashed",
+                verified.unchecked
+            );
         }
+    }
+    Ok(())
+}
 
-        parquet::basic::Type::FIXED_LEN_BYTE_ARRAY => todo!(),
+async fn gen_fixture(args: GenFixtureArgs) -> Result<()> {
+    let shapes = match &args.shape {
+        Some(path) => fixture::Shapes::load(path)?,
+        None => fixture::Shapes::default(),
     };
-    Ok(())
+    fixture::generate(&shapes, args.records, args.seed, &args.output).await
+}
+
+async fn convert(mut args: ConvertCommandArgs, cancel: CancellationToken) -> Result<()> {
+    args.convert = args.convert.with_options_file()?;
+    let quiet = args.convert.quiet();
+    let mut converter = CarToParquetConverter::new(args.output_dir);
+    for input in &args.input {
+        converter = converter.input(input);
+    }
+    let converter = converter.options(args.convert).cancel_token(cancel);
+    run_and_report(&converter, quiet).await
+}
+
+/// Runs the conversion and prints its report unless `quiet`.
+async fn run_and_report(converter: &CarToParquetConverter, quiet: bool) -> Result<()> {
+    let mut report = converter.report();
+    let result = converter.run_with(&mut report).await;
+    // Files written before a cancellation are complete, report on them.
+    if !quiet && (result.is_ok() || report.cancelled) {
+        print!("{}", report);
+    }
+    result
 }
 
-fn resolve_index<'a, 'b>(
-    cid: &Cid,
-    mut data: &'a Ipld,
-    bytes: &[u8],
-    path: &'b ColumnPath,
-    max_rep_level: i16,
-) -> Result<Vec<(Ipld, i16)>> {
-    let root = &path.parts()[0];
-    match root.as_str() {
-        "cid" => return Ok(vec![(Ipld::Link(*cid), 0)]),
-        "data" => {
-            for p in &path.parts()[1..] {
-                data = data.get(p.to_string())?
+/// Converts each shard as an independent conversion, at most `jobs` at a time,
+/// then with `--manifest` merges the manifests of the shards into one.
+///
+/// Shards share the settings, so with `--pin-schemas` every shard produces the
+/// same file names and the directories can be read as one table per schema.
+/// The files of the shards are not compacted into fewer, the directories are
+/// read together instead.
+async fn convert_sharded(mut args: ConvertShardedArgs, cancel: CancellationToken) -> Result<()> {
+    args.convert = args.convert.with_options_file()?;
+    args.convert.check_sharded()?;
+    let mut dirs = HashSet::new();
+    let mut names = Vec::new();
+    for shard in &args.shards {
+        let stem = shard
+            .file_stem()
+            .ok_or_else(|| anyhow!("shard {} has no file name", shard.display()))?;
+        names.push(PathBuf::from(stem));
+        if !dirs.insert(stem.to_owned()) {
+            return Err(anyhow!(
+                "shards must have distinct file names, {} is repeated",
+                stem.to_string_lossy()
+            ));
+        }
+    }
+
+    let (jobs, options) = args.convert.sharded_jobs();
+    let jobs = Arc::new(tokio::sync::Semaphore::new(jobs));
+    let mut tasks = Vec::new();
+    for shard in args.shards.iter().cloned() {
+        let dir = args.output_dir.join(shard.file_stem().unwrap_or_default());
+        let converter = CarToParquetConverter::new(&dir)
+            .input(&shard)
+            .options(options.clone())
+            .cancel_token(cancel.clone());
+        let jobs = jobs.clone();
+        tasks.push(tokio::spawn(async move {
+            let _permit = jobs.acquire().await?;
+            let mut report = converter.report();
+            let result = converter
+                .run_with(&mut report)
+                .await
+                .context(format!("converting shard {}", shard.display()));
+            anyhow::Ok((shard, report, result))
+        }));
+    }
+
+    let quiet = args.convert.quiet();
+    let mut failed = Vec::new();
+    let mut files_written = 0;
+    for task in tasks {
+        let (shard, report, result) = task.await??;
+        files_written += report.files_written;
+        match result {
+            Ok(()) if quiet => {}
+            Ok(()) => {
+                println!("{}:", shard.display());
+                print!("{}", report);
             }
-            if let Ipld::List(l) = data {
-                let mut values: Vec<(Ipld, i16)> = l
-                    .into_iter()
-                    .map(|ipld| (ipld.clone(), max_rep_level))
-                    .collect();
-                if !values.is_empty() {
-                    values[0].1 = 0;
-                }
-                Ok(values)
-            } else {
-                Ok(vec![(data.to_owned(), 0)])
+            Err(e) => {
+                println!("{}:", shard.display());
+                println!("failed: {:#}", e);
+                failed.push(e);
             }
         }
-        "rawdata" => Ok(vec![(Ipld::Bytes(bytes.to_vec()), 0)]),
-        _ => Err(anyhow!("unexpected root path")),
     }
+    if failed.is_empty() {
+        args.convert.merge_manifests(&args.output_dir, &names)?;
+    }
+    if quiet {
+        return failed.into_iter().next().map_or(Ok(()), Err);
+    }
+    println!(
+        "{} shards converted into {} files, {} failed",
+        args.shards.len() - failed.len(),
+        files_written,
+        failed.len()
+    );
+    match failed.into_iter().next() {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+async fn run_plan(mut args: RunPlanArgs, cancel: CancellationToken) -> Result<()> {
+    args.convert = args.convert.with_options_file()?;
+    let plan = Plan::load(&args.plan)?;
+    let task = plan.tasks.get(args.task).ok_or_else(|| {
+        anyhow!(
+            "task {} is not in {}, which has {} tasks",
+            args.task,
+            args.plan.display(),
+            plan.tasks.len()
+        )
+    })?;
+    let quiet = args.convert.quiet();
+    let converter = CarToParquetConverter::new(args.output_dir.join(format!("task-{}", args.task)))
+        .units(task.units.iter().cloned())
+        .options(args.convert)
+        .cancel_token(cancel);
+    run_and_report(&converter, quiet).await
 }
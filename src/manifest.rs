@@ -0,0 +1,247 @@
+//! The manifest describing the files written by a run, `manifest.json`.
+//!
+//! Each file lists the schema of its columns, as in pins files, with the
+//! fingerprint of the schema, the fields moved to its footer by
+//! `--prune-constant-columns`, its rows, its bytes and the CID of one of its
+//! blocks, and with `--samples` a reservoir sample of its records as DAG-JSON,
+//! so the shape of the data can be previewed without a parquet reader.
+//!
+//! `convert-sharded` also writes a manifest of the output directory, the
+//! manifests of every run of every shard merged by [`merge`].
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use libipld::{Cid, Ipld};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    capability_gaps, exploded_lists, fixture::Rng, json, registry, without_fields, Config, Format,
+    Schema,
+};
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    pub files: Vec<FileEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FileEntry {
+    pub file: String,
+    pub rows: usize,
+    /// Size of the file.
+    pub bytes: u64,
+    /// The schema of the columns of the file, without the fields moved to the
+    /// footer, the exploded lists and the fields parquet cannot represent.
+    pub schema: Schema,
+    /// SHA-256 of the JSON of the schema without the `_schema_id` and
+    /// `_schema_fingerprint` fields, the value of the latter.
+    pub fingerprint: String,
+    /// The fields with one value in every row, by path, moved to the footer.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub constants: BTreeMap<String, serde_json::Value>,
+    /// CID of the first block written to the file.
+    pub example_cid: Option<String>,
+    /// Records drawn uniformly from the file, as `{"cid": .., "data": ..}`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub samples: Vec<serde_json::Value>,
+}
+
+impl Manifest {
+    pub fn load(path: &Path) -> Result<Self> {
+        let f = std::fs::File::open(path).context(format!("opening {}", path.display()))?;
+        serde_json::from_reader(std::io::BufReader::new(f))
+            .context(format!("parsing {}", path.display()))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let f = std::fs::File::create(path).context(format!("creating {}", path.display()))?;
+        serde_json::to_writer_pretty(f, self)?;
+        Ok(())
+    }
+}
+
+/// The manifest of the files of the shard directories of `dir`, those of
+/// each run of a shard after the ones of runs before, named by their path
+/// from `dir`, `shard-0/schema_0.parquet`.
+pub fn merge(dir: &Path, shards: &[PathBuf]) -> Result<Manifest> {
+    let mut merged = Manifest::default();
+    for shard in shards {
+        // manifest.json, then manifest-1.json and so on with --append.
+        let mut runs = Vec::new();
+        for entry in std::fs::read_dir(dir.join(shard))? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            let run = match name
+                .strip_prefix("manifest")
+                .and_then(|n| n.strip_suffix(".json"))
+            {
+                Some("") => 0,
+                Some(n) => match n.strip_prefix('-').and_then(|n| n.parse::<u64>().ok()) {
+                    Some(run) => run,
+                    None => continue,
+                },
+                None => continue,
+            };
+            runs.push((run, name));
+        }
+        runs.sort_unstable();
+        for (_, name) in runs {
+            for mut file in Manifest::load(&dir.join(shard).join(name))?.files {
+                file.file = format!("{}/{}", shard.display(), file.file);
+                merged.files.push(file);
+            }
+        }
+    }
+    Ok(merged)
+}
+
+/// A uniform sample of at most `size` records of a stream of unknown length.
+pub struct Reservoir {
+    size: usize,
+    seen: usize,
+    first: Option<Cid>,
+    samples: Vec<(Cid, Ipld)>,
+    // Seeded the same for every file so runs are reproducible.
+    rng: Rng,
+}
+
+impl Reservoir {
+    pub fn new(size: usize) -> Self {
+        Self {
+            size,
+            seen: 0,
+            first: None,
+            samples: Vec::with_capacity(size),
+            rng: Rng::new(0),
+        }
+    }
+
+    pub fn push(&mut self, cid: &Cid, data: &Ipld) {
+        self.seen += 1;
+        self.first.get_or_insert(*cid);
+        if self.samples.len() < self.size {
+            self.samples.push((*cid, data.clone()));
+            return;
+        }
+        let i = self.rng.below(self.seen as u64) as usize;
+        if i < self.size {
+            self.samples[i] = (*cid, data.clone());
+        }
+    }
+
+    /// The manifest entry of the file at `path` the records were written to,
+    /// with `schema` and the `constants` moved to its footer. Records and
+    /// constants with floats JSON cannot represent are left out.
+    pub fn entry(
+        self,
+        file: &str,
+        schema: &Schema,
+        constants: &[(String, Ipld)],
+        path: &Path,
+        config: &Config,
+    ) -> Result<FileEntry> {
+        let dag_json = |value: &Ipld| {
+            let mut out = String::new();
+            json::write_json(&mut out, value, config.json).ok()?;
+            serde_json::from_str(&out).ok()
+        };
+        let samples = self
+            .samples
+            .into_iter()
+            .filter_map(|(cid, data)| {
+                dag_json(&Ipld::Map(
+                    [
+                        ("cid".to_string(), Ipld::Link(cid)),
+                        ("data".to_string(), data),
+                    ]
+                    .into(),
+                ))
+            })
+            .collect();
+        // Exploded lists are written to tables of their own.
+        let mut left_out = exploded_lists(schema, "", config);
+        if config.format == Format::Parquet {
+            left_out.extend(
+                capability_gaps(schema, "", config)
+                    .into_iter()
+                    .map(|(p, _)| p),
+            );
+        }
+        let paths: Vec<&str> = left_out.iter().map(String::as_str).collect();
+        let schema = without_fields(schema, "", &paths);
+        Ok(FileEntry {
+            file: file.to_string(),
+            rows: self.seen,
+            bytes: std::fs::metadata(path)
+                .context(format!("reading {}", path.display()))?
+                .len(),
+            fingerprint: registry::table_fingerprint(&schema),
+            schema,
+            constants: constants
+                .iter()
+                .filter_map(|(path, value)| Some((path.clone(), dag_json(value)?)))
+                .collect(),
+            example_cid: self.first.map(|cid| cid.to_string()),
+            samples,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(files: &[&str]) -> Manifest {
+        Manifest {
+            files: files
+                .iter()
+                .map(|file| FileEntry {
+                    file: file.to_string(),
+                    rows: 1,
+                    bytes: 1,
+                    schema: Schema::Integer,
+                    fingerprint: String::new(),
+                    constants: BTreeMap::new(),
+                    example_cid: None,
+                    samples: Vec::new(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn merges_the_runs_of_each_shard() {
+        let dir =
+            std::env::temp_dir().join(format!("carquet-manifest-merge-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        for (shard, runs) in [("a", &["", "-2", "-10", "-1"][..]), ("b", &[""][..])] {
+            std::fs::create_dir_all(dir.join(shard)).unwrap();
+            for run in runs {
+                let file = format!("schema_0{}.parquet", run);
+                manifest(&[&file])
+                    .save(&dir.join(shard).join(format!("manifest{}.json", run)))
+                    .unwrap();
+            }
+        }
+        // Files in the shards that are not manifests of runs.
+        std::fs::write(dir.join("a/manifest-x.json"), "{}").unwrap();
+        std::fs::write(dir.join("a/manifests.json"), "{}").unwrap();
+
+        let merged = merge(&dir, &["a".into(), "b".into()]).unwrap();
+        let files: Vec<&str> = merged.files.iter().map(|f| f.file.as_str()).collect();
+        assert_eq!(
+            files,
+            [
+                "a/schema_0.parquet",
+                "a/schema_0-1.parquet",
+                "a/schema_0-2.parquet",
+                "a/schema_0-10.parquet",
+                "b/schema_0.parquet",
+            ]
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,558 @@
+//! The options of a conversion as data, e.g. read from a file.
+//!
+//! [`ConvertArgs`] is also the serde form of the flags of `carquet convert`,
+//! each by its long name, and [`ConvertOptions`] builds it in code, writes the
+//! options that differ from the defaults and reads them back checked as the
+//! flags are:
+//!
+//! ```
+//! use carquet::ConvertOptions;
+//!
+//! let options = ConvertOptions::new()
+//!     .set("compression", "zstd")?
+//!     .add("explode", "data.entries")?
+//!     .insert("int-type", "data.height", "int32")?
+//!     .enable("lenient")?;
+//! let json = serde_json::to_string(&options)?;
+//! assert_eq!(
+//!     json,
+//!     r#"{"compression":"zstd","explode":["data.entries"],"int-type":{"data.height":"int32"},"lenient":true}"#
+//! );
+//! let args = serde_json::from_str::<ConvertOptions>(&json)?.to_args()?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! A switch is `true` or `false`, a flag taking a value has the value, a
+//! string or for counts and sizes a number, and a flag that may be repeated a
+//! list of them. The flags of `PATH=VALUE` pairs are maps of the values by
+//! path, e.g. `{"dictionary": {"data.sig": false}}`. `carquet convert
+//! --options FILE` reads such a file.
+
+use std::{fmt, marker::PhantomData, path::Path};
+
+use anyhow::{anyhow, Context, Result};
+use clap::Args as _;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Map, Value};
+
+use crate::{hooks, parse_partition_path, time_type, ConvertArgs};
+
+/// The options of a conversion, serialized as those that differ from the
+/// defaults and turned into [`ConvertArgs`] by [`ConvertOptions::to_args`].
+#[derive(Debug, Default, Clone)]
+pub struct ConvertOptions {
+    args: ConvertArgs,
+}
+
+impl ConvertOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads the options from a JSON file of an object of flags.
+    pub fn load(path: &Path) -> Result<Self> {
+        let f = std::fs::File::open(path).context(format!("opening {}", path.display()))?;
+        serde_json::from_reader(std::io::BufReader::new(f))
+            .context(format!("parsing {}", path.display()))
+    }
+
+    /// Gives the flag a value, replacing any it had.
+    pub fn set(self, flag: &str, value: impl Serialize) -> Result<Self> {
+        let value = serde_json::to_value(value)?;
+        self.edit(flag, |field| {
+            *field = value;
+            Ok(())
+        })
+    }
+
+    /// Adds a value to a flag that may be repeated.
+    pub fn add(self, flag: &str, value: impl Serialize) -> Result<Self> {
+        let value = serde_json::to_value(value)?;
+        self.edit(flag, |field| match field {
+            Value::Array(values) => {
+                values.push(value);
+                Ok(())
+            }
+            _ => Err(anyhow!("--{flag} cannot be repeated")),
+        })
+    }
+
+    /// Gives the field path a value of a flag of `PATH=VALUE` pairs.
+    pub fn insert(self, flag: &str, path: &str, value: impl Serialize) -> Result<Self> {
+        let value = serde_json::to_value(value)?;
+        self.edit(flag, |field| match field {
+            Value::Object(values) => {
+                values.insert(path.to_string(), value);
+                Ok(())
+            }
+            _ => Err(anyhow!("--{flag} does not take PATH=VALUE")),
+        })
+    }
+
+    /// Turns a switch on.
+    pub fn enable(self, flag: &str) -> Result<Self> {
+        self.set(flag, true)
+    }
+
+    /// Changes the serde form of the flag, failing on values it does not take.
+    fn edit(self, flag: &str, edit: impl FnOnce(&mut Value) -> Result<()>) -> Result<Self> {
+        let mut value = serde_json::to_value(&self.args)?;
+        let field = value
+            .get_mut(flag)
+            .ok_or_else(|| anyhow!("unknown option --{flag}"))?;
+        edit(field)?;
+        let args = ConvertArgs::deserialize(value).context(format!("--{flag}"))?;
+        Ok(Self { args })
+    }
+
+    /// The options, checked as the flags of `carquet convert` are.
+    pub fn to_args(&self) -> Result<ConvertArgs> {
+        check(&self.args)?;
+        Ok(self.args.clone())
+    }
+}
+
+impl TryFrom<&ConvertOptions> for ConvertArgs {
+    type Error = anyhow::Error;
+
+    fn try_from(options: &ConvertOptions) -> Result<Self> {
+        options.to_args()
+    }
+}
+
+impl Serialize for ConvertOptions {
+    fn serialize<S: Serializer>(&self, s: S) -> std::result::Result<S::Ok, S::Error> {
+        changed(&self.args)
+            .map_err(serde::ser::Error::custom)?
+            .serialize(s)
+    }
+}
+
+impl<'de> Deserialize<'de> for ConvertOptions {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> std::result::Result<Self, D::Error> {
+        let args = ConvertArgs::deserialize(d)?;
+        check(&args).map_err(de::Error::custom)?;
+        Ok(Self { args })
+    }
+}
+
+/// The serde form of the options that differ from the defaults.
+fn changed(args: &ConvertArgs) -> serde_json::Result<Map<String, Value>> {
+    let defaults = serde_json::to_value(ConvertArgs::default())?;
+    let Value::Object(mut flags) = serde_json::to_value(args)? else {
+        unreachable!("the options serialize to an object")
+    };
+    flags.retain(|flag, value| defaults.get(flag) != Some(value));
+    Ok(flags)
+}
+
+/// The options of the file with the flags that differ from the defaults in
+/// place of its own.
+pub(crate) fn merge(file: ConvertOptions, flags: &ConvertArgs) -> Result<ConvertArgs> {
+    let mut value = serde_json::to_value(&file.args)?;
+    for (flag, v) in changed(flags)? {
+        value[flag] = v;
+    }
+    let args = ConvertArgs::deserialize(value)?;
+    check(&args)?;
+    Ok(args)
+}
+
+/// Rejects the options the flags of `carquet convert` would, the conflicts
+/// between flags and the values their parsers reject that serde takes.
+fn check(args: &ConvertArgs) -> Result<()> {
+    let set = changed(args)?;
+    let command = ConvertArgs::augment_args(clap::Command::new("convert"));
+    for arg in command.get_arguments() {
+        let Some(flag) = arg.get_long().filter(|flag| set.contains_key(*flag)) else {
+            continue;
+        };
+        let conflict = command
+            .get_arg_conflicts_with(arg)
+            .into_iter()
+            .filter_map(|other| other.get_long())
+            .find(|other| set.contains_key(*other));
+        if let Some(other) = conflict {
+            return Err(anyhow!("--{flag} cannot be used with --{other}"));
+        }
+    }
+    if !args.roots.is_empty() && !args.root_cids {
+        return Err(anyhow!("--root needs --root-cids"));
+    }
+    for (path, ty) in &args.type_hints {
+        time_type(*ty).context(format!("--type-hint {path}"))?;
+    }
+    if let Some(path) = &args.partition_by {
+        parse_partition_path(path)?;
+    }
+    if let Some(url) = &args.on_complete_webhook {
+        hooks::parse_webhook(url)?;
+    }
+    for header in &args.webhook_headers {
+        hooks::parse_header(header)?;
+    }
+    Ok(())
+}
+
+/// A value given as a number, or as a string as the flag takes it.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum NumberOrString {
+    Number(u64),
+    String(String),
+}
+
+/// Flags of `PATH=VALUE` pairs, as a map of the values by path.
+pub(crate) mod pairs {
+    use super::*;
+
+    pub fn serialize<T: Serialize, S: Serializer>(
+        pairs: &[(String, T)],
+        s: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        s.collect_map(pairs.iter().map(|(path, value)| (path, value)))
+    }
+
+    pub fn deserialize<'de, T: Deserialize<'de>, D: Deserializer<'de>>(
+        d: D,
+    ) -> std::result::Result<Vec<(String, T)>, D::Error> {
+        struct Pairs<T>(PhantomData<T>);
+
+        impl<'de, T: Deserialize<'de>> de::Visitor<'de> for Pairs<T> {
+            type Value = Vec<(String, T)>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a map of values by field path")
+            }
+
+            fn visit_map<A: de::MapAccess<'de>>(
+                self,
+                mut map: A,
+            ) -> std::result::Result<Self::Value, A::Error> {
+                let mut pairs = Vec::new();
+                while let Some(pair) = map.next_entry()? {
+                    pairs.push(pair);
+                }
+                Ok(pairs)
+            }
+        }
+
+        d.deserialize_map(Pairs(PhantomData))
+    }
+}
+
+/// Sizes in bytes, a number or a string with a unit suffix such as 64MiB.
+pub(crate) mod bytes {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(n: &usize, s: S) -> std::result::Result<S::Ok, S::Error> {
+        s.serialize_u64(*n as u64)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> std::result::Result<usize, D::Error> {
+        parse(NumberOrString::deserialize(d)?).map_err(de::Error::custom)
+    }
+
+    pub(super) fn parse(value: NumberOrString) -> Result<usize> {
+        match value {
+            NumberOrString::Number(n) => Ok(usize::try_from(n)?),
+            NumberOrString::String(s) => crate::parse_bytes(&s),
+        }
+    }
+
+    pub(crate) mod option {
+        use super::*;
+
+        pub fn serialize<S: Serializer>(
+            n: &Option<usize>,
+            s: S,
+        ) -> std::result::Result<S::Ok, S::Error> {
+            n.map(|n| n as u64).serialize(s)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            d: D,
+        ) -> std::result::Result<Option<usize>, D::Error> {
+            Option::deserialize(d)?
+                .map(parse)
+                .transpose()
+                .map_err(de::Error::custom)
+        }
+    }
+}
+
+/// The row group size, a number of rows or a string of bytes such as 64MiB.
+pub(crate) mod row_group_size {
+    use super::*;
+    use crate::RowGroupSize;
+
+    pub fn serialize<S: Serializer>(
+        size: &Option<RowGroupSize>,
+        s: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        match size {
+            Some(RowGroupSize::Rows(n)) => s.serialize_some(n),
+            Some(RowGroupSize::Bytes(n)) => s.serialize_some(&format!("{n}B")),
+            None => s.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        d: D,
+    ) -> std::result::Result<Option<RowGroupSize>, D::Error> {
+        let size = match Option::deserialize(d)? {
+            Some(NumberOrString::Number(n)) => crate::parse_row_group_size(&n.to_string()),
+            Some(NumberOrString::String(s)) => crate::parse_row_group_size(&s),
+            None => return Ok(None),
+        };
+        size.map(Some).map_err(de::Error::custom)
+    }
+}
+
+/// A duration, seconds or a string with a unit suffix such as 30m.
+pub(crate) mod duration {
+    use super::*;
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(
+        duration: &Option<Duration>,
+        s: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        match duration.map(|d| d.as_millis()) {
+            Some(ms) if ms % 1000 == 0 => s.serialize_some(&format!("{}s", ms / 1000)),
+            Some(ms) => s.serialize_some(&format!("{ms}ms")),
+            None => s.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        d: D,
+    ) -> std::result::Result<Option<Duration>, D::Error> {
+        let duration = match Option::deserialize(d)? {
+            Some(NumberOrString::Number(n)) => Ok(Duration::from_secs(n)),
+            Some(NumberOrString::String(s)) => crate::parse_duration(&s),
+            None => return Ok(None),
+        };
+        duration.map(Some).map_err(de::Error::custom)
+    }
+}
+
+/// CIDs as strings.
+pub(crate) mod cids {
+    use super::*;
+    use libipld::Cid;
+
+    pub fn serialize<S: Serializer>(cids: &[Cid], s: S) -> std::result::Result<S::Ok, S::Error> {
+        s.collect_seq(cids.iter().map(|cid| cid.to_string()))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> std::result::Result<Vec<Cid>, D::Error> {
+        Vec::<String>::deserialize(d)?
+            .iter()
+            .map(|cid| cid.parse().map_err(de::Error::custom))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use clap::ValueEnum;
+
+    use super::*;
+    use crate::{adl::Adl, preset::Preset, utf8::Utf8Policy};
+    use crate::{Compression, Duplicates, Format, IntOverflow, LinkForm, LinkListRepr, Order};
+
+    fn options(json: &str) -> Result<ConvertArgs> {
+        serde_json::from_str::<ConvertOptions>(json)?.to_args()
+    }
+
+    #[test]
+    fn keys_are_the_long_flags() {
+        let keys: BTreeSet<String> = serde_json::to_value(ConvertArgs::default())
+            .unwrap()
+            .as_object()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect();
+        let command = ConvertArgs::augment_args(clap::Command::new("convert"));
+        let flags: BTreeSet<String> = command
+            .get_arguments()
+            .filter_map(|arg| arg.get_long())
+            .filter(|flag| *flag != "options")
+            .map(String::from)
+            .collect();
+        assert_eq!(keys, flags);
+    }
+
+    fn names_match<T: ValueEnum + Serialize>() {
+        for value in T::value_variants() {
+            let name = value.to_possible_value().unwrap().get_name().to_string();
+            assert_eq!(serde_json::to_value(value).unwrap(), Value::String(name));
+        }
+    }
+
+    #[test]
+    fn values_are_the_flag_values() {
+        names_match::<LinkListRepr>();
+        names_match::<LinkForm>();
+        names_match::<Duplicates>();
+        names_match::<IntOverflow>();
+        names_match::<Order>();
+        names_match::<Compression>();
+        names_match::<Format>();
+        names_match::<Utf8Policy>();
+        names_match::<Adl>();
+        names_match::<Preset>();
+    }
+
+    #[test]
+    fn defaults_serialize_empty() {
+        let json = serde_json::to_string(&ConvertOptions::new()).unwrap();
+        assert_eq!(json, "{}");
+        assert_eq!(
+            format!("{:?}", options("{}").unwrap()),
+            format!("{:?}", ConvertArgs::default())
+        );
+    }
+
+    #[test]
+    fn matches_the_flags() {
+        let json = r#"{
+            "format": "jsonl",
+            "compression": "none",
+            "link-list": {"data.prev": "list"},
+            "type-hint": {"data.createdAt": "timestamp_ms"},
+            "dictionary": {"data.sig": false},
+            "block-cache-size": "64MiB",
+            "max-output-bytes": 1000,
+            "row-group-size": 5000,
+            "max-runtime": "30m",
+            "root-cids": true,
+            "root": ["bafyreihdwdcefgh4dqkjv67uzcmw7ojee6xedzdetojuzjevtenxquvyku"],
+            "column-order": ["cid", "data.height"],
+            "verbose": 2
+        }"#;
+        let flags = ConvertArgs::from_flags([
+            "--format",
+            "jsonl",
+            "--compression",
+            "none",
+            "--link-list",
+            "data.prev=list",
+            "--type-hint",
+            "data.createdAt=timestamp_ms",
+            "--dictionary",
+            "data.sig=off",
+            "--block-cache-size",
+            "64MiB",
+            "--max-output-bytes",
+            "1000",
+            "--row-group-size",
+            "5000",
+            "--max-runtime",
+            "30m",
+            "--root-cids",
+            "--root",
+            "bafyreihdwdcefgh4dqkjv67uzcmw7ojee6xedzdetojuzjevtenxquvyku",
+            "--column-order",
+            "cid,data.height",
+            "-vv",
+        ])
+        .unwrap();
+        assert_eq!(
+            format!("{:?}", options(json).unwrap()),
+            format!("{:?}", flags)
+        );
+        // The options written read back the same.
+        let written = serde_json::to_string(&ConvertOptions {
+            args: flags.clone(),
+        })
+        .unwrap();
+        assert_eq!(
+            format!("{:?}", options(&written).unwrap()),
+            format!("{:?}", flags)
+        );
+    }
+
+    #[test]
+    fn rejects() {
+        for json in [
+            r#"{"no-such-flag": true}"#,
+            r#"{"format": "csv"}"#,
+            r#"{"int-type": ["data.height=int32"]}"#,
+            r#"{"int-type": {"data.height": "int33"}}"#,
+            r#"{"type-hint": {"data.height": "int32"}}"#,
+            r#"{"block-cache-size": "64 parsecs"}"#,
+            r#"{"row-group-size": 0}"#,
+            r#"{"partition-by": "cid"}"#,
+            r#"{"on-complete-webhook": "https://example.com"}"#,
+            r#"{"webhook-header": ["no colon"]}"#,
+            r#"{"root": ["bafyreihdwdcefgh4dqkjv67uzcmw7ojee6xedzdetojuzjevtenxquvyku"]}"#,
+            r#"{"quiet": true, "verbose": 1}"#,
+            r#"{"stream": true, "schema-cache": "schemas.json"}"#,
+            r#"{"explode-cids": true, "cid-strings": true}"#,
+        ] {
+            assert!(options(json).is_err(), "{json}");
+        }
+    }
+
+    #[test]
+    fn builds() {
+        let options = ConvertOptions::new()
+            .set("max-blocks", 10)
+            .unwrap()
+            .add("explode", "data.entries")
+            .unwrap()
+            .insert("dictionary", "data.sig", false)
+            .unwrap();
+        assert_eq!(
+            serde_json::to_value(&options).unwrap(),
+            serde_json::json!({
+                "max-blocks": 10,
+                "explode": ["data.entries"],
+                "dictionary": {"data.sig": false}
+            })
+        );
+        assert!(ConvertOptions::new().set("format", "csv").is_err());
+        assert!(ConvertOptions::new().set("no-such-flag", 1).is_err());
+        assert!(ConvertOptions::new().add("format", "jsonl").is_err());
+        assert!(ConvertOptions::new()
+            .insert("explode", "data", "x")
+            .is_err());
+        // Conflicts are reported once the options are complete.
+        let conflicting = ConvertOptions::new()
+            .enable("stream")
+            .unwrap()
+            .enable("merge-schemas")
+            .unwrap();
+        assert!(conflicting.to_args().is_err());
+    }
+
+    #[test]
+    fn flags_win_over_the_file() {
+        let file = serde_json::from_str::<ConvertOptions>(
+            r#"{"format": "jsonl", "explode": ["data.entries"], "max-blocks": 10}"#,
+        )
+        .unwrap();
+        let flags = ConvertArgs::from_flags(["--max-blocks", "5", "--lenient"]).unwrap();
+        let args = merge(file, &flags).unwrap();
+        let expected = ConvertArgs::from_flags([
+            "--format",
+            "jsonl",
+            "--explode",
+            "data.entries",
+            "--max-blocks",
+            "5",
+            "--lenient",
+        ])
+        .unwrap();
+        assert_eq!(format!("{:?}", args), format!("{:?}", expected));
+        // Conflicts between the file and the flags are caught.
+        let file = serde_json::from_str::<ConvertOptions>(r#"{"stream": true}"#).unwrap();
+        let flags = ConvertArgs::from_flags(["--merge-schemas"]).unwrap();
+        assert!(merge(file, &flags).is_err());
+    }
+}
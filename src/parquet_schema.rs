@@ -0,0 +1,232 @@
+//! The parquet schema of a [`Schema`](crate::Schema), its columns laid out
+//! by the options.
+
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::Result;
+use parquet::{basic::Repetition, schema::types::Type};
+
+use crate::{
+    field_path, is_exploded_list, writer::order_fields, Config, Error, IntType, LinkListRepr,
+    Schema, TimeUnit,
+};
+
+/// Repetition of a primitive column. Optional fields are nullable, as are value
+/// columns in lenient conversion so values that fail to convert can be dropped.
+pub fn leaf_repetition(repeated: bool, optional: bool, path: &str, config: &Config) -> Repetition {
+    if repeated {
+        Repetition::REPEATED
+    } else if optional || (config.lenient && path != "cid" && path != "parent_cid") {
+        Repetition::OPTIONAL
+    } else {
+        Repetition::REQUIRED
+    }
+}
+
+pub fn parquet_schema(
+    schema: &Schema,
+    name: &str,
+    path: &str,
+    repeated: bool,
+    optional: bool,
+    config: &Config,
+    ints: &HashMap<String, IntType>,
+) -> Result<Type, Error> {
+    let invalid = |e: parquet::errors::ParquetError| Error::Schema {
+        path: path.to_string(),
+        reason: e.to_string(),
+    };
+    // Strings of times are written as the type hinted or inferred for them.
+    let schema = match schema {
+        Schema::String if ints.get(path).is_some_and(IntType::is_time) => &Schema::Integer,
+        schema => schema,
+    };
+    match schema {
+        // Empty lists are written without values, as lists of booleans.
+        Schema::Null if repeated => {
+            Type::primitive_type_builder(name, parquet::basic::Type::BOOLEAN)
+                .with_repetition(Repetition::REPEATED)
+                .build()
+                .map_err(invalid)
+        }
+        // Null fields have no values to store, only definition levels, so they
+        // are optional columns marked as always null.
+        Schema::Null => Type::primitive_type_builder(name, parquet::basic::Type::INT32)
+            .with_repetition(Repetition::OPTIONAL)
+            .with_logical_type(Some(parquet::basic::LogicalType::Unknown))
+            .build()
+            .map_err(invalid),
+
+        Schema::Bool => Type::primitive_type_builder(name, parquet::basic::Type::BOOLEAN)
+            .with_repetition(leaf_repetition(repeated, optional, path, config))
+            .build()
+            .map_err(invalid),
+
+        Schema::Integer => {
+            let int_type = ints.get(path).copied().unwrap_or_default();
+            let builder =
+                Type::primitive_type_builder(name, int_type.physical_type(config.legacy_int96))
+                    .with_repetition(leaf_repetition(repeated, optional, path, config));
+            match int_type {
+                // INT96 predates logical types, readers know it as a timestamp.
+                IntType::Timestamp { .. } if config.legacy_int96 => builder,
+                IntType::Timestamp { unit } => {
+                    let unit = match IntType::stored_unit(unit) {
+                        TimeUnit::Micros => {
+                            parquet::basic::TimeUnit::MICROS(parquet::format::MicroSeconds {})
+                        }
+                        TimeUnit::Nanos => {
+                            parquet::basic::TimeUnit::NANOS(parquet::format::NanoSeconds {})
+                        }
+                        _ => parquet::basic::TimeUnit::MILLIS(parquet::format::MilliSeconds {}),
+                    };
+                    builder.with_logical_type(Some(parquet::basic::LogicalType::Timestamp {
+                        is_adjusted_to_u_t_c: true,
+                        unit,
+                    }))
+                }
+                IntType::Int { bits, signed } => {
+                    builder.with_logical_type(Some(parquet::basic::LogicalType::Integer {
+                        bit_width: bits as i8,
+                        is_signed: signed,
+                    }))
+                }
+                IntType::Decimal { precision, scale } => builder
+                    .with_logical_type(Some(parquet::basic::LogicalType::Decimal {
+                        scale: scale as i32,
+                        precision: precision as i32,
+                    }))
+                    .with_precision(precision as i32)
+                    .with_scale(scale as i32)
+                    .with_length(int_type.byte_len() as i32),
+                IntType::Date => builder.with_logical_type(Some(parquet::basic::LogicalType::Date)),
+            }
+            .build()
+            .map_err(invalid)
+        }
+        Schema::Float => Type::primitive_type_builder(name, parquet::basic::Type::DOUBLE)
+            .with_repetition(leaf_repetition(repeated, optional, path, config))
+            .build()
+            .map_err(invalid),
+
+        Schema::String => Type::primitive_type_builder(name, parquet::basic::Type::BYTE_ARRAY)
+            .with_repetition(leaf_repetition(repeated, optional, path, config))
+            .with_converted_type(parquet::basic::ConvertedType::UTF8)
+            .build()
+            .map_err(invalid),
+        // The CID columns are bytes in the schemas and written as links.
+        Schema::Link | Schema::Bytes
+            if config.cid_strings
+                && (*schema == Schema::Link || path == "cid" || path == "parent_cid") =>
+        {
+            Type::primitive_type_builder(name, parquet::basic::Type::BYTE_ARRAY)
+                .with_repetition(leaf_repetition(repeated, optional, path, config))
+                .with_converted_type(parquet::basic::ConvertedType::UTF8)
+                .build()
+                .map_err(invalid)
+        }
+        Schema::Bytes => Type::primitive_type_builder(name, parquet::basic::Type::BYTE_ARRAY)
+            .with_repetition(leaf_repetition(repeated, optional, path, config))
+            .build()
+            .map_err(invalid),
+        Schema::Link => Type::primitive_type_builder(name, parquet::basic::Type::BYTE_ARRAY)
+            .with_repetition(leaf_repetition(repeated, optional, path, config))
+            .build()
+            .map_err(invalid),
+
+        // Lists of lists, at every level, lists with null elements and lists
+        // of links written as lists are LIST annotated groups, other lists
+        // are repeated fields.
+        Schema::List(l)
+            if matches!(**l, Schema::List(_) | Schema::Optional(_))
+                || (**l == Schema::Link && config.link_list_repr(path) == LinkListRepr::List) =>
+        {
+            let repetition = if optional {
+                Repetition::OPTIONAL
+            } else {
+                Repetition::REQUIRED
+            };
+            list_group(name, list_element(l, path, config, ints)?, repetition).map_err(invalid)
+        }
+        Schema::List(l) => parquet_schema(l, name, path, true, false, config, ints),
+        Schema::Map(m) => {
+            let mut fields = m
+                .iter()
+                .map(|(k, v)| (field_path(path, k), k, v))
+                .filter(|(path, _, v)| !is_exploded_list(v, path, config))
+                .map(|(path, k, v)| {
+                    let field = parquet_schema(v, k, &path, false, false, config, ints)?;
+                    Ok((path, Arc::new(field)))
+                })
+                .collect::<Result<Vec<_>, Error>>()?;
+            order_fields(&mut fields, config);
+            let mut fields: Vec<_> = fields.into_iter().map(|(_, f)| f).collect();
+            if fields.is_empty() {
+                return Err(Error::Schema {
+                    path: path.to_string(),
+                    reason: "the map has no fields besides exploded lists".to_string(),
+                });
+            }
+            Type::group_type_builder(name)
+                .with_repetition(if repeated {
+                    Repetition::REPEATED
+                } else if optional {
+                    Repetition::OPTIONAL
+                } else {
+                    Repetition::REQUIRED
+                })
+                .with_fields(&mut fields)
+                .build()
+                .map_err(invalid)
+        }
+        Schema::Optional(s) => parquet_schema(s, name, path, repeated, true, config, ints),
+        Schema::Json => Type::primitive_type_builder(name, parquet::basic::Type::BYTE_ARRAY)
+            .with_repetition(leaf_repetition(repeated, optional, path, config))
+            .with_logical_type(Some(parquet::basic::LogicalType::Json))
+            .build()
+            .map_err(invalid),
+    }
+}
+
+/// Element of a LIST annotated group for elements of the schema.
+pub fn list_element(
+    schema: &Schema,
+    path: &str,
+    config: &Config,
+    ints: &HashMap<String, IntType>,
+) -> Result<Type, Error> {
+    let (list, repetition) = match schema {
+        Schema::List(l) => (l, Repetition::REQUIRED),
+        Schema::Optional(s) => match &**s {
+            Schema::List(l) => (l, Repetition::OPTIONAL),
+            _ => return parquet_schema(schema, "element", path, false, false, config, ints),
+        },
+        _ => return parquet_schema(schema, "element", path, false, false, config, ints),
+    };
+    list_group(
+        "element",
+        list_element(list, path, config, ints)?,
+        repetition,
+    )
+    .map_err(|e| Error::Schema {
+        path: path.to_string(),
+        reason: e.to_string(),
+    })
+}
+
+/// A LIST annotated group, the standard three levels of a list.
+pub fn list_group(
+    name: &str,
+    element: Type,
+    repetition: Repetition,
+) -> Result<Type, parquet::errors::ParquetError> {
+    let list = Type::group_type_builder("list")
+        .with_repetition(Repetition::REPEATED)
+        .with_fields(&mut vec![Arc::new(element)])
+        .build()?;
+    Type::group_type_builder(name)
+        .with_repetition(repetition)
+        .with_logical_type(Some(parquet::basic::LogicalType::List))
+        .with_fields(&mut vec![Arc::new(list)])
+        .build()
+}
@@ -0,0 +1,143 @@
+//! Pinned schemas, fixing the output files and columns across runs.
+//!
+//! A pins file lists named schemas, usually written by a previous run with
+//! `--write-pins`. Each block must fit one of them, possibly after widening:
+//!
+//! * an integer widens to a float,
+//! * a link fits bytes, as which it is stored, and a string, as which it is
+//!   written as with `--cid-strings`, as does the CID column,
+//! * an empty list fits a list of any element type,
+//! * a field of a merged schema that is optional may be missing,
+//! * otherwise the kinds and the keys of maps must match exactly.
+//!
+//! Blocks are assigned to the pinned schema they match exactly, or else to
+//! the first one they fit.
+
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use libipld::Ipld;
+use serde::{Deserialize, Serialize};
+
+use crate::{field_path, Schema};
+
+/// The schemas of a pins file, each the schema of the rows of a block table.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Pins {
+    pub schemas: Vec<Pin>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Pin {
+    /// File name of the schema without extension.
+    pub name: String,
+    pub schema: Schema,
+}
+
+impl Pins {
+    pub fn load(path: &Path) -> Result<Self> {
+        let f = std::fs::File::open(path).context(format!("opening {}", path.display()))?;
+        serde_json::from_reader(std::io::BufReader::new(f))
+            .context(format!("parsing {}", path.display()))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let f = std::fs::File::create(path).context(format!("creating {}", path.display()))?;
+        serde_json::to_writer_pretty(f, self)?;
+        Ok(())
+    }
+
+    /// Index of the pinned schema the inferred schema maps to.
+    pub fn find(&self, schema: &Schema) -> Result<usize> {
+        self.schemas
+            .iter()
+            .position(|p| p.schema == *schema)
+            .or_else(|| self.schemas.iter().position(|p| fits(schema, &p.schema)))
+            .ok_or_else(|| anyhow!("schema does not fit any pinned schema: {:?}", schema))
+    }
+}
+
+/// Reports whether values of the inferred schema can be written as the pinned one.
+pub fn fits(schema: &Schema, pinned: &Schema) -> bool {
+    mismatch(schema, pinned, "").is_none()
+}
+
+/// Describes the first difference that stops the inferred schema fitting the
+/// pinned one, naming fields by their path below `path`.
+pub fn mismatch(schema: &Schema, pinned: &Schema, path: &str) -> Option<String> {
+    match (schema, pinned) {
+        (Schema::Integer, Schema::Float)
+        | (Schema::Link, Schema::Bytes)
+        | (Schema::Link, Schema::String) => None,
+        // The CID columns are bytes in the schemas and written as links.
+        (Schema::Bytes, Schema::String) if path == "cid" || path == "parent_cid" => None,
+        (Schema::List(l), Schema::List(_)) if **l == Schema::Null => None,
+        (Schema::List(l), Schema::List(p)) => mismatch(l, p, path),
+        (_, Schema::Json) => None,
+        (Schema::Optional(s), Schema::Optional(p)) => mismatch(s, p, path),
+        (_, Schema::Optional(p)) => mismatch(schema, p, path),
+        (Schema::Map(m), Schema::Map(p)) => {
+            // Columns are resolved by name, so the order of the fields does not matter.
+            if let Some((k, _)) = p.iter().find(|(k, v)| {
+                !matches!(v, Schema::Optional(_)) && !m.iter().any(|(mk, _)| mk == k)
+            }) {
+                return Some(format!("{} is missing", field_path(path, k)));
+            }
+            m.iter().find_map(|(k, v)| {
+                let path = field_path(path, k);
+                match p.iter().find(|(pk, _)| pk == k) {
+                    Some((_, pv)) => mismatch(v, pv, &path),
+                    None => Some(format!("{} is not expected", path)),
+                }
+            })
+        }
+        _ if schema == pinned => None,
+        _ => Some(format!(
+            "{} is {} but expected {}",
+            if path.is_empty() { "the block" } else { path },
+            kind(schema),
+            kind(pinned)
+        )),
+    }
+}
+
+fn kind(schema: &Schema) -> &'static str {
+    match schema {
+        Schema::Null => "null",
+        Schema::Bool => "a bool",
+        Schema::Integer => "an integer",
+        Schema::Float => "a float",
+        Schema::String => "a string",
+        Schema::Bytes => "bytes",
+        Schema::Link => "a link",
+        Schema::List(_) => "a list",
+        Schema::Map(_) => "a map",
+        Schema::Optional(s) => kind(s),
+        Schema::Json => "a mix of kinds",
+    }
+}
+
+/// Converts the value to the representation of the pinned schema it fits.
+pub fn coerce(data: &mut Ipld, pinned: &Schema) {
+    match (data, pinned) {
+        (data, Schema::Optional(p)) => coerce(data, p),
+        (data @ Ipld::Integer(_), Schema::Float) => {
+            if let Ipld::Integer(i) = *data {
+                *data = Ipld::Float(i as f64);
+            }
+        }
+        (Ipld::List(l), Schema::List(p)) => {
+            for v in l {
+                coerce(v, p);
+            }
+        }
+        (Ipld::Map(m), Schema::Map(p)) => {
+            for (k, s) in p {
+                if let Some(v) = m.get_mut(k) {
+                    coerce(v, s);
+                }
+            }
+        }
+        _ => {}
+    }
+}
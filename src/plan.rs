@@ -0,0 +1,222 @@
+//! Splitting a set of CAR files into balanced work units for separate workers.
+//!
+//! A plan assigns contiguous ranges of blocks to tasks so each task reads
+//! roughly the same number of bytes. Every task is an independent conversion
+//! and can run on another machine with `carquet run-plan --task N`, given the
+//! same paths to the inputs.
+//!
+//! The sizes of the sections are taken from the index of a fully indexed
+//! CARv2, or else by scanning the file. Each unit records the offset of its
+//! first section, so a task seeks to its range instead of reading the blocks
+//! before it.
+
+use std::{
+    fs::File,
+    io::{BufReader, Take},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    carv2,
+    scan::{self, Sections},
+};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Plan {
+    pub tasks: Vec<Task>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Task {
+    pub units: Vec<Unit>,
+}
+
+/// A range of blocks within one CAR file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Unit {
+    pub path: PathBuf,
+    /// Index of the first block of the range.
+    pub first_block: usize,
+    /// Number of blocks in the range.
+    pub blocks: usize,
+    /// Number of bytes of the sections of the range.
+    pub bytes: u64,
+    /// Offset within the CARv1 of the section of the first block, `None` to
+    /// find it by skipping the blocks before it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offset: Option<u64>,
+}
+
+impl Unit {
+    /// All blocks of the file.
+    pub fn whole(path: &Path) -> Self {
+        Unit {
+            path: path.to_path_buf(),
+            first_block: 0,
+            blocks: usize::MAX,
+            bytes: 0,
+            offset: None,
+        }
+    }
+
+    /// Opens the sections of the file at the first block of the unit.
+    pub fn open(&self) -> Result<Sections<BufReader<Take<File>>>> {
+        let Some(offset) = self.offset else {
+            let mut car = carv2::open_blocks(&self.path)?;
+            for _ in 0..self.first_block {
+                let skipped = car
+                    .next_section()
+                    .context(format!("reading {}", self.path.display()))?;
+                if skipped.is_none() {
+                    break;
+                }
+            }
+            return Ok(car);
+        };
+        let f = carv2::open(&self.path, offset)?;
+        Ok(Sections::resume(
+            BufReader::with_capacity(scan::BUFFER, f),
+            offset,
+        ))
+    }
+
+    /// Whether the block at index `i` of the file is within the unit.
+    pub fn contains(&self, i: usize) -> bool {
+        i >= self.first_block && i - self.first_block < self.blocks
+    }
+}
+
+impl Plan {
+    pub fn load(path: &Path) -> Result<Self> {
+        let f = std::fs::File::open(path).context(format!("opening {}", path.display()))?;
+        serde_json::from_reader(std::io::BufReader::new(f))
+            .context(format!("parsing {}", path.display()))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let f = std::fs::File::create(path).context(format!("creating {}", path.display()))?;
+        serde_json::to_writer_pretty(f, self)?;
+        Ok(())
+    }
+
+    /// Scans the inputs and splits their blocks into `tasks` tasks of about
+    /// the same number of bytes. Tasks may be empty if there are fewer blocks.
+    pub async fn new(inputs: &[PathBuf], tasks: usize) -> Result<Self> {
+        if tasks == 0 {
+            return Err(anyhow!("a plan needs at least one task"));
+        }
+        // Offsets and sizes of the sections of each input, read once so the
+        // split can be balanced.
+        let mut sizes = Vec::with_capacity(inputs.len());
+        for path in inputs {
+            sizes.push(sections(path).context(format!("reading {}", path.display()))?);
+        }
+        let total: u64 = sizes.iter().flatten().map(|(_, size)| size).sum();
+        let target = total.div_ceil(tasks as u64).max(1);
+
+        let mut plan = Plan {
+            tasks: (0..tasks).map(|_| Task::default()).collect(),
+        };
+        let mut task = 0;
+        let mut task_bytes = 0;
+        for (path, file_sizes) in inputs.iter().zip(sizes) {
+            let mut unit: Option<Unit> = None;
+            for (i, (offset, size)) in file_sizes.into_iter().enumerate() {
+                if task_bytes >= target && task + 1 < tasks {
+                    if let Some(unit) = unit.take() {
+                        plan.tasks[task].units.push(unit);
+                    }
+                    task += 1;
+                    task_bytes = 0;
+                }
+                let unit = unit.get_or_insert_with(|| Unit {
+                    path: path.clone(),
+                    first_block: i,
+                    blocks: 0,
+                    bytes: 0,
+                    offset: Some(offset),
+                });
+                unit.blocks += 1;
+                unit.bytes += size;
+                task_bytes += size;
+            }
+            if let Some(unit) = unit {
+                plan.tasks[task].units.push(unit);
+            }
+        }
+        Ok(plan)
+    }
+}
+
+/// The offset within the CARv1 and the length of each section of the file.
+fn sections(path: &Path) -> Result<Vec<(u64, u64)>> {
+    if let Some(mut index) = carv2::Index::open(path)? {
+        if index.fully_indexed() {
+            return index.sections();
+        }
+    }
+    let mut car = carv2::open_blocks(path)?;
+    let mut sections = Vec::new();
+    loop {
+        let offset = car.position();
+        if car.next_section()?.is_none() {
+            return Ok(sections);
+        }
+        sections.push((offset, car.position() - offset));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A copy of the fixture wrapped as a fully indexed CARv2.
+    fn wrapped(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("carquet-plan-{}-{}.car", std::process::id(), name));
+        let fixture = Path::new(env!("CARGO_MANIFEST_DIR")).join(format!("fixtures/{}.car", name));
+        std::fs::copy(fixture, &path).unwrap();
+        carv2::wrap(&path).unwrap();
+        path
+    }
+
+    #[test]
+    fn index_sections_match_scan() {
+        let fixture = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/generic.car");
+        let path = wrapped("generic");
+        let mut index = carv2::Index::open(&path).unwrap().unwrap();
+        assert!(index.fully_indexed());
+        assert_eq!(index.sections().unwrap(), sections(&fixture).unwrap());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn units_open_at_their_first_block() {
+        let path = wrapped("unixfs");
+        let mut cids = Vec::new();
+        let mut car = carv2::open_blocks(&path).unwrap();
+        while let Some((cid, _)) = car.next_block().unwrap() {
+            cids.push(cid);
+        }
+        let plan = Plan::new(std::slice::from_ref(&path), 3).await.unwrap();
+        let units: Vec<&Unit> = plan.tasks.iter().flat_map(|t| &t.units).collect();
+        assert_eq!(units.iter().map(|u| u.blocks).sum::<usize>(), cids.len());
+        for unit in units {
+            assert!(unit.offset.is_some());
+            let mut car = unit.open().unwrap();
+            let (cid, _) = car.next_block().unwrap().unwrap();
+            assert_eq!(cid, cids[unit.first_block]);
+            // Without an offset the blocks before the unit are skipped.
+            let unit = Unit {
+                offset: None,
+                ..unit.clone()
+            };
+            let (cid, _) = unit.open().unwrap().next_block().unwrap().unwrap();
+            assert_eq!(cid, cids[unit.first_block]);
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,97 @@
+//! Defaults of the conversion options for the blocks of common ecosystems,
+//! chosen with `--preset`.
+//!
+//! A preset only fills in options: the file name prefix is its name unless
+//! `--schema-prefix` is given, fields given a form by `--link-as` keep it and
+//! the lists and flags of the preset are added to those given.
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::{ConvertArgs, LinkForm};
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Preset {
+    /// Ceramic streams: events and their dag-jose envelopes, with the stream
+    /// `id` and the `prev` event as CID strings.
+    Ceramic,
+    /// AT Protocol repositories: commits with their links as CID strings and
+    /// the entries of the MST nodes exploded into a table of their own.
+    Atproto,
+    /// Filecoin chain snapshots: headers, messages and receipts with the codec
+    /// and multihash of each link, as CIDs mix codecs and blake2b hashes.
+    /// Their tuples mix kinds and are written as JSON, any values that still
+    /// do not fit as null.
+    Filecoin,
+    /// UnixFS trees: dag-pb nodes with their links exploded into a table of
+    /// their own and the codec of each link, raw leaves go to raw_blocks.
+    Unixfs,
+    /// No defaults beyond those of the flags, for blocks of any other shape.
+    Generic,
+}
+
+/// Options filled in by a preset.
+struct Defaults {
+    link_forms: &'static [&'static str],
+    explode: &'static [&'static str],
+    link_codecs: bool,
+    lenient: bool,
+}
+
+impl Preset {
+    fn defaults(self) -> Defaults {
+        match self {
+            Preset::Ceramic => Defaults {
+                link_forms: &["data.id", "data.prev"],
+                explode: &[],
+                link_codecs: false,
+                lenient: false,
+            },
+            Preset::Atproto => Defaults {
+                link_forms: &["data.data", "data.prev", "data.l"],
+                explode: &["data.e"],
+                link_codecs: false,
+                lenient: false,
+            },
+            Preset::Filecoin => Defaults {
+                link_forms: &[],
+                explode: &[],
+                link_codecs: true,
+                lenient: true,
+            },
+            Preset::Unixfs => Defaults {
+                link_forms: &[],
+                explode: &["data.Links"],
+                link_codecs: true,
+                lenient: false,
+            },
+            Preset::Generic => Defaults {
+                link_forms: &[],
+                explode: &[],
+                link_codecs: false,
+                lenient: false,
+            },
+        }
+    }
+
+    /// Fills in the options not given with the defaults of the preset.
+    pub(crate) fn apply(self, args: &mut ConvertArgs) {
+        let defaults = self.defaults();
+        if args.schema_prefix.is_none() && self != Preset::Generic {
+            args.schema_prefix = self.to_possible_value().map(|v| v.get_name().to_string());
+        }
+        for path in defaults.link_forms {
+            if !args.link_forms.iter().any(|(p, _)| p == path) {
+                args.link_forms.push((path.to_string(), LinkForm::String));
+            }
+        }
+        for path in defaults.explode {
+            if !args.explode.iter().any(|p| p == path) {
+                args.explode.push(path.to_string());
+            }
+        }
+        args.link_codecs |= defaults.link_codecs;
+        args.lenient |= defaults.lenient;
+    }
+}
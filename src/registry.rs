@@ -0,0 +1,117 @@
+//! Stable numbers of the schemas across runs, kept by `--schema-registry`.
+//!
+//! The registry file lists each schema seen by its fingerprint, the SHA-256
+//! of its JSON as in pins files, with the id it was given:
+//!
+//! ```json
+//! {"schemas": [{"id": 0, "fingerprint": "5d41402abc4b2a76b9719d911017c592.."}]}
+//! ```
+//!
+//! Schemas not listed get the next id and are added to the file once the run
+//! succeeds. Runs sharing a registry must not overlap, as each rewrites it.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use libipld::multihash::{Code, MultihashDigest};
+use serde::{Deserialize, Serialize};
+
+use crate::Schema;
+
+#[derive(Debug, Default)]
+pub struct Registry {
+    path: PathBuf,
+    file: RegistryFile,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RegistryFile {
+    schemas: Vec<Entry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    id: u64,
+    fingerprint: String,
+}
+
+impl Registry {
+    /// Loads the registry, empty if the file does not exist yet.
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = if path.exists() {
+            let f = std::fs::File::open(path).context(format!("opening {}", path.display()))?;
+            serde_json::from_reader(std::io::BufReader::new(f))
+                .context(format!("parsing {}", path.display()))?
+        } else {
+            RegistryFile::default()
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+        })
+    }
+
+    /// Id of the schema, giving it the next one if it is new.
+    pub fn id(&mut self, schema: &Schema) -> u64 {
+        let fingerprint = fingerprint(schema);
+        if let Some(e) = self
+            .file
+            .schemas
+            .iter()
+            .find(|e| e.fingerprint == fingerprint)
+        {
+            return e.id;
+        }
+        let id = self
+            .file
+            .schemas
+            .iter()
+            .map(|e| e.id + 1)
+            .max()
+            .unwrap_or(0);
+        self.file.schemas.push(Entry { id, fingerprint });
+        id
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let f = std::fs::File::create(&self.path)
+            .context(format!("creating {}", self.path.display()))?;
+        serde_json::to_writer_pretty(f, &self.file)?;
+        Ok(())
+    }
+}
+
+/// The SHA-256 of the JSON of the schema, in hex.
+pub(crate) fn fingerprint(schema: &Schema) -> String {
+    digest(&serde_json::to_vec(schema).expect("schemas serialize"))
+}
+
+/// The SHA-256 of the bytes in hex.
+pub(crate) fn digest(bytes: &[u8]) -> String {
+    Code::Sha2_256
+        .digest(bytes)
+        .digest()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// The fingerprint of the blocks of a table of the schema, that of the schema
+/// before the fields recording it are added to their data.
+pub(crate) fn table_fingerprint(schema: &Schema) -> String {
+    let Schema::Map(fields) = schema else {
+        return fingerprint(schema);
+    };
+    let fields = fields.iter().map(|(k, v)| match (k.as_str(), v) {
+        ("data", Schema::Map(data)) => {
+            let data = data
+                .iter()
+                .filter(|(k, _)| k != "_schema_id" && k != "_schema_fingerprint")
+                .cloned()
+                .collect();
+            (k.clone(), Schema::Map(data))
+        }
+        _ => (k.clone(), v.clone()),
+    });
+    fingerprint(&Schema::Map(fields.collect()))
+}
@@ -0,0 +1,146 @@
+//! Ordering the blocks of the CAR files the commands write, `--order`.
+//!
+//! A CAR is written in the order its blocks are found, then rewritten in
+//! place in the order asked for: by binary CID, or depth first from the roots
+//! of its header, each block before the blocks it links to in the order of
+//! the links, as verifiers of trustless gateway responses expect. Only the
+//! CIDs and offsets of the sections are held in memory, and the blocks are
+//! read again as the links are followed.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use libipld::{cbor::DagCborCodec, json::DagJsonCodec, pb::DagPbCodec, prelude::Codec, Cid, Ipld};
+
+use crate::scan::{self, Sections};
+
+/// Order of the blocks of a written CAR.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CarOrder {
+    /// The order the blocks are found in.
+    #[default]
+    Input,
+    /// Sorted by the binary CID.
+    Cid,
+    /// Depth first from the roots, blocks reachable from none after them.
+    DfsFromRoots,
+}
+
+/// Rewrites the CARv1 with its blocks in the order, returning the number of
+/// blocks not reachable from the roots, which a depth first order writes
+/// after the others in the order they were in.
+pub fn reorder(path: &Path, order: CarOrder) -> Result<usize> {
+    if order == CarOrder::Input {
+        return Ok(0);
+    }
+    let mut input = File::open(path).context(format!("opening {}", path.display()))?;
+    let mut car = Sections::new(BufReader::with_capacity(scan::BUFFER, &mut input))
+        .context(format!("reading {}", path.display()))?;
+    let header_len = car.position();
+    let mut sections = Vec::new();
+    loop {
+        let start = car.position();
+        let Some(section) = car.next_section()? else {
+            break;
+        };
+        sections.push((section.cid, start, car.position()));
+    }
+    // Those of all the headers, of concatenated CARs.
+    let roots = car.roots().to_vec();
+    drop(car);
+
+    let (order, unreachable) = match order {
+        CarOrder::Input => unreachable!("returned above"),
+        CarOrder::Cid => {
+            let mut order: Vec<usize> = (0..sections.len()).collect();
+            order.sort_by_cached_key(|i| sections[*i].0.to_bytes());
+            (order, 0)
+        }
+        CarOrder::DfsFromRoots => dfs(&mut input, &roots, &sections)?,
+    };
+
+    let tmp = PathBuf::from(format!("{}.tmp", path.display()));
+    let result = (|| {
+        let mut out =
+            BufWriter::new(File::create(&tmp).context(format!("creating {}", tmp.display()))?);
+        copy(&mut input, &mut out, 0, header_len)?;
+        for i in order {
+            let (_, start, end) = sections[i];
+            copy(&mut input, &mut out, start, end)?;
+        }
+        out.flush()?;
+        std::fs::rename(&tmp, path).context(format!("replacing {}", path.display()))
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    result.map(|()| unreachable)
+}
+
+/// The sections in depth first order from the roots, each CID at its first
+/// section, followed by the others, with the number of those.
+fn dfs(
+    input: &mut File,
+    roots: &[Cid],
+    sections: &[(Cid, u64, u64)],
+) -> Result<(Vec<usize>, usize)> {
+    let mut first = HashMap::with_capacity(sections.len());
+    for (i, (cid, _, _)) in sections.iter().enumerate() {
+        first.entry(*cid).or_insert(i);
+    }
+    let mut visited = vec![false; sections.len()];
+    let mut order = Vec::with_capacity(sections.len());
+    let mut stack: Vec<Cid> = roots.iter().rev().copied().collect();
+    while let Some(cid) = stack.pop() {
+        // Links to blocks the CAR does not hold are left out.
+        let Some(&i) = first.get(&cid) else {
+            continue;
+        };
+        if visited[i] {
+            continue;
+        }
+        visited[i] = true;
+        order.push(i);
+        let (_, start, end) = sections[i];
+        let links = links(input, &cid, start, end)?;
+        stack.extend(links.into_iter().rev());
+    }
+    let reached = order.len();
+    order.extend((0..sections.len()).filter(|i| !visited[*i]));
+    Ok((order, sections.len() - reached))
+}
+
+/// The links of the block of the section, in the order they are encoded.
+/// Blocks of codecs other than dag-pb and dag-json are read as dag-cbor, as
+/// the conversion decodes them, and raw blocks and blocks that do not decode
+/// have none.
+fn links(input: &mut File, cid: &Cid, start: u64, end: u64) -> Result<Vec<Cid>> {
+    input.seek(SeekFrom::Start(start))?;
+    let section = (&mut *input).take(end - start);
+    let Some((_, data)) = Sections::resume(BufReader::new(section), start).next_block()? else {
+        return Ok(Vec::new());
+    };
+    let mut links = Vec::new();
+    let found = match cid.codec() {
+        0x70 => DagPbCodec.references::<Ipld, _>(&data, &mut links),
+        0x0129 => DagJsonCodec.references::<Ipld, _>(&data, &mut links),
+        0x55 => Ok(()),
+        _ => DagCborCodec.references::<Ipld, _>(&data, &mut links),
+    };
+    if found.is_err() {
+        links.clear();
+    }
+    Ok(links)
+}
+
+fn copy(input: &mut File, out: &mut impl Write, start: u64, end: u64) -> Result<()> {
+    input.seek(SeekFrom::Start(start))?;
+    std::io::copy(&mut (&mut *input).take(end - start), out)?;
+    Ok(())
+}
@@ -0,0 +1,763 @@
+use std::{
+    collections::{btree_map::Entry, BTreeMap, HashMap},
+    fmt,
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+use libipld::{Cid, Ipld};
+use parquet::{
+    basic::Compression,
+    file::reader::{FileReader, SerializedFileReader},
+    schema::types::ColumnPath,
+};
+
+use crate::{
+    cid_index::CidIndex,
+    field_path, is_exploded_list,
+    manifest::Manifest,
+    shred::{column_types, option_path},
+    Block, Columns, Config, Limit, Schema, CONSTANTS_KEY,
+};
+
+/// Values of a file that could not be converted to the types of their columns,
+/// and the fields of constructs the file cannot represent.
+#[derive(Debug, Default)]
+pub(crate) struct Failures {
+    /// Number of rows with at least one value that failed.
+    pub(crate) rows: usize,
+    /// Failed values by column and reason.
+    groups: BTreeMap<(String, String), FailureGroup>,
+    /// Fields left out by path.
+    gaps: BTreeMap<String, CapabilityGap>,
+}
+
+/// A field of a construct the output cannot represent, left out of a file.
+#[derive(Debug, Clone)]
+pub struct CapabilityGap {
+    pub file: String,
+    /// Path of the field, e.g. `data.matrix`.
+    pub path: String,
+    /// The construct, e.g. `exploded list within a list`.
+    pub what: &'static str,
+    /// Number of blocks with the field.
+    pub blocks: usize,
+    /// The first few of the blocks.
+    pub examples: Vec<Cid>,
+}
+
+/// Blocks named as examples of a capability gap.
+const GAP_EXAMPLES: usize = 3;
+
+#[derive(Debug)]
+struct FailureGroup {
+    values: usize,
+    /// The first value if the reason leaves it out, and its row and block.
+    first: (Option<String>, usize, Cid),
+}
+
+impl Failures {
+    /// Records a value of the column at `path` that failed for `reason`, in
+    /// the row of the file and block `at`.
+    pub(crate) fn push(
+        &mut self,
+        path: &str,
+        reason: String,
+        value: Option<String>,
+        at: (usize, &Cid),
+    ) {
+        self.groups
+            .entry((path.to_string(), reason))
+            .or_insert_with(|| FailureGroup {
+                values: 0,
+                first: (value, at.0, *at.1),
+            })
+            .values += 1;
+    }
+
+    /// Records the rows as having the field at `path`, which is left out as
+    /// the file cannot represent it.
+    pub(crate) fn gap(&mut self, path: &str, what: &'static str, rows: &[Block]) {
+        let gap = self
+            .gaps
+            .entry(path.to_string())
+            .or_insert_with(|| CapabilityGap {
+                file: String::new(),
+                path: path.to_string(),
+                what,
+                blocks: 0,
+                examples: Vec::new(),
+            });
+        gap.blocks += rows.len();
+        let n = GAP_EXAMPLES.saturating_sub(gap.examples.len());
+        gap.examples
+            .extend(rows.iter().take(n).map(|(cid, _, _)| *cid));
+    }
+
+    pub(crate) fn append(&mut self, other: Failures) {
+        self.rows += other.rows;
+        for (path, other) in other.gaps {
+            match self.gaps.entry(path) {
+                Entry::Occupied(mut e) => {
+                    let gap = e.get_mut();
+                    gap.blocks += other.blocks;
+                    let n = GAP_EXAMPLES.saturating_sub(gap.examples.len());
+                    gap.examples.extend(other.examples.into_iter().take(n));
+                }
+                Entry::Vacant(e) => {
+                    e.insert(other);
+                }
+            }
+        }
+        for (key, group) in other.groups {
+            match self.groups.entry(key) {
+                Entry::Occupied(mut e) => e.get_mut().values += group.values,
+                Entry::Vacant(e) => {
+                    e.insert(group);
+                }
+            }
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+}
+
+/// Groups of failures listed per file before the rest are summarized.
+const MAX_FAILURE_GROUPS: usize = 5;
+
+/// One line per column and reason, e.g. `data.height: expected integer, got
+/// string, 3 values, first in row 7 of block bafy..`, or `data.height: integer
+/// out of range for uint8, 2 values, first 300 in row 4 of block bafy..`.
+impl fmt::Display for Failures {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for ((path, reason), group) in self.groups.iter().take(MAX_FAILURE_GROUPS) {
+            let (value, row, cid) = &group.first;
+            write!(f, "{}: {}, {} values, first ", path, reason, group.values)?;
+            if let Some(value) = value {
+                write!(f, "{} ", value)?;
+            }
+            writeln!(f, "in row {} of block {}", row, cid)?;
+        }
+        if self.groups.len() > MAX_FAILURE_GROUPS {
+            writeln!(
+                f,
+                "and {} more columns and reasons",
+                self.groups.len() - MAX_FAILURE_GROUPS
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Limits beyond which a record is considered to produce a bad parquet layout.
+#[derive(Debug, Clone, Copy)]
+pub struct Thresholds {
+    /// Number of leaf columns a single row may flatten into.
+    pub row_width: usize,
+    /// Number of elements a single list may contain.
+    pub list_len: usize,
+}
+
+/// Summary of a conversion run, printed once all files are written.
+#[derive(Debug)]
+pub struct Report {
+    /// Number of blocks skipped because an earlier block had identical bytes.
+    pub payload_aliases: usize,
+    /// Number of blocks with strings that were not valid UTF-8.
+    pub invalid_utf8: usize,
+    /// Number of rows with values nulled by lenient conversion.
+    pub conversion_errors: usize,
+    /// Number of copies of CIDs with different bytes skipped or flagged by --duplicates.
+    pub duplicate_conflicts: usize,
+    /// Number of raw blocks written to raw_blocks.
+    pub raw_blocks: usize,
+    /// Number of links inlined from a block in the block cache.
+    pub block_cache_hits: usize,
+    /// Number of links inlined by reading and decoding their block.
+    pub block_cache_misses: usize,
+    /// Number of schemas of blocks fitting none inferred from the sample of
+    /// --infer-sample.
+    pub unsampled_schemas: usize,
+    /// Number of shards left out for being part of the tree of another, by --adl.
+    pub adl_shards: usize,
+    /// Number of shards linked from another that the inputs lack.
+    pub adl_missing: usize,
+    /// Number of blocks skipped for being written by an earlier run, by --append.
+    pub appended_skipped: usize,
+    /// Number of copies of a CID skipped for an earlier copy, by --dedup-cids.
+    pub cid_copies: usize,
+    /// The limit that stopped the reading of blocks, if one did.
+    pub limit: Option<Limit>,
+    /// Number of rows of the tables left out once --max-output-bytes was reached.
+    pub rows_left_out: usize,
+    /// Number of output files completely written.
+    pub files_written: usize,
+    /// Whether the run was cancelled, leaving the report partial.
+    pub cancelled: bool,
+    /// Fields of each file moved to the footer metadata for having one value.
+    pub constant_columns: Vec<(String, Vec<String>)>,
+    /// Fields of constructs the files cannot represent, left out of them.
+    pub capability_gaps: Vec<CapabilityGap>,
+    /// Files written, for manifest.json.
+    pub manifest: Manifest,
+    /// CIDs of the row groups written, for cid_index.json.
+    pub cid_index: CidIndex,
+    thresholds: Thresholds,
+    conversion_failures: Vec<(String, Failures)>,
+    long_lists: HashMap<String, LongList>,
+    wide_schemas: Vec<WideSchema>,
+    size_advice: Vec<String>,
+}
+
+#[derive(Debug)]
+struct LongList {
+    records: usize,
+    max_len: usize,
+    example: Cid,
+    // Whether the list is nested inside another list and so cannot be exploded.
+    nested: bool,
+}
+
+#[derive(Debug)]
+struct WideSchema {
+    file: String,
+    records: usize,
+    width: usize,
+    widest: Vec<(String, usize)>,
+}
+
+impl Report {
+    pub(crate) fn new(thresholds: Thresholds) -> Self {
+        Self {
+            payload_aliases: 0,
+            invalid_utf8: 0,
+            conversion_errors: 0,
+            duplicate_conflicts: 0,
+            raw_blocks: 0,
+            block_cache_hits: 0,
+            block_cache_misses: 0,
+            unsampled_schemas: 0,
+            adl_shards: 0,
+            adl_missing: 0,
+            appended_skipped: 0,
+            cid_copies: 0,
+            limit: None,
+            rows_left_out: 0,
+            files_written: 0,
+            cancelled: false,
+            constant_columns: Vec::new(),
+            capability_gaps: Vec::new(),
+            manifest: Manifest::default(),
+            cid_index: CidIndex::default(),
+            thresholds,
+            conversion_failures: Vec::new(),
+            long_lists: HashMap::new(),
+            wide_schemas: Vec::new(),
+            size_advice: Vec::new(),
+        }
+    }
+
+    /// An empty report with the same thresholds, for a part of the conversion
+    /// run on its own and added back with [`Report::append`].
+    pub(crate) fn part(&self) -> Self {
+        Self::new(self.thresholds)
+    }
+
+    /// Adds what a part of the conversion wrote and found, after what this
+    /// report has.
+    pub(crate) fn append(&mut self, other: Report) {
+        self.payload_aliases += other.payload_aliases;
+        self.invalid_utf8 += other.invalid_utf8;
+        self.conversion_errors += other.conversion_errors;
+        self.duplicate_conflicts += other.duplicate_conflicts;
+        self.raw_blocks += other.raw_blocks;
+        self.block_cache_hits += other.block_cache_hits;
+        self.block_cache_misses += other.block_cache_misses;
+        self.unsampled_schemas += other.unsampled_schemas;
+        self.adl_shards += other.adl_shards;
+        self.adl_missing += other.adl_missing;
+        self.appended_skipped += other.appended_skipped;
+        self.cid_copies += other.cid_copies;
+        self.limit = self.limit.or(other.limit);
+        self.rows_left_out += other.rows_left_out;
+        self.files_written += other.files_written;
+        self.cancelled |= other.cancelled;
+        self.constant_columns.extend(other.constant_columns);
+        self.capability_gaps.extend(other.capability_gaps);
+        self.manifest.files.extend(other.manifest.files);
+        self.cid_index.append(other.cid_index);
+        self.conversion_failures.extend(other.conversion_failures);
+        for (path, other) in other.long_lists {
+            let list = self.long_lists.entry(path).or_insert(LongList {
+                records: 0,
+                max_len: 0,
+                example: other.example,
+                nested: other.nested,
+            });
+            list.records += other.records;
+            if other.max_len > list.max_len {
+                list.max_len = other.max_len;
+                list.example = other.example;
+            }
+        }
+        self.wide_schemas.extend(other.wide_schemas);
+        self.size_advice.extend(other.size_advice);
+    }
+
+    /// The counters of the report and its text, as posted by the hooks.
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "files_written": self.files_written,
+            "cancelled": self.cancelled,
+            "limit": self.limit.map(|l| l.to_string()),
+            "conversion_errors": self.conversion_errors,
+            "invalid_utf8": self.invalid_utf8,
+            "duplicate_conflicts": self.duplicate_conflicts,
+            "payload_aliases": self.payload_aliases,
+            "raw_blocks": self.raw_blocks,
+            "block_cache_hits": self.block_cache_hits,
+            "block_cache_misses": self.block_cache_misses,
+            "unsampled_schemas": self.unsampled_schemas,
+            "adl_shards": self.adl_shards,
+            "adl_missing": self.adl_missing,
+            "appended_skipped": self.appended_skipped,
+            "cid_copies": self.cid_copies,
+            "rows_left_out": self.rows_left_out,
+            "text": self.to_string(),
+        })
+    }
+
+    /// Records the values of a written file that failed to convert or were left out.
+    pub(crate) fn add_failures(&mut self, file: &str, mut failures: Failures) {
+        self.conversion_errors += failures.rows;
+        for (_, mut gap) in std::mem::take(&mut failures.gaps) {
+            gap.file = file.to_string();
+            self.capability_gaps.push(gap);
+        }
+        if !failures.is_empty() {
+            self.conversion_failures.push((file.to_string(), failures));
+        }
+    }
+
+    /// Records any lists within the block data that exceed the list length threshold.
+    pub(crate) fn check_lists(&mut self, cid: &Cid, data: &Ipld) {
+        let mut found = HashMap::new();
+        long_lists(data, "data", false, self.thresholds.list_len, &mut found);
+        for (path, (len, nested)) in found {
+            let entry = self.long_lists.entry(path).or_insert(LongList {
+                records: 0,
+                max_len: 0,
+                example: *cid,
+                nested,
+            });
+            entry.records += 1;
+            if len > entry.max_len {
+                entry.max_len = len;
+                entry.example = *cid;
+            }
+        }
+    }
+
+    /// Records the schema if its rows flatten into more columns than the width threshold.
+    pub(crate) fn check_width(
+        &mut self,
+        file: &str,
+        schema: &Schema,
+        records: usize,
+        config: &Config,
+    ) {
+        let width = width(schema, "", config);
+        if width <= self.thresholds.row_width {
+            return;
+        }
+        let mut widest = Vec::new();
+        widest_fields(schema, "", width / 10, config, &mut widest);
+        widest.sort_by_key(|(_, w)| std::cmp::Reverse(*w));
+        widest.truncate(3);
+        self.wide_schemas.push(WideSchema {
+            file: file.to_string(),
+            records,
+            width,
+            widest,
+        });
+    }
+}
+
+/// Columns smaller than this are not worth tuning.
+const MIN_ADVICE_BYTES: i64 = 64 * 1024;
+
+/// Sizes of a column summed over the row groups of a file.
+#[derive(Default)]
+struct ColumnSizes {
+    compressed: i64,
+    uncompressed: i64,
+    dictionary: i64,
+}
+
+impl Report {
+    /// Suggests settings that would likely shrink a written parquet file, based
+    /// on the sizes and encodings of its column chunks, naming the columns by
+    /// their paths in the options.
+    pub(crate) fn check_sizes(&mut self, file: &str, path: &Path, names: &Columns) -> Result<()> {
+        let f = std::fs::File::open(path).context(format!("opening {}", path.display()))?;
+        let reader = SerializedFileReader::new(f).context(format!("reading {}", path.display()))?;
+        let descr = reader.metadata().file_metadata().schema_descr();
+        let option_paths: Vec<String> = (0..descr.num_columns())
+            .map(|i| {
+                let desc = descr.column(i);
+                let internal = ColumnPath::new(names.internal(desc.path().parts()));
+                match column_types(descr.get_column_root(i), &desc) {
+                    Ok(types) => option_path(&internal, &types),
+                    Err(_) => internal.string(),
+                }
+            })
+            .collect();
+        let mut columns: Vec<(String, ColumnSizes)> = Vec::new();
+        let mut snappy = true;
+        for rg in reader.metadata().row_groups() {
+            for (i, col) in rg.columns().iter().enumerate() {
+                snappy &= col.compression() == Compression::SNAPPY;
+                if columns.len() <= i {
+                    columns.push((col.column_path().string(), ColumnSizes::default()));
+                }
+                let sizes = &mut columns[i].1;
+                sizes.compressed += col.compressed_size();
+                sizes.uncompressed += col.uncompressed_size();
+                if let Some(offset) = col.dictionary_page_offset() {
+                    sizes.dictionary += col.data_page_offset() - offset;
+                }
+            }
+        }
+
+        let compressed: i64 = columns.iter().map(|(_, s)| s.compressed).sum();
+        let uncompressed: i64 = columns.iter().map(|(_, s)| s.uncompressed).sum();
+        if snappy && compressed > 16 * MIN_ADVICE_BYTES && uncompressed >= 2 * compressed {
+            self.size_advice.push(format!(
+                "{}: snappy compresses it {:.1}x, --compression zstd is likely to shrink it further, and more with a higher --compression-level",
+                file,
+                uncompressed as f64 / compressed as f64
+            ));
+        }
+        for ((column, sizes), option_path) in columns.iter().zip(&option_paths) {
+            if sizes.compressed < MIN_ADVICE_BYTES {
+                continue;
+            }
+            if sizes.dictionary * 2 > sizes.compressed {
+                self.size_advice.push(format!(
+                    "{} {}: the dictionary makes up most of the column, its values are mostly distinct and would be smaller with --dictionary {}=off",
+                    file, column, option_path
+                ));
+            }
+            if sizes.compressed * 20 > sizes.uncompressed * 19 {
+                self.size_advice.push(format!(
+                    "{} {}: compression saves under 5%, the values are likely hashes or CIDs and compressing them only costs time",
+                    file, column
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.cancelled {
+            writeln!(
+                f,
+                "cancelled after writing {} files, the remaining files were not written",
+                self.files_written
+            )?;
+        }
+        if let Some(limit) = self.limit {
+            writeln!(
+                f,
+                "stopped by {}, the files hold the blocks read before it was reached",
+                limit
+            )?;
+        }
+        if self.rows_left_out > 0 {
+            writeln!(
+                f,
+                "{} rows of tables not written before --max-output-bytes was reached were left out",
+                self.rows_left_out
+            )?;
+        }
+        if self.payload_aliases > 0 {
+            writeln!(
+                f,
+                "{} blocks duplicated the bytes of an earlier block under a different CID, see payload_aliases",
+                self.payload_aliases
+            )?;
+        }
+        if self.raw_blocks > 0 {
+            writeln!(f, "{} raw blocks written to raw_blocks", self.raw_blocks)?;
+        }
+        if self.block_cache_hits + self.block_cache_misses > 0 {
+            writeln!(
+                f,
+                "{} links resolved, {} from the block cache and {} by decoding their block, see --block-cache-size",
+                self.block_cache_hits + self.block_cache_misses,
+                self.block_cache_hits,
+                self.block_cache_misses
+            )?;
+        }
+        if self.unsampled_schemas > 0 {
+            writeln!(
+                f,
+                "{} schemas were not seen in the sample of --infer-sample, their blocks were written to files of their own",
+                self.unsampled_schemas
+            )?;
+        }
+        if self.adl_shards > 0 {
+            writeln!(
+                f,
+                "{} shards were written as part of the map at the root of their tree, see --adl",
+                self.adl_shards
+            )?;
+        }
+        if self.adl_missing > 0 {
+            writeln!(
+                f,
+                "{} shards linked from another shard are not in the inputs, their entries are missing",
+                self.adl_missing
+            )?;
+        }
+        if self.cid_copies > 0 {
+            writeln!(
+                f,
+                "{} copies of a CID written once were skipped, see the _copies field and --dedup-cids",
+                self.cid_copies
+            )?;
+        }
+        if self.appended_skipped > 0 {
+            writeln!(
+                f,
+                "{} blocks were written by an earlier run into the output directory and skipped, see --append",
+                self.appended_skipped
+            )?;
+        }
+        if self.invalid_utf8 > 0 {
+            writeln!(
+                f,
+                "{} blocks had strings that were not valid UTF-8, handled by --invalid-utf8",
+                self.invalid_utf8
+            )?;
+        }
+        if self.conversion_errors > 0 {
+            writeln!(
+                f,
+                "{} rows had values that could not be converted, see their _conversion_errors",
+                self.conversion_errors
+            )?;
+        }
+        for (file, failures) in &self.conversion_failures {
+            for line in failures.to_string().lines() {
+                writeln!(f, "  {} {}", file, line)?;
+            }
+        }
+        if self.duplicate_conflicts > 0 {
+            writeln!(
+                f,
+                "{} copies of blocks had the CID of another copy but different bytes, handled by --duplicates",
+                self.duplicate_conflicts
+            )?;
+        }
+        if !self.capability_gaps.is_empty() {
+            writeln!(f, "left out as unsupported:")?;
+            for gap in &self.capability_gaps {
+                let examples: Vec<String> = gap.examples.iter().map(|c| c.to_string()).collect();
+                writeln!(
+                    f,
+                    "  {} {}: {}, in {} blocks, e.g. {}",
+                    gap.file,
+                    gap.path,
+                    gap.what,
+                    gap.blocks,
+                    examples.join(", ")
+                )?;
+            }
+        }
+        for (file, columns) in &self.constant_columns {
+            writeln!(
+                f,
+                "{}: constant columns {} moved to the {} footer metadata",
+                file,
+                columns.join(", "),
+                CONSTANTS_KEY
+            )?;
+        }
+        if !self.size_advice.is_empty() {
+            writeln!(f, "size suggestions:")?;
+            for advice in &self.size_advice {
+                writeln!(f, "  {}", advice)?;
+            }
+        }
+        if self.long_lists.is_empty() && self.wide_schemas.is_empty() {
+            return writeln!(f, "no warnings");
+        }
+        writeln!(f, "warnings:")?;
+        for w in &self.wide_schemas {
+            let widest: Vec<String> = w
+                .widest
+                .iter()
+                .map(|(p, n)| format!("{} ({})", p, n))
+                .collect();
+            writeln!(
+                f,
+                "  {}: {} records flatten into {} columns (threshold {}), widest fields: {}",
+                w.file,
+                w.records,
+                w.width,
+                self.thresholds.row_width,
+                widest.join(", ")
+            )?;
+            writeln!(
+                f,
+                "    consider --explode for lists beneath these fields, or storing them as a key/value map or JSON"
+            )?;
+        }
+        let mut long_lists: Vec<(&String, &LongList)> = self.long_lists.iter().collect();
+        long_lists.sort_by_key(|(p, _)| *p);
+        for (path, l) in long_lists {
+            writeln!(
+                f,
+                "  {}: {} records have lists longer than {} (max {}, e.g. {})",
+                path, l.records, self.thresholds.list_len, l.max_len, l.example
+            )?;
+            if l.nested {
+                writeln!(
+                    f,
+                    "    consider exploding the enclosing list or storing it as JSON"
+                )?;
+            } else {
+                writeln!(f, "    consider --explode {}", path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn long_lists(
+    data: &Ipld,
+    path: &str,
+    nested: bool,
+    threshold: usize,
+    found: &mut HashMap<String, (usize, bool)>,
+) {
+    match data {
+        Ipld::List(l) => {
+            if l.len() > threshold {
+                let entry = found.entry(path.to_string()).or_insert((0, nested));
+                entry.0 = entry.0.max(l.len());
+            }
+            for v in l {
+                long_lists(v, path, true, threshold, found);
+            }
+        }
+        Ipld::Map(m) => {
+            for (k, v) in m {
+                long_lists(v, &field_path(path, k), nested, threshold, found);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Number of leaf columns the schema is written as.
+fn width(schema: &Schema, path: &str, config: &Config) -> usize {
+    match schema {
+        Schema::List(l) | Schema::Optional(l) => width(l, path, config),
+        Schema::Map(m) => m
+            .iter()
+            .map(|(k, v)| {
+                let path = field_path(path, k);
+                if is_exploded_list(v, &path, config) {
+                    0
+                } else {
+                    width(v, &path, config)
+                }
+            })
+            .sum(),
+        _ => 1,
+    }
+}
+
+/// Finds the deepest fields that still account for at least `min` columns.
+fn widest_fields(
+    schema: &Schema,
+    path: &str,
+    min: usize,
+    config: &Config,
+    out: &mut Vec<(String, usize)>,
+) {
+    let fields = match schema {
+        Schema::List(l) | Schema::Optional(l) => return widest_fields(l, path, min, config, out),
+        Schema::Map(m) => m,
+        _ => return,
+    };
+    for (k, v) in fields {
+        let path = field_path(path, k);
+        if is_exploded_list(v, &path, config) {
+            continue;
+        }
+        let w = width(v, &path, config);
+        if w < min.max(2) {
+            continue;
+        }
+        let before = out.len();
+        widest_fields(v, &path, w / 2, config, out);
+        if out.len() == before {
+            out.push((path, w));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libipld::{
+        ipld,
+        multihash::{Code, MultihashDigest},
+    };
+    use tokio_util::sync::CancellationToken;
+
+    use super::*;
+    use crate::{wrapper_schema, writer::write_output, ConvertArgs};
+
+    #[test]
+    fn size_advice_names_the_flags() {
+        // Distinct hashes, written with dictionary and snappy.
+        let rows: Vec<Block> = (0..5000u32)
+            .map(|i| {
+                let hash = Code::Sha2_256.digest(&i.to_le_bytes()).digest().to_vec();
+                let hex: String = hash.iter().map(|b| format!("{:02x}", b)).collect();
+                (Cid::default(), ipld!({"h": hex}), Vec::new())
+            })
+            .collect();
+        let schema = wrapper_schema(&rows[0].1, false, false);
+        let config = ConvertArgs {
+            dictionary: vec![("data.h".to_string(), true)],
+            ..ConvertArgs::default()
+        }
+        .config(CancellationToken::new())
+        .unwrap();
+        let path =
+            std::env::temp_dir().join(format!("carquet-report-{}.parquet", std::process::id()));
+        write_output(&path, &schema, &rows, &[], &config.columns, &config, None).unwrap();
+        let mut report = Report::new(Thresholds {
+            row_width: usize::MAX,
+            list_len: usize::MAX,
+        });
+        report
+            .check_sizes("schema_0.parquet", &path, &config.columns)
+            .unwrap();
+        let _ = std::fs::remove_file(&path);
+        let advice = report.size_advice.join("\n");
+        assert!(
+            advice.contains("schema_0.parquet data.h: the dictionary makes up most of the column, its values are mostly distinct and would be smaller with --dictionary data.h=off"),
+            "{}",
+            advice
+        );
+    }
+}
@@ -0,0 +1,450 @@
+//! Scanning of the framing of CAR files, for commands that need the CIDs and
+//! sizes of the blocks but not their data.
+//!
+//! A CAR is a varint framed header followed by varint framed sections, each a
+//! CID and the block data. The scan reads the lengths and CIDs and skips over
+//! the data within the read buffer, without copying it out, which makes it
+//! several times faster than reading the blocks when those are large.
+//!
+//! CARv1 files concatenated back to back are read as one CAR: a section that
+//! starts like a dag-cbor map rather than a CID is the header of the next
+//! file, whose roots are added to those of the first.
+
+use std::io::{BufRead, Read, Write};
+
+use anyhow::{anyhow, Context, Result};
+use libipld::{cbor::DagCborCodec, prelude::Codec, Cid, Ipld};
+
+/// Bytes read at a time, large enough that most sections are skipped within it.
+pub const BUFFER: usize = 1 << 20;
+
+/// A section of a CAR: the CID of a block and the length of its data.
+#[derive(Debug, Clone, Copy)]
+pub struct Section {
+    pub cid: Cid,
+    pub len: u64,
+}
+
+/// Reader of the sections of a CARv1.
+pub struct Sections<R> {
+    reader: R,
+    roots: Vec<Cid>,
+    /// Offset in the CAR of the next section.
+    pos: u64,
+}
+
+impl<R: BufRead> Sections<R> {
+    /// Reads the header, checking it is that of a CARv1.
+    pub fn new(mut reader: R) -> Result<Self> {
+        let len = read_varint(&mut reader)?.ok_or_else(|| anyhow!("empty CAR"))?;
+        let roots = read_header(&mut reader, len)?;
+        Ok(Self {
+            reader,
+            roots,
+            pos: varint_len(len) + len,
+        })
+    }
+
+    /// Reads the sections from `pos`, the offset of a section within a CAR
+    /// whose header was read before, at which the reader is.
+    pub fn resume(reader: R, pos: u64) -> Self {
+        Self {
+            reader,
+            roots: Vec::new(),
+            pos,
+        }
+    }
+
+    /// The roots of the headers read so far, none if resumed before another.
+    pub fn roots(&self) -> &[Cid] {
+        &self.roots
+    }
+
+    /// Offset in the CAR of the next section.
+    pub fn position(&self) -> u64 {
+        self.pos
+    }
+
+    /// The next section, skipping the block data, `None` at the end.
+    pub fn next_section(&mut self) -> Result<Option<Section>> {
+        let Some(section) = self.section_head()? else {
+            return Ok(None);
+        };
+        skip(&mut self.reader, section.len).context(format!("reading block {}", section.cid))?;
+        Ok(Some(section))
+    }
+
+    /// The next block with its data, `None` at the end.
+    pub fn next_block(&mut self) -> Result<Option<(Cid, Vec<u8>)>> {
+        let Some(Section { cid, len }) = self.section_head()? else {
+            return Ok(None);
+        };
+        let mut data = Vec::new();
+        (&mut self.reader).take(len).read_to_end(&mut data)?;
+        if data.len() as u64 != len {
+            return Err(anyhow!("CAR truncated in block {}", cid));
+        }
+        Ok(Some((cid, data)))
+    }
+
+    /// Reads the length and CID of the next section, leaving its data.
+    fn section_head(&mut self) -> Result<Option<Section>> {
+        let Some(mut len) = read_varint(&mut self.reader)? else {
+            return Ok(None);
+        };
+        // A CID starts with its version, 0x01, or the 0x12 of a CIDv0, and a
+        // header with the 0xa0 to 0xbf of a dag-cbor map.
+        while matches!(self.reader.fill_buf()?.first(), Some(0xa0..=0xbf)) {
+            let roots = read_header(&mut self.reader, len)
+                .context(format!("reading the CAR header at {}", self.pos))?;
+            for root in roots {
+                if !self.roots.contains(&root) {
+                    self.roots.push(root);
+                }
+            }
+            self.pos += varint_len(len) + len;
+            let Some(next) = read_varint(&mut self.reader)? else {
+                return Ok(None);
+            };
+            len = next;
+        }
+        let cid = Cid::read_bytes(&mut self.reader).context("reading a block CID")?;
+        let Some(data) = len.checked_sub(cid_len(&cid)) else {
+            return Err(anyhow!(
+                "section of {} bytes shorter than its CID {}",
+                len,
+                cid
+            ));
+        };
+        self.pos += varint_len(len) + len;
+        Ok(Some(Section { cid, len: data }))
+    }
+}
+
+/// Reads a CARv1 header of `len` bytes, returning its roots.
+fn read_header(reader: &mut impl BufRead, len: u64) -> Result<Vec<Cid>> {
+    let mut header = Vec::new();
+    reader.take(len).read_to_end(&mut header)?;
+    if header.len() as u64 != len {
+        return Err(anyhow!("CAR header truncated"));
+    }
+    let header: Ipld = DagCborCodec
+        .decode(&header)
+        .context("decoding the CAR header")?;
+    if header.get("version").ok() != Some(&Ipld::Integer(1)) {
+        return Err(anyhow!("not a CARv1 header: {:?}", header));
+    }
+    let Ok(Ipld::List(roots)) = header.get("roots") else {
+        return Err(anyhow!("CAR header without roots"));
+    };
+    roots
+        .iter()
+        .map(|r| match r {
+            Ipld::Link(cid) => Ok(*cid),
+            _ => Err(anyhow!("CAR root {:?} is not a link", r)),
+        })
+        .collect()
+}
+
+/// Writes a section of a CARv1, the CID and data of a block, or the header if
+/// `cid` is `None`.
+pub fn write_section(out: &mut impl Write, cid: Option<&Cid>, data: &[u8]) -> Result<()> {
+    let cid = cid.map(|c| c.to_bytes()).unwrap_or_default();
+    let mut len = (cid.len() + data.len()) as u64;
+    while len >= 0x80 {
+        out.write_all(&[len as u8 | 0x80])?;
+        len >>= 7;
+    }
+    out.write_all(&[len as u8])?;
+    out.write_all(&cid)?;
+    out.write_all(data)?;
+    Ok(())
+}
+
+/// Writes the header of a CARv1 of the roots.
+pub fn write_header(out: &mut impl Write, roots: &[Cid]) -> Result<()> {
+    let header = Ipld::Map(
+        [
+            (
+                "roots".to_string(),
+                Ipld::List(roots.iter().map(|r| Ipld::Link(*r)).collect()),
+            ),
+            ("version".to_string(), Ipld::Integer(1)),
+        ]
+        .into(),
+    );
+    write_section(out, None, &DagCborCodec.encode(&header)?)
+}
+
+/// Length of the binary CID, as read from the section.
+fn cid_len(cid: &Cid) -> u64 {
+    let hash = cid.hash();
+    match cid.version() {
+        libipld::cid::Version::V0 => 2 + u64::from(hash.size()),
+        libipld::cid::Version::V1 => {
+            varint_len(1)
+                + varint_len(cid.codec())
+                + varint_len(hash.code())
+                + varint_len(hash.size().into())
+                + u64::from(hash.size())
+        }
+    }
+}
+
+fn varint_len(value: u64) -> u64 {
+    u64::from((64 - value.leading_zeros()).max(1).div_ceil(7))
+}
+
+/// Decodes the unsigned varint at the start of `buf`, with its length, `None`
+/// if it is truncated, over 64 bits or not minimally encoded.
+///
+/// Varints of up to 8 bytes, all lengths below 2^56, are decoded from one
+/// little endian word: the first byte without its high bit ends the varint,
+/// and the 7 bit groups are packed with a fixed sequence of shifts, so there
+/// is no branch for each byte.
+pub fn varint(buf: &[u8]) -> Option<(u64, usize)> {
+    if let Some(word) = buf.get(..8) {
+        let word = u64::from_le_bytes(word.try_into().expect("8 bytes"));
+        let ends = !word & 0x8080_8080_8080_8080;
+        if ends != 0 {
+            let len = ends.trailing_zeros() as usize / 8 + 1;
+            let word = word & (u64::MAX >> (64 - 8 * len));
+            let value = (word & 0x7f)
+                | ((word >> 1) & (0x7f << 7))
+                | ((word >> 2) & (0x7f << 14))
+                | ((word >> 3) & (0x7f << 21))
+                | ((word >> 4) & (0x7f << 28))
+                | ((word >> 5) & (0x7f << 35))
+                | ((word >> 6) & (0x7f << 42))
+                | ((word >> 7) & (0x7f << 49));
+            return minimal(value, len);
+        }
+    }
+    let mut value = 0u64;
+    for (i, b) in buf.iter().take(10).enumerate() {
+        if i == 9 && *b > 1 {
+            return None;
+        }
+        value |= u64::from(b & 0x7f) << (7 * i);
+        if b & 0x80 == 0 {
+            return minimal(value, i + 1);
+        }
+    }
+    None
+}
+
+/// Rejects varints with high zero groups, which have a shorter encoding.
+fn minimal(value: u64, len: usize) -> Option<(u64, usize)> {
+    (len == 1 || value >> (7 * (len - 1)) != 0).then_some((value, len))
+}
+
+/// Reads a varint, `None` at the end of the input.
+fn read_varint(reader: &mut impl BufRead) -> Result<Option<u64>> {
+    let buf = reader.fill_buf()?;
+    if buf.is_empty() {
+        return Ok(None);
+    }
+    if let Some((value, len)) = varint(buf) {
+        reader.consume(len);
+        return Ok(Some(value));
+    }
+    // The varint runs past the buffer, or is invalid.
+    let mut bytes = Vec::with_capacity(10);
+    while bytes.len() < 10 {
+        let buf = reader.fill_buf()?;
+        let Some(&b) = buf.first() else {
+            return Err(anyhow!("varint truncated"));
+        };
+        reader.consume(1);
+        bytes.push(b);
+        if b & 0x80 == 0 {
+            break;
+        }
+    }
+    match varint(&bytes) {
+        Some((value, _)) => Ok(Some(value)),
+        None => Err(anyhow!("invalid varint {:02x?}", bytes)),
+    }
+}
+
+/// Skips `n` bytes, consuming them from the buffer without copying them.
+fn skip(reader: &mut impl BufRead, mut n: u64) -> Result<()> {
+    while n > 0 {
+        let available = reader.fill_buf()?.len();
+        if available == 0 {
+            return Err(anyhow!("CAR truncated, {} bytes missing", n));
+        }
+        let k = available.min(usize::try_from(n).unwrap_or(usize::MAX));
+        reader.consume(k);
+        n -= k as u64;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufReader, Cursor};
+
+    use libipld::multihash::{Code, MultihashDigest};
+
+    use super::*;
+
+    fn encode(mut value: u64) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        while value >= 0x80 {
+            bytes.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        bytes.push(value as u8);
+        bytes
+    }
+
+    /// Values of every length of varint, 1 to 10 bytes, at both ends.
+    fn values() -> Vec<(u64, usize)> {
+        let mut values = vec![(0, 1), (u64::MAX, 10)];
+        for len in 1..10 {
+            values.push(((1 << (7 * len)) - 1, len));
+            values.push((1 << (7 * len), len + 1));
+        }
+        values
+    }
+
+    #[test]
+    fn varints_of_every_length() {
+        for (value, len) in values() {
+            let bytes = encode(value);
+            assert_eq!(bytes.len(), len, "{}", value);
+            assert_eq!(varint(&bytes), Some((value, len)), "{}", value);
+            assert_eq!(varint_len(value), len as u64, "{}", value);
+            // Followed by more bytes, as in a buffer, for the word path.
+            let mut padded = bytes.clone();
+            padded.extend_from_slice(&[0xff; 10]);
+            assert_eq!(varint(&padded), Some((value, len)), "{}", value);
+        }
+    }
+
+    #[test]
+    fn invalid_varints() {
+        let table: &[&[u8]] = &[
+            // Truncated.
+            &[],
+            &[0x80],
+            &[0xff; 9],
+            // Not minimal.
+            &[0x80, 0x00],
+            &[0xff, 0x80, 0x00],
+            &[0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x00, 0x01],
+            &[0x81, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x00],
+            // Over 64 bits.
+            &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x02],
+            &[
+                0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x01,
+            ],
+        ];
+        for bytes in table {
+            assert_eq!(varint(bytes), None, "{:02x?}", bytes);
+        }
+    }
+
+    #[test]
+    fn minimal_lengths() {
+        let table = [
+            (0, 1, true),
+            (127, 1, true),
+            (0, 2, false),
+            (127, 2, false),
+            (128, 2, true),
+            (1 << 62, 9, true),
+            ((1 << 56) - 1, 9, false),
+            (1 << 63, 10, true),
+            ((1 << 63) - 1, 10, false),
+        ];
+        for (value, len, ok) in table {
+            assert_eq!(
+                minimal(value, len),
+                ok.then_some((value, len)),
+                "{} in {}",
+                value,
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn read_varints_across_buffers() {
+        let bytes: Vec<u8> = values().iter().flat_map(|(v, _)| encode(*v)).collect();
+        // Buffers of every size smaller than a varint split some of them.
+        for capacity in 1..=11 {
+            let mut reader = BufReader::with_capacity(capacity, Cursor::new(&bytes));
+            for (value, _) in values() {
+                assert_eq!(
+                    read_varint(&mut reader).unwrap(),
+                    Some(value),
+                    "{} in buffers of {}",
+                    value,
+                    capacity
+                );
+            }
+            assert_eq!(read_varint(&mut reader).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn read_invalid_varints_across_buffers() {
+        let mut reader = BufReader::with_capacity(2, Cursor::new([0x80, 0x80, 0x80]));
+        let err = read_varint(&mut reader).unwrap_err().to_string();
+        assert_eq!(err, "varint truncated");
+        let mut reader = BufReader::with_capacity(2, Cursor::new([0x80, 0x80, 0x00]));
+        let err = read_varint(&mut reader).unwrap_err().to_string();
+        assert_eq!(err, "invalid varint [80, 80, 00]");
+    }
+
+    fn raw(i: u8) -> Cid {
+        Cid::new_v1(0x55, Code::Sha2_256.digest(&[i]))
+    }
+
+    fn car(roots: &[Cid], blocks: &[u8]) -> Vec<u8> {
+        let mut car = Vec::new();
+        write_header(&mut car, roots).unwrap();
+        for i in blocks {
+            write_section(&mut car, Some(&raw(*i)), &[*i; 3]).unwrap();
+        }
+        car
+    }
+
+    #[test]
+    fn concatenated_cars_read_as_one() {
+        let mut bytes = car(&[raw(0), raw(1)], &[0, 1]);
+        bytes.extend(car(&[raw(1), raw(2)], &[2]));
+        // A CAR of a header only, then one more.
+        bytes.extend(car(&[raw(3)], &[]));
+        bytes.extend(car(&[], &[3]));
+        let mut sections = Sections::new(&bytes[..]).unwrap();
+        let mut blocks = Vec::new();
+        while let Some(block) = sections.next_block().unwrap() {
+            blocks.push(block);
+        }
+        let expected: Vec<_> = (0..4).map(|i| (raw(i), vec![i; 3])).collect();
+        assert_eq!(blocks, expected);
+        // The roots of every header, each once.
+        assert_eq!(sections.roots(), [raw(0), raw(1), raw(2), raw(3)]);
+        assert_eq!(sections.position(), bytes.len() as u64);
+    }
+
+    #[test]
+    fn carv2_pragma_mid_stream_is_an_error() {
+        let mut bytes = car(&[], &[0]);
+        let at = bytes.len();
+        bytes.extend_from_slice(&crate::carv2::PRAGMA);
+        bytes.extend(car(&[], &[1]));
+        let mut sections = Sections::new(&bytes[..]).unwrap();
+        assert!(sections.next_block().unwrap().is_some());
+        let err = sections.next_block().unwrap_err();
+        assert_eq!(err.to_string(), format!("reading the CAR header at {}", at));
+        assert!(
+            format!("{:#}", err).contains("not a CARv1 header"),
+            "{:#}",
+            err
+        );
+    }
+}
@@ -0,0 +1,142 @@
+//! The [`Schema`] of IPLD values, inferred from the blocks and widened to
+//! hold the values of several.
+
+use anyhow::Result;
+use libipld::Ipld;
+use parquet::schema::types::Type;
+use serde::{Deserialize, Serialize};
+
+use crate::{writer::root_fields, Columns, Config, Error};
+
+/// The shape of IPLD values, inferred from the blocks or built with
+/// [`SchemaBuilder`](crate::SchemaBuilder). The fields of a map are sorted by name.
+#[derive(Debug, Clone, PartialEq, Hash, Eq, Serialize, Deserialize)]
+pub enum Schema {
+    /// A null, written as an optional column without values, or the
+    /// element of a list that was empty.
+    Null,
+    Bool,
+    Integer,
+    Float,
+    String,
+    Bytes,
+    List(Box<Schema>),
+    Map(Vec<(String, Schema)>),
+    Link,
+    /// A field of a map that some of the values lack, from merged schemas, or
+    /// the element of a list with null elements. It is written as an optional
+    /// field, or if a list as empty when absent.
+    Optional(Box<Schema>),
+    /// Values of mixed kinds, such as the elements of a list of integers and
+    /// strings, written as a JSON column of their DAG-JSON.
+    Json,
+}
+
+impl Schema {
+    /// The parquet schema of a table of rows of this map schema, as written
+    /// with the default options.
+    pub fn to_parquet(&self) -> Result<Type, Error> {
+        let config = Config::default();
+        let mut fields = root_fields(self, &Columns::default(), &config, &config.int_types)
+            .map_err(|e| {
+                e.downcast::<Error>().unwrap_or_else(|e| Error::Schema {
+                    path: String::new(),
+                    reason: e.to_string(),
+                })
+            })?;
+        Type::group_type_builder("")
+            .with_fields(&mut fields)
+            .build()
+            .map_err(|e| Error::Schema {
+                path: String::new(),
+                reason: e.to_string(),
+            })
+    }
+}
+
+pub fn schema(dag: &Ipld) -> Schema {
+    match dag {
+        Ipld::Null => Schema::Null,
+        Ipld::Bool(_) => Schema::Bool,
+        Ipld::Integer(_) => Schema::Integer,
+        Ipld::Float(_) => Schema::Float,
+        Ipld::String(_) => Schema::String,
+        Ipld::Bytes(_) => Schema::Bytes,
+        Ipld::Link(_) => Schema::Link,
+        Ipld::List(l) => Schema::List(Box::new(element_schema(l))),
+        Ipld::Map(m) => {
+            let mut sm = Vec::new();
+            for (k, v) in m {
+                sm.push((k.to_owned(), schema(v)));
+            }
+            sm.sort_by_key(|i| i.0.to_owned());
+            Schema::Map(sm)
+        }
+    }
+}
+
+/// Schema of the elements of a list, widened to hold all of them. Null
+/// elements are optional, so an empty list is the only one of null elements.
+pub fn element_schema(l: &[Ipld]) -> Schema {
+    l.iter()
+        .map(|v| match schema(v) {
+            Schema::Null => Schema::Optional(Box::new(Schema::Null)),
+            s => s,
+        })
+        .reduce(widened)
+        .unwrap_or(Schema::Null)
+}
+
+/// The schema that holds the values of both: nulls make the other optional,
+/// integers widen to floats, maps to the union of their fields, those one
+/// lacks optional, and lists to lists of the widened elements. Values of
+/// different kinds fall back to JSON.
+pub fn widened(a: Schema, b: Schema) -> Schema {
+    match (a, b) {
+        (a, b) if a == b => a,
+        (Schema::Null, b) | (b, Schema::Null) => optional(b),
+        (Schema::Optional(a), Schema::Optional(b)) => optional(widened(*a, *b)),
+        (Schema::Optional(a), b) | (b, Schema::Optional(a)) => optional(widened(*a, b)),
+        (Schema::Json, _) | (_, Schema::Json) => Schema::Json,
+        (Schema::Integer, Schema::Float) | (Schema::Float, Schema::Integer) => Schema::Float,
+        // The elements of empty lists say nothing of those of the others.
+        (Schema::List(a), b @ Schema::List(_)) | (b @ Schema::List(_), Schema::List(a))
+            if *a == Schema::Null =>
+        {
+            b
+        }
+        (Schema::List(a), Schema::List(b)) => Schema::List(Box::new(widened(*a, *b))),
+        (Schema::Map(a), Schema::Map(mut b)) => {
+            let mut fields = Vec::with_capacity(a.len().max(b.len()));
+            for (k, v) in a {
+                match b.iter().position(|(bk, _)| *bk == k) {
+                    Some(i) => {
+                        let (_, bv) = b.remove(i);
+                        fields.push((k, widened(v, bv)));
+                    }
+                    None => fields.push((k, optional(v))),
+                }
+            }
+            fields.extend(b.into_iter().map(|(k, v)| (k, optional(v))));
+            fields.sort_by(|(a, _), (b, _)| a.cmp(b));
+            Schema::Map(fields)
+        }
+        _ => Schema::Json,
+    }
+}
+
+/// The schema as that of a field some values lack.
+pub fn optional(schema: Schema) -> Schema {
+    match schema {
+        Schema::Optional(_) => schema,
+        s => Schema::Optional(Box::new(s)),
+    }
+}
+
+pub fn field_path(path: &str, name: &str) -> String {
+    if path.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}.{}", path, name)
+    }
+}
@@ -0,0 +1,105 @@
+//! Credentials of the hooks, given by name rather than in the flags.
+//!
+//! The URL and headers of `--on-complete-webhook` may name values as
+//! `${NAME}`, taken from the environment or else from the `--secrets-file`,
+//! so a script holding the flags can be committed without them:
+//!
+//! ```text
+//! # NAME=value, one per line
+//! HOOK_TOKEN=s3cr3t
+//! ```
+//!
+//! `$${` is a literal `${`. The values of the file are also set in the
+//! environment of `--on-complete-exec`. Values are resolved once as the run
+//! starts, by [`Hooks::new`](crate::hooks::Hooks::new), so a missing one fails
+//! the run before it reads a block, and are left out of errors and debug
+//! output, which name the template.
+
+use std::{collections::BTreeMap, fmt, path::Path};
+
+use anyhow::{anyhow, Context, Result};
+
+#[derive(Default)]
+pub(crate) struct Secrets {
+    file: Option<String>,
+    values: BTreeMap<String, String>,
+}
+
+impl fmt::Debug for Secrets {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Secrets")
+            .field("file", &self.file)
+            .field("names", &self.values.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl Secrets {
+    /// Loads the secrets file, if there is one.
+    pub(crate) fn load(path: Option<&Path>) -> Result<Self> {
+        let Some(path) = path else {
+            return Ok(Self::default());
+        };
+        let text = std::fs::read_to_string(path).context(format!("reading {}", path.display()))?;
+        let mut values = BTreeMap::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (name, value) = line.split_once('=').ok_or_else(|| {
+                anyhow!(
+                    "expected NAME=value on line {} of {}",
+                    i + 1,
+                    path.display()
+                )
+            })?;
+            values.insert(name.trim().to_string(), value.trim().to_string());
+        }
+        Ok(Self {
+            file: Some(path.display().to_string()),
+            values,
+        })
+    }
+
+    /// The values of the file, to set in the environment of a command.
+    pub(crate) fn values(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.values.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    /// The template with each `${NAME}` replaced by its value.
+    pub(crate) fn resolve(&self, template: &str) -> Result<String> {
+        let mut out = String::new();
+        let mut rest = template;
+        while let Some(i) = rest.find("${") {
+            if rest[..i].ends_with('$') {
+                out.push_str(&rest[..i - 1]);
+                out.push_str("${");
+                rest = &rest[i + 2..];
+                continue;
+            }
+            out.push_str(&rest[..i]);
+            let end = rest[i..]
+                .find('}')
+                .ok_or_else(|| anyhow!("unclosed ${{ in {template}"))?;
+            let name = &rest[i + 2..i + end];
+            out.push_str(&self.get(name)?);
+            rest = &rest[i + end + 1..];
+        }
+        out.push_str(rest);
+        Ok(out)
+    }
+
+    fn get(&self, name: &str) -> Result<String> {
+        if let Ok(value) = std::env::var(name) {
+            return Ok(value);
+        }
+        if let Some(value) = self.values.get(name) {
+            return Ok(value.clone());
+        }
+        Err(match &self.file {
+            Some(file) => anyhow!("{name} is set neither in the environment nor in {file}"),
+            None => anyhow!("{name} is not set in the environment, nor is there a --secrets-file"),
+        })
+    }
+}
@@ -0,0 +1,918 @@
+//! Copying the blocks of a CAR whose CIDs are in a list, or which have the
+//! codec, size or hash function of a [`Filter`], into a CAR of their own, for
+//! lists too large to hold in memory.
+//!
+//! The list is read a line at a time, each a CID in a [`CidEncoding`], and
+//! sorted on disk into an index of 128 bit keys, the first 16 bytes of the
+//! SHA-256 of each CID, which is kept beside the list and built again only
+//! when the list changes, or every time for a list read from stdin:
+//!
+//! ```text
+//! "CQCIDS01" | count: u64 le | count sorted 16 byte keys
+//! ```
+//!
+//! A lookup checks a bloom filter of the keys, built as the index is opened,
+//! then reads the one 4 KiB block of keys that may hold it, found by a key of
+//! every block kept in memory. A hundred million CIDs take 1.6 GB of disk and
+//! about 130 MB of memory, and most blocks not in the list are ruled out by
+//! the filter without reading the disk. Distinct CIDs share a key only by a
+//! SHA-256 collision, so the lookup is exact.
+//!
+//! With a checkpoint file the copy records how far it got every
+//! [`CHECKPOINT_BYTES`] of input, and a copy stopped part way is resumed from
+//! there, truncating the output to the blocks written by then.
+//!
+//! A CARv2 with an index can instead be copied from by [`select_indexed`],
+//! which looks the listed CIDs up in the index and reads only their blocks.
+//!
+//! The set can also be of the blocks of converted files, by [`parquet_set`]:
+//! the CIDs of the rows of the tables of blocks that pass a condition in the
+//! language of `--sql`, so a selection made on the parquet side can be copied
+//! out of the CAR.
+
+use std::{
+    collections::BinaryHeap,
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use base64::{engine::general_purpose, Engine as _};
+use cid::multibase::Base;
+use clap::ValueEnum;
+use libipld::{
+    multihash::{Code, MultihashDigest},
+    Cid, Ipld,
+};
+use parquet::{
+    file::reader::{FileReader, SerializedFileReader},
+    record::{reader::RowIter, Field},
+    schema::types::Type,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    carv2, export,
+    scan::{self, Sections},
+    sql::Query,
+    Error, Source,
+};
+
+const MAGIC: &[u8; 8] = b"CQCIDS01";
+const HEADER: u64 = 16;
+const KEY: usize = 16;
+
+/// Keys in each block read by a lookup, 4 KiB.
+const BLOCK_KEYS: usize = 256;
+
+/// Keys sorted in memory at a time while building the index, 128 MiB.
+const RUN_KEYS: usize = 1 << 23;
+
+/// Bits of the bloom filter for each key and number of its hashes, for about
+/// 1% false positives.
+const BLOOM_BITS: usize = 10;
+const BLOOM_HASHES: u64 = 7;
+
+/// Bytes of input between checkpoints.
+pub const CHECKPOINT_BYTES: u64 = 256 * 1024 * 1024;
+
+/// Encoding of the CIDs of a list.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CidEncoding {
+    /// Base64 of the binary CID, padded.
+    #[default]
+    Base64,
+    /// Base32 of the binary CID without multibase prefix, in either case.
+    Base32,
+    /// The CID as a string, a CIDv1 in any multibase such as bafy.. or a
+    /// CIDv0.
+    String,
+}
+
+/// A list of CIDs, one per line, in a file or, for the path `-`, on stdin.
+#[derive(Debug, Clone)]
+pub struct CidList {
+    pub path: PathBuf,
+    pub encoding: CidEncoding,
+}
+
+impl CidList {
+    pub fn is_stdin(&self) -> bool {
+        self.path == Path::new("-")
+    }
+
+    fn lines(&self) -> Result<std::io::Lines<Box<dyn BufRead>>> {
+        let reader: Box<dyn BufRead> = if self.is_stdin() {
+            Box::new(std::io::stdin().lock())
+        } else {
+            let file =
+                File::open(&self.path).context(format!("opening {}", self.path.display()))?;
+            Box::new(BufReader::with_capacity(scan::BUFFER, file))
+        };
+        Ok(reader.lines())
+    }
+
+    /// The CIDs of the list, skipping blank lines.
+    fn cids(&self) -> Result<impl Iterator<Item = Result<Cid>> + '_> {
+        Ok(self.lines()?.filter_map(move |line| {
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => return Some(Err(e.into())),
+            };
+            let line = line.trim();
+            (!line.is_empty()).then(|| self.parse(line))
+        }))
+    }
+
+    fn parse(&self, line: &str) -> Result<Cid> {
+        let decode = |source: Source| Error::Decode {
+            what: format!("CID {}", line),
+            source,
+        };
+        let bytes = match self.encoding {
+            CidEncoding::Base64 => general_purpose::STANDARD
+                .decode(line)
+                .map_err(|e| decode(e.into()))?,
+            CidEncoding::Base32 => Base::Base32Lower
+                .decode(line.trim_end_matches('=').to_ascii_lowercase())
+                .map_err(|e| decode(e.into()))?,
+            CidEncoding::String => return Ok(Cid::try_from(line).map_err(|e| decode(e.into()))?),
+        };
+        Ok(Cid::try_from(bytes).map_err(|e| decode(e.into()))?)
+    }
+}
+
+/// The on-disk set of the CIDs of a list.
+pub struct CidSet {
+    file: File,
+    count: u64,
+    /// The first key of each block.
+    fences: Vec<u128>,
+    bloom: Vec<u64>,
+    block: Vec<u8>,
+}
+
+impl CidSet {
+    /// Opens the index of the list, building it first if it is missing or
+    /// older than the list, or the list is read from stdin.
+    pub fn open_or_build(list: &CidList, index: &Path) -> Result<Self> {
+        let modified = |p: &Path| std::fs::metadata(p).and_then(|m| m.modified()).ok();
+        let stale = match (modified(index), modified(&list.path)) {
+            _ if list.is_stdin() => true,
+            (Some(index), Some(list)) => index < list,
+            _ => true,
+        };
+        if stale {
+            build(list.cids()?, index, RUN_KEYS)
+                .context(format!("indexing {}", list.path.display()))?;
+        }
+        Self::open(index)
+    }
+
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path).context(format!("opening {}", path.display()))?;
+        let mut reader = BufReader::with_capacity(scan::BUFFER, file.try_clone()?);
+        let mut header = [0; HEADER as usize];
+        reader.read_exact(&mut header)?;
+        if &header[..8] != MAGIC {
+            return Err(anyhow!("{} is not a CID index", path.display()));
+        }
+        let count = u64::from_le_bytes(header[8..].try_into().expect("8 bytes"));
+        if file.metadata()?.len() != HEADER + count * KEY as u64 {
+            return Err(anyhow!("{} is truncated", path.display()));
+        }
+        let bits = (count as usize * BLOOM_BITS).max(64);
+        let mut set = Self {
+            file,
+            count,
+            fences: Vec::with_capacity(count as usize / BLOCK_KEYS + 1),
+            bloom: vec![0; bits.div_ceil(64)],
+            block: vec![0; BLOCK_KEYS * KEY],
+        };
+        let mut key = [0; KEY];
+        for i in 0..count {
+            reader.read_exact(&mut key)?;
+            let key = u128::from_be_bytes(key);
+            if i.is_multiple_of(BLOCK_KEYS as u64) {
+                set.fences.push(key);
+            }
+            for bit in set.bloom_bits(key) {
+                set.bloom[bit / 64] |= 1 << (bit % 64);
+            }
+        }
+        Ok(set)
+    }
+
+    /// Number of distinct CIDs in the set.
+    pub fn len(&self) -> u64 {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn contains(&mut self, cid: &Cid) -> Result<bool> {
+        let key = key(cid);
+        if !self
+            .bloom_bits(key)
+            .all(|bit| self.bloom[bit / 64] & (1 << (bit % 64)) != 0)
+        {
+            return Ok(false);
+        }
+        let Some(block) = self.fences.partition_point(|f| *f <= key).checked_sub(1) else {
+            return Ok(false);
+        };
+        let first = (block * BLOCK_KEYS) as u64;
+        let keys = (self.count - first).min(BLOCK_KEYS as u64) as usize;
+        let block = &mut self.block[..keys * KEY];
+        self.file
+            .seek(SeekFrom::Start(HEADER + first * KEY as u64))?;
+        self.file.read_exact(block)?;
+        let at =
+            |i: usize| u128::from_be_bytes(block[i * KEY..][..KEY].try_into().expect("16 bytes"));
+        let (mut lo, mut hi) = (0, keys);
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            match at(mid).cmp(&key) {
+                std::cmp::Ordering::Less => lo = mid + 1,
+                std::cmp::Ordering::Greater => hi = mid,
+                std::cmp::Ordering::Equal => return Ok(true),
+            }
+        }
+        Ok(false)
+    }
+
+    /// The bits of the key in the bloom filter, by double hashing the halves
+    /// of the key, which is itself a hash.
+    fn bloom_bits(&self, key: u128) -> impl Iterator<Item = usize> {
+        let bits = self.bloom.len() as u64 * 64;
+        let (h1, h2) = (key as u64, (key >> 64) as u64 | 1);
+        (0..BLOOM_HASHES).map(move |i| {
+            let h = h1.wrapping_add(i.wrapping_mul(h2));
+            ((u128::from(h) * u128::from(bits)) >> 64) as usize
+        })
+    }
+}
+
+/// Builds the set of the CIDs of the blocks of the parquet files, and of the
+/// files in the directories, that pass the condition if any, the clause of a
+/// `WHERE` such as `data.type = 3`, into the index. Files without a top level
+/// CID column, such as the tables of exploded lists, are passed over, and the
+/// fields of files without the data column are the other top level columns,
+/// as written by `--hoist-data`.
+pub fn parquet_set(
+    inputs: &[PathBuf],
+    cid_column: &str,
+    data_column: &str,
+    condition: Option<&str>,
+    index: &Path,
+) -> Result<CidSet> {
+    let query = condition
+        .map(Query::filter)
+        .transpose()
+        .context("parsing the condition")?;
+    let files = export::parquet_files(inputs)?;
+    let cids = files.iter().flat_map(|path| {
+        match parquet_cids(path, cid_column, data_column, query.as_ref()) {
+            Ok(cids) => cids,
+            Err(e) => Box::new(std::iter::once(Err(e))),
+        }
+    });
+    build(cids, index, RUN_KEYS)?;
+    CidSet::open(index)
+}
+
+/// The CIDs of the rows of the file that pass the query, reading only the
+/// CID and data columns, or all but the block bytes without a data column.
+fn parquet_cids<'a>(
+    path: &Path,
+    cid_column: &'a str,
+    data_column: &'a str,
+    query: Option<&'a Query>,
+) -> Result<Box<dyn Iterator<Item = Result<Cid>> + 'a>> {
+    let f = File::open(path).context(format!("opening {}", path.display()))?;
+    let reader = SerializedFileReader::new(f).context(format!("reading {}", path.display()))?;
+    let schema = reader.metadata().file_metadata().schema();
+    let top_level = |name: &str| schema.get_fields().iter().any(|f| f.name() == name);
+    if !top_level(cid_column) {
+        return Ok(Box::new(std::iter::empty()));
+    }
+    let wrapped = top_level(data_column);
+    let mut fields: Vec<_> = schema
+        .get_fields()
+        .iter()
+        .filter(|f| match f.name() {
+            name if name == cid_column => true,
+            name if wrapped => name == data_column,
+            name => name != export::RAWDATA,
+        })
+        .cloned()
+        .collect();
+    let projection = Type::group_type_builder(schema.name())
+        .with_fields(&mut fields)
+        .build()?;
+    let rows = RowIter::from_file_into(Box::new(reader)).project(Some(projection))?;
+    let path = path.to_path_buf();
+    Ok(Box::new(rows.filter_map(move |row| {
+        let (mut cid, mut data) = (None, Ipld::Null);
+        let mut hoisted = std::collections::BTreeMap::new();
+        for (name, field) in row.get_column_iter() {
+            if name == cid_column {
+                cid = match field {
+                    Field::Bytes(b) => Some(Cid::try_from(b.data()).map_err(anyhow::Error::from)),
+                    Field::Str(s) => Some(Cid::try_from(s.as_str()).map_err(anyhow::Error::from)),
+                    _ => None,
+                };
+            } else if wrapped {
+                data = field_ipld(field);
+            } else {
+                hoisted.insert(name.clone(), field_ipld(field));
+            }
+        }
+        if !wrapped {
+            data = Ipld::Map(hoisted);
+        }
+        match cid? {
+            Ok(cid) => query
+                .is_none_or(|q| q.matches(&cid, &data))
+                .then_some(Ok(cid)),
+            Err(e) => Some(Err(
+                e.context(format!("reading a CID of {}", path.display()))
+            )),
+        }
+    })))
+}
+
+/// The value of a parquet field as the IPLD the conditions compare, dates and
+/// times as their integers and decimals as their strings.
+fn field_ipld(field: &Field) -> Ipld {
+    match field {
+        Field::Null => Ipld::Null,
+        Field::Bool(b) => Ipld::Bool(*b),
+        Field::Byte(i) => Ipld::Integer((*i).into()),
+        Field::Short(i) => Ipld::Integer((*i).into()),
+        Field::Int(i) | Field::Date(i) => Ipld::Integer((*i).into()),
+        Field::Long(i) | Field::TimestampMillis(i) | Field::TimestampMicros(i) => {
+            Ipld::Integer((*i).into())
+        }
+        Field::UByte(i) => Ipld::Integer((*i).into()),
+        Field::UShort(i) => Ipld::Integer((*i).into()),
+        Field::UInt(i) => Ipld::Integer((*i).into()),
+        Field::ULong(i) => Ipld::Integer((*i).into()),
+        Field::Float(f) => Ipld::Float((*f).into()),
+        Field::Double(f) => Ipld::Float(*f),
+        Field::Decimal(_) => Ipld::String(field.to_string()),
+        Field::Str(s) => Ipld::String(s.clone()),
+        Field::Bytes(b) => Ipld::Bytes(b.data().to_vec()),
+        Field::Group(row) => Ipld::Map(
+            row.get_column_iter()
+                .map(|(name, field)| (name.clone(), field_ipld(field)))
+                .collect(),
+        ),
+        Field::ListInternal(list) => Ipld::List(list.elements().iter().map(field_ipld).collect()),
+        Field::MapInternal(map) => Ipld::Map(
+            map.entries()
+                .iter()
+                .map(|(k, v)| {
+                    let key = match k {
+                        Field::Str(s) => s.clone(),
+                        k => k.to_string(),
+                    };
+                    (key, field_ipld(v))
+                })
+                .collect(),
+        ),
+    }
+}
+
+fn key(cid: &Cid) -> u128 {
+    let digest = Code::Sha2_256.digest(&cid.to_bytes());
+    u128::from_be_bytes(digest.digest()[..KEY].try_into().expect("16 bytes"))
+}
+
+/// Builds the index of the CIDs: sorts runs of `run_keys` keys in memory,
+/// writes each to a file beside the index and merges them.
+fn build(cids: impl Iterator<Item = Result<Cid>>, index: &Path, run_keys: usize) -> Result<()> {
+    let mut runs = Vec::new();
+    let mut keys = Vec::new();
+    let result = (|| {
+        for cid in cids {
+            keys.push(key(&cid?));
+            if keys.len() == run_keys {
+                runs.push(write_run(index, runs.len(), &mut keys)?);
+            }
+        }
+        if runs.is_empty() {
+            keys.sort_unstable();
+            keys.dedup();
+            return write_index(index, keys.len() as u64, keys.into_iter());
+        }
+        if !keys.is_empty() {
+            runs.push(write_run(index, runs.len(), &mut keys)?);
+        }
+        merge(index, &runs)
+    })();
+    for run in &runs {
+        let _ = std::fs::remove_file(run);
+    }
+    if result.is_err() {
+        let _ = std::fs::remove_file(index);
+    }
+    result
+}
+
+fn write_run(index: &Path, i: usize, keys: &mut Vec<u128>) -> Result<PathBuf> {
+    keys.sort_unstable();
+    keys.dedup();
+    let path = PathBuf::from(format!("{}.run{}", index.display(), i));
+    let mut out =
+        BufWriter::new(File::create(&path).context(format!("creating {}", path.display()))?);
+    for key in keys.drain(..) {
+        out.write_all(&key.to_be_bytes())?;
+    }
+    out.flush()?;
+    Ok(path)
+}
+
+/// Merges the sorted runs into the index, dropping the keys of repeated CIDs.
+fn merge(index: &Path, runs: &[PathBuf]) -> Result<()> {
+    let mut readers = runs
+        .iter()
+        .map(|r| Ok(BufReader::new(File::open(r)?)))
+        .collect::<Result<Vec<_>>>()?;
+    let next = |r: &mut BufReader<File>| -> Result<Option<u128>> {
+        let mut key = [0; KEY];
+        match r.read_exact(&mut key) {
+            Ok(()) => Ok(Some(u128::from_be_bytes(key))),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    };
+    let mut heap = BinaryHeap::new();
+    for (i, r) in readers.iter_mut().enumerate() {
+        if let Some(key) = next(r)? {
+            heap.push(std::cmp::Reverse((key, i)));
+        }
+    }
+    // The count is written in the header once known.
+    let mut out =
+        BufWriter::new(File::create(index).context(format!("creating {}", index.display()))?);
+    out.write_all(MAGIC)?;
+    out.write_all(&0u64.to_le_bytes())?;
+    let (mut count, mut last) = (0u64, None);
+    while let Some(std::cmp::Reverse((key, i))) = heap.pop() {
+        if last != Some(key) {
+            out.write_all(&key.to_be_bytes())?;
+            count += 1;
+            last = Some(key);
+        }
+        if let Some(key) = next(&mut readers[i])? {
+            heap.push(std::cmp::Reverse((key, i)));
+        }
+    }
+    let mut file = out.into_inner().map_err(|e| e.into_error())?;
+    file.seek(SeekFrom::Start(8))?;
+    file.write_all(&count.to_le_bytes())?;
+    Ok(())
+}
+
+fn write_index(index: &Path, count: u64, keys: impl Iterator<Item = u128>) -> Result<()> {
+    let mut out =
+        BufWriter::new(File::create(index).context(format!("creating {}", index.display()))?);
+    out.write_all(MAGIC)?;
+    out.write_all(&count.to_le_bytes())?;
+    for key in keys {
+        out.write_all(&key.to_be_bytes())?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// How far a copy got, saved to its checkpoint file.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Checkpoint {
+    /// Offset in the input of the next section to read.
+    input: u64,
+    /// Length of the output written up to there.
+    output: u64,
+    blocks: u64,
+    selected: u64,
+}
+
+impl Checkpoint {
+    fn load(path: &Path) -> Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let f = File::open(path).context(format!("opening {}", path.display()))?;
+        Ok(Some(
+            serde_json::from_reader(BufReader::new(f))
+                .context(format!("parsing {}", path.display()))?,
+        ))
+    }
+
+    /// Saves the checkpoint, replacing the last one only once written whole.
+    fn save(&self, path: &Path) -> Result<()> {
+        let tmp = PathBuf::from(format!("{}.tmp", path.display()));
+        std::fs::write(&tmp, serde_json::to_vec(self)?)
+            .context(format!("writing {}", tmp.display()))?;
+        std::fs::rename(&tmp, path).context(format!("writing {}", path.display()))?;
+        Ok(())
+    }
+}
+
+/// Characteristics of the blocks to copy, read from their CIDs and lengths
+/// without decoding them. Empty lists allow any codec or hash function.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    pub codecs: Vec<u64>,
+    /// Multihash codes.
+    pub hashes: Vec<u64>,
+    /// Bounds of the length of the block data, inclusive.
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+}
+
+impl Filter {
+    pub fn is_empty(&self) -> bool {
+        self.codecs.is_empty()
+            && self.hashes.is_empty()
+            && self.min_size.is_none()
+            && self.max_size.is_none()
+    }
+
+    pub fn matches(&self, cid: &Cid, size: u64) -> bool {
+        (self.codecs.is_empty() || self.codecs.contains(&cid.codec()))
+            && (self.hashes.is_empty() || self.hashes.contains(&cid.hash().code()))
+            && self.min_size.is_none_or(|min| size >= min)
+            && self.max_size.is_none_or(|max| size <= max)
+    }
+}
+
+/// Totals of a copy.
+#[derive(Debug)]
+pub struct Selection {
+    pub blocks: u64,
+    pub selected: u64,
+    /// Whether the copy carried on from a checkpoint.
+    pub resumed: bool,
+}
+
+/// Copies the blocks of the CAR that match the filter, and are in the set if
+/// any, to the output, a CAR with the given roots or else those of the input,
+/// resuming from the checkpoint file if given and present. The checkpoint is
+/// removed once the copy is done.
+pub fn select(
+    car: &Path,
+    mut set: Option<&mut CidSet>,
+    filter: &Filter,
+    roots: Option<&[Cid]>,
+    output: &Path,
+    checkpoint: Option<&Path>,
+) -> Result<Selection> {
+    let saved = checkpoint.map(Checkpoint::load).transpose()?.flatten();
+    let resumed = saved.is_some();
+    let (mut sections, mut out, mut progress) = match saved {
+        Some(saved) => {
+            let input = carv2::open(car, saved.input)?;
+            let sections =
+                Sections::resume(BufReader::with_capacity(scan::BUFFER, input), saved.input);
+            let mut out = std::fs::OpenOptions::new()
+                .write(true)
+                .open(output)
+                .context(format!("opening {}", output.display()))?;
+            if out.metadata()?.len() < saved.output {
+                return Err(anyhow!(
+                    "{} is shorter than its checkpoint, remove the checkpoint to start over",
+                    output.display()
+                ));
+            }
+            out.set_len(saved.output)?;
+            out.seek(SeekFrom::End(0))?;
+            (sections, BufWriter::new(out), saved)
+        }
+        None => {
+            let sections =
+                Sections::new(BufReader::with_capacity(scan::BUFFER, carv2::open(car, 0)?))
+                    .context(format!("reading {}", car.display()))?;
+            let mut out = BufWriter::new(
+                File::create(output).context(format!("creating {}", output.display()))?,
+            );
+            scan::write_header(&mut out, roots.unwrap_or(sections.roots()))?;
+            (sections, out, Checkpoint::default())
+        }
+    };
+    let mut next_checkpoint = progress.input + CHECKPOINT_BYTES;
+    while let Some((cid, data)) = sections
+        .next_block()
+        .context(format!("reading {}", car.display()))?
+    {
+        progress.blocks += 1;
+        let selected = filter.matches(&cid, data.len() as u64)
+            && match &mut set {
+                Some(set) => set.contains(&cid)?,
+                None => true,
+            };
+        if selected {
+            scan::write_section(&mut out, Some(&cid), &data)?;
+            progress.selected += 1;
+        }
+        if let (Some(path), true) = (checkpoint, sections.position() >= next_checkpoint) {
+            out.flush()?;
+            out.get_ref().sync_data()?;
+            progress.input = sections.position();
+            progress.output = out.get_mut().stream_position()?;
+            progress.save(path)?;
+            next_checkpoint = progress.input + CHECKPOINT_BYTES;
+        }
+    }
+    out.flush()?;
+    if let Some(path) = checkpoint {
+        let _ = std::fs::remove_file(path);
+    }
+    Ok(Selection {
+        blocks: progress.blocks,
+        selected: progress.selected,
+        resumed,
+    })
+}
+
+/// Copies the blocks of the CIDs of the list that match the filter from a
+/// CARv2, found by its index, to the output like [`select`], in the order of
+/// the CAR. The offsets of the listed blocks are held in memory, 24 bytes
+/// each, and the blocks counted are the entries of the index.
+pub fn select_indexed(
+    car: &Path,
+    list: &CidList,
+    filter: &Filter,
+    roots: Option<&[Cid]>,
+    output: &Path,
+) -> Result<Selection> {
+    let mut index = carv2::Index::open(car)?.ok_or_else(|| {
+        anyhow!(
+            "{} is not a CARv2 with an index, select from it without the index",
+            car.display()
+        )
+    })?;
+    let mut wanted = Vec::new();
+    for cid in list.cids()? {
+        let cid = cid?;
+        // Entries are by multihash, the block found may have another codec.
+        for offset in index.find(&cid)? {
+            wanted.push((offset, key(&cid)));
+        }
+    }
+    wanted.sort_unstable();
+    wanted.dedup();
+
+    let mut input = File::open(car).context(format!("opening {}", car.display()))?;
+    let header =
+        carv2::header(&mut input)?.ok_or_else(|| anyhow!("{} is not a CARv2", car.display()))?;
+    let roots = match roots {
+        Some(roots) => roots.to_vec(),
+        None => Sections::new(BufReader::new(carv2::open(car, 0)?))
+            .context(format!("reading {}", car.display()))?
+            .roots()
+            .to_vec(),
+    };
+    let mut out =
+        BufWriter::new(File::create(output).context(format!("creating {}", output.display()))?);
+    scan::write_header(&mut out, &roots)?;
+    let mut selected = 0;
+    for (offset, key_of) in wanted {
+        if offset >= header.data_size {
+            return Err(anyhow!("index entry at {} is outside the CAR data", offset));
+        }
+        input.seek(SeekFrom::Start(header.data_offset + offset))?;
+        let data = (&mut input).take(header.data_size - offset);
+        let block = Sections::resume(BufReader::new(data), offset)
+            .next_block()
+            .context(format!(
+                "reading the block at {} of {}",
+                offset,
+                car.display()
+            ))?;
+        let Some((cid, data)) = block else {
+            return Err(anyhow!("no block at {} of {}", offset, car.display()));
+        };
+        if key(&cid) == key_of && filter.matches(&cid, data.len() as u64) {
+            scan::write_section(&mut out, Some(&cid), &data)?;
+            selected += 1;
+        }
+    }
+    out.flush()?;
+    Ok(Selection {
+        blocks: index.len(),
+        selected,
+        resumed: false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    static FILES: AtomicUsize = AtomicUsize::new(0);
+
+    fn temp(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "carquet-select-{}-{}-{}",
+            std::process::id(),
+            FILES.fetch_add(1, Ordering::Relaxed),
+            name
+        ))
+    }
+
+    fn cid(i: u32) -> Cid {
+        Cid::new_v1(0x55, Code::Sha2_256.digest(&i.to_le_bytes()))
+    }
+
+    /// Builds the set of the CIDs in runs of `run_keys` and opens it.
+    fn set(cids: impl Iterator<Item = Cid>, run_keys: usize) -> (PathBuf, CidSet) {
+        let path = temp("cids");
+        build(cids.map(Ok), &path, run_keys).unwrap();
+        let set = CidSet::open(&path).unwrap();
+        (path, set)
+    }
+
+    #[test]
+    fn set_finds_keys_of_every_block() {
+        // Three blocks of keys, the last one partial.
+        let (path, mut set) = set((0..600).map(cid), RUN_KEYS);
+        assert_eq!(set.len(), 600);
+        let mut keys: Vec<u128> = (0..600).map(|i| key(&cid(i))).collect();
+        keys.sort_unstable();
+        let fences: Vec<u128> = keys.iter().step_by(BLOCK_KEYS).copied().collect();
+        assert_eq!(set.fences, fences);
+        for i in 0..600 {
+            assert!(set.contains(&cid(i)).unwrap(), "cid {}", i);
+        }
+        for i in 600..1200 {
+            assert!(!set.contains(&cid(i)).unwrap(), "cid {}", i);
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn set_finds_keys_at_fences() {
+        let (path, mut set) = set((0..600).map(cid), RUN_KEYS);
+        let mut cids: Vec<Cid> = (0..600).map(cid).collect();
+        cids.sort_unstable_by_key(key);
+        // The first and last keys of each block and the keys beside them.
+        for i in (0..600usize).step_by(BLOCK_KEYS) {
+            for c in &cids[i.saturating_sub(1)..(i + 2).min(600)] {
+                assert!(set.contains(c).unwrap());
+            }
+        }
+        assert!(set.contains(&cids[599]).unwrap());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn merged_runs_drop_repeated_cids() {
+        // Runs of 100 keys, each CID listed twice in different runs.
+        let cids = (0..350).chain(0..350).map(cid);
+        let (path, mut set) = set(cids, 100);
+        assert_eq!(set.len(), 350);
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().len(),
+            HEADER + 350 * KEY as u64
+        );
+        for i in 0..350 {
+            assert!(set.contains(&cid(i)).unwrap());
+        }
+        assert!(!set.contains(&cid(350)).unwrap());
+        // The runs are removed once merged.
+        assert!(!PathBuf::from(format!("{}.run0", path.display())).exists());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn empty_set_contains_nothing() {
+        let (path, mut set) = set(std::iter::empty(), RUN_KEYS);
+        assert!(set.is_empty());
+        assert!(!set.contains(&cid(0)).unwrap());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn truncated_set_is_an_error() {
+        let (path, set) = set((0..10).map(cid), RUN_KEYS);
+        drop(set);
+        let len = std::fs::metadata(&path).unwrap().len();
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 1)
+            .unwrap();
+        let err = CidSet::open(&path).err().unwrap().to_string();
+        assert!(err.ends_with("is truncated"), "{}", err);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn checkpoint_round_trips() {
+        let path = temp("checkpoint");
+        assert!(Checkpoint::load(&path).unwrap().is_none());
+        let saved = Checkpoint {
+            input: 10,
+            output: 20,
+            blocks: 3,
+            selected: 2,
+        };
+        saved.save(&path).unwrap();
+        let loaded = Checkpoint::load(&path).unwrap().unwrap();
+        assert_eq!(
+            (loaded.input, loaded.output, loaded.blocks, loaded.selected),
+            (10, 20, 3, 2)
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn resume_truncates_output_to_checkpoint() {
+        let car = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/generic.car");
+        let expected = temp("expected.car");
+        let full = select(&car, None, &Filter::default(), None, &expected, None).unwrap();
+        assert!(full.blocks >= 2);
+
+        // The input and output as they were after the first block.
+        let mut sections = carv2::open_blocks(&car).unwrap();
+        let mut head = Vec::new();
+        scan::write_header(&mut head, sections.roots()).unwrap();
+        let (cid, data) = sections.next_block().unwrap().unwrap();
+        scan::write_section(&mut head, Some(&cid), &data).unwrap();
+        let checkpoint = temp("checkpoint");
+        Checkpoint {
+            input: sections.position(),
+            output: head.len() as u64,
+            blocks: 1,
+            selected: 1,
+        }
+        .save(&checkpoint)
+        .unwrap();
+        // The output goes on past the checkpoint, as if stopped mid block.
+        let output = temp("output.car");
+        head.extend_from_slice(b"partial block");
+        std::fs::write(&output, &head).unwrap();
+
+        let resumed = select(
+            &car,
+            None,
+            &Filter::default(),
+            None,
+            &output,
+            Some(&checkpoint),
+        )
+        .unwrap();
+        assert!(resumed.resumed);
+        assert_eq!(
+            (resumed.blocks, resumed.selected),
+            (full.blocks, full.selected)
+        );
+        assert_eq!(
+            std::fs::read(&output).unwrap(),
+            std::fs::read(&expected).unwrap()
+        );
+        assert!(!checkpoint.exists());
+        for path in [expected, output] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn resume_rejects_output_shorter_than_checkpoint() {
+        let car = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/generic.car");
+        let checkpoint = temp("checkpoint");
+        Checkpoint {
+            input: 0,
+            output: 1000,
+            blocks: 0,
+            selected: 0,
+        }
+        .save(&checkpoint)
+        .unwrap();
+        let output = temp("output.car");
+        std::fs::write(&output, b"short").unwrap();
+        let err = select(
+            &car,
+            None,
+            &Filter::default(),
+            None,
+            &output,
+            Some(&checkpoint),
+        )
+        .err()
+        .unwrap()
+        .to_string();
+        assert!(err.contains("shorter than its checkpoint"), "{}", err);
+        for path in [checkpoint, output] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+}
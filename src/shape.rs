@@ -0,0 +1,132 @@
+//! Schemas of blocks, inferred once for each shape of block.
+//!
+//! Inferring the schema of a block copies every field name in it, which over
+//! the hundreds of millions of blocks of a chain snapshot is most of the
+//! allocation of a conversion. Blocks of the same shape, the kinds of their
+//! values and the names of their fields, have the same schema, so each block
+//! is only hashed by its shape as it is walked, which allocates nothing, and
+//! its schema inferred if the shape has not been seen before.
+//!
+//! Lists are hashed by the distinct shapes of their elements, as their schema
+//! is the widened schema of all of them, so lists of any length of the same
+//! elements have one shape. Shapes are 128 bit hashes, two SipHashes of the
+//! walk, so distinct shapes do not collide in practice.
+
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+};
+
+use libipld::Ipld;
+
+use crate::{wrapper_schema, Schema};
+
+/// Shapes kept at most, beyond which all are forgotten and inferred again,
+/// for blocks whose field names are data such as the keys of a HAMT.
+const MAX_SHAPES: usize = 1 << 16;
+
+/// The wrapper schemas of the blocks seen, by their shape.
+#[derive(Debug, Default)]
+pub struct Shapes {
+    schemas: HashMap<u128, Schema>,
+    /// Whether the schemas have the `rawdata` column of the block bytes.
+    rawdata: bool,
+    /// Whether the schemas have the `block_size` column of their length.
+    block_size: bool,
+}
+
+impl Shapes {
+    pub fn new(rawdata: bool, block_size: bool) -> Self {
+        Self {
+            schemas: HashMap::new(),
+            rawdata,
+            block_size,
+        }
+    }
+
+    /// Schema of the row written for a block of the data.
+    pub fn schema(&mut self, dag: &Ipld) -> &Schema {
+        let shape = shape(dag);
+        if self.schemas.len() >= MAX_SHAPES && !self.schemas.contains_key(&shape) {
+            self.schemas.clear();
+        }
+        self.schemas
+            .entry(shape)
+            .or_insert_with(|| wrapper_schema(dag, self.rawdata, self.block_size))
+    }
+}
+
+/// Hash of the shape of the value.
+fn shape(dag: &Ipld) -> u128 {
+    let mut h = ShapeHasher::default();
+    match dag {
+        Ipld::Null => h.write_u8(0),
+        Ipld::Bool(_) => h.write_u8(1),
+        Ipld::Integer(_) => h.write_u8(2),
+        Ipld::Float(_) => h.write_u8(3),
+        Ipld::String(_) => h.write_u8(4),
+        Ipld::Bytes(_) => h.write_u8(5),
+        Ipld::Link(_) => h.write_u8(6),
+        Ipld::List(l) => {
+            h.write_u8(7);
+            let mut elements = l.iter().map(shape);
+            let first = elements.next();
+            // Lists of one shape of element, the most common, need no set.
+            let mut others: Vec<u128> = elements.filter(|s| Some(*s) != first).collect();
+            match first {
+                None => h.write_usize(0),
+                Some(first) if others.is_empty() => {
+                    h.write_usize(1);
+                    h.write_u128(first);
+                }
+                Some(first) => {
+                    others.push(first);
+                    others.sort_unstable();
+                    others.dedup();
+                    h.write_usize(others.len());
+                    for s in others {
+                        h.write_u128(s);
+                    }
+                }
+            }
+        }
+        Ipld::Map(m) => {
+            h.write_u8(8);
+            h.write_usize(m.len());
+            // The fields of IPLD maps are sorted, so equal shapes hash alike.
+            for (k, v) in m {
+                k.hash(&mut h);
+                h.write_u128(shape(v));
+            }
+        }
+    }
+    h.finish128()
+}
+
+/// Two SipHashes of the same input, one of it after a seed, for 128 bits.
+struct ShapeHasher(DefaultHasher, DefaultHasher);
+
+impl Default for ShapeHasher {
+    fn default() -> Self {
+        let mut seeded = DefaultHasher::new();
+        seeded.write_u8(0x5a);
+        Self(DefaultHasher::new(), seeded)
+    }
+}
+
+impl ShapeHasher {
+    fn finish128(&self) -> u128 {
+        (self.0.finish() as u128) << 64 | self.1.finish() as u128
+    }
+}
+
+impl Hasher for ShapeHasher {
+    fn finish(&self) -> u64 {
+        self.0.finish()
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.write(bytes);
+        self.1.write(bytes);
+    }
+}
@@ -0,0 +1,804 @@
+//! Shredding of the rows into parquet columns, the values of each leaf with
+//! their repetition and definition levels.
+
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{anyhow, Result};
+use libipld::{Cid, Ipld};
+use parquet::{
+    basic::Repetition,
+    data_type::{
+        BoolType, ByteArray, ByteArrayType, DataType, DoubleType, FixedLenByteArray,
+        FixedLenByteArrayType, FloatType, Int32Type, Int64Type, Int96Type,
+    },
+    file::writer::SerializedColumnWriter,
+    schema::types::{ColumnDescPtr, ColumnDescriptor, ColumnPath, Type},
+};
+
+use crate::{json, wrong_kind, Block, Columns, Config, Error, Failures, IntType, OutOfRange};
+
+pub fn col_desc<'a>(col_writer: &'a mut SerializedColumnWriter) -> &'a ColumnDescPtr {
+    match col_writer.untyped() {
+        parquet::column::writer::ColumnWriter::BoolColumnWriter(cw) => cw.get_descriptor(),
+        parquet::column::writer::ColumnWriter::Int32ColumnWriter(cw) => cw.get_descriptor(),
+        parquet::column::writer::ColumnWriter::Int64ColumnWriter(cw) => cw.get_descriptor(),
+        parquet::column::writer::ColumnWriter::Int96ColumnWriter(cw) => cw.get_descriptor(),
+        parquet::column::writer::ColumnWriter::FloatColumnWriter(cw) => cw.get_descriptor(),
+        parquet::column::writer::ColumnWriter::DoubleColumnWriter(cw) => cw.get_descriptor(),
+        parquet::column::writer::ColumnWriter::ByteArrayColumnWriter(cw) => cw.get_descriptor(),
+        parquet::column::writer::ColumnWriter::FixedLenByteArrayColumnWriter(cw) => {
+            cw.get_descriptor()
+        }
+    }
+}
+
+/// A value resolved for a column within one row.
+pub enum Resolved {
+    Value(Ipld),
+    /// No value, as the field is null or absent or the enclosing list empty.
+    Empty,
+    /// The value is missing or could not be converted to the column type.
+    Invalid(anyhow::Error),
+}
+
+/// Conversion errors of each row, collected in lenient mode.
+pub type RowErrors = Vec<Vec<String>>;
+
+/// The integer type of a column written without an --int-type, from its
+/// logical type.
+pub fn column_int_type(desc: &ColumnDescriptor) -> Option<IntType> {
+    match desc.logical_type()? {
+        parquet::basic::LogicalType::Integer {
+            bit_width,
+            is_signed,
+        } => Some(IntType::Int {
+            bits: bit_width as u8,
+            signed: is_signed,
+        }),
+        parquet::basic::LogicalType::Decimal { precision, scale } => Some(IntType::Decimal {
+            precision: precision as u32,
+            scale: scale as u32,
+        }),
+        parquet::basic::LogicalType::Date => Some(IntType::Date),
+        _ => None,
+    }
+}
+
+/// The value as `bits` of two's complement, the bits of an unsigned value
+/// of that width kept as they are, failing if it fits neither.
+pub fn int_bits(i: i128, bits: u8) -> Result<i128> {
+    let signed = IntType::Int { bits, signed: true };
+    if signed.convert(i).is_ok() {
+        return Ok(i);
+    }
+    let unsigned = IntType::Int {
+        bits,
+        signed: false,
+    };
+    unsigned.convert(i)?;
+    Ok(i - (1 << bits))
+}
+
+/// Path of the column as the options name it, without the `list` and `element`
+/// levels of LIST annotated groups.
+pub fn option_path(path: &ColumnPath, types: &[&Type]) -> String {
+    // The data group is left out of the types of top level fields of the data.
+    let skip = path.parts().len() - types.len();
+    let mut parts: Vec<&str> = path.parts()[..skip].iter().map(|p| p.as_str()).collect();
+    for (j, name) in path.parts()[skip..].iter().enumerate() {
+        let in_list =
+            (j > 0 && is_list_group(types[j - 1])) || (j > 1 && is_list_group(types[j - 2]));
+        if !in_list {
+            parts.push(name);
+        }
+    }
+    parts.join(".")
+}
+
+/// The parquet types along the path of the column, from its top level field.
+pub fn column_types<'a>(root: &'a Type, desc: &ColumnDescriptor) -> Result<Vec<&'a Type>> {
+    let mut types = vec![root];
+    for name in &desc.path().parts()[1..] {
+        let parent = types[types.len() - 1];
+        match parent.get_fields().iter().find(|f| f.name() == name) {
+            Some(t) => types.push(t),
+            None => return Err(anyhow!("column {} is not in the schema", desc.path())),
+        }
+    }
+    Ok(types)
+}
+
+/// Writes the leaf column of the writer for the rows, the values of each row
+/// with their levels through the lists and optional fields above the leaf.
+#[allow(clippy::too_many_arguments)]
+pub fn parquet_write_col(
+    col_writer: &mut SerializedColumnWriter,
+    root: &Type,
+    ints: &HashMap<String, IntType>,
+    cids: &[(Cid, Ipld, Vec<u8>)],
+    first_row: usize,
+    columns: &Columns,
+    config: &Config,
+    errors: Option<&mut RowErrors>,
+    failures: &mut Failures,
+) -> Result<()> {
+    let desc = col_desc(col_writer).clone();
+    let path = &ColumnPath::new(columns.internal(desc.path().parts()));
+    let types = column_types(root, &desc)?;
+    // The type given or chosen from the rows of the file, or the one of the
+    // column, of a target schema.
+    let int_type = ints
+        .get(&option_path(path, &types))
+        .copied()
+        .or_else(|| column_int_type(&desc));
+    // Integers of columns of neither are only checked to fit their bits.
+    let int_value = move |i: i128| match int_type {
+        Some(t) => t.convert(i),
+        None => Ok(i),
+    };
+
+    let mut entries: Vec<(usize, Resolved, i16, i16)> = Vec::new();
+    for (row, (cid, data, bytes)) in cids.iter().enumerate() {
+        let resolved = resolve_entries(cid, data, bytes.as_slice(), path, &types);
+        entries.extend(resolved.into_iter().map(|(v, rep, def)| (row, v, rep, def)));
+    }
+    if desc.logical_type() == Some(parquet::basic::LogicalType::Json) {
+        let json = config.json;
+        return write_typed::<ByteArrayType>(
+            col_writer,
+            entries,
+            cids,
+            first_row,
+            errors,
+            failures,
+            |v| {
+                let mut out = String::new();
+                json::write_json(&mut out, &v, json)?;
+                Ok(ByteArray::from(out.as_str()))
+            },
+        );
+    }
+    if desc.logical_type() == Some(parquet::basic::LogicalType::Unknown) {
+        return write_typed::<Int32Type>(
+            col_writer,
+            entries,
+            cids,
+            first_row,
+            errors,
+            failures,
+            |v| Err(wrong_kind(&v, "null")),
+        );
+    }
+    match desc.physical_type() {
+        parquet::basic::Type::BOOLEAN => write_typed::<BoolType>(
+            col_writer,
+            entries,
+            cids,
+            first_row,
+            errors,
+            failures,
+            |v| match v {
+                Ipld::Bool(b) => Ok(b),
+                _ => Err(wrong_kind(&v, "bool")),
+            },
+        ),
+        parquet::basic::Type::INT32 => write_typed::<Int32Type>(
+            col_writer,
+            entries,
+            cids,
+            first_row,
+            errors,
+            failures,
+            |v| match v {
+                Ipld::Integer(i) => int_bits(int_value(i)?, 32).map(|v| v as i32),
+                Ipld::String(s) if int_type.is_some_and(|t| t.is_time()) => {
+                    int_bits(int_value(int_type.unwrap_or_default().time(&s)?)?, 32)
+                        .map(|v| v as i32)
+                }
+                _ => Err(wrong_kind(&v, "integer")),
+            },
+        ),
+        parquet::basic::Type::INT64 => write_typed::<Int64Type>(
+            col_writer,
+            entries,
+            cids,
+            first_row,
+            errors,
+            failures,
+            |v| match v {
+                Ipld::Integer(i) => int_bits(int_value(i)?, 64).map(|v| v as i64),
+                Ipld::String(s) if int_type.is_some_and(|t| t.is_time()) => {
+                    int_bits(int_value(int_type.unwrap_or_default().time(&s)?)?, 64)
+                        .map(|v| v as i64)
+                }
+                _ => Err(wrong_kind(&v, "integer")),
+            },
+        ),
+        parquet::basic::Type::INT96 => {
+            let int_type = int_type.unwrap_or_default();
+            write_typed::<Int96Type>(
+                col_writer,
+                entries,
+                cids,
+                first_row,
+                errors,
+                failures,
+                |v| match v {
+                    Ipld::Integer(i) => int_type.int96(i),
+                    Ipld::String(s) => int_type.int96(int_type.time(&s)?),
+                    _ => Err(wrong_kind(&v, "integer")),
+                },
+            )
+        }
+        parquet::basic::Type::FLOAT => write_typed::<FloatType>(
+            col_writer,
+            entries,
+            cids,
+            first_row,
+            errors,
+            failures,
+            |v| match v {
+                Ipld::Float(f) => Ok(f as f32),
+                _ => Err(wrong_kind(&v, "float")),
+            },
+        ),
+        parquet::basic::Type::DOUBLE => write_typed::<DoubleType>(
+            col_writer,
+            entries,
+            cids,
+            first_row,
+            errors,
+            failures,
+            |v| match v {
+                Ipld::Float(f) => Ok(f),
+                // Lists of integers and floats are widened to floats.
+                Ipld::Integer(i) => Ok(i as f64),
+                _ => Err(wrong_kind(&v, "float")),
+            },
+        ),
+        parquet::basic::Type::BYTE_ARRAY => write_typed::<ByteArrayType>(
+            col_writer,
+            entries,
+            cids,
+            first_row,
+            errors,
+            failures,
+            |v| match v {
+                Ipld::String(s) => Ok(ByteArray::from(s.as_bytes())),
+                Ipld::Bytes(b) => Ok(ByteArray::from(b)),
+                // String columns of links, from --cid-strings or a target schema.
+                Ipld::Link(cid) if desc.converted_type() == parquet::basic::ConvertedType::UTF8 => {
+                    Ok(ByteArray::from(cid.to_string().into_bytes()))
+                }
+                Ipld::Link(cid) => Ok(ByteArray::from(cid.to_bytes())),
+                _ => Err(wrong_kind(&v, "string, bytes or link")),
+            },
+        ),
+
+        parquet::basic::Type::FIXED_LEN_BYTE_ARRAY => {
+            let len = desc.type_length() as usize;
+            write_typed::<FixedLenByteArrayType>(
+                col_writer,
+                entries,
+                cids,
+                first_row,
+                errors,
+                failures,
+                |v| match v {
+                    // Decimals are stored as big endian two's complement.
+                    Ipld::Integer(i) => Ok(FixedLenByteArray::from(
+                        int_value(i)?.to_be_bytes()[16 - len..].to_vec(),
+                    )),
+                    _ => Err(wrong_kind(&v, "integer")),
+                },
+            )
+        }
+    }
+}
+
+/// Converts and writes the resolved entries of the rows `cids` of a column,
+/// recording invalid values in `failures` with their row counted from `first_row`.
+///
+/// Without `errors` invalid values fail the column, once all of them are found.
+/// With `errors` invalid values are also recorded against their row and written
+/// as null, or for columns that cannot hold nulls the whole list of the row is
+/// dropped.
+pub fn write_typed<T: DataType>(
+    col_writer: &mut SerializedColumnWriter,
+    entries: Vec<(usize, Resolved, i16, i16)>,
+    cids: &[Block],
+    first_row: usize,
+    mut errors: Option<&mut RowErrors>,
+    failures: &mut Failures,
+    convert: impl Fn(Ipld) -> Result<T::T>,
+) -> Result<()> {
+    let desc = col_desc(col_writer).clone();
+    let path = desc.path().string();
+    let nullable = desc.self_type().get_basic_info().repetition() == Repetition::OPTIONAL;
+    let max_def = desc.max_def_level();
+    let mut converted = Vec::with_capacity(entries.len());
+    let mut dropped = HashSet::new();
+    let mut fatal = Failures::default();
+    let mut out_of_range = false;
+    for (row, value, rep, def) in entries {
+        let value = match value {
+            Resolved::Value(v) => convert(v).map(Some),
+            Resolved::Empty => Ok(None),
+            Resolved::Invalid(e) => Err(e),
+        };
+        let e = match value {
+            Ok(v) => {
+                converted.push((row, v, rep, def));
+                continue;
+            }
+            Err(e) => e,
+        };
+        // A value that fails is written as the absence of the innermost
+        // optional or repeated field it is in, if there is one.
+        let null_def = if def < max_def {
+            Some(def)
+        } else {
+            nullable.then_some(max_def - 1)
+        };
+        // Failures are grouped by reason, so the values out of range are kept apart.
+        let (reason, value) = match e.downcast_ref::<OutOfRange>() {
+            Some(e) => (
+                format!("integer out of range for {}", e.ty),
+                Some(e.value.to_string()),
+            ),
+            None => (format!("{:#}", e), None),
+        };
+        let failed = (first_row + row, &cids[row].0);
+        match errors.as_deref_mut() {
+            Some(errors) if null_def.is_some() || desc.max_rep_level() > 0 => {
+                errors[row].push(format!("{}: {:#}", path, e));
+                if null_def.is_none() {
+                    dropped.insert(row);
+                }
+                converted.push((row, None, rep, null_def.unwrap_or(0)));
+                failures.push(&path, reason, value, failed);
+            }
+            _ => {
+                out_of_range |= e.is::<OutOfRange>();
+                fatal.push(&path, reason, value, failed)
+            }
+        }
+    }
+    if !fatal.is_empty() {
+        let mut hint = String::new();
+        if errors.is_none() {
+            hint.push_str(", --lenient writes them as null");
+        }
+        if out_of_range {
+            hint.push_str(
+                ", --int-type gives the field a type that holds them, which is otherwise chosen from the first rows of the file",
+            );
+        }
+        return Err(Error::Write {
+            what: format!("column {}", path),
+            source: format!(
+                "values could not be converted{}:\n{}",
+                hint,
+                fatal.to_string().trim_end()
+            )
+            .into(),
+        }
+        .into());
+    }
+
+    let mut values = Vec::with_capacity(converted.len());
+    let mut def_levels = Vec::with_capacity(converted.len());
+    let mut rep_levels = Vec::with_capacity(converted.len());
+    let mut last_dropped = None;
+    for (row, value, rep, def) in converted {
+        if dropped.contains(&row) {
+            if last_dropped != Some(row) {
+                last_dropped = Some(row);
+                def_levels.push(0);
+                rep_levels.push(0);
+            }
+            continue;
+        }
+        if let Some(v) = value {
+            values.push(v);
+        }
+        def_levels.push(def);
+        rep_levels.push(rep);
+    }
+    col_writer.typed::<T>().write_batch(
+        &values,
+        (max_def > 0).then_some(def_levels.as_slice()),
+        Some(rep_levels.as_slice()),
+    )?;
+    Ok(())
+}
+
+/// Writes the `_conversion_errors` column from the errors collected for each row.
+pub fn write_errors_col(col_writer: &mut SerializedColumnWriter, errors: &RowErrors) -> Result<()> {
+    let mut values = Vec::new();
+    let mut def_levels = Vec::new();
+    let mut rep_levels = Vec::new();
+    for row in errors {
+        if row.is_empty() {
+            def_levels.push(0);
+            rep_levels.push(0);
+        }
+        for (i, e) in row.iter().enumerate() {
+            values.push(ByteArray::from(e.as_str()));
+            def_levels.push(1);
+            rep_levels.push(if i == 0 { 0 } else { 1 });
+        }
+    }
+    col_writer.typed::<ByteArrayType>().write_batch(
+        &values,
+        Some(def_levels.as_slice()),
+        Some(rep_levels.as_slice()),
+    )?;
+    Ok(())
+}
+
+/// The entries of the column within a row: each value, or the absence of one,
+/// with its repetition and definition levels.
+///
+/// `path` is the column path rooted at `cid` and `data` and `types` the parquet
+/// types along it, without the `data` group for top level fields of the data.
+pub fn resolve_entries(
+    cid: &Cid,
+    data: &Ipld,
+    bytes: &[u8],
+    path: &ColumnPath,
+    types: &[&Type],
+) -> Vec<(Resolved, i16, i16)> {
+    let mut entries = Vec::new();
+    let (Some(root), Some(top)) = (path.parts().first(), types.first()) else {
+        return vec![(Resolved::Invalid(anyhow!("empty column path")), 0, 0)];
+    };
+    let link = Ipld::Link(*cid);
+    let raw;
+    let value = match root.as_str() {
+        "cid" | "parent_cid" => Ok(Some(&link)),
+        "rawdata" => {
+            raw = Ipld::Bytes(bytes.to_vec());
+            Ok(Some(&raw))
+        }
+        "block_size" => {
+            raw = Ipld::Integer(bytes.len() as i128);
+            Ok(Some(&raw))
+        }
+        "data" if path.parts().len() == types.len() => Ok(Some(data)),
+        // Child table columns are resolved directly against the row.
+        _ => field(data, top.name()),
+    };
+    shred(types, 0, value, 0, 0, &mut entries);
+    entries
+}
+
+/// The field of a map, `None` if absent.
+pub fn field<'a>(value: &'a Ipld, name: &str) -> Result<Option<&'a Ipld>> {
+    match value {
+        Ipld::Map(m) => Ok(m.get(name)),
+        _ => Err(wrong_kind(value, "map")),
+    }
+}
+
+/// Adds the entries of the value of `types[i]`, whose enclosing values are
+/// at repetition level `rep` and definition level `def`.
+///
+/// Absent and null values of optional fields and absent and empty lists are
+/// entries without a value at the definition level of the enclosing value,
+/// any other value that does not fit is an invalid entry.
+pub fn shred(
+    types: &[&Type],
+    i: usize,
+    value: Result<Option<&Ipld>>,
+    rep: i16,
+    def: i16,
+    entries: &mut Vec<(Resolved, i16, i16)>,
+) {
+    let t = types[i];
+    let value = match value {
+        Ok(value) => value,
+        Err(e) => return entries.push((Resolved::Invalid(e), rep, def)),
+    };
+    match (t.get_basic_info().repetition(), value) {
+        (Repetition::OPTIONAL, None | Some(Ipld::Null)) => {
+            entries.push((Resolved::Empty, rep, def))
+        }
+        (Repetition::OPTIONAL, Some(v)) => descend(types, i, v, rep, def + 1, entries),
+        (Repetition::REQUIRED, Some(v)) => descend(types, i, v, rep, def, entries),
+        (Repetition::REQUIRED, None) => entries.push((
+            Resolved::Invalid(anyhow!("field {} is missing", t.name())),
+            rep,
+            def,
+        )),
+        (Repetition::REPEATED, None) => entries.push((Resolved::Empty, rep, def)),
+        (Repetition::REPEATED, Some(Ipld::List(l))) if l.is_empty() => {
+            entries.push((Resolved::Empty, rep, def))
+        }
+        (Repetition::REPEATED, Some(Ipld::List(l))) => {
+            // Elements after the first repeat at the level of this list.
+            let level = types[..=i]
+                .iter()
+                .filter(|t| t.get_basic_info().repetition() == Repetition::REPEATED)
+                .count() as i16;
+            for (j, v) in l.iter().enumerate() {
+                let rep = if j == 0 { rep } else { level };
+                descend(types, i, v, rep, def + 1, entries);
+            }
+        }
+        (Repetition::REPEATED, Some(v)) => {
+            entries.push((Resolved::Invalid(wrong_kind(v, "list")), rep, def))
+        }
+    }
+}
+
+/// Adds the entries of the present value `v` of `types[i]`.
+pub fn descend(
+    types: &[&Type],
+    i: usize,
+    v: &Ipld,
+    rep: i16,
+    def: i16,
+    entries: &mut Vec<(Resolved, i16, i16)>,
+) {
+    let Some(child) = types.get(i + 1) else {
+        return entries.push((Resolved::Value(v.clone()), rep, def));
+    };
+    // A LIST annotated group holds its list in the repeated `list` group,
+    // whose `element` is each element.
+    let value = if is_list_group(types[i]) || (i > 0 && is_list_group(types[i - 1])) {
+        Ok(Some(v))
+    } else {
+        field(v, child.name())
+    };
+    shred(types, i + 1, value, rep, def, entries)
+}
+
+pub fn is_list_group(t: &Type) -> bool {
+    let info = t.get_basic_info();
+    t.is_group()
+        && (info.logical_type() == Some(parquet::basic::LogicalType::List)
+            || info.converted_type() == parquet::basic::ConvertedType::LIST)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use libipld::ipld;
+    use parquet::{
+        file::reader::{FileReader, SerializedFileReader},
+        schema::types::SchemaDescriptor,
+    };
+    use tokio_util::sync::CancellationToken;
+
+    use super::*;
+    use crate::{
+        conformance::column_values,
+        schema::widened,
+        wrapper_schema,
+        writer::{root_fields, write_output, OutputFile},
+        ConvertArgs,
+    };
+
+    /// The repetition level, definition level and value of each entry of the
+    /// column for the data of each row, in the layout inferred for the rows.
+    fn entries(data: &[Ipld], column: &str) -> Vec<(i16, i16, Option<Ipld>)> {
+        let rows: Vec<Block> = data
+            .iter()
+            .map(|d| (Cid::default(), d.clone(), Vec::new()))
+            .collect();
+        let schema = rows
+            .iter()
+            .map(|(_, d, _)| wrapper_schema(d, false, false))
+            .reduce(widened)
+            .unwrap();
+        let config = ConvertArgs::default()
+            .config(CancellationToken::new())
+            .unwrap();
+        let columns = Columns::default();
+        let mut fields = root_fields(&schema, &columns, &config, &HashMap::new()).unwrap();
+        let root = Arc::new(
+            Type::group_type_builder("")
+                .with_fields(&mut fields)
+                .build()
+                .unwrap(),
+        );
+        let descr = SchemaDescriptor::new(root.clone());
+        let i = (0..descr.num_columns())
+            .find(|i| descr.column(*i).path().string() == column)
+            .unwrap_or_else(|| panic!("no column {column}"));
+        let desc = descr.column(i);
+        let types = column_types(descr.get_column_root(i), &desc).unwrap();
+        let path = ColumnPath::new(columns.internal(desc.path().parts()));
+        rows.iter()
+            .flat_map(|(cid, data, bytes)| resolve_entries(cid, data, bytes, &path, &types))
+            .map(|(resolved, rep, def)| match resolved {
+                Resolved::Value(v) => (rep, def, Some(v)),
+                Resolved::Empty => (rep, def, None),
+                Resolved::Invalid(e) => panic!("{e:#}"),
+            })
+            .collect()
+    }
+
+    /// The entries of the column as written to a parquet file of the rows and
+    /// read back, each value rendered as by the conformance checks.
+    fn written(data: &[Ipld], column: &str) -> Vec<(i16, i16, String)> {
+        static FILES: AtomicUsize = AtomicUsize::new(0);
+        let rows: Vec<Block> = data
+            .iter()
+            .map(|d| (Cid::default(), d.clone(), Vec::new()))
+            .collect();
+        let schema = rows
+            .iter()
+            .map(|(_, d, _)| wrapper_schema(d, false, false))
+            .reduce(widened)
+            .unwrap();
+        let config = ConvertArgs::default()
+            .config(CancellationToken::new())
+            .unwrap();
+        let path = std::env::temp_dir().join(format!(
+            "carquet-shred-{}-{}.parquet",
+            std::process::id(),
+            FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        write_output(
+            &path,
+            &schema,
+            &rows,
+            &[],
+            &Columns::default(),
+            &config,
+            None,
+        )
+        .unwrap();
+        let reader = SerializedFileReader::new(std::fs::File::open(&path).unwrap()).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let row_group = reader.get_row_group(0).unwrap();
+        let c = (0..row_group.num_columns())
+            .find(|c| row_group.metadata().column(*c).column_path().string() == column)
+            .unwrap_or_else(|| panic!("no column {column}"));
+        let descr = row_group.metadata().column(c).column_descr_ptr();
+        column_values(row_group.get_column_reader(c).unwrap(), &descr).unwrap()
+    }
+
+    fn int(i: i128) -> Option<Ipld> {
+        Some(Ipld::Integer(i))
+    }
+
+    #[test]
+    fn lists_of_lists_repeat_at_the_level_of_each_list() {
+        let rows = [ipld!({"m": [[1, 2], [], [3]]}), ipld!({"m": [[4], [5, 6]]})];
+        assert_eq!(
+            entries(&rows, "data.m.list.element.list.element"),
+            [
+                (0, 2, int(1)),
+                (2, 2, int(2)),
+                (1, 1, None),
+                (1, 2, int(3)),
+                (0, 2, int(4)),
+                (1, 2, int(5)),
+                (2, 2, int(6)),
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_lists_are_defined_at_their_parent() {
+        let column = "data.m.list.element.list.element";
+        assert_eq!(entries(&[ipld!({"m": [[]]})], column), [(0, 1, None)]);
+        // A list only ever empty has no elements to nest.
+        assert_eq!(entries(&[ipld!({"m": []})], "data.m"), [(0, 0, None)]);
+    }
+
+    #[test]
+    fn maps_in_lists_repeat_their_fields() {
+        let rows = [ipld!({"p": [{"x": 1, "t": [[true]]}, {"x": 2, "t": []}]})];
+        assert_eq!(entries(&rows, "data.p.x"), [(0, 1, int(1)), (1, 1, int(2))]);
+        assert_eq!(
+            entries(&rows, "data.p.t.list.element.list.element"),
+            [(0, 3, Some(Ipld::Bool(true))), (1, 1, None)]
+        );
+    }
+
+    #[test]
+    fn nulls_in_nested_lists_are_defined_below_the_element() {
+        let rows = [ipld!({"q": [[1, null], [null]]}), ipld!({"q": [[null, 2]]})];
+        assert_eq!(
+            entries(&rows, "data.q.list.element.list.element"),
+            [
+                (0, 3, int(1)),
+                (2, 2, None),
+                (1, 2, None),
+                (0, 2, None),
+                (2, 3, int(2)),
+            ]
+        );
+    }
+
+    #[test]
+    fn int_bits_keeps_signed_and_unsigned_bit_patterns() {
+        assert_eq!(int_bits(-1, 64).unwrap(), -1);
+        assert_eq!(int_bits(i64::MIN as i128, 64).unwrap(), i64::MIN as i128);
+        assert_eq!(int_bits(i64::MAX as i128, 64).unwrap(), i64::MAX as i128);
+        assert_eq!(int_bits(u64::MAX as i128, 64).unwrap(), -1);
+        assert_eq!(int_bits(1 << 63, 64).unwrap(), i64::MIN as i128);
+        assert_eq!(int_bits(u32::MAX as i128, 32).unwrap(), -1);
+        // dag-cbor reaches down to -2^64, beyond 64 bits either way.
+        assert!(int_bits(i64::MIN as i128 - 1, 64).is_err());
+        assert!(int_bits(-(1 << 64), 64).is_err());
+        assert!(int_bits(1 << 64, 64).is_err());
+        assert!(int_bits(1 << 32, 32).is_err());
+    }
+
+    fn levels(entries: &[(i16, i16, &str)]) -> Vec<(i16, i16, String)> {
+        entries
+            .iter()
+            .map(|(rep, def, v)| (*rep, *def, v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn nulls_in_repeated_groups_are_written_undefined() {
+        let rows = [ipld!({"a": [{"x": 1}, {"x": null}, {"x": 3}]})];
+        assert_eq!(
+            written(&rows, "data.a.x"),
+            levels(&[(0, 2, "1"), (1, 1, "-"), (1, 2, "3")])
+        );
+    }
+
+    #[test]
+    fn optional_groups_in_lists_are_written_undefined_when_absent() {
+        let rows = [ipld!({"a": [{"g": {"v": 1}}, {"h": true}, {"g": null}]})];
+        assert_eq!(
+            written(&rows, "data.a.g.v"),
+            levels(&[(0, 2, "1"), (1, 1, "-"), (1, 1, "-")])
+        );
+        assert_eq!(
+            written(&rows, "data.a.h"),
+            levels(&[(0, 1, "-"), (1, 2, "true"), (1, 1, "-")])
+        );
+    }
+
+    #[test]
+    fn fields_some_rows_lack_are_written_undefined() {
+        let rows = [
+            ipld!({"a": 1, "b": {"c": "x"}}),
+            ipld!({"a": 2}),
+            ipld!({"a": 3, "b": {"c": "y"}}),
+        ];
+        assert_eq!(
+            written(&rows, "data.a"),
+            levels(&[(0, 0, "1"), (0, 0, "2"), (0, 0, "3")])
+        );
+        assert_eq!(
+            written(&rows, "data.b.c"),
+            levels(&[(0, 1, "\"x\""), (0, 0, "-"), (0, 1, "\"y\"")])
+        );
+    }
+
+    #[test]
+    fn later_values_out_of_the_type_of_the_first_rows_name_int_type() {
+        let first: Vec<Block> = vec![(Cid::default(), ipld!({"x": 1}), Vec::new())];
+        let later: Vec<Block> = vec![(Cid::default(), ipld!({"x": -1}), Vec::new())];
+        let schema = wrapper_schema(&first[0].1, false, false);
+        let config = ConvertArgs::default()
+            .config(CancellationToken::new())
+            .unwrap();
+        let path = std::env::temp_dir().join(format!(
+            "carquet-shred-{}-later.parquet",
+            std::process::id()
+        ));
+        let columns = Columns::default();
+        let mut file = OutputFile::create(&path, &schema, &first, &[], &columns, &config).unwrap();
+        file.write(&schema, &first, &config, None).unwrap();
+        let err = file.write(&schema, &later, &config, None).unwrap_err();
+        let _ = std::fs::remove_file(&path);
+        let err = format!("{:#}", err);
+        assert!(err.contains("integer out of range for uint64"), "{}", err);
+        assert!(err.contains("--int-type gives the field a type"), "{}", err);
+    }
+}
@@ -0,0 +1,463 @@
+//! A subset of SQL for projecting and filtering the blocks of a conversion.
+//!
+//! The query reads from a single table, `blocks`, whose columns are `cid` and
+//! the fields below `data`:
+//!
+//! ```sql
+//! SELECT cid, data.height FROM blocks WHERE data.type = 'post' AND data.height > 10
+//! ```
+//!
+//! The select list is `*`, `data` or paths below it. `cid` is always written
+//! and blocks with none of the selected fields are left out.
+//!
+//! The filter compares paths with literals using `=`, `<>`, `<`, `<=`, `>`,
+//! `>=`, `IS [NOT] NULL`, `AND`, `OR` and `NOT`. Paths are unquoted, so a
+//! double quoted name is read as a string, as in `data.type = "post"`. A
+//! missing field is null and, as in SQL, a comparison with null is neither
+//! true nor false.
+//!
+//! A query selecting fields decodes only the fields it reads of dag-cbor
+//! blocks, see the lazy module, unless links are inlined or `--root-cids`
+//! follows them, which needs the whole blocks.
+
+use std::cmp::Ordering;
+
+use anyhow::{anyhow, Result};
+use libipld::{Cid, Ipld};
+use sqlparser::{
+    ast::{
+        BinaryOperator, Expr, GroupByExpr, Ident, SelectItem, SetExpr, Statement, TableFactor,
+        UnaryOperator, Value,
+    },
+    dialect::GenericDialect,
+    parser::Parser,
+};
+
+#[derive(Debug)]
+pub struct Query {
+    /// Paths below `data` to keep, all fields if `None`.
+    fields: Option<Vec<Vec<String>>>,
+    filter: Option<Cond>,
+}
+
+#[derive(Debug)]
+enum Cond {
+    And(Box<Cond>, Box<Cond>),
+    Or(Box<Cond>, Box<Cond>),
+    Not(Box<Cond>),
+    Compare(Vec<String>, BinaryOperator, Ipld),
+    IsNull(Vec<String>, bool),
+}
+
+impl Query {
+    pub fn parse(sql: &str) -> Result<Self> {
+        let mut statements = Parser::parse_sql(&GenericDialect {}, sql)?;
+        let query = match (statements.pop(), statements.is_empty()) {
+            (Some(Statement::Query(query)), true) => query,
+            _ => return Err(anyhow!("expected a single SELECT statement")),
+        };
+        if query.with.is_some()
+            || !query.order_by.is_empty()
+            || query.limit.is_some()
+            || query.offset.is_some()
+        {
+            return Err(anyhow!(
+                "only SELECT .. FROM blocks [WHERE ..] is supported, without WITH, ORDER BY, LIMIT or OFFSET"
+            ));
+        }
+        let SetExpr::Select(select) = *query.body else {
+            return Err(anyhow!("expected a plain SELECT"));
+        };
+        let grouped = !matches!(&select.group_by, GroupByExpr::Expressions(e) if e.is_empty());
+        if select.distinct.is_some() || grouped || select.having.is_some() {
+            return Err(anyhow!(
+                "DISTINCT, GROUP BY and HAVING are not supported, rows are blocks"
+            ));
+        }
+        match select.from.as_slice() {
+            [t] if t.joins.is_empty()
+                && matches!(&t.relation, TableFactor::Table { name, .. } if name.to_string() == "blocks") =>
+                {}
+            _ => return Err(anyhow!("expected FROM blocks")),
+        }
+
+        let mut fields = Some(Vec::new());
+        for item in &select.projection {
+            match item {
+                SelectItem::Wildcard(_) => fields = None,
+                SelectItem::UnnamedExpr(expr) => {
+                    let path = path(expr)?;
+                    match path.split_first() {
+                        Some((root, _)) if root == "cid" => {}
+                        Some((_, [])) => fields = None,
+                        Some((_, rest)) => {
+                            if let Some(fields) = &mut fields {
+                                fields.push(rest.to_vec());
+                            }
+                        }
+                        None => {}
+                    }
+                }
+                _ => {
+                    return Err(anyhow!(
+                        "unsupported select item {}, expected * or a path",
+                        item
+                    ))
+                }
+            }
+        }
+        if fields.as_ref().is_some_and(|f| f.is_empty()) {
+            return Err(anyhow!(
+                "select * or at least one data field, cid is always written"
+            ));
+        }
+        let filter = select.selection.as_ref().map(cond).transpose()?;
+        Ok(Query { fields, filter })
+    }
+
+    /// The query of all the blocks that pass the condition, the clause of a
+    /// `WHERE` alone such as `data.type = 3`.
+    pub fn filter(condition: &str) -> Result<Self> {
+        Self::parse(&format!("SELECT * FROM blocks WHERE {}", condition))
+    }
+
+    /// Whether the block passes the filter.
+    pub fn matches(&self, cid: &Cid, data: &Ipld) -> bool {
+        match &self.filter {
+            Some(filter) => eval(filter, cid, data) == Some(true),
+            None => true,
+        }
+    }
+
+    /// Paths below `data` the query reads, selected or filtered on, `None`
+    /// if it needs the whole data.
+    pub fn paths(&self) -> Option<Vec<Vec<String>>> {
+        let mut paths = self.fields.clone()?;
+        if let Some(filter) = &self.filter {
+            filter_paths(filter, &mut paths);
+        }
+        if paths.iter().any(|p| p.is_empty()) {
+            return None;
+        }
+        Some(paths)
+    }
+
+    /// Keeps only the selected fields of the data, returning false if none of
+    /// them are present as a row without fields cannot be written.
+    pub fn project(&self, data: &mut Ipld) -> bool {
+        let Some(fields) = &self.fields else {
+            return true;
+        };
+        let mut projected = Ipld::Map(Default::default());
+        for path in fields {
+            if let Some(v) = get(data, path) {
+                insert(&mut projected, path, v.clone());
+            }
+        }
+        *data = projected;
+        !matches!(data, Ipld::Map(m) if m.is_empty())
+    }
+}
+
+/// Path of a column, starting at `cid` or `data`.
+fn path(expr: &Expr) -> Result<Vec<String>> {
+    let idents: &[Ident] = match expr {
+        Expr::Identifier(i) => std::slice::from_ref(i),
+        Expr::CompoundIdentifier(idents) => idents,
+        _ => {
+            return Err(anyhow!(
+                "expected a column such as data.height, got {}",
+                expr
+            ))
+        }
+    };
+    let path: Vec<String> = idents.iter().map(|i| i.value.clone()).collect();
+    match path.split_first() {
+        Some((root, [])) if root == "cid" => Ok(path),
+        Some((root, _)) if root == "data" => Ok(path),
+        _ => Err(anyhow!(
+            "unknown column {}, expected cid or data.<field>",
+            expr
+        )),
+    }
+}
+
+fn literal(expr: &Expr) -> Option<Ipld> {
+    Some(match expr {
+        Expr::Value(Value::Number(n, _)) => match n.parse::<i128>() {
+            Ok(i) => Ipld::Integer(i),
+            Err(_) => Ipld::Float(n.parse().ok()?),
+        },
+        Expr::Value(Value::SingleQuotedString(s) | Value::DoubleQuotedString(s)) => {
+            Ipld::String(s.clone())
+        }
+        Expr::Identifier(Ident {
+            value,
+            quote_style: Some('"'),
+        }) => Ipld::String(value.clone()),
+        Expr::Value(Value::Boolean(b)) => Ipld::Bool(*b),
+        Expr::Value(Value::Null) => Ipld::Null,
+        Expr::UnaryOp {
+            op: UnaryOperator::Minus,
+            expr,
+        } => match literal(expr)? {
+            Ipld::Integer(i) => Ipld::Integer(-i),
+            Ipld::Float(f) => Ipld::Float(-f),
+            _ => return None,
+        },
+        _ => return None,
+    })
+}
+
+fn cond(expr: &Expr) -> Result<Cond> {
+    Ok(match expr {
+        Expr::Nested(e) => cond(e)?,
+        Expr::UnaryOp {
+            op: UnaryOperator::Not,
+            expr,
+        } => Cond::Not(Box::new(cond(expr)?)),
+        Expr::IsNull(e) => Cond::IsNull(path(e)?, true),
+        Expr::IsNotNull(e) => Cond::IsNull(path(e)?, false),
+        Expr::BinaryOp { left, op, right } => match op {
+            BinaryOperator::And => Cond::And(Box::new(cond(left)?), Box::new(cond(right)?)),
+            BinaryOperator::Or => Cond::Or(Box::new(cond(left)?), Box::new(cond(right)?)),
+            BinaryOperator::Eq
+            | BinaryOperator::NotEq
+            | BinaryOperator::Lt
+            | BinaryOperator::LtEq
+            | BinaryOperator::Gt
+            | BinaryOperator::GtEq => {
+                if let Some(value) = literal(right) {
+                    Cond::Compare(path(left)?, op.clone(), value)
+                } else if let Some(value) = literal(left) {
+                    Cond::Compare(path(right)?, flip(op), value)
+                } else {
+                    return Err(anyhow!(
+                        "expected a comparison of a column with a literal, got {}",
+                        expr
+                    ));
+                }
+            }
+            _ => return Err(anyhow!("unsupported operator {}", op)),
+        },
+        // A bare column is a boolean field.
+        Expr::Identifier(_) | Expr::CompoundIdentifier(_) => {
+            Cond::Compare(path(expr)?, BinaryOperator::Eq, Ipld::Bool(true))
+        }
+        _ => return Err(anyhow!("unsupported condition {}", expr)),
+    })
+}
+
+/// The operator with its operands swapped, `1 < a` is `a > 1`.
+fn flip(op: &BinaryOperator) -> BinaryOperator {
+    match op {
+        BinaryOperator::Lt => BinaryOperator::Gt,
+        BinaryOperator::LtEq => BinaryOperator::GtEq,
+        BinaryOperator::Gt => BinaryOperator::Lt,
+        BinaryOperator::GtEq => BinaryOperator::LtEq,
+        op => op.clone(),
+    }
+}
+
+/// Evaluates the condition with SQL's three valued logic, `None` is unknown.
+fn eval(cond: &Cond, cid: &Cid, data: &Ipld) -> Option<bool> {
+    match cond {
+        Cond::And(a, b) => match (eval(a, cid, data), eval(b, cid, data)) {
+            (Some(false), _) | (_, Some(false)) => Some(false),
+            (Some(true), Some(true)) => Some(true),
+            _ => None,
+        },
+        Cond::Or(a, b) => match (eval(a, cid, data), eval(b, cid, data)) {
+            (Some(true), _) | (_, Some(true)) => Some(true),
+            (Some(false), Some(false)) => Some(false),
+            _ => None,
+        },
+        Cond::Not(c) => eval(c, cid, data).map(|b| !b),
+        Cond::IsNull(path, is_null) => {
+            let null = matches!(resolve(path, cid, data), None | Some(Ipld::Null));
+            Some(null == *is_null)
+        }
+        Cond::Compare(path, op, value) => {
+            let ordering = compare(&resolve(path, cid, data)?, value)?;
+            Some(match op {
+                BinaryOperator::Eq => ordering == Ordering::Equal,
+                BinaryOperator::NotEq => ordering != Ordering::Equal,
+                BinaryOperator::Lt => ordering == Ordering::Less,
+                BinaryOperator::LtEq => ordering != Ordering::Greater,
+                BinaryOperator::Gt => ordering == Ordering::Greater,
+                BinaryOperator::GtEq => ordering != Ordering::Less,
+                _ => return None,
+            })
+        }
+    }
+}
+
+/// Adds the paths below `data` the condition compares.
+fn filter_paths(cond: &Cond, out: &mut Vec<Vec<String>>) {
+    match cond {
+        Cond::And(a, b) | Cond::Or(a, b) => {
+            filter_paths(a, out);
+            filter_paths(b, out);
+        }
+        Cond::Not(c) => filter_paths(c, out),
+        Cond::Compare(path, _, _) | Cond::IsNull(path, _) => match path.split_first() {
+            Some((root, [])) if root == "cid" => {}
+            Some((_, rest)) => out.push(rest.to_vec()),
+            None => {}
+        },
+    }
+}
+
+fn resolve(path: &[String], cid: &Cid, data: &Ipld) -> Option<Ipld> {
+    match path.split_first()? {
+        (root, []) if root == "cid" => Some(Ipld::Link(*cid)),
+        (_, rest) => get(data, rest).cloned(),
+    }
+}
+
+/// Orders a value against a literal, `None` if they cannot be compared.
+fn compare(value: &Ipld, literal: &Ipld) -> Option<Ordering> {
+    match (value, literal) {
+        (Ipld::Integer(a), Ipld::Integer(b)) => Some(a.cmp(b)),
+        (Ipld::Integer(a), Ipld::Float(b)) => (*a as f64).partial_cmp(b),
+        (Ipld::Float(a), Ipld::Integer(b)) => a.partial_cmp(&(*b as f64)),
+        (Ipld::Float(a), Ipld::Float(b)) => a.partial_cmp(b),
+        (Ipld::String(a), Ipld::String(b)) => Some(a.as_str().cmp(b)),
+        (Ipld::Bool(a), Ipld::Bool(b)) => Some(a.cmp(b)),
+        (Ipld::Link(cid), Ipld::String(s)) => Some(cid.to_string().as_str().cmp(s)),
+        _ => None,
+    }
+}
+
+fn get<'a>(data: &'a Ipld, path: &[String]) -> Option<&'a Ipld> {
+    path.iter().try_fold(data, |data, p| match data {
+        Ipld::Map(m) => m.get(p),
+        _ => None,
+    })
+}
+
+fn insert(data: &mut Ipld, path: &[String], value: Ipld) {
+    let (Some((p, rest)), Ipld::Map(m)) = (path.split_first(), data) else {
+        return;
+    };
+    if rest.is_empty() {
+        m.insert(p.clone(), value);
+    } else {
+        let child = m
+            .entry(p.clone())
+            .or_insert_with(|| Ipld::Map(Default::default()));
+        insert(child, rest, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libipld::{
+        ipld,
+        multihash::{Code, MultihashDigest},
+    };
+
+    use super::*;
+
+    fn cid() -> Cid {
+        Cid::new_v1(0x71, Code::Sha2_256.digest(b"block"))
+    }
+
+    fn matches(condition: &str, data: &Ipld) -> bool {
+        Query::filter(condition).unwrap().matches(&cid(), data)
+    }
+
+    fn path(p: &str) -> Vec<String> {
+        p.split('.').map(str::to_string).collect()
+    }
+
+    #[test]
+    fn compares_fields_with_literals() {
+        let data = ipld!({"height": 12, "type": "post", "score": 0.5, "ok": true});
+        assert!(matches("data.height > 10", &data));
+        assert!(matches("10 < data.height", &data));
+        assert!(!matches("data.height <= 11", &data));
+        assert!(matches("data.height = 12.0", &data));
+        assert!(matches("data.type = 'post'", &data));
+        assert!(matches("data.type = \"post\"", &data));
+        assert!(matches("data.type <> 'like'", &data));
+        assert!(matches("data.score >= -1", &data));
+        assert!(matches("data.ok", &data));
+        assert!(matches(
+            "data.height > 10 AND NOT data.type = 'like'",
+            &data
+        ));
+        assert!(matches(
+            "(data.height < 0 OR data.ok) AND data.score < 1",
+            &data
+        ));
+    }
+
+    #[test]
+    fn compares_the_cid_as_a_string() {
+        let condition = format!("cid = '{}'", cid());
+        assert!(matches(&condition, &ipld!({})));
+    }
+
+    #[test]
+    fn null_comparisons_are_unknown() {
+        let data = ipld!({"a": null});
+        assert!(matches("data.missing IS NULL", &data));
+        assert!(matches("data.a IS NULL", &data));
+        assert!(!matches("data.a IS NOT NULL", &data));
+        assert!(!matches("data.missing = 1", &data));
+        assert!(!matches("NOT data.missing = 1", &data));
+        assert!(matches("data.missing = 1 OR data.a IS NULL", &data));
+        // Strings and integers do not compare.
+        assert!(!matches(
+            "data.a = 'x' OR data.missing <> 1",
+            &ipld!({"a": 1})
+        ));
+    }
+
+    #[test]
+    fn projects_the_selected_fields() {
+        let query = Query::parse("SELECT cid, data.a.b, data.c FROM blocks").unwrap();
+        let mut data = ipld!({"a": {"b": 1, "x": 2}, "c": "c", "d": 3});
+        assert!(query.project(&mut data));
+        assert_eq!(data, ipld!({"a": {"b": 1}, "c": "c"}));
+        let mut data = ipld!({"d": 3});
+        assert!(!query.project(&mut data));
+
+        let query = Query::parse("SELECT * FROM blocks").unwrap();
+        let mut data = ipld!({"d": 3});
+        assert!(query.project(&mut data));
+        assert_eq!(data, ipld!({"d": 3}));
+    }
+
+    #[test]
+    fn reads_the_selected_and_filtered_paths() {
+        let query = Query::parse("SELECT data.a FROM blocks WHERE data.b.c = 1 AND cid <> 'x'");
+        assert_eq!(query.unwrap().paths(), Some(vec![path("a"), path("b.c")]));
+        let query = Query::parse("SELECT data FROM blocks WHERE data.b = 1").unwrap();
+        assert_eq!(query.paths(), None);
+        let query = Query::parse("SELECT data.a FROM blocks WHERE data IS NULL").unwrap();
+        assert_eq!(query.paths(), None);
+    }
+
+    #[test]
+    fn rejects_unsupported_queries() {
+        for sql in [
+            "SELECT * FROM blocks; SELECT * FROM blocks",
+            "DELETE FROM blocks",
+            "SELECT * FROM other",
+            "SELECT * FROM blocks JOIN other ON blocks.cid = other.cid",
+            "SELECT * FROM blocks ORDER BY data.a",
+            "SELECT * FROM blocks LIMIT 1",
+            "SELECT DISTINCT data.a FROM blocks",
+            "SELECT data.a FROM blocks GROUP BY data.a",
+            "SELECT cid FROM blocks",
+            "SELECT count(*) FROM blocks",
+            "SELECT other.a FROM blocks",
+            "SELECT * FROM blocks WHERE data.a = data.b",
+            "SELECT * FROM blocks WHERE data.a LIKE 'x%'",
+        ] {
+            assert!(Query::parse(sql).is_err(), "{sql}");
+        }
+    }
+}
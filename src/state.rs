@@ -0,0 +1,108 @@
+//! The blocks already written to an output directory, kept by `--append`.
+//!
+//! The state file `_carquet_state` lists the CIDs each run wrote, after a
+//! line numbering the run:
+//!
+//! ```text
+//! # run 0
+//! bafyreib..
+//! # run 1
+//! bafyreic..
+//! ```
+//!
+//! Blocks listed are skipped, so converting an updated CAR or one overlapping
+//! those converted before writes only the blocks that are new, into files
+//! named after the run, `schema_0-1.parquet`. The CIDs of a run are added to
+//! the file once it succeeds; a failed run is repeated with the same number,
+//! replacing the files it wrote.
+
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::{Context, Result};
+use libipld::Cid;
+
+/// Name of the state file within the output directory.
+pub(crate) const FILE: &str = "_carquet_state";
+
+#[derive(Debug, Default)]
+pub(crate) struct State {
+    path: PathBuf,
+    written: HashSet<Cid>,
+    /// Number of the runs listed, that of this one.
+    run: usize,
+    added: Mutex<HashSet<Cid>>,
+}
+
+impl State {
+    /// Loads the state of the directory, without runs if it has no file yet.
+    pub(crate) fn load(dir: &Path) -> Result<Self> {
+        let path = dir.join(FILE);
+        let mut state = Self {
+            path,
+            ..Self::default()
+        };
+        let file = match File::open(&state.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(state),
+            Err(e) => return Err(e).context(format!("opening {}", state.path.display())),
+        };
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.context(format!("reading {}", state.path.display()))?;
+            let line = line.trim();
+            if line.starts_with('#') {
+                state.run += 1;
+            } else if !line.is_empty() {
+                let cid = Cid::try_from(line).context(format!(
+                    "reading line {} of {}",
+                    i + 1,
+                    state.path.display()
+                ))?;
+                state.written.insert(cid);
+            }
+        }
+        Ok(state)
+    }
+
+    /// Number of this run, 0 for the first.
+    pub(crate) fn run(&self) -> usize {
+        self.run
+    }
+
+    /// Whether the block is new, to be written by this run, adding it to
+    /// the run if so.
+    pub(crate) fn admit(&self, cid: &Cid) -> bool {
+        if self.written.contains(cid) {
+            return false;
+        }
+        self.added.lock().expect("state lock").insert(*cid);
+        true
+    }
+
+    /// Adds the CIDs of this run to the file, unless it had none.
+    pub(crate) fn save(&self) -> Result<()> {
+        let added = self.added.lock().expect("state lock");
+        if added.is_empty() {
+            return Ok(());
+        }
+        let file = File::options()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .context(format!("opening {}", self.path.display()))?;
+        let mut out = BufWriter::new(file);
+        writeln!(out, "# run {}", self.run)?;
+        let mut cids: Vec<String> = added.iter().map(Cid::to_string).collect();
+        cids.sort_unstable();
+        for cid in cids {
+            writeln!(out, "{}", cid)?;
+        }
+        out.flush()
+            .context(format!("writing {}", self.path.display()))
+    }
+}
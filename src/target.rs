@@ -0,0 +1,226 @@
+//! Target schemas read from existing parquet files.
+//!
+//! Appending to an existing table requires the new files to have exactly its
+//! columns. The schema of the table is mapped back to the schema of blocks
+//! and the column options that reproduce it, so every block can be checked
+//! against it before anything is written. The top level columns are named
+//! as by `--cid-column-name`, `--data-column-name` and `--no-wrapper`.
+
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use parquet::{
+    basic::{ConvertedType, LogicalType, Repetition, Type as PhysicalType},
+    file::reader::{FileReader, SerializedFileReader},
+    schema::types::Type,
+};
+
+use crate::{field_path, Columns, IntType, Schema, TimeUnit};
+
+/// The schema of an existing table and the options needed to write it.
+#[derive(Debug)]
+pub struct Target {
+    /// File name of the table without extension.
+    pub name: String,
+    pub schema: Schema,
+    pub int_types: Vec<(String, IntType)>,
+    /// Paths of lists of links written as LIST annotated groups.
+    pub link_lists: Vec<String>,
+}
+
+impl Target {
+    pub fn load(path: &Path, columns: &Columns) -> Result<Self> {
+        let f = std::fs::File::open(path).context(format!("opening {}", path.display()))?;
+        let reader = SerializedFileReader::new(f).context(format!("reading {}", path.display()))?;
+        let mut target = Target {
+            name: path
+                .file_stem()
+                .map(|s| s.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            schema: Schema::Null,
+            int_types: Vec::new(),
+            link_lists: Vec::new(),
+        };
+        let root = reader.metadata().file_metadata().schema();
+        // The root keeps its column order, nested maps are sorted like inferred schemas.
+        let mut fields = Vec::new();
+        let mut data = Vec::new();
+        if columns.cid.is_none() {
+            fields.push(("cid".to_string(), Schema::Bytes));
+        }
+        for field in root.get_fields() {
+            if field.name() == "_conversion_errors" {
+                continue;
+            }
+            let internal = columns.internal(&[field.name().to_string()]);
+            let schema = target
+                .field(field, &internal.join("."))
+                .context(format!("mapping the schema of {}", path.display()))?;
+            match internal.as_slice() {
+                [root, name] if root == "data" => data.push((name.clone(), schema)),
+                _ => fields.push((internal.join("."), schema)),
+            }
+        }
+        if columns.data.is_none() {
+            fields.push(("data".to_string(), Schema::Map(data)));
+        }
+        target.schema = Schema::Map(fields);
+        Ok(target)
+    }
+
+    /// Maps a field, including its repetition.
+    fn field(&mut self, field: &Type, path: &str) -> Result<Schema> {
+        let value = self.value(field, path)?;
+        Ok(match field.get_basic_info().repetition() {
+            Repetition::REPEATED => Schema::List(Box::new(value)),
+            Repetition::OPTIONAL => Schema::Optional(Box::new(value)),
+            Repetition::REQUIRED => value,
+        })
+    }
+
+    /// Maps a list within a list, whose elements may be lists again.
+    fn nested_list(&mut self, field: &Type, path: &str) -> Result<Schema> {
+        let element = list_element(field, path)?;
+        let schema = if is_list(element) {
+            self.nested_list(element, path)?
+        } else {
+            self.value(element, path)?
+        };
+        Ok(Schema::List(Box::new(optional_element(element, schema))))
+    }
+
+    /// Maps the values of a field, ignoring its repetition.
+    fn value(&mut self, field: &Type, path: &str) -> Result<Schema> {
+        let info = field.get_basic_info();
+        if field.is_group() {
+            if is_list(field) {
+                let element = list_element(field, path)?;
+                if is_list(element) {
+                    let list = self.nested_list(element, path)?;
+                    return Ok(Schema::List(Box::new(optional_element(element, list))));
+                }
+                if element.get_basic_info().repetition() == Repetition::OPTIONAL {
+                    let schema = self.value(element, path)?;
+                    return Ok(Schema::List(Box::new(optional_element(element, schema))));
+                }
+                if !element.is_primitive()
+                    || element.get_physical_type() != PhysicalType::BYTE_ARRAY
+                {
+                    return Err(anyhow!(
+                        "{}: LIST annotated lists are only written for links, lists of lists and lists with null elements",
+                        path
+                    ));
+                }
+                self.link_lists.push(path.to_string());
+                return Ok(Schema::List(Box::new(Schema::Link)));
+            }
+            let mut fields = field
+                .get_fields()
+                .iter()
+                .map(|f| {
+                    Ok((
+                        f.name().to_string(),
+                        self.field(f, &field_path(path, f.name()))?,
+                    ))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            fields.sort_by(|a, b| a.0.cmp(&b.0));
+            return Ok(Schema::Map(fields));
+        }
+        if info.logical_type() == Some(LogicalType::Unknown) {
+            return Ok(Schema::Null);
+        }
+        let int_type = match (field.get_physical_type(), info.logical_type()) {
+            (
+                PhysicalType::INT32 | PhysicalType::INT64,
+                Some(LogicalType::Integer {
+                    bit_width,
+                    is_signed,
+                }),
+            ) => Some(IntType::Int {
+                bits: bit_width as u8,
+                signed: is_signed,
+            }),
+            (PhysicalType::INT64, Some(LogicalType::Timestamp { unit, .. })) => {
+                Some(IntType::Timestamp {
+                    unit: match unit {
+                        parquet::basic::TimeUnit::MILLIS(_) => TimeUnit::Millis,
+                        parquet::basic::TimeUnit::MICROS(_) => TimeUnit::Micros,
+                        parquet::basic::TimeUnit::NANOS(_) => TimeUnit::Nanos,
+                    },
+                })
+            }
+            (PhysicalType::INT32, Some(LogicalType::Date)) => Some(IntType::Date),
+            (PhysicalType::INT32, None) => Some(IntType::Int {
+                bits: 32,
+                signed: true,
+            }),
+            (PhysicalType::INT64, None) => Some(IntType::Int {
+                bits: 64,
+                signed: true,
+            }),
+            (
+                PhysicalType::INT32 | PhysicalType::INT64 | PhysicalType::FIXED_LEN_BYTE_ARRAY,
+                Some(LogicalType::Decimal { precision, scale }),
+            ) => Some(IntType::Decimal {
+                precision: precision as u32,
+                scale: scale as u32,
+            }),
+            _ => None,
+        };
+        if let Some(int_type) = int_type {
+            if int_type.physical_type(false) != field.get_physical_type() {
+                return Err(anyhow!(
+                    "{}: {} stored as {} is not supported",
+                    path,
+                    int_type,
+                    field.get_physical_type()
+                ));
+            }
+            self.int_types.push((path.to_string(), int_type));
+            return Ok(Schema::Integer);
+        }
+        match field.get_physical_type() {
+            PhysicalType::BYTE_ARRAY if info.logical_type() == Some(LogicalType::Json) => {
+                Ok(Schema::Json)
+            }
+            PhysicalType::BOOLEAN => Ok(Schema::Bool),
+            PhysicalType::DOUBLE => Ok(Schema::Float),
+            PhysicalType::BYTE_ARRAY
+                if info.converted_type() == ConvertedType::UTF8
+                    || info.logical_type() == Some(LogicalType::String) =>
+            {
+                Ok(Schema::String)
+            }
+            // Links are stored as bytes, so they cannot be told apart.
+            PhysicalType::BYTE_ARRAY => Ok(Schema::Bytes),
+            t => Err(anyhow!("{}: {} columns are not supported", path, t)),
+        }
+    }
+}
+
+/// The schema of the element of a LIST group, optional if its elements may be null.
+fn optional_element(element: &Type, schema: Schema) -> Schema {
+    if element.get_basic_info().repetition() == Repetition::OPTIONAL {
+        Schema::Optional(Box::new(schema))
+    } else {
+        schema
+    }
+}
+
+fn is_list(field: &Type) -> bool {
+    let info = field.get_basic_info();
+    field.is_group()
+        && (info.logical_type() == Some(LogicalType::List)
+            || info.converted_type() == ConvertedType::LIST)
+}
+
+/// The element of the repeated `list` group of a LIST annotated group.
+fn list_element<'a>(field: &'a Type, path: &str) -> Result<&'a Type> {
+    field
+        .get_fields()
+        .first()
+        .and_then(|list| list.get_fields().first())
+        .map(|element| &**element)
+        .ok_or_else(|| anyhow!("{}: LIST group without an element", path))
+}
@@ -0,0 +1,205 @@
+//! Parsing the times of strings, for `--type-hint` and `--infer-times`.
+//!
+//! Times are RFC 3339, `2023-04-01T12:30:00.5Z` or with an offset such as
+//! `+02:00`, also with a space or a lowercase `t` between the date and the
+//! time, and dates are `2023-04-01`, for midnight UTC.
+
+/// Nanoseconds in a day.
+pub(crate) const NANOS_PER_DAY: i128 = 86_400_000_000_000;
+
+/// A time parsed from a string, in nanoseconds since the Unix epoch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Parsed {
+    pub(crate) nanos: i128,
+    /// Whether the string was a date without a time.
+    pub(crate) date_only: bool,
+}
+
+pub(crate) fn parse(s: &str) -> Option<Parsed> {
+    let b = s.as_bytes();
+    if b.len() < 10 || b[4] != b'-' || b[7] != b'-' {
+        return None;
+    }
+    let days = days_from_civil(digits(&b[0..4])?, digits(&b[5..7])?, digits(&b[8..10])?)?;
+    if b.len() == 10 {
+        return Some(Parsed {
+            nanos: days * NANOS_PER_DAY,
+            date_only: true,
+        });
+    }
+    if !matches!(b[10], b'T' | b't' | b' ') || b.len() < 19 || b[13] != b':' || b[16] != b':' {
+        return None;
+    }
+    let (hour, minute, second) = (
+        digits(&b[11..13])?,
+        digits(&b[14..16])?,
+        digits(&b[17..19])?,
+    );
+    // A leap second is counted as the first second of the next minute.
+    if hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+    let mut rest = &b[19..];
+    let mut fraction = 0;
+    if let Some((b'.', tail)) = rest.split_first() {
+        let n = tail.iter().take_while(|c| c.is_ascii_digit()).count();
+        if n == 0 {
+            return None;
+        }
+        // Digits beyond nanoseconds are dropped.
+        for (i, c) in tail[..n].iter().take(9).enumerate() {
+            fraction += i128::from(c - b'0') * 10i128.pow(8 - i as u32);
+        }
+        rest = &tail[n..];
+    }
+    let offset = match rest {
+        [b'Z' | b'z'] => 0,
+        [sign @ (b'+' | b'-'), h1, h2, b':', m1, m2] => {
+            let (h, m) = (digits(&[*h1, *h2])?, digits(&[*m1, *m2])?);
+            if h > 23 || m > 59 {
+                return None;
+            }
+            let offset = (h * 60 + m) * 60;
+            if *sign == b'-' {
+                -offset
+            } else {
+                offset
+            }
+        }
+        _ => return None,
+    };
+    let seconds = days * 86_400 + (hour * 60 + minute) * 60 + second - offset;
+    Some(Parsed {
+        nanos: seconds * 1_000_000_000 + fraction,
+        date_only: false,
+    })
+}
+
+/// Whether the last field of a path is named like a time, e.g. `createdAt`,
+/// `updated_at`, `timestamp` or `birthDate`.
+pub(crate) fn named_like_time(path: &str) -> bool {
+    let name = path.rsplit('.').next().unwrap_or(path);
+    let lower = name.to_ascii_lowercase();
+    name.ends_with("At")
+        || lower.ends_with("_at")
+        || ["time", "date"].iter().any(|word| lower.contains(word))
+}
+
+fn digits(b: &[u8]) -> Option<i128> {
+    b.iter().try_fold(0, |n, c| {
+        c.is_ascii_digit().then(|| n * 10 + i128::from(c - b'0'))
+    })
+}
+
+/// Days since the Unix epoch of a date of the proleptic Gregorian calendar,
+/// `None` if there is no such day.
+fn days_from_civil(year: i128, month: i128, day: i128) -> Option<i128> {
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days_in_month = match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        1..=12 => 31,
+        _ => return None,
+    };
+    if !(1..=days_in_month).contains(&day) {
+        return None;
+    }
+    // Years start in March, so the leap day is the last of the year.
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    Some(era * 146_097 + day_of_era - 719_468)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: i128 = 1_000_000_000;
+
+    fn nanos(s: &str) -> i128 {
+        parse(s)
+            .unwrap_or_else(|| panic!("{s} did not parse"))
+            .nanos
+    }
+
+    #[test]
+    fn parses_dates() {
+        assert_eq!(
+            parse("1970-01-01"),
+            Some(Parsed {
+                nanos: 0,
+                date_only: true
+            })
+        );
+        assert_eq!(nanos("1969-12-31"), -NANOS_PER_DAY);
+        assert_eq!(nanos("2000-02-29"), 11_016 * NANOS_PER_DAY);
+        assert_eq!(nanos("0001-01-01"), -719_162 * NANOS_PER_DAY);
+        for s in [
+            "1900-02-29",
+            "2001-02-29",
+            "2023-04-31",
+            "2023-13-01",
+            "2023-00-10",
+        ] {
+            assert_eq!(parse(s), None, "{s}");
+        }
+    }
+
+    #[test]
+    fn parses_rfc_3339_times() {
+        let noon = 1_680_352_200 * SECOND;
+        assert_eq!(nanos("2023-04-01T12:30:00Z"), noon);
+        assert_eq!(nanos("2023-04-01t12:30:00z"), noon);
+        assert_eq!(nanos("2023-04-01 12:30:00Z"), noon);
+        assert_eq!(nanos("2023-04-01T14:30:00+02:00"), noon);
+        assert_eq!(nanos("2023-04-01T07:00:00-05:30"), noon);
+        assert_eq!(nanos("2023-04-01T12:30:00.5Z"), noon + SECOND / 2);
+        assert!(!parse("2023-04-01T12:30:00Z").unwrap().date_only);
+        // Digits beyond nanoseconds are dropped.
+        assert_eq!(nanos("1970-01-01T00:00:00.1234567899Z"), 123_456_789);
+        assert_eq!(nanos("1969-12-31T23:59:59.5Z"), -SECOND / 2);
+        // A leap second is the first second of the next minute.
+        assert_eq!(nanos("2016-12-31T23:59:60Z"), 1_483_228_800 * SECOND);
+    }
+
+    #[test]
+    fn rejects_other_strings() {
+        for s in [
+            "",
+            "post",
+            "2023-4-01",
+            "2023-04-01T",
+            "2023-04-01T12:30Z",
+            "2023-04-01T12:30:00",
+            "2023-04-01T12:30:00.Z",
+            "2023-04-01T24:00:00Z",
+            "2023-04-01T12:60:00Z",
+            "2023-04-01T12:30:61Z",
+            "2023-04-01T12:30:00+02:60",
+            "2023-04-01T12:30:00+0200",
+            "2023-04-01X12:30:00Z",
+            "1680352200",
+        ] {
+            assert_eq!(parse(s), None, "{s}");
+        }
+    }
+
+    #[test]
+    fn names_like_times() {
+        for path in [
+            "data.createdAt",
+            "data.updated_at",
+            "data.timestamp",
+            "data.birthDate",
+        ] {
+            assert!(named_like_time(path), "{path}");
+        }
+        for path in ["data.height", "data.seq", "data.format", "data.cat"] {
+            assert!(!named_like_time(path), "{path}");
+        }
+    }
+}
@@ -0,0 +1,215 @@
+//! Writer properties chosen for each table from its schema and a sample of its
+//! rows, unless `--default-writer-props` keeps those of the parquet crate.
+//!
+//! The parquet defaults suit tables of a few columns of small values, and
+//! blocks come in every shape from maps of a few integers to records of
+//! thousands of columns or of large byte strings. From the first rows of a
+//! table:
+//!
+//! - Columns other than strings whose values are nearly all distinct, such as
+//!   CIDs, hashes, signatures and heights, are written without a dictionary.
+//!   The writer would otherwise build one, find it no smaller than the values
+//!   and fall back to plain encoding only once the dictionary page is full,
+//!   paying for both. Strings keep it, as names and kinds repeat across a
+//!   table more than its first rows show.
+//! - Data pages are sized to hold about [`PAGE_VALUES`] values, so the page
+//!   indexes of columns of small values can skip in small steps while pages of
+//!   large values stay at the default size.
+//! - Row groups are cut at about [`ROW_GROUP_BYTES`], or the size given by
+//!   `--row-group-size`, instead of a fixed number of rows, so narrow rows do
+//!   not make many tiny row groups and wide rows are not all buffered at once.
+
+use std::{
+    collections::{hash_map::DefaultHasher, HashSet},
+    hash::{Hash, Hasher},
+};
+
+use libipld::Ipld;
+use parquet::{
+    basic::ConvertedType,
+    file::properties::WriterPropertiesBuilder,
+    schema::types::{ColumnPath, SchemaDescriptor},
+};
+
+use crate::{
+    column_types, option_path, resolve_entries, Block, Columns, Config, Resolved, RowGroupSize,
+};
+
+/// Rows of a table looked at to choose its properties.
+const SAMPLE_ROWS: usize = 1000;
+
+/// Values a column must have in the sample for its dictionary to be judged.
+const MIN_VALUES: usize = 100;
+
+/// Share of distinct values above which a column is written without dictionary.
+const MAX_DISTINCT: f64 = 0.9;
+
+/// Values aimed for in each data page.
+pub const PAGE_VALUES: usize = 20_000;
+const MIN_PAGE_BYTES: usize = 64 * 1024;
+const MAX_PAGE_BYTES: usize = 1024 * 1024;
+
+/// Size aimed for of the row groups, uncompressed.
+pub const ROW_GROUP_BYTES: usize = 128 * 1024 * 1024;
+const MIN_ROW_GROUP_ROWS: usize = 1_000;
+const MAX_ROW_GROUP_ROWS: usize = 1_000_000;
+
+/// The properties chosen for a table.
+#[derive(Debug)]
+pub struct Tuning {
+    /// Output paths of the columns written without dictionary.
+    no_dictionary: Vec<ColumnPath>,
+    page_bytes: Option<usize>,
+    /// Rows of the row groups, a file is written as one if `None`.
+    pub row_group_rows: Option<usize>,
+}
+
+impl Tuning {
+    /// Chooses the properties of the table of the parquet schema from its
+    /// first rows, with those given by the options in place of the chosen ones.
+    pub fn new(
+        descr: &SchemaDescriptor,
+        rows: &[Block],
+        columns: &Columns,
+        config: &Config,
+    ) -> Self {
+        let sample = &rows[..rows.len().min(SAMPLE_ROWS)];
+        let mut tuning = Tuning {
+            no_dictionary: Vec::new(),
+            page_bytes: config.page_bytes,
+            row_group_rows: None,
+        };
+        // Rows sized in bytes need the sizes of all the columns.
+        let measure = !config.default_writer_props
+            || matches!(config.row_group_size, Some(RowGroupSize::Bytes(_)));
+        let (mut values, mut bytes) = (0, 0);
+        for i in 0..descr.num_columns() {
+            let desc = descr.column(i);
+            let Ok(types) = column_types(descr.get_column_root(i), &desc) else {
+                continue;
+            };
+            let path = ColumnPath::new(columns.internal(desc.path().parts()));
+            let stats = if measure {
+                column_stats(sample, &path, &types)
+            } else {
+                ColumnStats::default()
+            };
+            values += stats.values;
+            bytes += stats.bytes;
+            let dictionary = match config.dictionary.get(&option_path(&path, &types)) {
+                Some(on) => *on,
+                None if config.default_writer_props => true,
+                None if matches!(
+                    desc.converted_type(),
+                    ConvertedType::UTF8 | ConvertedType::JSON
+                ) =>
+                {
+                    true
+                }
+                None => {
+                    stats.values < MIN_VALUES
+                        || (stats.distinct as f64) <= MAX_DISTINCT * stats.values as f64
+                }
+            };
+            if !dictionary {
+                tuning.no_dictionary.push(desc.path().clone());
+            }
+        }
+        let row_bytes = bytes.div_ceil(sample.len().max(1)).max(1);
+        tuning.row_group_rows = match config.row_group_size {
+            Some(RowGroupSize::Rows(rows)) => Some(rows),
+            Some(RowGroupSize::Bytes(b)) => Some((b / row_bytes).max(1)),
+            None if config.default_writer_props || sample.is_empty() => None,
+            None => {
+                Some((ROW_GROUP_BYTES / row_bytes).clamp(MIN_ROW_GROUP_ROWS, MAX_ROW_GROUP_ROWS))
+            }
+        };
+        if config.default_writer_props || sample.is_empty() {
+            return tuning;
+        }
+        if tuning.page_bytes.is_none() && values > 0 {
+            let value_bytes = bytes.div_ceil(values).max(1);
+            tuning.page_bytes =
+                Some((value_bytes * PAGE_VALUES).clamp(MIN_PAGE_BYTES, MAX_PAGE_BYTES));
+        }
+        tuning
+    }
+
+    pub fn apply(&self, mut props: WriterPropertiesBuilder) -> WriterPropertiesBuilder {
+        for path in &self.no_dictionary {
+            props = props.set_column_dictionary_enabled(path.clone(), false);
+        }
+        if let Some(bytes) = self.page_bytes {
+            props = props.set_data_pagesize_limit(bytes);
+        }
+        props
+    }
+}
+
+#[derive(Debug, Default)]
+struct ColumnStats {
+    values: usize,
+    distinct: usize,
+    bytes: usize,
+}
+
+fn column_stats(
+    rows: &[Block],
+    path: &ColumnPath,
+    types: &[&parquet::schema::types::Type],
+) -> ColumnStats {
+    let mut stats = ColumnStats::default();
+    let mut seen = HashSet::new();
+    for (cid, data, raw) in rows {
+        for (value, _, _) in resolve_entries(cid, data, raw, path, types) {
+            if let Resolved::Value(v) = value {
+                stats.values += 1;
+                stats.bytes += value_bytes(&v);
+                let mut h = DefaultHasher::new();
+                hash_value(&v, &mut h);
+                seen.insert(h.finish());
+            }
+        }
+    }
+    stats.distinct = seen.len();
+    stats
+}
+
+/// Rough size of the value as written, before encoding and compression.
+fn value_bytes(v: &Ipld) -> usize {
+    match v {
+        Ipld::Null => 0,
+        Ipld::Bool(_) => 1,
+        Ipld::Integer(_) | Ipld::Float(_) => 8,
+        Ipld::String(s) => s.len(),
+        Ipld::Bytes(b) => b.len(),
+        Ipld::Link(cid) => 4 + usize::from(cid.hash().size()),
+        Ipld::List(l) => l.iter().map(value_bytes).sum(),
+        Ipld::Map(m) => m.iter().map(|(k, v)| k.len() + value_bytes(v)).sum(),
+    }
+}
+
+fn hash_value(v: &Ipld, h: &mut DefaultHasher) {
+    match v {
+        Ipld::Null => h.write_u8(0),
+        Ipld::Bool(b) => b.hash(h),
+        Ipld::Integer(i) => i.hash(h),
+        Ipld::Float(f) => f.to_bits().hash(h),
+        Ipld::String(s) => s.hash(h),
+        Ipld::Bytes(b) => b.hash(h),
+        Ipld::Link(cid) => Hash::hash(cid, h),
+        Ipld::List(l) => {
+            h.write_usize(l.len());
+            for v in l {
+                hash_value(v, h);
+            }
+        }
+        Ipld::Map(m) => {
+            h.write_usize(m.len());
+            for (k, v) in m {
+                k.hash(h);
+                hash_value(v, h);
+            }
+        }
+    }
+}
@@ -0,0 +1,206 @@
+//! Decoding of dag-cbor blocks whose text strings are not valid UTF-8.
+//!
+//! The dag-cbor codec rejects such blocks outright. This decoder is only used
+//! as a fallback for them and applies a policy to each invalid string instead.
+
+use clap::ValueEnum;
+use libipld::{Cid, Ipld};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::diag::{argument, f16_to_f64, next, take, MAX_DEPTH};
+
+/// What to do with text strings that are not valid UTF-8.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Utf8Policy {
+    /// Fail the block.
+    #[default]
+    Reject,
+    /// Replace invalid sequences with U+FFFD.
+    Lossy,
+    /// Keep the string value as bytes, so it lands in a bytes column. Map keys
+    /// cannot be bytes and are replaced lossily.
+    Bytes,
+}
+
+/// Decodes a dag-cbor block, applying the policy to invalid text strings.
+/// Returns the number of strings the policy was applied to alongside the value.
+pub fn decode(bytes: &[u8], policy: Utf8Policy) -> Result<(Ipld, usize), String> {
+    let mut decoder = Decoder { policy, invalid: 0 };
+    let mut pos = 0;
+    let ipld = decoder.item(bytes, &mut pos, 0, false)?;
+    if pos != bytes.len() {
+        return Err(format!("{} trailing bytes", bytes.len() - pos));
+    }
+    Ok((ipld, decoder.invalid))
+}
+
+struct Decoder {
+    policy: Utf8Policy,
+    invalid: usize,
+}
+
+fn length(bytes: &[u8], pos: &mut usize, info: u8) -> Result<u64, String> {
+    argument(bytes, pos, info)?.ok_or_else(|| "indefinite length items are not dag-cbor".into())
+}
+
+impl Decoder {
+    fn text(&mut self, s: &[u8], key: bool) -> Result<Ipld, String> {
+        let e = match std::str::from_utf8(s) {
+            Ok(s) => return Ok(Ipld::String(s.to_string())),
+            Err(e) => e,
+        };
+        self.invalid += 1;
+        match self.policy {
+            Utf8Policy::Reject => Err(e.to_string()),
+            Utf8Policy::Bytes if !key => Ok(Ipld::Bytes(s.to_vec())),
+            _ => Ok(Ipld::String(String::from_utf8_lossy(s).into_owned())),
+        }
+    }
+
+    fn item(
+        &mut self,
+        bytes: &[u8],
+        pos: &mut usize,
+        depth: usize,
+        key: bool,
+    ) -> Result<Ipld, String> {
+        if depth > MAX_DEPTH {
+            return Err("nesting too deep".to_string());
+        }
+        let initial = next(bytes, pos)?;
+        let info = initial & 0x1f;
+        Ok(match initial >> 5 {
+            0 => Ipld::Integer(length(bytes, pos, info)? as i128),
+            1 => Ipld::Integer(-1 - length(bytes, pos, info)? as i128),
+            2 => {
+                let n = length(bytes, pos, info)?;
+                Ipld::Bytes(take(bytes, pos, n)?.to_vec())
+            }
+            3 => {
+                let n = length(bytes, pos, info)?;
+                self.text(take(bytes, pos, n)?, key)?
+            }
+            4 => {
+                let n = length(bytes, pos, info)?;
+                let mut list = Vec::new();
+                for _ in 0..n {
+                    list.push(self.item(bytes, pos, depth + 1, false)?);
+                }
+                Ipld::List(list)
+            }
+            5 => {
+                let n = length(bytes, pos, info)?;
+                let mut map = BTreeMap::new();
+                for _ in 0..n {
+                    let key = match self.item(bytes, pos, depth + 1, true)? {
+                        Ipld::String(key) => key,
+                        key => return Err(format!("map key {:?} is not a string", key)),
+                    };
+                    map.insert(key, self.item(bytes, pos, depth + 1, false)?);
+                }
+                Ipld::Map(map)
+            }
+            6 => match length(bytes, pos, info)? {
+                42 => match self.item(bytes, pos, depth + 1, false)? {
+                    Ipld::Bytes(b) if b.first() == Some(&0) => {
+                        Ipld::Link(Cid::try_from(&b[1..]).map_err(|e| e.to_string())?)
+                    }
+                    _ => return Err("tag 42 must be bytes with a 0x00 prefix".to_string()),
+                },
+                tag => return Err(format!("unsupported tag {}", tag)),
+            },
+            _ => match info {
+                20 => Ipld::Bool(false),
+                21 => Ipld::Bool(true),
+                22 => Ipld::Null,
+                25 => {
+                    let b = take(bytes, pos, 2)?;
+                    Ipld::Float(f16_to_f64(u16::from_be_bytes([b[0], b[1]])))
+                }
+                26 => {
+                    let b = take(bytes, pos, 4)?;
+                    Ipld::Float(f32::from_be_bytes([b[0], b[1], b[2], b[3]]) as f64)
+                }
+                27 => {
+                    let b = take(bytes, pos, 8)?;
+                    let mut f = [0; 8];
+                    f.copy_from_slice(b);
+                    Ipld::Float(f64::from_be_bytes(f))
+                }
+                _ => return Err(format!("unsupported simple value {}", info)),
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libipld::cbor::DagCborCodec;
+    use libipld::multihash::{Code, MultihashDigest};
+    use libipld::prelude::Codec;
+    use libipld::{cid::Version, ipld};
+
+    // {"a": "\xff", "\xfe": 1}: an invalid value and an invalid key.
+    const INVALID: &[u8] = &[0xa2, 0x61, b'a', 0x61, 0xff, 0x61, 0xfe, 0x01];
+
+    #[test]
+    fn reject_fails_the_block() {
+        assert!(decode(INVALID, Utf8Policy::Reject).is_err());
+    }
+
+    #[test]
+    fn lossy_replaces_values_and_keys() {
+        let (ipld, invalid) = decode(INVALID, Utf8Policy::Lossy).unwrap();
+        assert_eq!(ipld, ipld!({"a": "\u{fffd}", "\u{fffd}": 1}));
+        assert_eq!(invalid, 2);
+    }
+
+    #[test]
+    fn bytes_keeps_values_as_bytes_and_replaces_keys() {
+        let (ipld, invalid) = decode(INVALID, Utf8Policy::Bytes).unwrap();
+        let mut map = BTreeMap::new();
+        map.insert("a".to_string(), Ipld::Bytes(vec![0xff]));
+        map.insert("\u{fffd}".to_string(), Ipld::Integer(1));
+        assert_eq!(ipld, Ipld::Map(map));
+        assert_eq!(invalid, 2);
+    }
+
+    #[test]
+    fn valid_blocks_decode_as_the_codec_does() {
+        let cid = Cid::new(Version::V1, 0x71, Code::Sha2_256.digest(b"x")).unwrap();
+        let value = ipld!({
+            "text": "héllo",
+            "bytes": Ipld::Bytes(vec![1, 2, 3]),
+            "ints": [0, 23, 24, 255, 65536, -1, -25, i64::MIN],
+            "float": 1.5,
+            "flags": [true, false, null],
+            "link": cid,
+            "nested": {"list": [[{"k": "v"}]]},
+        });
+        let bytes = DagCborCodec.encode(&value).unwrap();
+        for policy in [Utf8Policy::Reject, Utf8Policy::Lossy, Utf8Policy::Bytes] {
+            assert_eq!(decode(&bytes, policy).unwrap(), (value.clone(), 0));
+        }
+    }
+
+    #[test]
+    fn malformed_blocks_are_rejected_under_every_policy() {
+        let mut trailing = INVALID.to_vec();
+        trailing.push(0x00);
+        let cases: &[&[u8]] = &[
+            &trailing,
+            &INVALID[..5],
+            &[0x9f, 0xff],
+            &[0xa1, 0x01, 0x01],
+            &[0xd8, 0x2a, 0x41, 0x01],
+        ];
+        for bytes in cases {
+            for policy in [Utf8Policy::Reject, Utf8Policy::Lossy, Utf8Policy::Bytes] {
+                assert!(decode(bytes, policy).is_err(), "{:x?} {:?}", bytes, policy);
+            }
+        }
+    }
+}
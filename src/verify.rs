@@ -0,0 +1,87 @@
+//! Checking a written CAR by reading it again, `--verify`.
+//!
+//! The header must be that of a CARv1, or a CARv2 whose data is one, every
+//! section must be framed within the file, every block must hash to its CID
+//! and every root must be one of the blocks. The index of a CARv2 must find
+//! each block at its section, and hold no more entries than there are blocks.
+
+use std::{collections::HashSet, io::BufReader, path::Path};
+
+use anyhow::{anyhow, Context, Result};
+use libipld::{
+    multihash::{Code, MultihashDigest},
+    Cid,
+};
+
+use crate::{
+    carv2::{self, Index},
+    scan::{self, Sections},
+};
+
+/// What was checked of a CAR.
+#[derive(Debug, Default)]
+pub struct Verified {
+    pub blocks: u64,
+    /// Blocks of hash functions this build does not have, not checked.
+    pub unchecked: u64,
+}
+
+/// Reads the CAR again, returning an error for the first thing wrong with it.
+pub fn verify(path: &Path) -> Result<Verified> {
+    verify_car(path).context(format!("verifying {}", path.display()))
+}
+
+fn verify_car(path: &Path) -> Result<Verified> {
+    let mut car = Sections::new(BufReader::with_capacity(
+        scan::BUFFER,
+        carv2::open(path, 0)?,
+    ))?;
+    let mut missing: HashSet<Cid> = car.roots().iter().copied().collect();
+    let mut index = Index::open(path)?;
+    let mut verified = Verified::default();
+    loop {
+        let offset = car.position();
+        let Some((cid, data)) = car.next_block()? else {
+            break;
+        };
+        if !check_hash(&cid, &data)? {
+            verified.unchecked += 1;
+        }
+        missing.remove(&cid);
+        if let Some(index) = &mut index {
+            if !index.find(&cid)?.contains(&offset) {
+                return Err(anyhow!(
+                    "the index does not find block {} at {}",
+                    cid,
+                    offset
+                ));
+            }
+        }
+        verified.blocks += 1;
+    }
+    if let Some(root) = missing.iter().next() {
+        return Err(anyhow!("root {} is not a block of the CAR", root));
+    }
+    if let Some(index) = &index {
+        if index.len() > verified.blocks {
+            return Err(anyhow!(
+                "the index has {} entries for {} blocks",
+                index.len(),
+                verified.blocks
+            ));
+        }
+    }
+    Ok(verified)
+}
+
+/// Checks the bytes hash to the CID, returning whether they could be: `false`
+/// for hash functions this build does not have.
+pub(crate) fn check_hash(cid: &Cid, bytes: &[u8]) -> Result<bool> {
+    let Ok(code) = Code::try_from(cid.hash().code()) else {
+        return Ok(false);
+    };
+    if code.digest(bytes).digest() != cid.hash().digest() {
+        return Err(anyhow!("the bytes of block {} do not match its hash", cid));
+    }
+    Ok(true)
+}
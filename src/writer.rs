@@ -0,0 +1,482 @@
+//! Writing the rows of a table to a parquet or JSON lines file.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io::Write,
+    path::Path,
+    sync::Arc,
+};
+
+use anyhow::{anyhow, Context, Result};
+use libipld::Ipld;
+use parquet::{
+    basic::Repetition,
+    file::{
+        metadata::KeyValue,
+        properties::{EnabledStatistics, WriterProperties},
+        writer::SerializedFileWriter,
+    },
+    schema::types::{SchemaDescPtr, SchemaDescriptor, Type},
+};
+
+use crate::{
+    capability_gaps, exploded_lists, field_path, is_exploded_list, json, null_non_finite,
+    parquet_schema::parquet_schema,
+    remove_path,
+    shred::{col_desc, parquet_write_col, write_errors_col, RowErrors},
+    without_fields, Block, CidIndex, Columns, Config, Error, Failures, Format, IntType, Schema,
+    Tuning, CONSTANTS_KEY,
+};
+
+/// Writes the rows to a file, returning the values that failed to convert.
+///
+/// A file that fails part way, e.g. because the conversion was cancelled, is
+/// removed rather than left truncated.
+pub fn write_output(
+    path: &Path,
+    schema: &Schema,
+    rows: &[Block],
+    constants: &[(String, Ipld)],
+    columns: &Columns,
+    config: &Config,
+    cid_index: Option<&mut CidIndex>,
+) -> Result<Failures> {
+    if config.cancel.is_cancelled() {
+        return Err(Error::Cancelled.into());
+    }
+    let result =
+        OutputFile::create(path, schema, rows, constants, columns, config).and_then(|mut file| {
+            file.write(schema, rows, config, cid_index)?;
+            file.close()
+        });
+    if result.is_err() {
+        let _ = std::fs::remove_file(path);
+    }
+    result
+}
+
+/// An output file open for writing rows in batches, each batch of a parquet
+/// file is written as row groups of at most `row_group_rows` rows.
+pub struct OutputFile {
+    /// File name, under which the CIDs of the row groups are indexed.
+    name: String,
+    writer: OutputWriter,
+    columns: Columns,
+    /// Rows of the row groups, chosen for the table, each batch is one if `None`.
+    pub row_group_rows: Option<usize>,
+    /// Number of row groups written.
+    row_groups: usize,
+    /// Number of rows written.
+    rows: usize,
+    /// Values nulled by lenient conversion, and those of the fields left out.
+    failures: Failures,
+    /// Fields left out of a parquet file, see capability_gaps.
+    gaps: Vec<(String, &'static str)>,
+    /// Types of the integer and time columns, chosen from the first rows.
+    ints: HashMap<String, IntType>,
+}
+
+pub enum OutputWriter {
+    Parquet(SerializedFileWriter<std::fs::File>, SchemaDescPtr),
+    Jsonl(std::io::BufWriter<std::fs::File>),
+}
+
+impl OutputFile {
+    /// Creates the file, choosing its writer properties from the first rows.
+    pub fn create(
+        path: &Path,
+        schema: &Schema,
+        first: &[Block],
+        constants: &[(String, Ipld)],
+        columns: &Columns,
+        config: &Config,
+    ) -> Result<Self> {
+        let mut gaps = Vec::new();
+        let mut row_group_rows = None;
+        let mut ints = HashMap::new();
+        let writer = match config.format {
+            Format::Parquet => {
+                gaps = capability_gaps(schema, "", config);
+                let paths: Vec<&str> = gaps.iter().map(|(p, _)| p.as_str()).collect();
+                let schema = without_fields(schema, "", &paths);
+                ints = config.int_types_of(first);
+                let (writer, descr, tuning) =
+                    create_parquet(path, &schema, first, constants, columns, config, &ints)?;
+                row_group_rows = tuning.row_group_rows;
+                OutputWriter::Parquet(writer, descr)
+            }
+            Format::Jsonl => {
+                OutputWriter::Jsonl(std::io::BufWriter::new(std::fs::File::create(path)?))
+            }
+        };
+        Ok(Self {
+            name: path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned(),
+            writer,
+            columns: columns.clone(),
+            row_group_rows,
+            ints,
+            row_groups: 0,
+            rows: 0,
+            failures: Failures::default(),
+            gaps,
+        })
+    }
+
+    /// Writes the rows, recording the CIDs of each row group in the index if any.
+    pub fn write(
+        &mut self,
+        schema: &Schema,
+        rows: &[Block],
+        config: &Config,
+        mut cid_index: Option<&mut CidIndex>,
+    ) -> Result<()> {
+        let group_rows = self.row_group_rows.unwrap_or(rows.len()).max(1);
+        for rows in rows.chunks(group_rows) {
+            if let Some(index) = &mut cid_index {
+                index.push(&self.name, self.row_groups, rows);
+            }
+            let failures = match &mut self.writer {
+                OutputWriter::Parquet(writer, descr) => write_row_group(
+                    writer,
+                    descr,
+                    &self.ints,
+                    rows,
+                    self.rows,
+                    &self.columns,
+                    config,
+                )?,
+                OutputWriter::Jsonl(out) => {
+                    write_jsonl(out, schema, rows, self.rows, &self.columns, config)?
+                }
+            };
+            self.failures.append(failures);
+            for (path, what) in &self.gaps {
+                self.failures.gap(path, what, rows);
+            }
+            self.row_groups += 1;
+            self.rows += rows.len();
+        }
+        Ok(())
+    }
+
+    /// Finishes the file, returning the values that failed to convert or were left out.
+    pub fn close(self) -> Result<Failures> {
+        match self.writer {
+            OutputWriter::Parquet(writer, _) => {
+                writer.close()?;
+            }
+            OutputWriter::Jsonl(mut out) => out.flush()?,
+        }
+        Ok(self.failures)
+    }
+}
+
+pub fn write_jsonl(
+    out: &mut impl Write,
+    schema: &Schema,
+    rows: &[Block],
+    first_row: usize,
+    columns: &Columns,
+    config: &Config,
+) -> Result<Failures> {
+    let fields = match schema {
+        Schema::Map(m) => m,
+        _ => return Err(anyhow!("expected a map schema for rows")),
+    };
+    let exploded = exploded_lists(schema, "", config);
+    let mut line = String::new();
+    let mut failures = Failures::default();
+    for (i, (cid, data, bytes)) in rows.iter().enumerate() {
+        if config.cancel.is_cancelled() {
+            return Err(Error::Cancelled.into());
+        }
+        // Mirrors the columns of the parquet output, see resolve_entries.
+        let mut row = BTreeMap::new();
+        for (name, _) in fields {
+            let value = match name.as_str() {
+                "cid" => match &columns.cid {
+                    Some(column) => (column.clone(), Ipld::Link(*cid)),
+                    None => continue,
+                },
+                "parent_cid" => (name.clone(), Ipld::Link(*cid)),
+                "data" => {
+                    let mut data = data.clone();
+                    for path in &exploded {
+                        let parts: Vec<&str> = path.split('.').skip(1).collect();
+                        remove_path(&mut data, &parts);
+                    }
+                    match (&columns.data, data) {
+                        (Some(column), data) => (column.clone(), data),
+                        (None, Ipld::Map(m)) => {
+                            if let Some(k) = m.keys().find(|k| row.contains_key(*k)) {
+                                return Err(anyhow!(
+                                    "block {} has a field {} with the name of another column",
+                                    cid,
+                                    k
+                                ));
+                            }
+                            row.extend(m);
+                            continue;
+                        }
+                        (None, _) => {
+                            return Err(anyhow!(
+                                "block {} has data that is not a map, which has no fields to write as columns",
+                                cid
+                            ))
+                        }
+                    }
+                }
+                "rawdata" => (name.clone(), Ipld::Bytes(bytes.clone())),
+                "block_size" => (name.clone(), Ipld::Integer(bytes.len() as i128)),
+                _ => (name.clone(), data.get(name.as_str())?.clone()),
+            };
+            row.insert(value.0, value.1);
+        }
+        let mut row = Ipld::Map(row);
+        line.clear();
+        if let Err(e) = json::write_json(&mut line, &row, config.json) {
+            if !config.lenient {
+                return Err(e.context(format!("encoding block {}", cid)));
+            }
+            // Floats JSON cannot represent are the only values that fail to encode.
+            let mut errors = Vec::new();
+            null_non_finite(&mut row, "", &mut errors);
+            let errors = errors
+                .into_iter()
+                .map(|(path, reason)| {
+                    let error = Ipld::String(format!("{}: {}", path, reason));
+                    failures.push(&path, reason, None, (first_row + i, cid));
+                    error
+                })
+                .collect();
+            if let Ipld::Map(m) = &mut row {
+                m.insert("_conversion_errors".to_string(), Ipld::List(errors));
+            }
+            failures.rows += 1;
+            line.clear();
+            json::write_json(&mut line, &row, config.json)?;
+        }
+        line.push('\n');
+        out.write_all(line.as_bytes())?;
+    }
+    Ok(failures)
+}
+
+pub fn create_parquet(
+    path: &Path,
+    schema: &Schema,
+    first: &[Block],
+    constants: &[(String, Ipld)],
+    columns: &Columns,
+    config: &Config,
+    ints: &HashMap<String, IntType>,
+) -> Result<(SerializedFileWriter<std::fs::File>, SchemaDescPtr, Tuning)> {
+    let mut fields = root_fields(schema, columns, config, ints)?;
+    if config.lenient {
+        fields.push(Arc::new(
+            Type::primitive_type_builder("_conversion_errors", parquet::basic::Type::BYTE_ARRAY)
+                .with_repetition(Repetition::REPEATED)
+                .with_converted_type(parquet::basic::ConvertedType::UTF8)
+                .build()?,
+        ));
+    }
+    let mut names = HashSet::new();
+    if let Some(dup) = fields.iter().find(|f| !names.insert(f.name())) {
+        return Err(Error::Schema {
+            path: dup.name().to_string(),
+            reason: "more than one top level column has this name".to_string(),
+        }
+        .into());
+    }
+    let p_schema = Arc::new(
+        Type::group_type_builder("")
+            .with_fields(&mut fields)
+            .build()?,
+    );
+    if config.verbose > 1 {
+        eprintln!("p schema: {:#?}", p_schema);
+    }
+    let mut metadata = Vec::new();
+    if !constants.is_empty() {
+        // Constant fields are stored once as a DAG-JSON map from path to value.
+        let constants = Ipld::Map(
+            constants
+                .iter()
+                .map(|(path, v)| (columns.output(path), v.clone()))
+                .collect(),
+        );
+        let mut value = String::new();
+        json::write_json(&mut value, &constants, config.json)?;
+        metadata.push(KeyValue::new(CONSTANTS_KEY.to_string(), value));
+    }
+    // The page indexes are built from the page statistics.
+    let mut props = WriterProperties::builder()
+        .set_compression(config.compression.parquet(config.compression_level)?)
+        .set_statistics_enabled(if config.page_index {
+            EnabledStatistics::Page
+        } else {
+            EnabledStatistics::Chunk
+        })
+        .set_key_value_metadata((!metadata.is_empty()).then_some(metadata));
+    if let Some(rows) = config.page_rows {
+        // The row limit is only checked between batches of written values,
+        // which are 1024 by default.
+        props = props
+            .set_data_page_row_count_limit(rows)
+            .set_write_batch_size(rows.min(1024));
+    }
+    let descr = Arc::new(SchemaDescriptor::new(p_schema.clone()));
+    let tuning = Tuning::new(&descr, first, columns, config);
+    let props = Arc::new(tuning.apply(props).build());
+    let f = std::fs::File::create(path)?;
+    Ok((
+        SerializedFileWriter::new(f, p_schema, props)?,
+        descr,
+        tuning,
+    ))
+}
+
+/// Top level columns of a table, named by `columns` for the `cid` and `data`
+/// fields of a block table.
+pub fn root_fields(
+    schema: &Schema,
+    columns: &Columns,
+    config: &Config,
+    ints: &HashMap<String, IntType>,
+) -> Result<Vec<Arc<Type>>> {
+    let Schema::Map(m) = schema else {
+        return Err(anyhow!("expected a map schema for rows"));
+    };
+    let mut fields = Vec::new();
+    for (k, v) in m {
+        if is_exploded_list(v, k, config) {
+            continue;
+        }
+        match (k.as_str(), v) {
+            ("cid", _) => {
+                if let Some(name) = &columns.cid {
+                    fields.push((
+                        k.clone(),
+                        parquet_schema(v, name, k, false, false, config, ints)?,
+                    ));
+                }
+            }
+            // Data whose fields are all exploded lists has no columns of its own.
+            ("data", Schema::Map(data))
+                if !data.is_empty()
+                    && data
+                        .iter()
+                        .all(|(dk, dv)| is_exploded_list(dv, &field_path(k, dk), config)) => {}
+            ("data", _) if columns.data.is_some() => {
+                let name = columns.data.as_deref().unwrap_or(k);
+                fields.push((
+                    k.clone(),
+                    parquet_schema(v, name, k, false, false, config, ints)?,
+                ));
+            }
+            ("data", Schema::Map(data)) => {
+                for (dk, dv) in data {
+                    let path = field_path(k, dk);
+                    if !is_exploded_list(dv, &path, config) {
+                        let field = parquet_schema(dv, dk, &path, false, false, config, ints)?;
+                        fields.push((path, field));
+                    }
+                }
+            }
+            ("data", _) => {
+                return Err(Error::Schema {
+                    path: k.clone(),
+                    reason: "the data is not a map, so it has no fields to write as columns"
+                        .to_string(),
+                }
+                .into())
+            }
+            _ => fields.push((
+                k.clone(),
+                parquet_schema(v, k, k, false, false, config, ints)?,
+            )),
+        }
+    }
+    if fields.is_empty() {
+        return Err(Error::Schema {
+            path: String::new(),
+            reason: "the table has no columns".to_string(),
+        }
+        .into());
+    }
+    order_fields(&mut fields, config);
+    Ok(fields.into_iter().map(|(_, f)| Arc::new(f)).collect())
+}
+
+/// Moves the fields, each with its path, listed by `--column-order` to the
+/// front in the listed order, keeping the order of the others.
+pub fn order_fields<T>(fields: &mut [(String, T)], config: &Config) {
+    if config.column_order.is_empty() {
+        return;
+    }
+    fields.sort_by_key(|(path, _)| {
+        config
+            .column_order
+            .iter()
+            .position(|p| p == path)
+            .unwrap_or(usize::MAX)
+    });
+}
+
+/// Writes the rows as a row group, returning the values that failed to convert.
+/// Rows are numbered within the file from `first_row`.
+///
+/// Columns are written with their levels directly rather than through arrow
+/// record batches and the `ArrowWriter`, which derives the parquet schema
+/// from the arrow one: it would write every list as a LIST group, where
+/// lists are repeated fields by default, and cannot write JSON columns,
+/// legacy INT96 timestamps or the nulls of values that failed to convert.
+#[allow(clippy::too_many_arguments)]
+pub fn write_row_group(
+    writer: &mut SerializedFileWriter<std::fs::File>,
+    descr: &SchemaDescriptor,
+    ints: &HashMap<String, IntType>,
+    rows: &[Block],
+    first_row: usize,
+    columns: &Columns,
+    config: &Config,
+) -> Result<Failures> {
+    let mut errors: RowErrors = vec![Vec::new(); rows.len()];
+    let mut failures = Failures::default();
+    let mut row_group_writer = writer.next_row_group().context("creating row group")?;
+    let mut column = 0;
+    while let Some(mut col_writer) = row_group_writer.next_column().context("next column")? {
+        let root = descr.get_column_root(column);
+        column += 1;
+        if config.cancel.is_cancelled() {
+            return Err(Error::Cancelled.into());
+        }
+        let desc = col_desc(&mut col_writer);
+        let path = desc.path().string();
+        if path == "_conversion_errors" {
+            write_errors_col(&mut col_writer, &errors).context("writing conversion errors")?;
+        } else {
+            parquet_write_col(
+                &mut col_writer,
+                root,
+                ints,
+                rows,
+                first_row,
+                columns,
+                config,
+                config.lenient.then_some(&mut errors),
+                &mut failures,
+            )?;
+        }
+        col_writer
+            .close()
+            .context(format!("closing col_writer {} {}", path, rows.len()))?;
+    }
+    row_group_writer.close()?;
+    failures.rows = errors.iter().filter(|e| !e.is_empty()).count();
+    Ok(failures)
+}
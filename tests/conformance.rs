@@ -0,0 +1,18 @@
+//! The golden file checks of the presets, see `carquet::conformance`.
+
+#[tokio::test]
+async fn presets_match_golden_files() {
+    if let Err(e) = carquet::conformance::check_all(&carquet::conformance::fixtures()).await {
+        panic!("{:#}", e);
+    }
+}
+
+/// Lists of lists, of maps and of nulls, whose entries repeat and are defined
+/// at more than one level.
+#[tokio::test]
+async fn nested_lists_match_golden_file() {
+    let fixtures = carquet::conformance::fixtures();
+    if let Err(e) = carquet::conformance::check_fixture("nested", &[], &fixtures).await {
+        panic!("{:#}", e);
+    }
+}
@@ -0,0 +1,83 @@
+//! The schemas of manifest.json against the columns of the files it lists.
+
+use std::path::Path;
+
+use carquet::{conformance, CarToParquetConverter, ConvertArgs};
+use parquet::file::reader::{FileReader, SerializedFileReader};
+use serde_json::Value;
+
+/// Paths of the leaf columns of a schema as serialized in the manifest.
+fn leaves(schema: &Value, path: &str, out: &mut Vec<String>) {
+    match schema {
+        Value::Object(o) if o.contains_key("Map") => {
+            for field in o["Map"].as_array().unwrap() {
+                let name = field[0].as_str().unwrap();
+                let path = if path.is_empty() {
+                    name.to_string()
+                } else {
+                    format!("{path}.{name}")
+                };
+                leaves(&field[1], &path, out);
+            }
+        }
+        Value::Object(o) => {
+            let inner = o.get("List").or_else(|| o.get("Optional")).unwrap();
+            leaves(inner, path, out);
+        }
+        _ => out.push(path.to_string()),
+    }
+}
+
+fn columns(path: &Path) -> Vec<String> {
+    let reader = SerializedFileReader::new(std::fs::File::open(path).unwrap()).unwrap();
+    let descr = reader.metadata().file_metadata().schema_descr_ptr();
+    let mut columns: Vec<String> = descr
+        .columns()
+        .iter()
+        .map(|c| c.path().string())
+        .filter(|p| p != "_conversion_errors")
+        .collect();
+    columns.sort();
+    columns
+}
+
+#[tokio::test]
+async fn manifest_schemas_match_the_files() {
+    let fixtures = conformance::fixtures();
+    for preset in conformance::presets() {
+        for prune in [false, true] {
+            let dir = std::env::temp_dir().join(format!(
+                "carquet-manifest-{}-{}-{}",
+                preset,
+                prune,
+                std::process::id()
+            ));
+            let _ = std::fs::remove_dir_all(&dir);
+            let mut flags = vec!["--preset", &preset, "--manifest"];
+            if prune {
+                flags.push("--prune-constant-columns");
+            }
+            CarToParquetConverter::new(&dir)
+                .input(fixtures.join(format!("{}.car", preset)))
+                .options(ConvertArgs::from_flags(flags).unwrap())
+                .run()
+                .await
+                .unwrap();
+            let manifest: Value =
+                serde_json::from_reader(std::fs::File::open(dir.join("manifest.json")).unwrap())
+                    .unwrap();
+            for entry in manifest["files"].as_array().unwrap() {
+                let file = entry["file"].as_str().unwrap();
+                let mut expected = Vec::new();
+                leaves(&entry["schema"], "", &mut expected);
+                expected.sort();
+                assert_eq!(expected, columns(&dir.join(file)), "{preset} {file}");
+                let constants = entry.get("constants").and_then(Value::as_object);
+                for path in constants.into_iter().flat_map(|c| c.keys()) {
+                    assert!(!expected.contains(path), "{preset} {file} {path}");
+                }
+            }
+            let _ = std::fs::remove_dir_all(&dir);
+        }
+    }
+}